@@ -0,0 +1,67 @@
+// Throughput of the host side frame path: slot extraction, CRC and serialization
+//
+// cargo bench --bench extract
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use bladerf_adsb::crc;
+use bladerf_adsb::format::{to_beast, OutputFormat};
+use bladerf_adsb::frame::{extract_frames, AdsbFrame, SLOT_BYTES};
+
+// DF17 airborne identification and a DF11 all-call reply
+const LONG_FRAME: [u8; 14] = [0x8d, 0x48, 0x40, 0xd6, 0x20, 0x2c, 0xc3, 0x71, 0xc3, 0x2c, 0xe0, 0x57, 0x60, 0x98];
+const SHORT_FRAME: [u8; 7] = [0x5d, 0x48, 0x40, 0xd6, 0x20, 0x2c, 0xc3];
+
+// A 4096 byte FPGA buffer like a busy receiver produces: mostly empty slots, with a short
+// frame in every 8th and a long frame in every 16th
+fn realistic_buffer() -> Vec<u8> {
+  let mut buf = vec![0u8; 4096];
+  for (index, slot) in buf.chunks_exact_mut(SLOT_BYTES).enumerate() {
+    if index % 16 == 0 {
+      slot[0] = 0x01;
+      slot[2..2 + LONG_FRAME.len()].copy_from_slice(&LONG_FRAME);
+    } else if index % 8 == 0 {
+      slot[0] = 0x01;
+      slot[2..2 + SHORT_FRAME.len()].copy_from_slice(&SHORT_FRAME);
+    }
+  }
+  buf
+}
+
+fn extract_crc_avr(c: &mut Criterion) {
+  let buf = realistic_buffer();
+  let frames_per_buffer = extract_frames(&buf).len() as u64;
+
+  let mut group = c.benchmark_group("extract_crc_avr");
+  group.throughput(Throughput::Elements(frames_per_buffer));
+  group.bench_function("buffer", |b| {
+    b.iter(|| {
+      let mut bytes = 0;
+      for frame in extract_frames(black_box(&buf)) {
+        black_box(crc::syndrome(&frame.data));
+        bytes += OutputFormat::Avr.serialize(&frame, None, None).len();
+      }
+      bytes
+    })
+  });
+  group.finish();
+}
+
+fn beast_escaping(c: &mut Criterion) {
+  // timestamp, frame and checksum bytes full of 0x1a, the worst case for escaping
+  let frame = AdsbFrame {
+    data: vec![0x1a; 14],
+    slot: 0,
+    timestamp: Some(0x1a1a_1a1a_1a1a),
+    frequency: None,
+    snr: None,
+  };
+
+  let mut group = c.benchmark_group("beast");
+  group.throughput(Throughput::Elements(1));
+  group.bench_function("escaped_frame", |b| b.iter(|| to_beast(black_box(&frame))));
+  group.finish();
+}
+
+criterion_group!(benches, extract_crc_avr, beast_escaping);
+criterion_main!(benches);
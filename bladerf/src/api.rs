@@ -0,0 +1,324 @@
+use std::ffi::CStr;
+use std::ptr;
+
+use crate::bladerf::*;
+
+// Thin interface over the libbladeRF calls BladeRFDevice makes, so the higher level methods
+// can run against MockBladeRf instead of hardware. Each method mirrors the C function of the
+// same name: it returns the raw libbladeRF status code and writes results through out params.
+//
+// Implementations must be Send so a BladeRFDevice can be opened on one thread and handed to
+// another, but they are deliberately not required to be Sync.
+pub trait BladeRfApi: Send {
+	fn close(&self);
+	// the handle has to be closed afterwards, the device re-enumerates
+	fn device_reset(&self) -> libc::c_int;
+
+	// Device properties and information
+	fn get_devinfo(&self, info: &mut Struct_bladerf_devinfo) -> libc::c_int;
+	fn get_serial(&self, serial: &mut [libc::c_char; 33]) -> libc::c_int;
+	// raw bladerf_fpga_size value, which may be one this crate doesn't know yet
+	fn get_fpga_size(&self, size: &mut libc::c_int) -> libc::c_int;
+	fn fw_version(&self, version: &mut Struct_bladerf_version) -> libc::c_int;
+	fn fpga_version(&self, version: &mut Struct_bladerf_version) -> libc::c_int;
+	// 1 if configured, 0 if not, negative on error
+	fn is_fpga_configured(&self) -> libc::c_int;
+	fn device_speed(&self) -> bladerf_dev_speed;
+	fn get_board_name(&self) -> String;
+
+	// RF ports of the RFIC, by name (e.g. "A_BALANCED")
+	fn set_rf_port(&self, channel: bladerf_channel, port: &CStr) -> libc::c_int;
+	fn get_rf_port(&self, channel: bladerf_channel, port: &mut String) -> libc::c_int;
+	fn get_rf_ports(&self, channel: bladerf_channel, ports: &mut Vec<String>) -> libc::c_int;
+
+	// Module control
+	fn enable_module(&self, module: bladerf_module, enable: bool) -> libc::c_int;
+	fn enable_channel(&self, channel: bladerf_channel, enable: bool) -> libc::c_int;
+
+	// Gain
+	fn set_gain(&self, module: bladerf_module, gain: bladerf_gain) -> libc::c_int;
+	fn get_gain(&self, module: bladerf_module, gain: &mut bladerf_gain) -> libc::c_int;
+	fn set_gain_mode(&self, module: bladerf_module, mode: bladerf_gain_mode) -> libc::c_int;
+	fn get_gain_mode(&self, module: bladerf_module, mode: &mut bladerf_gain_mode) -> libc::c_int;
+	fn get_gain_range(&self, module: bladerf_module, range: &mut Struct_bladerf_range) -> libc::c_int;
+	// returns the number of modes written to `modes`
+	fn get_gain_modes(&self, module: bladerf_module, modes: &mut Vec<bladerf_gain_mode>) -> libc::c_int;
+
+	// Sampling, bandwidth and tuning
+	fn set_sample_rate(&self, module: bladerf_module, rate: u32, actual: &mut u32) -> libc::c_int;
+	fn get_sample_rate(&self, module: bladerf_module, rate: &mut u32) -> libc::c_int;
+	fn get_sample_rate_range(&self, module: bladerf_module, range: &mut Struct_bladerf_range) -> libc::c_int;
+	fn set_bandwidth(&self, module: bladerf_module, bandwidth: u32, actual: &mut u32) -> libc::c_int;
+	fn get_bandwidth(&self, module: bladerf_module, bandwidth: &mut u32) -> libc::c_int;
+	fn set_frequency(&self, module: bladerf_module, frequency: u32) -> libc::c_int;
+	fn get_frequency(&self, module: bladerf_module, frequency: &mut u64) -> libc::c_int;
+	fn get_frequency_range(&self, module: bladerf_module, range: &mut Struct_bladerf_range) -> libc::c_int;
+	fn set_bias_tee(&self, module: bladerf_module, enable: bool) -> libc::c_int;
+	fn get_bias_tee(&self, module: bladerf_module, enable: &mut bool) -> libc::c_int;
+
+	// RX sample source
+	fn set_rx_mux(&self, mux: bladerf_rx_mux) -> libc::c_int;
+	fn get_rx_mux(&self, mux: &mut bladerf_rx_mux) -> libc::c_int;
+
+	// Expansion boards
+	fn expansion_attach(&self, xb: bladerf_xb) -> libc::c_int;
+	fn expansion_get_attached(&self, xb: &mut bladerf_xb) -> libc::c_int;
+
+	// Synchronous streaming
+	fn sync_config(&self, layout: bladerf_channel_layout, format: bladerf_format, num_buffers: u32,
+				   buffer_size: u32, num_transfers: u32, stream_timeout: u32) -> libc::c_int;
+	fn sync_rx(&self, data: &mut [u8], num_samples: u32, meta: Option<&mut Struct_bladerf_metadata>,
+			   stream_timeout: u32) -> libc::c_int;
+
+	// Device loading and programming
+	fn load_fpga(&self, file: &CStr) -> libc::c_int;
+
+	// Reading back from the device, for backups
+	fn get_fw_log(&self, file: &CStr) -> libc::c_int;
+	fn get_flash_size(&self, size: &mut u32, is_guess: &mut bool) -> libc::c_int;
+	// reads `count` pages starting at `page`, buf must hold at least count * BLADERF_FLASH_PAGE_SIZE bytes
+	fn read_flash(&self, buf: &mut [u8], page: u32, count: u32) -> libc::c_int;
+}
+
+// The real implementation, calling straight into libbladeRF on an open device handle
+pub struct LibBladeRf {
+	device: *mut Struct_bladerf
+}
+
+impl LibBladeRf {
+	/// # Safety
+	///
+	/// `device` must be a handle from a successful bladerf_open, owned by the caller, that stays
+	/// open for the lifetime of this object (until close() is called)
+	pub unsafe fn from_raw(device: *mut Struct_bladerf) -> LibBladeRf {
+		LibBladeRf { device }
+	}
+}
+
+// The range queries hand back a pointer to a static table in libbladeRF, copy it out
+fn copy_range(res: libc::c_int, range_ptr: *const Struct_bladerf_range, range: &mut Struct_bladerf_range) -> libc::c_int {
+	if res >= 0 && !range_ptr.is_null() {
+		*range = unsafe { *range_ptr };
+	}
+	res
+}
+
+// SAFETY: raw pointers aren't Send, so this has to be asserted by hand. The handle points at a
+// heap allocated context that libbladeRF never ties to the thread that opened it (no thread
+// locals, nothing checks the caller's thread id), and every API call on it takes the handle's
+// own mutex first, as libbladeRF documents its functions as thread safe per device. Moving it
+// to the RX thread, which is all the binary does, is therefore sound. LibBladeRf stays !Sync,
+// so only one thread can hold it and it is never driven from two at once; the mutex is not
+// relied on for that. The handle is only freed by close(), after which it isn't used again.
+unsafe impl Send for LibBladeRf {}
+
+impl BladeRfApi for LibBladeRf {
+	fn close(&self) {
+		unsafe { bladerf_close(self.device) }
+	}
+
+	fn device_reset(&self) -> libc::c_int {
+		unsafe { bladerf_device_reset(self.device) }
+	}
+
+	fn get_devinfo(&self, info: &mut Struct_bladerf_devinfo) -> libc::c_int {
+		unsafe { bladerf_get_devinfo(self.device, info as *mut Struct_bladerf_devinfo) }
+	}
+
+	fn get_serial(&self, serial: &mut [libc::c_char; 33]) -> libc::c_int {
+		unsafe { bladerf_get_serial(self.device, serial.as_mut_ptr()) }
+	}
+
+	fn get_fpga_size(&self, size: &mut libc::c_int) -> libc::c_int {
+		unsafe { bladerf_get_fpga_size(self.device, size as *mut libc::c_int) }
+	}
+
+	fn fw_version(&self, version: &mut Struct_bladerf_version) -> libc::c_int {
+		unsafe { bladerf_fw_version(self.device, version as *mut Struct_bladerf_version) }
+	}
+
+	fn fpga_version(&self, version: &mut Struct_bladerf_version) -> libc::c_int {
+		unsafe { bladerf_fpga_version(self.device, version as *mut Struct_bladerf_version) }
+	}
+
+	fn is_fpga_configured(&self) -> libc::c_int {
+		unsafe { bladerf_is_fpga_configured(self.device) }
+	}
+
+	fn device_speed(&self) -> bladerf_dev_speed {
+		unsafe { bladerf_device_speed(self.device) }
+	}
+
+	fn get_board_name(&self) -> String {
+		let name = unsafe { bladerf_get_board_name(self.device) };
+		if name.is_null() {
+			return String::from("unknown");
+		}
+		unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned()
+	}
+
+	fn set_rf_port(&self, channel: bladerf_channel, port: &CStr) -> libc::c_int {
+		unsafe { bladerf_set_rf_port(self.device, channel, port.as_ptr()) }
+	}
+
+	fn get_rf_port(&self, channel: bladerf_channel, port: &mut String) -> libc::c_int {
+		let mut name: *const libc::c_char = ptr::null();
+		let res = unsafe { bladerf_get_rf_port(self.device, channel, &mut name) };
+		if res >= 0 && !name.is_null() {
+			*port = unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned();
+		}
+		res
+	}
+
+	fn get_rf_ports(&self, channel: bladerf_channel, ports: &mut Vec<String>) -> libc::c_int {
+		let count = unsafe { bladerf_get_rf_ports(self.device, channel, ptr::null_mut(), 0) };
+		if count <= 0 {
+			return count;
+		}
+
+		// the names are static strings owned by libbladeRF
+		let mut names: Vec<*const libc::c_char> = vec![ptr::null(); count as usize];
+		let res = unsafe { bladerf_get_rf_ports(self.device, channel, names.as_mut_ptr(), count as libc::c_uint) };
+		if res > 0 {
+			ports.extend(names.iter().take(res as usize).filter(|name| !name.is_null())
+				.map(|&name| unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned()));
+		}
+		res
+	}
+
+	fn enable_module(&self, module: bladerf_module, enable: bool) -> libc::c_int {
+		unsafe { bladerf_enable_module(self.device, module as bladerf_channel, enable as u8) }
+	}
+
+	fn enable_channel(&self, channel: bladerf_channel, enable: bool) -> libc::c_int {
+		unsafe { bladerf_enable_module(self.device, channel, enable as u8) }
+	}
+
+	fn set_gain(&self, module: bladerf_module, gain: bladerf_gain) -> libc::c_int {
+		unsafe { bladerf_set_gain(self.device, module, gain) }
+	}
+
+	fn get_gain(&self, module: bladerf_module, gain: &mut bladerf_gain) -> libc::c_int {
+		unsafe { bladerf_get_gain(self.device, module, gain as *mut bladerf_gain) }
+	}
+
+	fn set_gain_mode(&self, module: bladerf_module, mode: bladerf_gain_mode) -> libc::c_int {
+		unsafe { bladerf_set_gain_mode(self.device, module, mode) }
+	}
+
+	fn get_gain_mode(&self, module: bladerf_module, mode: &mut bladerf_gain_mode) -> libc::c_int {
+		unsafe { bladerf_get_gain_mode(self.device, module, mode as *mut bladerf_gain_mode) }
+	}
+
+	fn get_gain_range(&self, module: bladerf_module, range: &mut Struct_bladerf_range) -> libc::c_int {
+		let mut range_ptr: *const Struct_bladerf_range = ptr::null();
+		let res = unsafe { bladerf_get_gain_range(self.device, module, &mut range_ptr) };
+		copy_range(res, range_ptr, range)
+	}
+
+	fn get_gain_modes(&self, module: bladerf_module, modes: &mut Vec<bladerf_gain_mode>) -> libc::c_int {
+		let mut modes_ptr: *const Struct_bladerf_gain_modes = ptr::null();
+		let res = unsafe { bladerf_get_gain_modes(self.device, module, &mut modes_ptr) };
+		if res > 0 && !modes_ptr.is_null() {
+			// a static table of `res` entries owned by libbladeRF
+			let table = unsafe { std::slice::from_raw_parts(modes_ptr, res as usize) };
+			modes.extend(table.iter().map(|entry| entry.mode));
+		}
+		res
+	}
+
+	fn set_sample_rate(&self, module: bladerf_module, rate: u32, actual: &mut u32) -> libc::c_int {
+		unsafe { bladerf_set_sample_rate(self.device, module, rate, actual as *mut u32) }
+	}
+
+	fn get_sample_rate(&self, module: bladerf_module, rate: &mut u32) -> libc::c_int {
+		unsafe { bladerf_get_sample_rate(self.device, module, rate as *mut u32) }
+	}
+
+	fn get_sample_rate_range(&self, module: bladerf_module, range: &mut Struct_bladerf_range) -> libc::c_int {
+		let mut range_ptr: *const Struct_bladerf_range = ptr::null();
+		let res = unsafe { bladerf_get_sample_rate_range(self.device, module, &mut range_ptr) };
+		copy_range(res, range_ptr, range)
+	}
+
+	fn set_bandwidth(&self, module: bladerf_module, bandwidth: u32, actual: &mut u32) -> libc::c_int {
+		unsafe { bladerf_set_bandwidth(self.device, module, bandwidth, actual as *mut u32) }
+	}
+
+	fn get_bandwidth(&self, module: bladerf_module, bandwidth: &mut u32) -> libc::c_int {
+		unsafe { bladerf_get_bandwidth(self.device, module, bandwidth as *mut u32) }
+	}
+
+	fn set_frequency(&self, module: bladerf_module, frequency: u32) -> libc::c_int {
+		unsafe { bladerf_set_frequency(self.device, module, frequency) }
+	}
+
+	fn get_frequency(&self, module: bladerf_module, frequency: &mut u64) -> libc::c_int {
+		unsafe { bladerf_get_frequency(self.device, module, frequency as *mut u64) }
+	}
+
+	fn get_frequency_range(&self, module: bladerf_module, range: &mut Struct_bladerf_range) -> libc::c_int {
+		let mut range_ptr: *const Struct_bladerf_range = ptr::null();
+		let res = unsafe { bladerf_get_frequency_range(self.device, module, &mut range_ptr) };
+		copy_range(res, range_ptr, range)
+	}
+
+	fn set_bias_tee(&self, module: bladerf_module, enable: bool) -> libc::c_int {
+		unsafe { bladerf_set_bias_tee(self.device, module, enable) }
+	}
+
+	fn get_bias_tee(&self, module: bladerf_module, enable: &mut bool) -> libc::c_int {
+		unsafe { bladerf_get_bias_tee(self.device, module, enable as *mut bool) }
+	}
+
+	fn set_rx_mux(&self, mux: bladerf_rx_mux) -> libc::c_int {
+		unsafe { bladerf_set_rx_mux(self.device, mux) }
+	}
+
+	fn get_rx_mux(&self, mux: &mut bladerf_rx_mux) -> libc::c_int {
+		unsafe { bladerf_get_rx_mux(self.device, mux as *mut bladerf_rx_mux) }
+	}
+
+	fn expansion_attach(&self, xb: bladerf_xb) -> libc::c_int {
+		unsafe { bladerf_expansion_attach(self.device, xb) }
+	}
+
+	fn expansion_get_attached(&self, xb: &mut bladerf_xb) -> libc::c_int {
+		unsafe { bladerf_expansion_get_attached(self.device, xb as *mut bladerf_xb) }
+	}
+
+	fn sync_config(&self, layout: bladerf_channel_layout, format: bladerf_format, num_buffers: u32,
+				   buffer_size: u32, num_transfers: u32, stream_timeout: u32) -> libc::c_int {
+		unsafe {
+			bladerf_sync_config(self.device, layout, format, num_buffers, buffer_size, num_transfers, stream_timeout)
+		}
+	}
+
+	fn sync_rx(&self, data: &mut [u8], num_samples: u32, meta: Option<&mut Struct_bladerf_metadata>,
+			   stream_timeout: u32) -> libc::c_int {
+		let meta_ptr: *mut Struct_bladerf_metadata = match meta {
+			Some(m) => m as *mut Struct_bladerf_metadata,
+			None => ptr::null_mut()
+		};
+
+		unsafe {
+			bladerf_sync_rx(self.device, data.as_mut_ptr() as *mut libc::c_void, num_samples, meta_ptr, stream_timeout)
+		}
+	}
+
+	fn load_fpga(&self, file: &CStr) -> libc::c_int {
+		unsafe { bladerf_load_fpga(self.device, file.as_ptr()) }
+	}
+
+	fn get_fw_log(&self, file: &CStr) -> libc::c_int {
+		unsafe { bladerf_get_fw_log(self.device, file.as_ptr()) }
+	}
+
+	fn get_flash_size(&self, size: &mut u32, is_guess: &mut bool) -> libc::c_int {
+		unsafe { bladerf_get_flash_size(self.device, size as *mut u32, is_guess as *mut bool) }
+	}
+
+	fn read_flash(&self, buf: &mut [u8], page: u32, count: u32) -> libc::c_int {
+		unsafe { bladerf_read_flash(self.device, buf.as_mut_ptr(), page, count) }
+	}
+}
@@ -11,7 +11,7 @@ pub type bladerf_gain = i32;
 pub type bladerf_channel = i32;
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum bladerf_backend {
     BLADERF_BACKEND_ANY = 0,    
     BLADERF_BACKEND_LINUX = 1,  
@@ -21,7 +21,7 @@ pub enum bladerf_backend {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum bladerf_dev_speed {
     BLADERF_DEVICE_SPEED_UNKNOWN = 0,
     BLADERF_DEVICE_SPEED_HIGH = 1,
@@ -110,7 +110,7 @@ impl ::std::default::Default for bladerf_sampling {
     fn default() -> Self { unsafe { ::std::mem::zeroed() } }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(C)]
 pub enum bladerf_gain_mode {
   BLADERF_GAIN_DEFAULT = 0,
@@ -142,12 +142,25 @@ impl ::std::clone::Clone for bladerf_lpf_mode {
     fn clone(&self) -> Self { *self }
 }*/
 
-//pub fn bladerf_channel_rx(ch: i32) -> bladerf_module {
-//  ((ch) << 1) | 0x0
-//}
+// libbladeRF 2 channel index of an RX channel. RX channels are even and TX channels odd, so
+// channel 0 of each direction has the same value as the matching bladerf_module.
+pub fn bladerf_channel_rx(ch: i32) -> bladerf_channel {
+  ch << 1
+}
 
+// Stream layouts for bladerf_sync_config. The _X2 layouts interleave two channels sample by
+// sample: ch0, ch1, ch0, ch1, ... with each sample a full SC16 I/Q pair.
 #[repr(C)]
-#[derive(Copy)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum bladerf_channel_layout {
+    BLADERF_RX_X1 = 0,
+    BLADERF_TX_X1 = 1,
+    BLADERF_RX_X2 = 2,
+    BLADERF_TX_X2 = 3,
+}
+
+#[repr(C)]
+#[derive(Copy, Debug, PartialEq)]
 pub enum bladerf_module {
     BLADERF_MODULE_RX = 0,
     BLADERF_MODULE_TX = 1,
@@ -156,14 +169,26 @@ impl ::std::clone::Clone for bladerf_module {
     fn clone(&self) -> Self { *self }
 }
 
-/*
 #[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum bladerf_xb {
     BLADERF_XB_NONE = 0,
     BLADERF_XB_100 = 1,
     BLADERF_XB_200 = 2,
+    BLADERF_XB_300 = 3,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum bladerf_rx_mux {
+    BLADERF_RX_MUX_INVALID = -1,
+    BLADERF_RX_MUX_BASEBAND = 0,
+    BLADERF_RX_MUX_12BIT_COUNTER = 1,
+    BLADERF_RX_MUX_32BIT_COUNTER = 2,
+    BLADERF_RX_MUX_DIGITAL_LOOPBACK = 4,
 }
 
+/*
 #[repr(C)]
 pub enum bladerf_xb200_filter {
     BLADERF_XB200_50M = 0,
@@ -214,12 +239,15 @@ pub enum bladerf_correction {
 }*/
 
 #[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum bladerf_format {
     BLADERF_FORMAT_SC16_Q11 = 0,
     BLADERF_FORMAT_SC16_Q11_META = 1,
+    BLADERF_FORMAT_PACKET_META = 2,
 }
 
-/*
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum bladerf_error {
     BLADERF_ERR_UNEXPECTED  = -1,
     BLADERF_ERR_RANGE       = -2,
@@ -239,7 +267,21 @@ pub enum bladerf_error {
     BLADERF_ERR_FPGA_OP     = -16,
     BLADERF_ERR_PERMISSION  = -17,
     BLADERF_ERR_WOULD_BLOCK = -18,
-}*/
+}
+
+// Size of a flash page, the unit bladerf_read_flash works in
+pub const BLADERF_FLASH_PAGE_SIZE: uint32_t = 256;
+
+// Struct_bladerf_metadata status bits
+pub const BLADERF_META_STATUS_OVERRUN: uint32_t = 1 << 0;
+pub const BLADERF_META_STATUS_UNDERRUN: uint32_t = 1 << 1;
+
+// Struct_bladerf_metadata flags
+pub const BLADERF_META_FLAG_TX_BURST_START: uint32_t = 1 << 0;
+pub const BLADERF_META_FLAG_TX_BURST_END: uint32_t = 1 << 1;
+pub const BLADERF_META_FLAG_TX_NOW: uint32_t = 1 << 2;
+pub const BLADERF_META_FLAG_TX_UPDATE_TIMESTAMP: uint32_t = 1 << 3;
+pub const BLADERF_META_FLAG_RX_NOW: uint32_t = 1 << 31;
 
 #[repr(C)]
 #[derive(Copy)]
@@ -267,6 +309,28 @@ pub type bladerf_stream_cb =
                                         user_data: *mut ::libc::c_void)
                               -> *mut ::libc::c_void>;
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Struct_bladerf_gain_modes {
+    pub name: *const ::libc::c_char,
+    pub mode: bladerf_gain_mode,
+}
+
+#[repr(C)]
+#[derive(Copy, Debug, PartialEq)]
+pub struct Struct_bladerf_range {
+    pub min: int64_t,
+    pub max: int64_t,
+    pub step: int64_t,
+    pub scale: ::libc::c_float,
+}
+impl ::std::clone::Clone for Struct_bladerf_range {
+    fn clone(&self) -> Self { *self }
+}
+impl ::std::default::Default for Struct_bladerf_range {
+    fn default() -> Self { unsafe { ::std::mem::zeroed() } }
+}
+
 #[repr(C)]
 #[derive(Copy, Debug)]
 pub struct Struct_bladerf_version {
@@ -283,7 +347,7 @@ impl ::std::default::Default for Struct_bladerf_version {
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum bladerf_fpga_size {
     BLADERF_FPGA_UNKNOWN = 0,
     BLADERF_FPGA_40KLE   = 40,
@@ -294,6 +358,7 @@ pub enum bladerf_fpga_size {
 }
 
 #[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum bladerf_log_level {
     BLADERF_LOG_LEVEL_VERBOSE = 0,
     BLADERF_LOG_LEVEL_DEBUG = 1,
@@ -370,13 +435,14 @@ extern "C" {
     -> libc::c_int;
     pub fn bladerf_free_device_list(devices: *mut Struct_bladerf_devinfo)
     -> ();*/
-    pub fn bladerf_open_with_devinfo(device: &*mut Struct_bladerf,
+    pub fn bladerf_open_with_devinfo(device: *mut *mut Struct_bladerf,
                                      devinfo: *const Struct_bladerf_devinfo)
      -> ::libc::c_int;
-    pub fn bladerf_open(device:  &*mut Struct_bladerf,
+    pub fn bladerf_open(device: *mut *mut Struct_bladerf,
                         device_identifier: *const ::libc::c_char)
      -> ::libc::c_int;
     pub fn bladerf_close(device: *mut Struct_bladerf) -> ();
+    pub fn bladerf_device_reset(device: *mut Struct_bladerf) -> ::libc::c_int;
     pub fn bladerf_set_usb_reset_on_open(enabled: u8) -> ();
     /*pub fn bladerf_init_devinfo(info: *mut Struct_bladerf_devinfo) -> ();*/
     pub fn bladerf_get_devinfo(dev: *mut Struct_bladerf,
@@ -391,7 +457,8 @@ extern "C" {
                                   info: *mut Struct_bladerf_devinfo) -> u8;
     pub fn bladerf_backend_str(backend: bladerf_backend)
      -> *const ::libc::c_char;*/
-    pub fn bladerf_enable_module(dev: *mut Struct_bladerf, m: bladerf_module,
+    // takes a channel since libbladeRF 2, a bladerf_module is channel 0 of its direction
+    pub fn bladerf_enable_module(dev: *mut Struct_bladerf, ch: bladerf_channel,
                                  enable: u8) -> ::libc::c_int;
     /*pub fn bladerf_set_loopback(dev: *mut Struct_bladerf, l: bladerf_loopback)
      -> ::libc::c_int;
@@ -459,6 +526,23 @@ extern "C" {
                             gain_mode: bladerf_gain_mode) -> ::libc::c_int;
     pub fn bladerf_get_gain_mode(dev: *mut Struct_bladerf, _mod: bladerf_module,
                             gain_mode: *mut bladerf_gain_mode) -> ::libc::c_int;
+    pub fn bladerf_get_gain_modes(dev: *mut Struct_bladerf, _mod: bladerf_module,
+                            modes: *mut *const Struct_bladerf_gain_modes) -> ::libc::c_int;
+    pub fn bladerf_get_gain_range(dev: *mut Struct_bladerf, _mod: bladerf_module,
+                            range: *mut *const Struct_bladerf_range) -> ::libc::c_int;
+    pub fn bladerf_get_sample_rate_range(dev: *mut Struct_bladerf, _mod: bladerf_module,
+                            range: *mut *const Struct_bladerf_range) -> ::libc::c_int;
+    pub fn bladerf_get_frequency_range(dev: *mut Struct_bladerf, _mod: bladerf_module,
+                            range: *mut *const Struct_bladerf_range) -> ::libc::c_int;
+    pub fn bladerf_get_board_name(dev: *mut Struct_bladerf) -> *const ::libc::c_char;
+    pub fn bladerf_set_rf_port(dev: *mut Struct_bladerf, ch: bladerf_channel,
+                               port: *const ::libc::c_char) -> ::libc::c_int;
+    pub fn bladerf_get_rf_port(dev: *mut Struct_bladerf, ch: bladerf_channel,
+                               port: *mut *const ::libc::c_char) -> ::libc::c_int;
+    // returns the number of ports; with a null `ports`, just the count
+    pub fn bladerf_get_rf_ports(dev: *mut Struct_bladerf, ch: bladerf_channel,
+                                ports: *mut *const ::libc::c_char,
+                                count: ::libc::c_uint) -> ::libc::c_int;
     pub fn bladerf_set_bandwidth(dev: *mut Struct_bladerf,
                                  module: bladerf_module,
                                  bandwidth: ::libc::c_uint,
@@ -507,13 +591,17 @@ extern "C" {
      -> ::libc::c_int;
     pub fn bladerf_set_tuning_mode(dev: *mut Struct_bladerf,
                                    mode: bladerf_tuning_mode)
+     -> ::libc::c_int;*/
+    pub fn bladerf_set_rx_mux(dev: *mut Struct_bladerf, mux: bladerf_rx_mux)
+     -> ::libc::c_int;
+    pub fn bladerf_get_rx_mux(dev: *mut Struct_bladerf, mux: *mut bladerf_rx_mux)
      -> ::libc::c_int;
     pub fn bladerf_expansion_attach(dev: *mut Struct_bladerf, xb: bladerf_xb)
      -> ::libc::c_int;
     pub fn bladerf_expansion_get_attached(dev: *mut Struct_bladerf,
                                           xb: *mut bladerf_xb)
      -> ::libc::c_int;
-    pub fn bladerf_xb200_set_filterbank(dev: *mut Struct_bladerf,
+    /*pub fn bladerf_xb200_set_filterbank(dev: *mut Struct_bladerf,
                                         _mod: bladerf_module,
                                         filter: bladerf_xb200_filter)
      -> ::libc::c_int;
@@ -553,7 +641,7 @@ extern "C" {
                                       timeout: *mut ::libc::c_uint)
      -> ::libc::c_int;*/
     pub fn bladerf_sync_config(dev: *mut Struct_bladerf,
-                               module: bladerf_module, format: bladerf_format,
+                               layout: bladerf_channel_layout, format: bladerf_format,
                                num_buffers: ::libc::c_uint,
                                buffer_size: ::libc::c_uint,
                                num_transfers: ::libc::c_uint,
@@ -570,11 +658,12 @@ extern "C" {
                            metadata: *mut Struct_bladerf_metadata,
                            timeout_ms: ::libc::c_uint) -> ::libc::c_int;
     pub fn bladerf_get_serial(dev: *mut Struct_bladerf,
-                              serial: *const ::libc::c_char) -> ::libc::c_int;
+                              serial: *mut ::libc::c_char) -> ::libc::c_int;
     /*pub fn bladerf_get_vctcxo_trim(dev: *mut Struct_bladerf,
                                    trim: *mut uint16_t) -> ::libc::c_int;*/
+    // read as a plain int, newer libbladeRF may report sizes bladerf_fpga_size doesn't list
     pub fn bladerf_get_fpga_size(dev: *mut Struct_bladerf,
-                                 size: *mut bladerf_fpga_size)
+                                 size: *mut ::libc::c_int)
      -> ::libc::c_int;
     pub fn bladerf_fw_version(dev: *mut Struct_bladerf,
                               version: *mut Struct_bladerf_version)
@@ -586,6 +675,15 @@ extern "C" {
      -> ::libc::c_int;
     pub fn bladerf_device_speed(dev: *mut Struct_bladerf)
      -> bladerf_dev_speed;
+    pub fn bladerf_get_fw_log(dev: *mut Struct_bladerf,
+                              filename: *const ::libc::c_char)
+     -> ::libc::c_int;
+    pub fn bladerf_get_flash_size(dev: *mut Struct_bladerf,
+                                  size: *mut uint32_t, is_guess: *mut bool)
+     -> ::libc::c_int;
+    pub fn bladerf_read_flash(dev: *mut Struct_bladerf, buf: *mut uint8_t,
+                              page: uint32_t, count: uint32_t)
+     -> ::libc::c_int;
     /*pub fn bladerf_flash_firmware(dev: *mut Struct_bladerf,
                                   firmware: *const ::libc::c_char)
      -> ::libc::c_int;*/
@@ -657,9 +755,6 @@ extern "C" {
     pub fn bladerf_erase_flash(dev: *mut Struct_bladerf,
                                erase_block: uint32_t, count: uint32_t)
      -> ::libc::c_int;
-    pub fn bladerf_read_flash(dev: *mut Struct_bladerf, buf: *mut uint8_t,
-                              page: uint32_t, count: uint32_t)
-     -> ::libc::c_int;
     pub fn bladerf_write_flash(dev: *mut Struct_bladerf, buf: *const uint8_t,
                                page: uint32_t, count: uint32_t)
      -> ::libc::c_int;
@@ -1,12 +1,16 @@
 extern crate libc;
 
 use std::*;
-use std::mem::MaybeUninit;
 
 #[allow(dead_code, non_camel_case_types)]
 pub mod bladerf;
 use bladerf::*;
 
+pub mod api;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+use api::{BladeRfApi, LibBladeRf};
+
 // Macro to simplify integer returns
 macro_rules! handle_res {
     ($e:expr) => (
@@ -25,18 +29,83 @@ macro_rules! handle_res {
 	);
 }
 
+// Short name of a gain mode, as bladerf-cli and the --gain-mode option spell it
+pub fn gain_mode_name(mode: bladerf_gain_mode) -> &'static str {
+	match mode {
+		bladerf_gain_mode::BLADERF_GAIN_DEFAULT => "default",
+		bladerf_gain_mode::BLADERF_GAIN_MGC => "manual",
+		bladerf_gain_mode::BLADERF_GAIN_FASTATTACK_AGC => "fast",
+		bladerf_gain_mode::BLADERF_GAIN_SLOWATTACK_AGC => "slow",
+		bladerf_gain_mode::BLADERF_GAIN_HYBRID_AGC => "hybrid",
+	}
+}
+
+// The gain mode with a short name from gain_mode_name
+pub fn gain_mode_from_name(name: &str) -> Option<bladerf_gain_mode> {
+	match name {
+		"default" => Some(bladerf_gain_mode::BLADERF_GAIN_DEFAULT),
+		"manual" => Some(bladerf_gain_mode::BLADERF_GAIN_MGC),
+		"fast" => Some(bladerf_gain_mode::BLADERF_GAIN_FASTATTACK_AGC),
+		"slow" => Some(bladerf_gain_mode::BLADERF_GAIN_SLOWATTACK_AGC),
+		"hybrid" => Some(bladerf_gain_mode::BLADERF_GAIN_HYBRID_AGC),
+		_ => None,
+	}
+}
+
+// bladerf_gain_mode is generated from the C header, so rather than deriving serde's traits on it
+// it's (de)serialized through its short name
+#[cfg(feature = "serde")]
+mod gain_mode_serde {
+	use serde::de::Error;
+	use serde::{Deserialize, Deserializer, Serializer};
+
+	use super::{gain_mode_from_name, gain_mode_name};
+	use crate::bladerf::bladerf_gain_mode;
+
+	pub fn serialize<S: Serializer>(mode: &bladerf_gain_mode, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(gain_mode_name(*mode))
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bladerf_gain_mode, D::Error> {
+		let name = String::deserialize(deserializer)?;
+		gain_mode_from_name(&name).ok_or_else(|| {
+			D::Error::custom(format!("unknown gain mode '{}', expected default, manual, fast, slow or hybrid", name))
+		})
+	}
+}
+
 // BladeRF module config object
+//
+// configure_module applies frequency, sample rate and bandwidth. The gain mode (lna_gain) is
+// left to set_gain_mode, since which gains a board accepts depends on the mode. vga1 and vga2
+// are the bladeRF 1 gain stages and aren't applied.
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BladeRFModuleConfig {
 	pub frequency: u32,
 	pub sample_rate: u32,
 	pub bandwidth: u32,
+	#[cfg_attr(feature = "serde", serde(with = "gain_mode_serde"))]
 	pub lna_gain: bladerf_gain_mode,
 	pub vga1: i32,
 	pub vga2: i32
 }
 
+// A module's settings as get_module_config reads them back, each from its own getter. The
+// bladeRF 1 VGA stages have no getters here, so the overall gain stands in for them.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BladeRFModuleSettings {
+	pub frequency: u32,
+	pub sample_rate: u32,
+	pub bandwidth: u32,
+	#[cfg_attr(feature = "serde", serde(with = "gain_mode_serde"))]
+	pub gain_mode: bladerf_gain_mode,
+	pub gain: bladerf_gain
+}
+
 // BladeRF overall config object
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BladeRFConfig {
 	pub tx: BladeRFModuleConfig,
 	pub rx: BladeRFModuleConfig
@@ -51,8 +120,12 @@ pub struct iq {
 }
 
 // BladeRF device object
+//
+// The device is Send, so it can be opened on one thread and moved to another (e.g. the RX
+// thread), but not Sync: a handle must only be used from one thread at a time. Share it
+// behind a Mutex if several threads need it.
 pub struct BladeRFDevice {
-   device: *mut Struct_bladerf
+   api: Box<dyn BladeRfApi>
 }
 
 
@@ -95,22 +168,277 @@ pub fn set_usb_reset_on_open(enabled: bool) {
     } 
 }
 
+pub fn log_set_verbosity(level: bladerf_log_level) {
+	unsafe {
+		bladerf_log_set_verbosity(level);
+	}
+	log::debug!(target: "libbladeRF", "verbosity set to {:?}", level);
+}
+
+// Version of the libbladeRF library we're linked against. Doesn't need a device.
+pub fn library_version() -> Struct_bladerf_version {
+	let mut version = Struct_bladerf_version::default();
+
+	unsafe {
+		bladerf_version(&mut version as *mut Struct_bladerf_version);
+	}
+
+	version
+}
+
+// libbladeRF has no log callback API, its messages always go straight to stderr (or syslog).
+// The closest we can get to unifying the output is keeping its verbosity in step with the
+// `log` facade, so these map between the two sets of levels.
+impl From<log::LevelFilter> for bladerf_log_level {
+	fn from(level: log::LevelFilter) -> Self {
+		match level {
+			log::LevelFilter::Off   => bladerf_log_level::BLADERF_LOG_LEVEL_SILENT,
+			log::LevelFilter::Error => bladerf_log_level::BLADERF_LOG_LEVEL_ERROR,
+			log::LevelFilter::Warn  => bladerf_log_level::BLADERF_LOG_LEVEL_WARNING,
+			log::LevelFilter::Info  => bladerf_log_level::BLADERF_LOG_LEVEL_INFO,
+			log::LevelFilter::Debug => bladerf_log_level::BLADERF_LOG_LEVEL_DEBUG,
+			log::LevelFilter::Trace => bladerf_log_level::BLADERF_LOG_LEVEL_VERBOSE
+		}
+	}
+}
+
+impl From<bladerf_log_level> for log::LevelFilter {
+	fn from(level: bladerf_log_level) -> Self {
+		match level {
+			bladerf_log_level::BLADERF_LOG_LEVEL_VERBOSE  => log::LevelFilter::Trace,
+			bladerf_log_level::BLADERF_LOG_LEVEL_DEBUG    => log::LevelFilter::Debug,
+			bladerf_log_level::BLADERF_LOG_LEVEL_INFO     => log::LevelFilter::Info,
+			bladerf_log_level::BLADERF_LOG_LEVEL_WARNING  => log::LevelFilter::Warn,
+			bladerf_log_level::BLADERF_LOG_LEVEL_ERROR    => log::LevelFilter::Error,
+			bladerf_log_level::BLADERF_LOG_LEVEL_CRITICAL => log::LevelFilter::Error,
+			bladerf_log_level::BLADERF_LOG_LEVEL_SILENT   => log::LevelFilter::Off
+		}
+	}
+}
+
+// Process wide libbladeRF settings, applied with apply() before opening a device
+#[derive(Clone, Debug)]
+pub struct GlobalConfig {
+	pub usb_reset_on_open: bool,
+	pub log_level: bladerf_log_level
+}
+
+impl GlobalConfig {
+	// libbladeRF's own defaults
+	pub fn new() -> GlobalConfig {
+		GlobalConfig {
+			usb_reset_on_open: false,
+			log_level: bladerf_log_level::BLADERF_LOG_LEVEL_WARNING
+		}
+	}
+
+	pub fn usb_reset_on_open(mut self, enabled: bool) -> GlobalConfig {
+		self.usb_reset_on_open = enabled;
+		self
+	}
+
+	pub fn log_level(mut self, level: bladerf_log_level) -> GlobalConfig {
+		self.log_level = level;
+		self
+	}
+
+	pub fn apply(&self) {
+		set_usb_reset_on_open(self.usb_reset_on_open);
+		log_set_verbosity(self.log_level);
+	}
+}
+
+impl Default for GlobalConfig {
+	fn default() -> Self {
+		GlobalConfig::new()
+	}
+}
+
+// Builds the device identifier string bladerf_open expects, "<backend>:[device=<bus>:<addr>]
+// [instance=<n>] [serial=<serial>]", so callers don't need to know libbladeRF's grammar.
+// Criteria left unset match any device; an empty selector matches the first device found.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceSelector {
+	pub backend: Option<bladerf_backend>,
+	pub serial: Option<String>,
+	pub usb: Option<(u8, u8)>,
+	pub instance: Option<u32>
+}
+
+impl DeviceSelector {
+	pub fn new() -> DeviceSelector {
+		DeviceSelector::default()
+	}
+
+	pub fn backend(mut self, backend: bladerf_backend) -> DeviceSelector {
+		self.backend = Some(backend);
+		self
+	}
+
+	// libbladeRF matches any device whose serial starts with this
+	pub fn serial(mut self, serial: &str) -> DeviceSelector {
+		self.serial = Some(String::from(serial));
+		self
+	}
+
+	pub fn usb(mut self, bus: u8, addr: u8) -> DeviceSelector {
+		self.usb = Some((bus, addr));
+		self
+	}
+
+	pub fn instance(mut self, instance: u32) -> DeviceSelector {
+		self.instance = Some(instance);
+		self
+	}
+
+	pub fn to_device_string(&self) -> String {
+		let backend = match self.backend {
+			None | Some(bladerf_backend::BLADERF_BACKEND_ANY) => "*",
+			Some(bladerf_backend::BLADERF_BACKEND_LINUX) => "linux",
+			Some(bladerf_backend::BLADERF_BACKEND_LIBUSB) => "libusb",
+			Some(bladerf_backend::BLADERF_BACKEND_CYPRESS) => "cypress",
+			Some(bladerf_backend::BLADERF_BACKEND_DUMMY) => "dummy",
+		};
+
+		let mut options = Vec::new();
+		if let Some((bus, addr)) = self.usb {
+			options.push(format!("device={}:{}", bus, addr));
+		}
+		if let Some(instance) = self.instance {
+			options.push(format!("instance={}", instance));
+		}
+		if let Some(ref serial) = self.serial {
+			options.push(format!("serial={}", serial));
+		}
+
+		format!("{}:{}", backend, options.join(" "))
+	}
+}
+
+// Parses comma separated criteria: "serial=<serial>", "usb=<bus>:<addr>", "instance=<n>" and
+// "backend=<any|linux|libusb|cypress|dummy>", e.g. "backend=libusb,serial=f12ce103"
+impl str::FromStr for DeviceSelector {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<DeviceSelector, String> {
+		let mut selector = DeviceSelector::new();
+		for criterion in s.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+			let (key, value) = criterion.split_once('=')
+				.ok_or_else(|| format!("expected key=value, got \"{}\"", criterion))?;
+			selector = match key {
+				"serial" if !value.is_empty() => selector.serial(value),
+				"usb" => {
+					let (bus, addr) = value.split_once(':')
+						.ok_or_else(|| format!("expected usb=<bus>:<addr>, got \"{}\"", value))?;
+					let bus = bus.parse().map_err(|_| format!("invalid USB bus \"{}\"", bus))?;
+					let addr = addr.parse().map_err(|_| format!("invalid USB address \"{}\"", addr))?;
+					selector.usb(bus, addr)
+				},
+				"instance" => selector.instance(value.parse().map_err(|_| format!("invalid instance \"{}\"", value))?),
+				"backend" => selector.backend(match value {
+					"any" | "*" => bladerf_backend::BLADERF_BACKEND_ANY,
+					"linux"     => bladerf_backend::BLADERF_BACKEND_LINUX,
+					"libusb"    => bladerf_backend::BLADERF_BACKEND_LIBUSB,
+					"cypress"   => bladerf_backend::BLADERF_BACKEND_CYPRESS,
+					"dummy"     => bladerf_backend::BLADERF_BACKEND_DUMMY,
+					_ => return Err(format!("unknown backend \"{}\"", value)),
+				}),
+				_ => return Err(format!("unknown device criterion \"{}\"", criterion)),
+			};
+		}
+		Ok(selector)
+	}
+}
+
+impl fmt::Display for DeviceSelector {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.to_device_string())
+	}
+}
+
+// Attempts and delay retry() is normally used with. USB hiccups clear up within a few hundred ms.
+pub const TRANSIENT_RETRY_ATTEMPTS: u32 = 3;
+pub const TRANSIENT_RETRY_DELAY: time::Duration = time::Duration::from_millis(250);
+
+// The bladerf_fpga_size for a raw value from libbladeRF, None for sizes added after these bindings
+pub fn fpga_size_from_raw(raw: libc::c_int) -> Option<bladerf_fpga_size> {
+	use bladerf_fpga_size::*;
+	[BLADERF_FPGA_UNKNOWN, BLADERF_FPGA_40KLE, BLADERF_FPGA_115KLE, BLADERF_FPGA_A4, BLADERF_FPGA_A5, BLADERF_FPGA_A9]
+		.into_iter()
+		.find(|&size| size as libc::c_int == raw)
+}
+
+// Errors that can go away by themselves, as opposed to bad arguments or a missing device
+pub fn is_transient(err: isize) -> bool {
+	err == bladerf_error::BLADERF_ERR_IO as isize || err == bladerf_error::BLADERF_ERR_TIMEOUT as isize
+}
+
+// Errors sync_rx gives once the device has gone, typically because it was unplugged
+pub fn is_disconnect(err: isize) -> bool {
+	err == bladerf_error::BLADERF_ERR_NODEV as isize || err == bladerf_error::BLADERF_ERR_IO as isize
+}
+
+// Run `f` up to `attempts` times, waiting `delay` after each transient failure. Any other error
+// is returned straight away, as is the last transient one once the attempts are used up.
+pub fn retry<T, F>(what: &str, attempts: u32, delay: time::Duration, mut f: F) -> Result<T, isize>
+where
+	F: FnMut() -> Result<T, isize>,
+{
+	let mut attempt = 1;
+	loop {
+		match f() {
+			Err(e) if is_transient(e) && attempt < attempts => {
+				log::warn!("{} failed with transient error {} (attempt {}/{}), retrying", what, e, attempt, attempts);
+				thread::sleep(delay);
+				attempt += 1;
+			},
+			result => return result,
+		}
+	}
+}
+
+// Run `f` up to `attempts` times whatever the error, waiting `initial_delay` after the first
+// failure and twice as long after each one after that, up to `max_delay`. For waiting out a
+// device that is expected to disappear for a while, e.g. while it re-enumerates on USB.
+pub fn retry_with_backoff<T, F>(what: &str, attempts: u32, initial_delay: time::Duration, max_delay: time::Duration,
+								mut f: F) -> Result<T, isize>
+where
+	F: FnMut() -> Result<T, isize>,
+{
+	let mut attempt = 1;
+	let mut delay = initial_delay;
+	loop {
+		match f() {
+			Err(e) if attempt < attempts => {
+				log::debug!("{} failed with error {} (attempt {}/{}), retrying in {:?}", what, e, attempt, attempts, delay);
+				thread::sleep(delay);
+				delay = (delay * 2).min(max_delay);
+				attempt += 1;
+			},
+			result => return result,
+		}
+	}
+}
+
 pub fn open(identifier: Option<String>) -> Result<BladeRFDevice, isize> {
+	// must outlive the bladerf_open call that reads it
+	let c_string = identifier.map(|id| ffi::CString::new(id.into_bytes()).unwrap());
+
 	unsafe {
-		let id_ptr = match identifier {
-			Some(id) => {
-				let c_string = ffi::CString::new(id.into_bytes()).unwrap();
-				c_string.as_ptr()
+		let id_ptr = match c_string {
+			Some(ref id) => {
+				id.as_ptr()
 			}, None => {
 				ptr::null()
 			}
 		};
 
-		let bladerf_device = BladeRFDevice { device: mem::uninitialized() };
+		// libbladeRF writes the new handle through this
+		let mut device: *mut Struct_bladerf = ptr::null_mut();
 
-		let res = bladerf_open(&(bladerf_device.device), id_ptr);
+		let res = bladerf_open(&mut device, id_ptr);
 
-		handle_res!(res, bladerf_device);
+		handle_res!(res, BladeRFDevice::with_api(Box::new(LibBladeRf::from_raw(device))));
 	}
 }
 
@@ -121,7 +449,7 @@ pub fn open(identifier: Option<String>) -> Result<BladeRFDevice, isize> {
 	unsafe {
 		let bladerf_device = BladeRFDevice { device: mem::uninitialized() };
 
-		let res = bladerf_open_with_devinfo(&(bladerf_device.device), devinfo_ptr);
+		let res = bladerf_open_with_devinfo(&mut bladerf_device.device, devinfo_ptr);
 
 		handle_res!(res, bladerf_device);
 	}
@@ -132,15 +460,17 @@ pub fn open(identifier: Option<String>) -> Result<BladeRFDevice, isize> {
 
 impl BladeRFDevice {
 
+  // Wrap an arbitrary libbladeRF implementation, e.g. mock::MockBladeRf for testing without hardware
+  pub fn with_api(api: Box<dyn BladeRfApi>) -> BladeRFDevice {
+    BladeRFDevice { api }
+  }
+
   pub fn get_devinfo(&self) -> Result<Struct_bladerf_devinfo, isize> {
-    let mut devinfo = MaybeUninit::<Struct_bladerf_devinfo>::uninit();
+    let mut devinfo = Struct_bladerf_devinfo::default();
 
-    unsafe{
-      let res = bladerf_get_devinfo(self.device, devinfo.as_mut_ptr());
-      let devinfo = devinfo.assume_init();
+    let res = self.api.get_devinfo(&mut devinfo);
 
-      handle_res!(res, devinfo);
-    }
+    handle_res!(res, devinfo);
   }
 
 	// Device Properties and Information
@@ -149,10 +479,10 @@ impl BladeRFDevice {
 	pub fn get_serial(&self) -> Result<String, isize> {
 		unsafe {
 			// Create raw data array for serial return
-			let serial_data : Vec<::libc::c_char> = vec![0; 33];
+			let mut serial_data : [::libc::c_char; 33] = [0; 33];
 
 			// Call underlying c method
-			let res = bladerf_get_serial(self.device, serial_data.as_ptr());
+			let res = self.api.get_serial(&mut serial_data);
 
 			if res >= 0 {
 				// Map ::libc::c_char back to u8 as required for string manipulation
@@ -169,61 +499,103 @@ impl BladeRFDevice {
 		}
 	}
 
+	// A size this crate doesn't know about comes back as BLADERF_FPGA_UNKNOWN
 	pub fn get_fpga_size(&self) -> Result<bladerf_fpga_size, isize> {
-		let mut fpga_size: bladerf_fpga_size = bladerf_fpga_size::BLADERF_FPGA_UNKNOWN;
+		let mut raw: libc::c_int = 0;
 
-		unsafe {
-			let res = bladerf_get_fpga_size(self.device, &mut fpga_size as *mut bladerf_fpga_size);
+		let res = self.api.get_fpga_size(&mut raw);
 
-			handle_res!(res, fpga_size);
-		}
+		let fpga_size = fpga_size_from_raw(raw).unwrap_or_else(|| {
+			log::warn!("libbladeRF reported unrecognized FPGA size {}", raw);
+			bladerf_fpga_size::BLADERF_FPGA_UNKNOWN
+		});
+		handle_res!(res, fpga_size);
 	}
 
 	pub fn fw_version(&self) -> Result<Struct_bladerf_version, isize> {
-        let mut version = MaybeUninit::<Struct_bladerf_version>::uninit();
-		unsafe {
-			let res = bladerf_fw_version(self.device, version.as_mut_ptr());
-            let version = version.assume_init();
+        let mut version = Struct_bladerf_version::default();
 
-			handle_res!(res, version);
-		}
+		let res = self.api.fw_version(&mut version);
+
+		handle_res!(res, version);
 	}
 
     pub fn device_speed(&self) -> bladerf_dev_speed {
-      unsafe {
-        let mut speed: bladerf_dev_speed = bladerf_device_speed(self.device);
+      self.api.device_speed()
+    }
 
-		return speed;
-      }
+    // Board model, e.g. "bladerf1" or "bladerf2"
+    pub fn get_board_name(&self) -> String {
+      self.api.get_board_name()
     }
 
-	/*pub fn is_fpga_configured(&self) -> Result<bool, isize> {
-		unsafe {
-			let res = bladerf_is_fpga_configured(self.device);
+    // RFIC port names available on a channel. Only the bladeRF 2.0 has selectable ports.
+    pub fn get_rf_ports(&self, channel: bladerf_channel) -> Result<Vec<String>, isize> {
+      let mut ports = Vec::new();
 
-			if res > 0 {
-				Ok(true)
-			} else if res == 0 {
-				Ok(false)
-			} else {
-				Err(res as isize)
-			}
+      let res = self.api.get_rf_ports(channel, &mut ports);
+
+      handle_res!(res, ports);
+    }
+
+    pub fn set_rf_port(&self, channel: bladerf_channel, port: &str) -> Result<isize, isize> {
+      let c_string = ffi::CString::new(port).unwrap();
+
+      let res = self.api.set_rf_port(channel, &c_string);
+
+      handle_res!(res);
+    }
+
+    pub fn get_rf_port(&self, channel: bladerf_channel) -> Result<String, isize> {
+      let mut port = String::new();
+
+      let res = self.api.get_rf_port(channel, &mut port);
+
+      handle_res!(res, port);
+    }
+
+	pub fn is_fpga_configured(&self) -> Result<bool, isize> {
+		let res = self.api.is_fpga_configured();
+
+		if res > 0 {
+			Ok(true)
+		} else if res == 0 {
+			Ok(false)
+		} else {
+			Err(res as isize)
 		}
-	}*/
+	}
 
 	pub fn fpga_version(&self) -> Result<Struct_bladerf_version, isize> {
-        let mut version = MaybeUninit::<Struct_bladerf_version>::uninit();
-		unsafe {
-			let res = bladerf_fpga_version(self.device, version.as_mut_ptr());
-            let version = version.assume_init();
+        let mut version = Struct_bladerf_version::default();
 
-			handle_res!(res, version);
-		}
+		let res = self.api.fpga_version(&mut version);
+
+		handle_res!(res, version);
 	}
 
 	pub fn close(&self) {
-		unsafe {
-			bladerf_close(self.device)
+		self.api.close()
+	}
+
+	// Reset the device, reloading its firmware and clearing the FPGA. The handle is useless
+	// afterwards: close it and open the device again once it has re-enumerated.
+	pub fn device_reset(&self) -> Result<isize, isize> {
+		let res = self.api.device_reset();
+
+		handle_res!(res);
+	}
+
+	// Best-effort teardown: disable the module, then close the device whatever that returned, so
+	// a device already in a bad state is still released. Returns the disable error, if any.
+	pub fn shutdown(&self, module: bladerf_module) -> Result<(), isize> {
+		let res = self.api.enable_module(module, false);
+		self.api.close();
+
+		if res >= 0 {
+			Ok(())
+		} else {
+			Err(res as isize)
 		}
 	}
 
@@ -232,11 +604,17 @@ impl BladeRFDevice {
 	// http://www.nuand.com/libbladeRF-doc/v1.7.2/group___f_n___m_o_d_u_l_e.html
 
 	pub fn enable_module(&self, module: bladerf_module, enable: bool) -> Result<isize, isize> {
-		unsafe {
-			let res = bladerf_enable_module(self.device, module, enable as u8);
+		let res = self.api.enable_module(module, enable);
 
-			handle_res!(res);
-		}
+		handle_res!(res);
+	}
+
+	// Enable a channel by its libbladeRF 2 index, e.g. bladerf_channel_rx(1) for the second RX
+	// channel of a bladeRF 2.0, which has no bladerf_module of its own
+	pub fn enable_channel(&self, channel: bladerf_channel, enable: bool) -> Result<isize, isize> {
+		let res = self.api.enable_channel(channel, enable);
+
+		handle_res!(res);
 	}
 
 
@@ -334,39 +712,49 @@ impl BladeRFDevice {
 	}*/
 
 	pub fn set_gain(&self, module: bladerf_module, gain: bladerf_gain) -> Result<isize, isize> {
-		unsafe {
-			let res = bladerf_set_gain(self.device, module, gain);
+		let res = self.api.set_gain(module, gain);
 
-			handle_res!(res);
-		}
+		handle_res!(res);
 	}
 
     pub fn get_gain(&self, module: bladerf_module) -> Result<bladerf_gain, isize> {
       let mut gain: bladerf_gain = 0;
 
-      unsafe {
-        let res = bladerf_get_gain(self.device, module, &mut gain as *mut bladerf_gain);
+      let res = self.api.get_gain(module, &mut gain);
 
-        handle_res!(res, gain);
-      }
+      handle_res!(res, gain);
     }
 
     pub fn set_gain_mode(&self, module: bladerf_module, gain_mode: bladerf_gain_mode) -> Result<isize, isize> {
-        unsafe {
-            let res = bladerf_set_gain_mode(self.device, module, gain_mode);
+        let res = self.api.set_gain_mode(module, gain_mode);
 
-            handle_res!(res);
-        }
+        handle_res!(res);
     }
 
     pub fn get_gain_mode(&self, module: bladerf_module) -> Result<bladerf_gain_mode, isize> {
       let mut gain_mode: bladerf_gain_mode = bladerf_gain_mode::BLADERF_GAIN_DEFAULT;
 
-      unsafe {
-        let res = bladerf_get_gain_mode(self.device, module, &mut gain_mode as *mut bladerf_gain_mode);
+      let res = self.api.get_gain_mode(module, &mut gain_mode);
 
-        handle_res!(res, gain_mode);
-      }
+      handle_res!(res, gain_mode);
+    }
+
+    // Gain modes the board supports on the module
+    pub fn get_gain_modes(&self, module: bladerf_module) -> Result<Vec<bladerf_gain_mode>, isize> {
+      let mut modes = Vec::new();
+
+      let res = self.api.get_gain_modes(module, &mut modes);
+
+      handle_res!(res, modes);
+    }
+
+    // Gain limits for the module, in dB once multiplied by the range's scale
+    pub fn get_gain_range(&self, module: bladerf_module) -> Result<Struct_bladerf_range, isize> {
+      let mut range = Struct_bladerf_range::default();
+
+      let res = self.api.get_gain_range(module, &mut range);
+
+      handle_res!(res, range);
     }
 
 	// Sampling Control
@@ -374,11 +762,9 @@ impl BladeRFDevice {
 	pub fn set_sample_rate(&self, module: bladerf_module, rate: u32) -> Result<u32, isize> {
 		let mut actual: u32 = 0;
 
-		unsafe {
-			let res = bladerf_set_sample_rate(self.device, module, rate, &mut actual as *mut u32);
+		let res = self.api.set_sample_rate(module, rate, &mut actual);
 
-			handle_res!(res, actual);
-		}
+		handle_res!(res, actual);
 	}
 
 	/*pub fn set_rational_sample_rate(&self, module: bladerf_module, rate: Struct_bladerf_rational_rate) -> Result<Struct_bladerf_rational_rate, isize> {
@@ -396,11 +782,9 @@ impl BladeRFDevice {
 	pub fn get_sample_rate(&self, module: bladerf_module) -> Result<u32, isize> {
 		let mut rate: u32 = 0;
 
-		unsafe {
-			let res = bladerf_get_sample_rate(self.device, module, &mut rate as *mut u32);
+		let res = self.api.get_sample_rate(module, &mut rate);
 
-			handle_res!(res, rate);
-		}
+		handle_res!(res, rate);
 	}
 
 	/*pub fn get_rational_sample_rate(&self, module: bladerf_module) -> Result<Struct_bladerf_rational_rate, isize> {
@@ -420,27 +804,29 @@ impl BladeRFDevice {
 			handle_res!(res);
 		}
 	}*/
-/*
-	Generated bladerf.rs needs update
 
+	// Where RX samples come from; the counter modes replace the RF samples with a known sequence
 	pub fn set_rx_mux(&self, mux: bladerf_rx_mux) -> Result<isize, isize> {
-		unsafe {
-			let res = bladerf_set_rx_mux(self.device, sampling);
+		let res = self.api.set_rx_mux(mux);
 
-			handle_res!(res);
-		}
+		handle_res!(res);
 	}
 
-	pub fn get_rx_mux(&self, ) -> Result<bladerf_rx_mux, isize> {
-		let mut mux: bladerf_rx_mux = 0;
+	pub fn get_rx_mux(&self) -> Result<bladerf_rx_mux, isize> {
+		let mut mux = bladerf_rx_mux::BLADERF_RX_MUX_BASEBAND;
 
-		unsafe {
-			let res = bladerf_get_rx_mux(self.device, &mut mux as *mut bladerf_rx_mux);
+		let res = self.api.get_rx_mux(&mut mux);
 
-			handle_res!(res, mux);
-		}
+		handle_res!(res, mux);
+	}
+
+	pub fn get_sample_rate_range(&self, module: bladerf_module) -> Result<Struct_bladerf_range, isize> {
+		let mut range = Struct_bladerf_range::default();
+
+		let res = self.api.get_sample_rate_range(module, &mut range);
+
+		handle_res!(res, range);
 	}
-*/
 
 	/*pub fn get_sampling(&self) -> Result<bladerf_sampling, isize> {
         let mut sampling = MaybeUninit::<bladerf_sampling>::uninit();
@@ -458,21 +844,17 @@ impl BladeRFDevice {
 	pub fn set_bandwidth(&self, module: bladerf_module, bandwidth: u32) -> Result<u32, isize> {
 		let mut actual: u32 = 0;
 
-		unsafe {
-			let res = bladerf_set_bandwidth(self.device, module, bandwidth, &mut actual as *mut u32);
+		let res = self.api.set_bandwidth(module, bandwidth, &mut actual);
 
-			handle_res!(res, actual);
-		}
+		handle_res!(res, actual);
 	}
 
 	pub fn get_bandwidth(&self, module: bladerf_module) -> Result<u32, isize> {
-		unsafe {
-			let mut bandwidth: u32 = 0;
+		let mut bandwidth: u32 = 0;
 
-			let res = bladerf_get_bandwidth(self.device, module, &mut bandwidth as *mut u32);
+		let res = self.api.get_bandwidth(module, &mut bandwidth);
 
-			handle_res!(res, bandwidth);
-		}
+		handle_res!(res, bandwidth);
 	}
 
 	/*pub fn set_lpf_mode(&self, module: bladerf_module, lpf_mode: bladerf_lpf_mode) -> Result<isize, isize> {
@@ -510,11 +892,9 @@ impl BladeRFDevice {
 	}*/
 
 	pub fn set_frequency(&self, module: bladerf_module, frequency: u32) -> Result<isize, isize> {
-		unsafe {
-			let res = bladerf_set_frequency(self.device, module, frequency);
+		let res = self.api.set_frequency(module, frequency);
 
-			handle_res!(res);
-		}
+		handle_res!(res);
 	}
 	 
 	/*pub fn schedule_retune(&self, module: bladerf_module, time: u64, frequency: u32, quick_tune: Option<Struct_bladerf_quick_tune>) -> Result<isize, isize> {
@@ -551,29 +931,40 @@ impl BladeRFDevice {
 	}*/
 
 	pub fn get_frequency(&self, module: bladerf_module) -> Result<u32, isize> {
-		unsafe {
-			let mut freq: u64 = 0;
+		let mut freq: u64 = 0;
 
-			let res = bladerf_get_frequency(self.device, module, &mut freq as *mut u64); 
+		let res = self.api.get_frequency(module, &mut freq);
 
-			handle_res!(res, freq as u32);
-		}
+		handle_res!(res, freq as u32);
+	}
+
+	pub fn get_frequency_range(&self, module: bladerf_module) -> Result<Struct_bladerf_range, isize> {
+		let mut range = Struct_bladerf_range::default();
+
+		let res = self.api.get_frequency_range(module, &mut range);
+
+		handle_res!(res, range);
+	}
+
+	// The tuning range as (min, max, step) in Hz, with libbladeRF's scale factor applied
+	pub fn get_frequency_limits(&self, module: bladerf_module) -> Result<(u64, u64, u64), isize> {
+		let range = self.get_frequency_range(module)?;
+		let scale = if range.scale == 0.0 { 1.0 } else { range.scale as f64 };
+
+		Ok(((range.min as f64 * scale) as u64, (range.max as f64 * scale) as u64, (range.step as f64 * scale) as u64))
 	}
 
     pub fn set_bias_tee(&self, module: bladerf_module, enable: bool) -> Result<bool, isize> {
-      unsafe {
-        let res = bladerf_set_bias_tee(self.device, module, enable);
+      let res = self.api.set_bias_tee(module, enable);
 
-        handle_res!(res, enable);
-      }
+      handle_res!(res, enable);
     }
     pub fn get_bias_tee(&self, module: bladerf_module) -> Result<bool, isize> {
       let mut enable: bool = false;
-      unsafe {
-        let res = bladerf_get_bias_tee(self.device, module, &mut enable as *mut bool);
 
-        handle_res!(res, enable);
-      }
+      let res = self.api.get_bias_tee(module, &mut enable);
+
+      handle_res!(res, enable);
     }
 
 	/*pub fn get_quick_tune(&self, module: bladerf_module) -> Result<Struct_bladerf_quick_tune, isize> {
@@ -640,6 +1031,22 @@ impl BladeRFDevice {
 
 	// Expansion boards	
 
+	// Attach an XB-100/200/300. Attaching the XB-200 transverter extends the RX tuning range
+	// down to 60 kHz, so query get_frequency_range again afterwards.
+	pub fn attach_expansion(&self, board: bladerf_xb) -> Result<isize, isize> {
+		let res = self.api.expansion_attach(board);
+
+		handle_res!(res);
+	}
+
+	pub fn get_attached_expansion(&self) -> Result<bladerf_xb, isize> {
+		let mut board = bladerf_xb::BLADERF_XB_NONE;
+
+		let res = self.api.expansion_get_attached(&mut board);
+
+		handle_res!(res, board);
+	}
+
 
 	// Expansion IO control	
 
@@ -655,17 +1062,29 @@ impl BladeRFDevice {
 
 	// Synchronous data transmission and reception	
 
+	// Single channel streams use the module's X1 layout. For BLADERF_RX_X2 use
+	// sync_config_layout, sync_rx then returns both channels interleaved sample by sample
+	// and num_samples counts the samples of both.
 	pub fn sync_config(&self, module: bladerf_module, format: bladerf_format,
 					   num_buffers: u32, buffer_size: u32, num_transfers: Option<u32>, stream_timeout: u32)
 					   -> Result<isize, isize> {
+		let layout = match module {
+			bladerf_module::BLADERF_MODULE_RX => bladerf_channel_layout::BLADERF_RX_X1,
+			bladerf_module::BLADERF_MODULE_TX => bladerf_channel_layout::BLADERF_TX_X1,
+		};
+
+		self.sync_config_layout(layout, format, num_buffers, buffer_size, num_transfers, stream_timeout)
+	}
+
+	pub fn sync_config_layout(&self, layout: bladerf_channel_layout, format: bladerf_format,
+					   num_buffers: u32, buffer_size: u32, num_transfers: Option<u32>, stream_timeout: u32)
+					   -> Result<isize, isize> {
 
 		let num_transfers = match num_transfers { Some(t) => t, None => 4};
 
-		unsafe {
-			let res = bladerf_sync_config(self.device, module, format, num_buffers, buffer_size, num_transfers, stream_timeout);
-		
-			handle_res!(res);
-		}
+		let res = self.api.sync_config(layout, format, num_buffers, buffer_size, num_transfers, stream_timeout);
+
+		handle_res!(res);
 	}
 
 	/*pub fn sync_tx(&self, data: Vec<iq>, meta: Option<Struct_bladerf_metadata>, stream_timeout: u32)
@@ -690,25 +1109,27 @@ impl BladeRFDevice {
 		}
 	}*/
 
-	pub fn sync_rx(&self, data: &mut [u8], num_samples: u32, meta: Option<Struct_bladerf_metadata>, stream_timeout: u32)
+	// On success returns the number of samples actually received. Without metadata libbladeRF
+	// blocks until all num_samples have arrived; with metadata (only filled in for the _META
+	// formats) it's whatever actual_count reports, which can be short on an overrun. libbladeRF
+	// strips the _META formats' in-band headers, so data only ever holds samples and
+	// meta.timestamp is the device sample counter of the first one. With PACKET_META each call
+	// returns a single packet from the FPGA, num_samples and the result counting its 32 bit words.
+	pub fn sync_rx(&self, data: &mut [u8], num_samples: u32, meta: Option<&mut Struct_bladerf_metadata>, stream_timeout: u32)
 		       -> Result<isize, isize> {
 
 		// Handle optional meta argument
-		let meta_ptr: *mut Struct_bladerf_metadata = match meta { 
-			Some(m) => {
-				let mut meta_int = m;
-				&mut meta_int
-			}, None => {
-				ptr::null_mut()
-			}
-		};
+		match meta {
+			Some(meta) => {
+				let res = self.api.sync_rx(data, num_samples, Some(&mut *meta), stream_timeout);
 
-		let data_ptr: *mut libc::c_void = data.as_ptr() as *mut libc::c_void;
+				handle_res!(res, meta.actual_count.min(num_samples) as isize);
+			},
+			None => {
+				let res = self.api.sync_rx(data, num_samples, None, stream_timeout);
 
-		unsafe {
-			let res = bladerf_sync_rx(self.device, data_ptr, num_samples, meta_ptr, stream_timeout);
-		
-			handle_res!(res);
+				handle_res!(res, num_samples as isize);
+			}
 		}
 	}
 
@@ -717,11 +1138,40 @@ impl BladeRFDevice {
 	pub fn load_fpga(&self, file: String) -> Result<isize, isize>  {
 		let c_string = ffi::CString::new(file.into_bytes()).unwrap();
 
-		unsafe {
-			let res = bladerf_load_fpga(self.device, c_string.as_ptr());
+		let res = self.api.load_fpga(&c_string);
 
-			handle_res!(res)
-		}
+		handle_res!(res)
+	}
+
+	// Reading back from the device
+
+	// Writes the FX3 firmware log to `file`. Needs firmware 1.10 or later, older firmware gives
+	// BLADERF_ERR_UNSUPPORTED.
+	pub fn get_fw_log(&self, file: String) -> Result<isize, isize> {
+		let c_string = ffi::CString::new(file.into_bytes()).unwrap();
+
+		let res = self.api.get_fw_log(&c_string);
+
+		handle_res!(res)
+	}
+
+	// Flash size in bytes, and whether libbladeRF had to guess it from the FPGA size
+	pub fn get_flash_size(&self) -> Result<(u32, bool), isize> {
+		let mut size: u32 = 0;
+		let mut is_guess = false;
+
+		let res = self.api.get_flash_size(&mut size, &mut is_guess);
+
+		handle_res!(res, (size, is_guess));
+	}
+
+	// Reads `count` pages of BLADERF_FLASH_PAGE_SIZE bytes starting at `page`
+	pub fn read_flash(&self, page: u32, count: u32) -> Result<Vec<u8>, isize> {
+		let mut buf = vec![0u8; (count * BLADERF_FLASH_PAGE_SIZE) as usize];
+
+		let res = self.api.read_flash(&mut buf, page, count);
+
+		handle_res!(res, buf);
 	}
 
 
@@ -744,4 +1194,405 @@ impl BladeRFDevice {
 		};*/
 		
 	}
+
+	// Read back what configure_module sets, along with the gain mode and gain, in one call
+	pub fn get_module_config(&self, module: bladerf_module) -> Result<BladeRFModuleSettings, isize> {
+		Ok(BladeRFModuleSettings {
+			frequency: self.get_frequency(module)?,
+			sample_rate: self.get_sample_rate(module)?,
+			bandwidth: self.get_bandwidth(module)?,
+			gain_mode: self.get_gain_mode(module)?,
+			gain: self.get_gain(module)?,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock::MockBladeRf;
+
+	fn rx_config() -> BladeRFModuleConfig {
+		BladeRFModuleConfig {
+			frequency: 1086000000,
+			sample_rate: 16000000,
+			bandwidth: 14000000,
+			lna_gain: bladerf_gain_mode::BLADERF_GAIN_DEFAULT,
+			vga1: 10,
+			vga2: 6
+		}
+	}
+
+	#[test]
+	fn configure_module_tunes_then_sets_sample_rate_then_bandwidth() {
+		let mock = MockBladeRf::new();
+		let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+		dev.configure_module(bladerf_module::BLADERF_MODULE_RX, rx_config());
+
+		assert_eq!(mock.calls(), vec![
+			"set_frequency(BLADERF_MODULE_RX, 1086000000)",
+			"set_sample_rate(BLADERF_MODULE_RX, 16000000)",
+			"set_bandwidth(BLADERF_MODULE_RX, 14000000)",
+		]);
+		let state = mock.state();
+		assert_eq!((state.frequency, state.sample_rate, state.bandwidth), (1086000000, 16000000, 14000000));
+	}
+
+	#[test]
+	#[should_panic]
+	fn configure_module_panics_when_a_setter_fails() {
+		let mock = MockBladeRf::new();
+		mock.push_result("set_sample_rate", bladerf_error::BLADERF_ERR_INVAL as libc::c_int);
+		let dev = BladeRFDevice::with_api(Box::new(mock));
+
+		dev.configure_module(bladerf_module::BLADERF_MODULE_RX, rx_config());
+	}
+
+	#[test]
+	fn queued_error_codes_come_back_as_errors_in_order() {
+		let mock = MockBladeRf::new();
+		mock.push_result("set_frequency", bladerf_error::BLADERF_ERR_TIMEOUT as libc::c_int);
+		mock.push_result("set_frequency", bladerf_error::BLADERF_ERR_INVAL as libc::c_int);
+		let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+		assert_eq!(dev.set_frequency(bladerf_module::BLADERF_MODULE_RX, 1090000000), Err(bladerf_error::BLADERF_ERR_TIMEOUT as isize));
+		assert_eq!(dev.set_frequency(bladerf_module::BLADERF_MODULE_RX, 1090000000), Err(bladerf_error::BLADERF_ERR_INVAL as isize));
+		// once the queue is empty calls succeed, and the mock reports back what was set
+		assert!(dev.set_frequency(bladerf_module::BLADERF_MODULE_RX, 1086000000).is_ok());
+		assert_eq!(dev.get_frequency(bladerf_module::BLADERF_MODULE_RX), Ok(1086000000));
+		assert_eq!(mock.calls().len(), 4);
+	}
+
+	#[test]
+	fn a_device_can_be_moved_to_another_thread() {
+		fn assert_send<T: Send>() {}
+		assert_send::<BladeRFDevice>();
+
+		let mock = MockBladeRf::new();
+		let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+		std::thread::spawn(move || dev.set_frequency(bladerf_module::BLADERF_MODULE_RX, 1086000000))
+			.join().unwrap().unwrap();
+
+		assert_eq!(mock.calls(), vec!["set_frequency(BLADERF_MODULE_RX, 1086000000)"]);
+	}
+
+	#[test]
+	fn a_device_behind_a_mutex_can_be_shared() {
+		let mock = MockBladeRf::new();
+		let dev = std::sync::Arc::new(std::sync::Mutex::new(BladeRFDevice::with_api(Box::new(mock.clone()))));
+		let threads: Vec<_> = (0..4).map(|_| {
+			let dev = dev.clone();
+			std::thread::spawn(move || dev.lock().unwrap().enable_module(bladerf_module::BLADERF_MODULE_RX, true).unwrap())
+		}).collect();
+		for thread in threads {
+			thread.join().unwrap();
+		}
+
+		assert_eq!(mock.calls().len(), 4);
+	}
+
+	#[test]
+	fn global_config_starts_from_libbladerf_defaults() {
+		let config = GlobalConfig::default();
+		assert!(!config.usb_reset_on_open);
+		assert_eq!(config.log_level, bladerf_log_level::BLADERF_LOG_LEVEL_WARNING);
+	}
+
+	#[test]
+	fn global_config_builder_sets_each_field() {
+		let config = GlobalConfig::new()
+			.usb_reset_on_open(true)
+			.log_level(bladerf_log_level::BLADERF_LOG_LEVEL_DEBUG);
+		assert!(config.usb_reset_on_open);
+		assert_eq!(config.log_level, bladerf_log_level::BLADERF_LOG_LEVEL_DEBUG);
+	}
+
+	#[test]
+	fn log_levels_round_trip_between_libbladerf_and_the_log_crate() {
+		for level in [log::LevelFilter::Off, log::LevelFilter::Error, log::LevelFilter::Warn,
+		              log::LevelFilter::Info, log::LevelFilter::Debug, log::LevelFilter::Trace] {
+			assert_eq!(log::LevelFilter::from(bladerf_log_level::from(level)), level);
+		}
+	}
+
+	#[test]
+	fn critical_maps_to_error_as_the_log_crate_has_nothing_higher() {
+		assert_eq!(log::LevelFilter::from(bladerf_log_level::BLADERF_LOG_LEVEL_CRITICAL), log::LevelFilter::Error);
+		assert_eq!(bladerf_log_level::from(log::LevelFilter::Trace), bladerf_log_level::BLADERF_LOG_LEVEL_VERBOSE);
+	}
+
+	#[test]
+	fn sync_rx_reports_a_short_read_through_metadata() {
+		let mock = MockBladeRf::new();
+		mock.push_rx_buffer(vec![0xab; 40]);
+		mock.push_rx_buffer(vec![0xcd; 40]);
+		let dev = BladeRFDevice::with_api(Box::new(mock));
+		let mut data = vec![0u8; 64];
+
+		let mut meta = Struct_bladerf_metadata::default();
+		assert_eq!(dev.sync_rx(&mut data, 16, Some(&mut meta), 5000), Ok(10));
+		// without metadata there's no way to tell, so the whole request is assumed to have arrived
+		assert_eq!(dev.sync_rx(&mut data, 16, None, 5000), Ok(16));
+	}
+
+	#[test]
+	fn sync_rx_times_out_with_nothing_queued() {
+		let dev = BladeRFDevice::with_api(Box::new(MockBladeRf::new()));
+		let mut data = vec![0u8; 64];
+
+		assert_eq!(dev.sync_rx(&mut data, 16, None, 5000), Err(bladerf_error::BLADERF_ERR_TIMEOUT as isize));
+	}
+
+	#[test]
+	fn get_gain_range_reports_the_device_limits() {
+		let mock = MockBladeRf::new();
+		let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+		let range = dev.get_gain_range(bladerf_module::BLADERF_MODULE_RX).unwrap();
+		assert_eq!((range.min, range.max, range.step), (5, 60, 1));
+		assert_eq!(mock.calls(), vec!["get_gain_range(BLADERF_MODULE_RX)"]);
+	}
+
+	#[test]
+	fn sync_rx_metadata_timestamps_each_buffer_from_the_sample_counter() {
+		let mock = MockBladeRf::new();
+		mock.push_rx_buffer(vec![0; 64]);
+		mock.push_rx_buffer(vec![0; 40]);
+		mock.push_rx_buffer(vec![0; 64]);
+		let dev = BladeRFDevice::with_api(Box::new(mock));
+		let mut data = vec![0u8; 64];
+		let mut meta = Struct_bladerf_metadata::default();
+
+		let mut timestamps = Vec::new();
+		for _ in 0..3 {
+			meta.flags = BLADERF_META_FLAG_RX_NOW;
+			dev.sync_rx(&mut data, 16, Some(&mut meta), 5000).unwrap();
+			timestamps.push(meta.timestamp);
+		}
+		// the short read still moves the counter on by what it delivered
+		assert_eq!(timestamps, vec![0, 16, 26]);
+	}
+
+	#[test]
+	fn get_gain_modes_lists_what_the_board_supports() {
+		let mock = MockBladeRf::new();
+		let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+		assert_eq!(dev.get_gain_modes(bladerf_module::BLADERF_MODULE_RX),
+		           Ok(vec![bladerf_gain_mode::BLADERF_GAIN_DEFAULT, bladerf_gain_mode::BLADERF_GAIN_MGC]));
+
+		mock.push_result("get_gain_modes", bladerf_error::BLADERF_ERR_UNSUPPORTED as libc::c_int);
+		assert_eq!(dev.get_gain_modes(bladerf_module::BLADERF_MODULE_RX), Err(bladerf_error::BLADERF_ERR_UNSUPPORTED as isize));
+	}
+
+	#[test]
+	fn sync_config_uses_the_single_channel_layout_unless_given_another() {
+		let mock = MockBladeRf::new();
+		let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+		dev.sync_config(bladerf_module::BLADERF_MODULE_RX, bladerf_format::BLADERF_FORMAT_SC16_Q11, 16, 8192, Some(8), 5000).unwrap();
+		dev.enable_channel(bladerf_channel_rx(1), true).unwrap();
+		dev.sync_config_layout(bladerf_channel_layout::BLADERF_RX_X2, bladerf_format::BLADERF_FORMAT_SC16_Q11, 16, 8192, Some(8), 5000).unwrap();
+
+		assert_eq!(mock.calls(), vec![
+			"sync_config(BLADERF_RX_X1, BLADERF_FORMAT_SC16_Q11, 16, 8192, 8, 5000)",
+			"enable_channel(2, true)",
+			"sync_config(BLADERF_RX_X2, BLADERF_FORMAT_SC16_Q11, 16, 8192, 8, 5000)",
+		]);
+	}
+
+	#[test]
+	fn an_empty_selector_opens_the_first_device() {
+		assert_eq!(DeviceSelector::new().to_device_string(), "*:");
+		assert_eq!("".parse::<DeviceSelector>(), Ok(DeviceSelector::new()));
+	}
+
+	#[test]
+	fn selectors_build_libbladerf_device_strings() {
+		let selector = DeviceSelector::new().backend(bladerf_backend::BLADERF_BACKEND_LIBUSB).serial("f12ce103").usb(2, 5).instance(1);
+		assert_eq!(selector.to_device_string(), "libusb:device=2:5 instance=1 serial=f12ce103");
+	}
+
+	#[test]
+	fn selectors_parse_from_comma_separated_criteria() {
+		assert_eq!("backend=libusb, serial=f12ce103".parse::<DeviceSelector>(),
+		           Ok(DeviceSelector::new().backend(bladerf_backend::BLADERF_BACKEND_LIBUSB).serial("f12ce103")));
+		assert_eq!("usb=2:5,instance=1".parse::<DeviceSelector>(), Ok(DeviceSelector::new().usb(2, 5).instance(1)));
+	}
+
+	#[test]
+	fn malformed_criteria_are_rejected() {
+		for spec in ["serial", "usb=2", "usb=2:x", "instance=first", "backend=usb3", "name=rx0", "serial="] {
+			assert!(spec.parse::<DeviceSelector>().is_err(), "{} parsed", spec);
+		}
+	}
+
+	#[test]
+	fn an_attached_expansion_board_reads_back() {
+		let mock = MockBladeRf::new();
+		let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+		assert_eq!(dev.get_attached_expansion(), Ok(bladerf_xb::BLADERF_XB_NONE));
+		dev.attach_expansion(bladerf_xb::BLADERF_XB_200).unwrap();
+		assert_eq!(dev.get_attached_expansion(), Ok(bladerf_xb::BLADERF_XB_200));
+		assert_eq!(mock.calls()[1], "expansion_attach(BLADERF_XB_200)");
+	}
+
+	#[test]
+	fn frequency_limits_apply_the_range_scale() {
+		let mock = MockBladeRf::new();
+		mock.state().frequency_range = Struct_bladerf_range { min: 70, max: 6000, step: 1, scale: 1e6 };
+		let dev = BladeRFDevice::with_api(Box::new(mock));
+
+		assert_eq!(dev.get_frequency_limits(bladerf_module::BLADERF_MODULE_RX), Ok((70000000, 6000000000, 1000000)));
+	}
+
+	#[test]
+	fn shutdown_disables_the_module_then_closes() {
+		let mock = MockBladeRf::new();
+		let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+		assert_eq!(dev.shutdown(bladerf_module::BLADERF_MODULE_RX), Ok(()));
+		assert_eq!(mock.calls(), vec!["enable_module(BLADERF_MODULE_RX, false)", "close()"]);
+	}
+
+	#[test]
+	fn shutdown_closes_even_when_disabling_fails() {
+		let mock = MockBladeRf::new();
+		mock.push_result("enable_module", bladerf_error::BLADERF_ERR_IO as libc::c_int);
+		let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+		assert_eq!(dev.shutdown(bladerf_module::BLADERF_MODULE_RX), Err(bladerf_error::BLADERF_ERR_IO as isize));
+		assert_eq!(mock.calls().last().map(String::as_str), Some("close()"));
+	}
+
+	#[test]
+	fn the_rx_mux_reads_back() {
+		let mock = MockBladeRf::new();
+		let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+		assert_eq!(dev.get_rx_mux(), Ok(bladerf_rx_mux::BLADERF_RX_MUX_BASEBAND));
+		dev.set_rx_mux(bladerf_rx_mux::BLADERF_RX_MUX_32BIT_COUNTER).unwrap();
+		assert_eq!(dev.get_rx_mux(), Ok(bladerf_rx_mux::BLADERF_RX_MUX_32BIT_COUNTER));
+		assert_eq!(mock.state().rx_mux, bladerf_rx_mux::BLADERF_RX_MUX_32BIT_COUNTER);
+	}
+
+	#[test]
+	fn retry_rides_out_transient_errors() {
+		let mut results = vec![Ok(7), Err(bladerf_error::BLADERF_ERR_TIMEOUT as isize), Err(bladerf_error::BLADERF_ERR_IO as isize)];
+		let mut calls = 0;
+
+		assert_eq!(retry("test", 3, time::Duration::ZERO, || { calls += 1; results.pop().unwrap() }), Ok(7));
+		assert_eq!(calls, 3);
+	}
+
+	#[test]
+	fn retry_gives_up_after_the_last_attempt() {
+		let mut calls = 0;
+
+		let result: Result<(), isize> = retry("test", 2, time::Duration::ZERO, || { calls += 1; Err(bladerf_error::BLADERF_ERR_IO as isize) });
+		assert_eq!(result, Err(bladerf_error::BLADERF_ERR_IO as isize));
+		assert_eq!(calls, 2);
+	}
+
+	#[test]
+	fn retry_returns_other_errors_straight_away() {
+		let mut calls = 0;
+
+		let result: Result<(), isize> = retry("test", 3, time::Duration::ZERO, || { calls += 1; Err(bladerf_error::BLADERF_ERR_NODEV as isize) });
+		assert_eq!(result, Err(bladerf_error::BLADERF_ERR_NODEV as isize));
+		assert_eq!(calls, 1);
+		assert!(!is_transient(bladerf_error::BLADERF_ERR_INVAL as isize));
+	}
+
+	#[test]
+	fn unrecognized_fpga_sizes_read_as_unknown() {
+		let mock = MockBladeRf::new();
+		let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+		assert_eq!(dev.get_fpga_size(), Ok(bladerf_fpga_size::BLADERF_FPGA_A4));
+		mock.state().fpga_size = 500;
+		assert_eq!(dev.get_fpga_size(), Ok(bladerf_fpga_size::BLADERF_FPGA_UNKNOWN));
+		assert_eq!(fpga_size_from_raw(bladerf_fpga_size::BLADERF_FPGA_A9 as libc::c_int), Some(bladerf_fpga_size::BLADERF_FPGA_A9));
+		assert_eq!(fpga_size_from_raw(500), None);
+	}
+
+	#[test]
+	fn only_a_missing_device_or_io_error_is_a_disconnect() {
+		assert!(is_disconnect(bladerf_error::BLADERF_ERR_NODEV as isize));
+		assert!(is_disconnect(bladerf_error::BLADERF_ERR_IO as isize));
+		assert!(!is_disconnect(bladerf_error::BLADERF_ERR_TIMEOUT as isize));
+	}
+
+	#[test]
+	fn get_module_config_reads_back_what_was_configured() {
+		let mock = MockBladeRf::new();
+		let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+		dev.configure_module(bladerf_module::BLADERF_MODULE_RX, rx_config());
+		dev.set_gain_mode(bladerf_module::BLADERF_MODULE_RX, bladerf_gain_mode::BLADERF_GAIN_MGC).unwrap();
+		dev.set_gain(bladerf_module::BLADERF_MODULE_RX, 30).unwrap();
+
+		let settings = dev.get_module_config(bladerf_module::BLADERF_MODULE_RX).unwrap();
+		assert_eq!((settings.frequency, settings.sample_rate, settings.bandwidth), (1086000000, 16000000, 14000000));
+		assert_eq!((settings.gain_mode, settings.gain), (bladerf_gain_mode::BLADERF_GAIN_MGC, 30));
+	}
+
+	#[test]
+	fn get_module_config_fails_if_any_query_does() {
+		let mock = MockBladeRf::new();
+		mock.push_result("get_bandwidth", bladerf_error::BLADERF_ERR_IO as libc::c_int);
+		let dev = BladeRFDevice::with_api(Box::new(mock));
+
+		assert_eq!(dev.get_module_config(bladerf_module::BLADERF_MODULE_RX).map(|_| ()), Err(bladerf_error::BLADERF_ERR_IO as isize));
+	}
+
+	#[test]
+	fn retry_with_backoff_retries_any_error() {
+		let mut results = vec![Ok(7), Err(bladerf_error::BLADERF_ERR_NODEV as isize), Err(bladerf_error::BLADERF_ERR_IO as isize)];
+		let mut calls = 0;
+
+		assert_eq!(retry_with_backoff("test", 3, time::Duration::ZERO, time::Duration::ZERO, || { calls += 1; results.pop().unwrap() }), Ok(7));
+		assert_eq!(calls, 3);
+	}
+
+	#[test]
+	fn retry_with_backoff_doubles_the_delay_up_to_the_maximum() {
+		let mut calls = Vec::new();
+		let start = time::Instant::now();
+
+		let result: Result<(), isize> = retry_with_backoff("test", 4, time::Duration::from_millis(10), time::Duration::from_millis(15), || {
+			calls.push(start.elapsed());
+			Err(bladerf_error::BLADERF_ERR_NODEV as isize)
+		});
+		assert_eq!(result, Err(bladerf_error::BLADERF_ERR_NODEV as isize));
+		assert_eq!(calls.len(), 4);
+		// 10ms, then 15ms rather than 20ms, then 15ms again
+		assert!(calls[1] - calls[0] >= time::Duration::from_millis(10));
+		assert!(calls[2] - calls[1] >= time::Duration::from_millis(15));
+		assert!(calls[3] >= time::Duration::from_millis(40));
+	}
+
+	#[test]
+	fn gain_modes_round_trip_through_their_names() {
+		for name in ["default", "manual", "fast", "slow", "hybrid"] {
+			assert_eq!(gain_mode_from_name(name).map(gain_mode_name), Some(name));
+		}
+		assert_eq!(gain_mode_from_name("agc"), None);
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn module_configs_round_trip_through_json() {
+		let json = serde_json::to_string(&rx_config()).unwrap();
+		assert_eq!(json, r#"{"frequency":1086000000,"sample_rate":16000000,"bandwidth":14000000,"lna_gain":"default","vga1":10,"vga2":6}"#);
+
+		let config: BladeRFModuleConfig = serde_json::from_str(&json.replace("default", "hybrid")).unwrap();
+		assert_eq!((config.frequency, config.sample_rate, config.bandwidth), (1086000000, 16000000, 14000000));
+		assert_eq!(config.lna_gain, bladerf_gain_mode::BLADERF_GAIN_HYBRID_AGC);
+		assert_eq!((config.vga1, config.vga2), (10, 6));
+
+		let err = serde_json::from_str::<BladeRFModuleConfig>(&json.replace("default", "agc")).err().unwrap();
+		assert!(err.to_string().contains("unknown gain mode 'agc'"), "{}", err);
+	}
 }
@@ -0,0 +1,435 @@
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CStr;
+use std::sync::{Arc, Mutex};
+
+use crate::api::BladeRfApi;
+use crate::bladerf::*;
+
+// Device state the mock reports back through its getters
+pub struct MockState {
+	// every call made, formatted as "name(args)"
+	pub calls: Vec<String>,
+	// queued status codes per call name, 0 (success) once a queue is empty
+	pub results: HashMap<String, VecDeque<libc::c_int>>,
+	// buffers handed out by successive sync_rx calls
+	pub rx_buffers: VecDeque<Vec<u8>>,
+	// sample counter reported in meta.timestamp, advanced by every sync_rx
+	pub rx_timestamp: u64,
+	pub devinfo: Struct_bladerf_devinfo,
+	// raw value, so sizes missing from bladerf_fpga_size can be simulated
+	pub fpga_size: libc::c_int,
+	pub fpga_configured: bool,
+	pub speed: bladerf_dev_speed,
+	pub frequency: u64,
+	pub sample_rate: u32,
+	pub bandwidth: u32,
+	pub gain: bladerf_gain,
+	pub gain_mode: bladerf_gain_mode,
+	pub gain_range: Struct_bladerf_range,
+	pub gain_modes: Vec<bladerf_gain_mode>,
+	pub sample_rate_range: Struct_bladerf_range,
+	pub frequency_range: Struct_bladerf_range,
+	pub board_name: String,
+	pub rf_ports: Vec<String>,
+	pub rf_port: String,
+	pub bias_tee: bool,
+	pub rx_mux: bladerf_rx_mux,
+	pub expansion: bladerf_xb,
+	// contents returned by read_flash, its length is the reported flash size
+	pub flash: Vec<u8>,
+	pub flash_size_guess: bool,
+	// written out by get_fw_log
+	pub fw_log: String
+}
+
+// In-memory stand-in for libbladeRF. Setters update MockState and getters read it back, so
+// BladeRFDevice behaves much like it would on hardware. Clones share the same state, so keep
+// one clone around to inspect calls after handing the other to BladeRFDevice::with_api.
+#[derive(Clone)]
+pub struct MockBladeRf {
+	state: Arc<Mutex<MockState>>
+}
+
+impl MockBladeRf {
+	pub fn new() -> MockBladeRf {
+		MockBladeRf {
+			state: Arc::new(Mutex::new(MockState {
+				calls: Vec::new(),
+				results: HashMap::new(),
+				rx_buffers: VecDeque::new(),
+				rx_timestamp: 0,
+				devinfo: Struct_bladerf_devinfo::default(),
+				fpga_size: bladerf_fpga_size::BLADERF_FPGA_A4 as libc::c_int,
+				fpga_configured: true,
+				speed: bladerf_dev_speed::BLADERF_DEVICE_SPEED_SUPER,
+				frequency: 0,
+				sample_rate: 0,
+				bandwidth: 0,
+				gain: 0,
+				gain_mode: bladerf_gain_mode::BLADERF_GAIN_DEFAULT,
+				// the bladeRF 1 RX gain range
+				gain_range: Struct_bladerf_range { min: 5, max: 60, step: 1, scale: 1.0 },
+				gain_modes: vec![bladerf_gain_mode::BLADERF_GAIN_DEFAULT, bladerf_gain_mode::BLADERF_GAIN_MGC],
+				sample_rate_range: Struct_bladerf_range { min: 160000, max: 40000000, step: 1, scale: 1.0 },
+				frequency_range: Struct_bladerf_range { min: 280000000, max: 3800000000, step: 1, scale: 1.0 },
+				board_name: String::from("bladerf1"),
+				// what libbladeRF reports for a bladeRF 2.0 RX channel
+				rf_ports: ["A_BALANCED", "B_BALANCED", "C_BALANCED", "A_N", "A_P", "B_N", "B_P", "C_N", "C_P",
+					"TX_MON1", "TX_MON2", "TX_MON1_2"].iter().map(|p| String::from(*p)).collect(),
+				rf_port: String::from("A_BALANCED"),
+				bias_tee: false,
+				rx_mux: bladerf_rx_mux::BLADERF_RX_MUX_BASEBAND,
+				expansion: bladerf_xb::BLADERF_XB_NONE,
+				// the 4 MiB flash of a bladeRF x40/x115, erased
+				flash: vec![0xff; 4 * 1024 * 1024],
+				flash_size_guess: false,
+				fw_log: String::new()
+			}))
+		}
+	}
+
+	// Direct access to the mock's state, e.g. to preload rx_buffers or change the fpga size
+	pub fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+		self.state.lock().unwrap()
+	}
+
+	// Calls made so far, in order
+	pub fn calls(&self) -> Vec<String> {
+		self.state().calls.clone()
+	}
+
+	// Queue a status code to be returned by the next call of the given name
+	pub fn push_result(&self, call: &str, result: libc::c_int) {
+		self.state().results.entry(call.to_string()).or_default().push_back(result);
+	}
+
+	pub fn push_rx_buffer(&self, buffer: Vec<u8>) {
+		self.state().rx_buffers.push_back(buffer);
+	}
+
+	// Record a call and pop its queued status code
+	fn record(&self, name: &str, call: String) -> libc::c_int {
+		let mut state = self.state();
+		state.calls.push(call);
+		match state.results.get_mut(name) {
+			Some(queue) => queue.pop_front().unwrap_or(0),
+			None => 0
+		}
+	}
+}
+
+impl Default for MockBladeRf {
+	fn default() -> Self {
+		MockBladeRf::new()
+	}
+}
+
+impl BladeRfApi for MockBladeRf {
+	fn close(&self) {
+		self.record("close", String::from("close()"));
+	}
+
+	fn device_reset(&self) -> libc::c_int {
+		self.record("device_reset", String::from("device_reset()"))
+	}
+
+	fn get_devinfo(&self, info: &mut Struct_bladerf_devinfo) -> libc::c_int {
+		let res = self.record("get_devinfo", String::from("get_devinfo()"));
+		*info = self.state().devinfo;
+		res
+	}
+
+	fn get_serial(&self, serial: &mut [libc::c_char; 33]) -> libc::c_int {
+		let res = self.record("get_serial", String::from("get_serial()"));
+		*serial = self.state().devinfo.serial;
+		res
+	}
+
+	fn get_fpga_size(&self, size: &mut libc::c_int) -> libc::c_int {
+		let res = self.record("get_fpga_size", String::from("get_fpga_size()"));
+		*size = self.state().fpga_size;
+		res
+	}
+
+	fn fw_version(&self, version: &mut Struct_bladerf_version) -> libc::c_int {
+		*version = Struct_bladerf_version::default();
+		self.record("fw_version", String::from("fw_version()"))
+	}
+
+	fn fpga_version(&self, version: &mut Struct_bladerf_version) -> libc::c_int {
+		*version = Struct_bladerf_version::default();
+		self.record("fpga_version", String::from("fpga_version()"))
+	}
+
+	// A queued result (an error, or 1 for configured) takes precedence over fpga_configured, so
+	// queueing [0, 1] with fpga_configured false reports unconfigured once, then configured
+	fn is_fpga_configured(&self) -> libc::c_int {
+		let res = self.record("is_fpga_configured", String::from("is_fpga_configured()"));
+		if res != 0 {
+			return res;
+		}
+		self.state().fpga_configured as libc::c_int
+	}
+
+	fn device_speed(&self) -> bladerf_dev_speed {
+		self.record("device_speed", String::from("device_speed()"));
+		self.state().speed
+	}
+
+	fn get_board_name(&self) -> String {
+		self.record("get_board_name", String::from("get_board_name()"));
+		self.state().board_name.clone()
+	}
+
+	fn set_rf_port(&self, channel: bladerf_channel, port: &CStr) -> libc::c_int {
+		let port = port.to_string_lossy().into_owned();
+		let res = self.record("set_rf_port", format!("set_rf_port({}, {})", channel, port));
+		if res < 0 {
+			return res;
+		}
+		let mut state = self.state();
+		if !state.rf_ports.contains(&port) {
+			return bladerf_error::BLADERF_ERR_INVAL as libc::c_int;
+		}
+		state.rf_port = port;
+		res
+	}
+
+	fn get_rf_port(&self, channel: bladerf_channel, port: &mut String) -> libc::c_int {
+		let res = self.record("get_rf_port", format!("get_rf_port({})", channel));
+		*port = self.state().rf_port.clone();
+		res
+	}
+
+	fn get_rf_ports(&self, channel: bladerf_channel, ports: &mut Vec<String>) -> libc::c_int {
+		let res = self.record("get_rf_ports", format!("get_rf_ports({})", channel));
+		if res < 0 {
+			return res;
+		}
+		let state = self.state();
+		ports.extend(state.rf_ports.iter().cloned());
+		state.rf_ports.len() as libc::c_int
+	}
+
+	fn enable_module(&self, module: bladerf_module, enable: bool) -> libc::c_int {
+		self.record("enable_module", format!("enable_module({:?}, {})", module, enable))
+	}
+
+	fn enable_channel(&self, channel: bladerf_channel, enable: bool) -> libc::c_int {
+		self.record("enable_channel", format!("enable_channel({}, {})", channel, enable))
+	}
+
+	fn set_gain(&self, module: bladerf_module, gain: bladerf_gain) -> libc::c_int {
+		let res = self.record("set_gain", format!("set_gain({:?}, {})", module, gain));
+		if res >= 0 {
+			self.state().gain = gain;
+		}
+		res
+	}
+
+	fn get_gain(&self, module: bladerf_module, gain: &mut bladerf_gain) -> libc::c_int {
+		let res = self.record("get_gain", format!("get_gain({:?})", module));
+		*gain = self.state().gain;
+		res
+	}
+
+	fn set_gain_mode(&self, module: bladerf_module, mode: bladerf_gain_mode) -> libc::c_int {
+		let res = self.record("set_gain_mode", format!("set_gain_mode({:?}, {:?})", module, mode));
+		if res >= 0 {
+			self.state().gain_mode = mode;
+		}
+		res
+	}
+
+	fn get_gain_mode(&self, module: bladerf_module, mode: &mut bladerf_gain_mode) -> libc::c_int {
+		let res = self.record("get_gain_mode", format!("get_gain_mode({:?})", module));
+		*mode = self.state().gain_mode;
+		res
+	}
+
+	fn get_gain_range(&self, module: bladerf_module, range: &mut Struct_bladerf_range) -> libc::c_int {
+		let res = self.record("get_gain_range", format!("get_gain_range({:?})", module));
+		*range = self.state().gain_range;
+		res
+	}
+
+	fn get_gain_modes(&self, module: bladerf_module, modes: &mut Vec<bladerf_gain_mode>) -> libc::c_int {
+		let res = self.record("get_gain_modes", format!("get_gain_modes({:?})", module));
+		if res < 0 {
+			return res;
+		}
+		let state = self.state();
+		modes.extend(state.gain_modes.iter().cloned());
+		state.gain_modes.len() as libc::c_int
+	}
+
+	fn set_sample_rate(&self, module: bladerf_module, rate: u32, actual: &mut u32) -> libc::c_int {
+		let res = self.record("set_sample_rate", format!("set_sample_rate({:?}, {})", module, rate));
+		if res >= 0 {
+			self.state().sample_rate = rate;
+			*actual = rate;
+		}
+		res
+	}
+
+	fn get_sample_rate(&self, module: bladerf_module, rate: &mut u32) -> libc::c_int {
+		let res = self.record("get_sample_rate", format!("get_sample_rate({:?})", module));
+		*rate = self.state().sample_rate;
+		res
+	}
+
+	fn get_sample_rate_range(&self, module: bladerf_module, range: &mut Struct_bladerf_range) -> libc::c_int {
+		let res = self.record("get_sample_rate_range", format!("get_sample_rate_range({:?})", module));
+		*range = self.state().sample_rate_range;
+		res
+	}
+
+	fn set_bandwidth(&self, module: bladerf_module, bandwidth: u32, actual: &mut u32) -> libc::c_int {
+		let res = self.record("set_bandwidth", format!("set_bandwidth({:?}, {})", module, bandwidth));
+		if res >= 0 {
+			self.state().bandwidth = bandwidth;
+			*actual = bandwidth;
+		}
+		res
+	}
+
+	fn get_bandwidth(&self, module: bladerf_module, bandwidth: &mut u32) -> libc::c_int {
+		let res = self.record("get_bandwidth", format!("get_bandwidth({:?})", module));
+		*bandwidth = self.state().bandwidth;
+		res
+	}
+
+	fn set_frequency(&self, module: bladerf_module, frequency: u32) -> libc::c_int {
+		let res = self.record("set_frequency", format!("set_frequency({:?}, {})", module, frequency));
+		if res >= 0 {
+			self.state().frequency = frequency as u64;
+		}
+		res
+	}
+
+	fn get_frequency(&self, module: bladerf_module, frequency: &mut u64) -> libc::c_int {
+		let res = self.record("get_frequency", format!("get_frequency({:?})", module));
+		*frequency = self.state().frequency;
+		res
+	}
+
+	fn get_frequency_range(&self, module: bladerf_module, range: &mut Struct_bladerf_range) -> libc::c_int {
+		let res = self.record("get_frequency_range", format!("get_frequency_range({:?})", module));
+		*range = self.state().frequency_range;
+		res
+	}
+
+	fn set_bias_tee(&self, module: bladerf_module, enable: bool) -> libc::c_int {
+		let res = self.record("set_bias_tee", format!("set_bias_tee({:?}, {})", module, enable));
+		if res >= 0 {
+			self.state().bias_tee = enable;
+		}
+		res
+	}
+
+	fn get_bias_tee(&self, module: bladerf_module, enable: &mut bool) -> libc::c_int {
+		let res = self.record("get_bias_tee", format!("get_bias_tee({:?})", module));
+		*enable = self.state().bias_tee;
+		res
+	}
+
+	fn set_rx_mux(&self, mux: bladerf_rx_mux) -> libc::c_int {
+		let res = self.record("set_rx_mux", format!("set_rx_mux({:?})", mux));
+		if res >= 0 {
+			self.state().rx_mux = mux;
+		}
+		res
+	}
+
+	fn get_rx_mux(&self, mux: &mut bladerf_rx_mux) -> libc::c_int {
+		let res = self.record("get_rx_mux", String::from("get_rx_mux()"));
+		*mux = self.state().rx_mux;
+		res
+	}
+
+	fn expansion_attach(&self, xb: bladerf_xb) -> libc::c_int {
+		let res = self.record("expansion_attach", format!("expansion_attach({:?})", xb));
+		if res >= 0 {
+			self.state().expansion = xb;
+		}
+		res
+	}
+
+	fn expansion_get_attached(&self, xb: &mut bladerf_xb) -> libc::c_int {
+		let res = self.record("expansion_get_attached", String::from("expansion_get_attached()"));
+		*xb = self.state().expansion;
+		res
+	}
+
+	fn sync_config(&self, layout: bladerf_channel_layout, format: bladerf_format, num_buffers: u32,
+				   buffer_size: u32, num_transfers: u32, stream_timeout: u32) -> libc::c_int {
+		self.record("sync_config", format!("sync_config({:?}, {:?}, {}, {}, {}, {})",
+			layout, format, num_buffers, buffer_size, num_transfers, stream_timeout))
+	}
+
+	// Copies the next queued buffer into data, or times out when none are left. A buffer shorter
+	// than requested is reported as a short read through meta.actual_count, 4 bytes per sample,
+	// and meta.timestamp counts samples handed out so far.
+	fn sync_rx(&self, data: &mut [u8], num_samples: u32, meta: Option<&mut Struct_bladerf_metadata>,
+			   stream_timeout: u32) -> libc::c_int {
+		let res = self.record("sync_rx", format!("sync_rx({}, {})", num_samples, stream_timeout));
+		if res < 0 {
+			return res;
+		}
+
+		let mut state = self.state();
+		match state.rx_buffers.pop_front() {
+			Some(buffer) => {
+				let n = buffer.len().min(data.len());
+				data[..n].copy_from_slice(&buffer[..n]);
+				let samples = ((n / 4) as u32).min(num_samples);
+				if let Some(meta) = meta {
+					meta.actual_count = samples;
+					meta.timestamp = state.rx_timestamp;
+				}
+				state.rx_timestamp += samples as u64;
+				res
+			},
+			None => bladerf_error::BLADERF_ERR_TIMEOUT as libc::c_int
+		}
+	}
+
+	fn load_fpga(&self, file: &CStr) -> libc::c_int {
+		self.record("load_fpga", format!("load_fpga({})", file.to_string_lossy()))
+	}
+
+	fn get_fw_log(&self, file: &CStr) -> libc::c_int {
+		let res = self.record("get_fw_log", format!("get_fw_log({})", file.to_string_lossy()));
+		if res < 0 {
+			return res;
+		}
+		let log = self.state().fw_log.clone();
+		match std::fs::write(file.to_string_lossy().as_ref(), log) {
+			Ok(()) => res,
+			Err(_) => bladerf_error::BLADERF_ERR_IO as libc::c_int
+		}
+	}
+
+	fn get_flash_size(&self, size: &mut u32, is_guess: &mut bool) -> libc::c_int {
+		let res = self.record("get_flash_size", String::from("get_flash_size()"));
+		let state = self.state();
+		*size = state.flash.len() as u32;
+		*is_guess = state.flash_size_guess;
+		res
+	}
+
+	fn read_flash(&self, buf: &mut [u8], page: u32, count: u32) -> libc::c_int {
+		let res = self.record("read_flash", format!("read_flash({}, {})", page, count));
+		if res < 0 {
+			return res;
+		}
+		let state = self.state();
+		let start = page as usize * BLADERF_FLASH_PAGE_SIZE as usize;
+		let end = start + count as usize * BLADERF_FLASH_PAGE_SIZE as usize;
+		if end > state.flash.len() || end - start > buf.len() {
+			return bladerf_error::BLADERF_ERR_INVAL as libc::c_int;
+		}
+		buf[..end - start].copy_from_slice(&state.flash[start..end]);
+		res
+	}
+}
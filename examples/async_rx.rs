@@ -0,0 +1,37 @@
+// Print frames from an async RX stream
+//
+// cargo run --example async_rx --features async -- /usr/share/Nuand/bladeRF/adsbxA4.rbf
+
+use bladerf::BladeRFModuleConfig;
+use bladerf::bladerf::bladerf_module::*;
+use bladerf::bladerf::bladerf_format::*;
+use bladerf::bladerf::bladerf_gain_mode::*;
+use bladerf_adsb::stream::AsyncRxStream;
+
+#[tokio::main]
+async fn main() {
+  let fpga_path = std::env::args().nth(1).expect("usage: async_rx <fpga image>");
+
+  let mut stream = AsyncRxStream::spawn(move || {
+    let dev = bladerf::open(None)?;
+    dev.load_fpga(fpga_path)?;
+    dev.close();
+
+    // the device re-enumerates with the new image
+    let dev = bladerf::open(None)?;
+    dev.configure_module(BLADERF_MODULE_RX, BladeRFModuleConfig {
+      frequency: 1086000000,
+      sample_rate: 16000000,
+      bandwidth: 14000000,
+      lna_gain: BLADERF_GAIN_DEFAULT,
+      vga1: 10,
+      vga2: 6,
+    });
+    dev.sync_config(BLADERF_MODULE_RX, BLADERF_FORMAT_SC16_Q11, 2, 1024, Some(1), 5000)?;
+    Ok(dev)
+  });
+
+  while let Some(frame) = stream.next_frame().await {
+    print!("{}", frame.to_avr());
+  }
+}
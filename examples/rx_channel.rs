@@ -0,0 +1,29 @@
+// Print the frames of a raw sample capture, read through run_rx
+//
+// cargo run --example rx_channel -- capture.sc16q11
+
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use bladerf_adsb::replay::CaptureFormat;
+use bladerf_adsb::rx::{run_rx, RxConfig, RxSource};
+
+fn main() {
+  let path = std::env::args().nth(1).expect("usage: rx_channel <capture>");
+
+  let stop = Arc::new(AtomicBool::new(false));
+  let (frames, handle) = run_rx(RxConfig::new(RxSource::Replay {
+    path: PathBuf::from(path),
+    format: CaptureFormat::Sc16Q11,
+    sample_rate: 16000000,
+  }), stop);
+
+  // ends once the capture has been read and the reader hangs up
+  for frame in frames {
+    print!("{}", frame.to_avr());
+  }
+  if let Err(e) = handle.join().expect("RX thread panicked") {
+    eprintln!("Error: {}", e);
+  }
+}
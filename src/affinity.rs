@@ -0,0 +1,30 @@
+// Pin the calling thread to one CPU core, so the RX loop isn't migrated between cores or left
+// waiting behind other work on a busy host. Fails on platforms without thread affinity and for
+// cores that don't exist.
+pub fn pin_current_thread(core: usize) -> Result<(), String> {
+  let cores = core_affinity::get_core_ids().ok_or_else(|| String::from("CPU affinity isn't supported on this platform"))?;
+  let id = cores.into_iter().find(|id| id.id == core)
+    .ok_or_else(|| format!("there is no CPU core {}", core))?;
+  if !core_affinity::set_for_current(id) {
+    return Err(format!("unable to pin to CPU core {}", core));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::thread;
+
+  #[test]
+  fn a_core_that_doesnt_exist_is_refused() {
+    assert_eq!(pin_current_thread(usize::MAX), Err(format!("there is no CPU core {}", usize::MAX)));
+  }
+
+  #[test]
+  fn a_thread_can_be_pinned_to_an_available_core() {
+    let core = core_affinity::get_core_ids().unwrap()[0].id;
+    // on its own thread, so the test harness's isn't left pinned
+    assert_eq!(thread::spawn(move || pin_current_thread(core)).join().unwrap(), Ok(()));
+  }
+}
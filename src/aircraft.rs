@@ -0,0 +1,587 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::time::{Duration, Instant};
+
+use crate::decode::{decode_all_call, decode_extended_squitter};
+use crate::frame::AdsbFrame;
+
+// An even and an odd CPR frame further apart than this may describe positions too far apart to
+// be combined, so the pair is discarded
+pub const CPR_PAIR_MAX_AGE: Duration = Duration::from_secs(10);
+
+// Aircraft not heard from for this long are dropped by prune()
+pub const AIRCRAFT_MAX_AGE: Duration = Duration::from_secs(60);
+
+// Number of latitude zones between the equator and a pole
+pub const CPR_NZ: f64 = 15.0;
+// CPR coordinates are 17 bit fractions of a zone
+pub const CPR_SCALE: f64 = 131072.0;
+
+// 6 bit character set of the aircraft identification message, '#' marks unassigned codes
+pub const CALLSIGN_CHARSET: &[u8; 64] = b"#ABCDEFGHIJKLMNOPQRSTUVWXYZ##### ###############0123456789######";
+
+// What is known about one aircraft, as handed to consumers
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aircraft {
+  pub icao: u32,
+  pub callsign: Option<String>,
+  // barometric altitude in feet
+  pub altitude: Option<i32>,
+  // latitude and longitude in degrees
+  pub position: Option<(f64, f64)>,
+  // knots and degrees clockwise from true north
+  pub ground_speed: Option<f64>,
+  pub track: Option<f64>,
+  // feet per minute, negative when descending
+  pub vertical_rate: Option<i32>,
+  pub messages: u64,
+  pub last_seen: Instant,
+}
+
+// What a single frame said about its aircraft, as opposed to everything known about it. Only
+// the fields its type code carries are set; position only once this frame completes a pair.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrameFields {
+  pub icao: u32,
+  pub type_code: u8,
+  pub callsign: Option<String>,
+  pub altitude: Option<i32>,
+  pub position: Option<(f64, f64)>,
+  pub ground_speed: Option<f64>,
+  pub track: Option<f64>,
+  pub vertical_rate: Option<i32>,
+}
+
+// One half of a CPR pair: the raw 17 bit latitude and longitude
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct CprFrame {
+  lat: u32,
+  lon: u32,
+  // from a surface position message, which can't be paired with an airborne one
+  surface: bool,
+  received: Instant,
+}
+
+struct AircraftState {
+  aircraft: Aircraft,
+  even: Option<CprFrame>,
+  odd: Option<CprFrame>,
+}
+
+// Per-ICAO state built up from decoded extended squitters. Positions need an even and an odd
+// CPR frame from the same aircraft, so every consumer that shows positions (the live table,
+// SBS output) should read from one shared registry, e.g. behind an Arc<Mutex<_>>, rather than
+// pairing frames on its own and ending up with different answers.
+pub struct AircraftRegistry {
+  aircraft: HashMap<u32, AircraftState>,
+  // where the receiver is, to pick the right one of the four positions a surface pair decodes to
+  reference: Option<(f64, f64)>,
+}
+
+impl AircraftRegistry {
+  pub fn new() -> AircraftRegistry {
+    AircraftRegistry {
+      aircraft: HashMap::new(),
+      reference: None,
+    }
+  }
+
+  // A registry that resolves surface positions against the receiver at `reference`. Without one,
+  // surface positions are only resolved for aircraft whose position is already known.
+  pub fn with_reference(reference: (f64, f64)) -> AircraftRegistry {
+    AircraftRegistry { reference: Some(reference), ..AircraftRegistry::new() }
+  }
+
+  // Fold a frame into its aircraft's state. Returns the ICAO address it was attributed to, or
+  // None for frames that aren't valid extended squitters or DF11 all-call replies.
+  pub fn ingest(&mut self, frame: &AdsbFrame, now: Instant) -> Option<u32> {
+    self.decode(frame, now).map(|fields| fields.icao)
+  }
+
+  // Like ingest, but also returns what this frame decoded to. A DF11 reply only tells us the
+  // aircraft is there, so its fields are just the address.
+  pub fn decode(&mut self, frame: &AdsbFrame, now: Instant) -> Option<FrameFields> {
+    if let Some(reply) = decode_all_call(&frame.data) {
+      self.seen(reply.icao, now);
+      return Some(FrameFields { icao: reply.icao, ..FrameFields::default() });
+    }
+
+    let squitter = decode_extended_squitter(&frame.data)?;
+    let me = squitter.me;
+    let receiver = self.reference;
+    let state = self.seen(squitter.icao, now);
+
+    let mut fields = FrameFields {
+      icao: squitter.icao,
+      type_code: squitter.type_code,
+      ..FrameFields::default()
+    };
+    match squitter.type_code {
+      1..=4 => {
+        let callsign = decode_callsign(&me);
+        state.aircraft.callsign = Some(callsign.clone());
+        fields.callsign = Some(callsign);
+      },
+      5..=8 => {
+        if let Some(speed) = decode_surface_movement(&me) {
+          state.aircraft.ground_speed = Some(speed);
+          fields.ground_speed = Some(speed);
+        }
+        // the track is only sent while it's valid, i.e. while the aircraft is moving
+        if me[1] & 0x08 != 0 {
+          let track = ((((me[1] & 0x07) as u32) << 4) | (me[2] >> 4) as u32) as f64 * 360.0 / 128.0;
+          state.aircraft.track = Some(track);
+          fields.track = Some(track);
+        }
+        // the receiver if we know where it is, otherwise wherever the aircraft was last seen
+        let reference = receiver.or(state.aircraft.position);
+        if let Some(position) = state.pair_position(&me, true, now, |even, odd, odd_is_newer| {
+          reference.and_then(|reference| cpr_surface(even, odd, odd_is_newer, reference))
+        }) {
+          state.aircraft.position = Some(position);
+          fields.position = Some(position);
+        }
+      },
+      9..=18 => {
+        if let Some(altitude) = decode_altitude(&me) {
+          state.aircraft.altitude = Some(altitude);
+          fields.altitude = Some(altitude);
+        }
+        if let Some(position) = state.pair_position(&me, false, now, cpr_global) {
+          state.aircraft.position = Some(position);
+          fields.position = Some(position);
+        }
+      },
+      19 => {
+        if let Some((speed, track)) = decode_ground_velocity(&me) {
+          state.aircraft.ground_speed = Some(speed);
+          state.aircraft.track = Some(track);
+          fields.ground_speed = Some(speed);
+          fields.track = Some(track);
+        }
+        if let Some(rate) = decode_vertical_rate(&me) {
+          state.aircraft.vertical_rate = Some(rate);
+          fields.vertical_rate = Some(rate);
+        }
+      },
+      _ => {},
+    }
+
+    Some(fields)
+  }
+
+  // Count a message from the aircraft, adding it if it's new
+  fn seen(&mut self, icao: u32, now: Instant) -> &mut AircraftState {
+    let state = self.aircraft.entry(icao).or_insert_with(|| AircraftState {
+      aircraft: Aircraft {
+        icao,
+        callsign: None,
+        altitude: None,
+        position: None,
+        ground_speed: None,
+        track: None,
+        vertical_rate: None,
+        messages: 0,
+        last_seen: now,
+      },
+      even: None,
+      odd: None,
+    });
+    state.aircraft.messages += 1;
+    state.aircraft.last_seen = now;
+    state
+  }
+
+  pub fn get(&self, icao: u32) -> Option<&Aircraft> {
+    self.aircraft.get(&icao).map(|state| &state.aircraft)
+  }
+
+  pub fn len(&self) -> usize {
+    self.aircraft.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.aircraft.is_empty()
+  }
+
+  // Every aircraft, ordered by ICAO address so consumers render a stable list
+  pub fn snapshot(&self) -> Vec<Aircraft> {
+    let mut aircraft: Vec<Aircraft> = self.aircraft.values().map(|state| state.aircraft.clone()).collect();
+    aircraft.sort_by_key(|a| a.icao);
+    aircraft
+  }
+
+  // Forget aircraft not heard from in max_age. Returns how many were dropped.
+  pub fn prune(&mut self, max_age: Duration, now: Instant) -> usize {
+    let before = self.aircraft.len();
+    self.aircraft.retain(|_, state| now.saturating_duration_since(state.aircraft.last_seen) <= max_age);
+    before - self.aircraft.len()
+  }
+}
+
+impl Default for AircraftRegistry {
+  fn default() -> Self {
+    AircraftRegistry::new()
+  }
+}
+
+// Parse a position written as "LAT,LON" in decimal degrees, e.g. "52.31,4.76"
+pub fn parse_position(s: &str) -> Result<(f64, f64), String> {
+  let invalid = || format!("'{}' isn't a position, expected LAT,LON in decimal degrees", s);
+  let (lat, lon) = s.split_once(',').ok_or_else(invalid)?;
+  let lat: f64 = lat.trim().parse().map_err(|_| invalid())?;
+  let lon: f64 = lon.trim().parse().map_err(|_| invalid())?;
+  if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+    return Err(invalid());
+  }
+  Ok((lat, lon))
+}
+
+impl AircraftState {
+  // Store the CPR half of a position message and, if it completes a pair of the same kind
+  // received close enough together, decode the pair with `decode`
+  fn pair_position<F>(&mut self, me: &[u8; 7], surface: bool, now: Instant, decode: F) -> Option<(f64, f64)>
+  where
+    F: Fn(&CprFrame, &CprFrame, bool) -> Option<(f64, f64)>,
+  {
+    let cpr = CprFrame {
+      lat: (((me[2] & 0x03) as u32) << 15) | ((me[3] as u32) << 7) | (me[4] >> 1) as u32,
+      lon: (((me[4] & 0x01) as u32) << 16) | ((me[5] as u32) << 8) | me[6] as u32,
+      surface,
+      received: now,
+    };
+    let odd = me[2] & 0x04 != 0;
+    if odd {
+      self.odd = Some(cpr);
+    } else {
+      self.even = Some(cpr);
+    }
+
+    let (even, odd_frame) = (self.even?, self.odd?);
+    let apart = if odd { now.saturating_duration_since(even.received) } else { now.saturating_duration_since(odd_frame.received) };
+    if even.surface != odd_frame.surface || apart > CPR_PAIR_MAX_AGE {
+      return None;
+    }
+    decode(&even, &odd_frame, odd)
+  }
+}
+
+// Ground speed in knots from the movement field of a surface position message. The steps get
+// coarser as the speed goes up, and 124 means 175 knots or more.
+fn decode_surface_movement(me: &[u8; 7]) -> Option<f64> {
+  let movement = (((me[0] & 0x07) as u32) << 4) | (me[1] >> 4) as u32;
+  let (first, base, step) = match movement {
+    1 => return Some(0.0),
+    2..=8 => (2, 0.125, 0.125),
+    9..=12 => (9, 1.0, 0.25),
+    13..=38 => (13, 2.0, 0.5),
+    39..=93 => (39, 15.0, 1.0),
+    94..=108 => (94, 70.0, 2.0),
+    109..=123 => (109, 100.0, 5.0),
+    124 => return Some(175.0),
+    // 0 is no information, 125 to 127 are reserved
+    _ => return None,
+  };
+  Some(base + (movement - first) as f64 * step)
+}
+
+// 8 character callsign of an identification message, trailing spaces trimmed
+fn decode_callsign(me: &[u8; 7]) -> String {
+  let mut bits: u64 = 0;
+  for &byte in &me[1..7] {
+    bits = (bits << 8) | byte as u64;
+  }
+  (0..8)
+    .map(|i| CALLSIGN_CHARSET[((bits >> (42 - 6 * i)) & 0x3f) as usize] as char)
+    .collect::<String>()
+    .trim_end()
+    .to_string()
+}
+
+// Barometric altitude of an airborne position message. Only the 25 ft encoding (Q bit set) is
+// decoded; the Gillham coded 100 ft encoding is rare in ADS-B and left as unknown.
+fn decode_altitude(me: &[u8; 7]) -> Option<i32> {
+  let ac = ((me[1] as u32) << 4) | (me[2] >> 4) as u32;
+  if ac == 0 || ac & 0x10 == 0 {
+    return None;
+  }
+  let n = ((ac & 0x0fe0) >> 1) | (ac & 0x000f);
+  Some(n as i32 * 25 - 1000)
+}
+
+// Ground speed in knots and track in degrees from a subtype 1 or 2 velocity message
+fn decode_ground_velocity(me: &[u8; 7]) -> Option<(f64, f64)> {
+  let subtype = me[0] & 0x07;
+  if subtype != 1 && subtype != 2 {
+    return None;
+  }
+
+  let ew_raw = (((me[1] & 0x03) as i32) << 8) | me[2] as i32;
+  let ns_raw = (((me[3] & 0x7f) as i32) << 3) | (me[4] >> 5) as i32;
+  if ew_raw == 0 || ns_raw == 0 {
+    return None;
+  }
+  // supersonic messages count in units of 4 knots
+  let unit = if subtype == 2 { 4 } else { 1 };
+  let ew = (ew_raw - 1) * unit * if me[1] & 0x04 != 0 { -1 } else { 1 };
+  let ns = (ns_raw - 1) * unit * if me[3] & 0x80 != 0 { -1 } else { 1 };
+
+  let speed = ((ew * ew + ns * ns) as f64).sqrt();
+  let track = (ew as f64).atan2(ns as f64).to_degrees().rem_euclid(360.0);
+  Some((speed, track))
+}
+
+fn decode_vertical_rate(me: &[u8; 7]) -> Option<i32> {
+  let raw = (((me[4] & 0x07) as i32) << 6) | (me[5] >> 2) as i32;
+  if raw == 0 {
+    return None;
+  }
+  let rate = (raw - 1) * 64;
+  Some(if me[4] & 0x08 != 0 { -rate } else { rate })
+}
+
+// Number of longitude zones at a latitude
+pub fn cpr_nl(lat: f64) -> i32 {
+  let lat = lat.abs();
+  if lat == 0.0 {
+    return 59;
+  } else if lat == 87.0 {
+    return 2;
+  } else if lat > 87.0 {
+    return 1;
+  }
+
+  let a = 1.0 - (PI / (2.0 * CPR_NZ)).cos();
+  let b = (PI / 180.0 * lat).cos().powi(2);
+  (2.0 * PI / (1.0 - a / b).acos()).floor() as i32
+}
+
+// Latitudes of the even and odd frame of a pair. `span` is 360 degrees for airborne positions;
+// surface positions spend the same 17 bits on 90 degrees, so theirs come out modulo 90.
+fn cpr_latitudes(even: &CprFrame, odd: &CprFrame, span: f64) -> (f64, f64) {
+  let lat_even = even.lat as f64 / CPR_SCALE;
+  let lat_odd = odd.lat as f64 / CPR_SCALE;
+  let j = (59.0 * lat_even - 60.0 * lat_odd + 0.5).floor();
+  let dlat_even = span / (4.0 * CPR_NZ);
+  let dlat_odd = span / (4.0 * CPR_NZ - 1.0);
+  (dlat_even * (j.rem_euclid(60.0) + lat_even), dlat_odd * (j.rem_euclid(59.0) + lat_odd))
+}
+
+// Longitude of the newer frame of a pair whose latitudes fall in `nl` longitude zones, modulo
+// `span` as for cpr_latitudes
+fn cpr_longitude(even: &CprFrame, odd: &CprFrame, odd_is_newer: bool, nl: i32, span: f64) -> f64 {
+  let lon_even = even.lon as f64 / CPR_SCALE;
+  let lon_odd = odd.lon as f64 / CPR_SCALE;
+  let (ni, lon_cpr) = if odd_is_newer { ((nl - 1).max(1), lon_odd) } else { (nl.max(1), lon_even) };
+  let m = (lon_even * (nl - 1) as f64 - lon_odd * nl as f64 + 0.5).floor();
+  (span / ni as f64) * (m.rem_euclid(ni as f64) + lon_cpr)
+}
+
+// Globally unambiguous position from an even/odd pair, using whichever frame is newer for the
+// final position. None if the two frames straddle a longitude zone boundary.
+fn cpr_global(even: &CprFrame, odd: &CprFrame, odd_is_newer: bool) -> Option<(f64, f64)> {
+  let (mut rlat_even, mut rlat_odd) = cpr_latitudes(even, odd, 360.0);
+  if rlat_even >= 270.0 {
+    rlat_even -= 360.0;
+  }
+  if rlat_odd >= 270.0 {
+    rlat_odd -= 360.0;
+  }
+
+  let nl = cpr_nl(rlat_even);
+  if nl != cpr_nl(rlat_odd) {
+    return None;
+  }
+
+  let lat = if odd_is_newer { rlat_odd } else { rlat_even };
+  let mut lon = cpr_longitude(even, odd, odd_is_newer, nl, 360.0);
+  if lon >= 180.0 {
+    lon -= 360.0;
+  }
+
+  Some((lat, lon))
+}
+
+// Position from an even/odd pair of surface position messages. The pair only pins the position
+// down to one of a set 90 degrees apart, so the one nearest `reference` (within 45 degrees of
+// latitude and longitude) is taken. None if the frames straddle a longitude zone boundary.
+fn cpr_surface(even: &CprFrame, odd: &CprFrame, odd_is_newer: bool, reference: (f64, f64)) -> Option<(f64, f64)> {
+  let (ref_lat, ref_lon) = reference;
+  // only the quadrants either side of the equator hold valid latitudes
+  let southern = |lat: f64| if lat - ref_lat > 45.0 { lat - 90.0 } else { lat };
+  let (rlat_even, rlat_odd) = cpr_latitudes(even, odd, 90.0);
+  let (rlat_even, rlat_odd) = (southern(rlat_even), southern(rlat_odd));
+
+  let nl = cpr_nl(rlat_even);
+  if nl != cpr_nl(rlat_odd) {
+    return None;
+  }
+
+  let lat = if odd_is_newer { rlat_odd } else { rlat_even };
+  let lon = cpr_longitude(even, odd, odd_is_newer, nl, 90.0);
+  let lon = lon + ((ref_lon - lon + 45.0) / 90.0).floor() * 90.0;
+  Some((lat, (lon + 180.0).rem_euclid(360.0) - 180.0))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{frame, DF17};
+  use crate::format::parse_avr;
+
+  // Messages from "The 1090 MHz Riddle"
+  const POSITION_EVEN: &str = "8d40621d58c382d690c8ac2863a7";
+  const POSITION_ODD: &str = "8d40621d58c386435cc412692ad6";
+  const VELOCITY: &str = "8d485020994409940838175b284f";
+
+  // One of those as a received frame
+  fn message(hex: &str) -> AdsbFrame {
+    frame(&parse_avr(&format!("*{};", hex)).unwrap())
+  }
+
+  fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+    assert!((actual - expected).abs() < tolerance, "{} is not within {} of {}", actual, tolerance, expected);
+  }
+
+  #[test]
+  fn identification_gives_the_callsign() {
+    let mut registry = AircraftRegistry::new();
+    let fields = registry.decode(&frame(&DF17), Instant::now()).unwrap();
+
+    assert_eq!((fields.icao, fields.type_code), (0x4840d6, 4));
+    assert_eq!(fields.callsign.as_deref(), Some("KLM1023"));
+    assert_eq!(registry.get(0x4840d6).unwrap().callsign.as_deref(), Some("KLM1023"));
+  }
+
+  #[test]
+  fn an_even_odd_pair_gives_the_position() {
+    let start = Instant::now();
+    let mut registry = AircraftRegistry::new();
+
+    let first = registry.decode(&message(POSITION_ODD), start).unwrap();
+    assert_eq!(first.position, None);
+    assert_eq!(first.altitude, Some(38000));
+
+    let (lat, lon) = registry.decode(&message(POSITION_EVEN), start + Duration::from_secs(2)).unwrap().position.unwrap();
+    assert_near(lat, 52.2572, 0.0001);
+    assert_near(lon, 3.91937, 0.0001);
+    assert_eq!(registry.get(0x40621d).unwrap().messages, 2);
+  }
+
+  #[test]
+  fn a_pair_received_too_far_apart_is_not_combined() {
+    let start = Instant::now();
+    let mut registry = AircraftRegistry::new();
+
+    registry.ingest(&message(POSITION_ODD), start);
+    let fields = registry.decode(&message(POSITION_EVEN), start + CPR_PAIR_MAX_AGE + Duration::from_secs(1)).unwrap();
+    assert_eq!(fields.position, None);
+  }
+
+  #[test]
+  fn velocity_gives_speed_track_and_vertical_rate() {
+    let mut registry = AircraftRegistry::new();
+    let fields = registry.decode(&message(VELOCITY), Instant::now()).unwrap();
+
+    assert_near(fields.ground_speed.unwrap(), 159.20, 0.01);
+    assert_near(fields.track.unwrap(), 182.88, 0.01);
+    assert_eq!(fields.vertical_rate, Some(-832));
+  }
+
+  #[test]
+  fn frames_failing_crc_are_not_attributed() {
+    let mut damaged = frame(&DF17);
+    damaged.data[5] ^= 0x01;
+    let mut registry = AircraftRegistry::new();
+
+    assert_eq!(registry.ingest(&damaged, Instant::now()), None);
+    assert!(registry.is_empty());
+  }
+
+  #[test]
+  fn aircraft_not_heard_from_are_pruned() {
+    let start = Instant::now();
+    let mut registry = AircraftRegistry::new();
+    registry.ingest(&frame(&DF17), start);
+    registry.ingest(&message(VELOCITY), start + AIRCRAFT_MAX_AGE);
+
+    assert_eq!(registry.snapshot().iter().map(|a| a.icao).collect::<Vec<_>>(), vec![0x4840d6, 0x485020]);
+    assert_eq!(registry.prune(AIRCRAFT_MAX_AGE, start + AIRCRAFT_MAX_AGE + Duration::from_secs(1)), 1);
+    assert!(registry.get(0x4840d6).is_none());
+    assert_eq!(registry.len(), 1);
+  }
+
+  #[test]
+  fn longitude_zones_shrink_towards_the_poles() {
+    assert_eq!(cpr_nl(0.0), 59);
+    assert_eq!(cpr_nl(52.2572), 36);
+    assert_eq!(cpr_nl(-52.2572), 36);
+    assert_eq!(cpr_nl(87.0), 2);
+    assert_eq!(cpr_nl(89.0), 1);
+  }
+
+  // Surface position pair from "The 1090 MHz Riddle", received near Amsterdam
+  const SURFACE_EVEN: &str = "8c4841753aab238733c8cd4020b1";
+  const SURFACE_ODD: &str = "8c4841753a8a35323faebdac702d";
+
+  #[test]
+  fn a_surface_pair_is_resolved_against_the_receiver() {
+    let start = Instant::now();
+    let mut registry = AircraftRegistry::with_reference((51.990, 4.375));
+
+    registry.ingest(&message(SURFACE_EVEN), start);
+    let fields = registry.decode(&message(SURFACE_ODD), start + Duration::from_secs(1)).unwrap();
+    let (lat, lon) = fields.position.unwrap();
+    assert_near(lat, 52.32061, 0.00001);
+    assert_near(lon, 4.73473, 0.00001);
+    assert_eq!(fields.ground_speed, Some(16.0));
+    assert_near(fields.track.unwrap(), 98.44, 0.01);
+  }
+
+  #[test]
+  fn a_surface_pair_needs_somewhere_to_resolve_against() {
+    let start = Instant::now();
+    let mut registry = AircraftRegistry::new();
+
+    registry.ingest(&message(SURFACE_ODD), start);
+    assert_eq!(registry.decode(&message(SURFACE_EVEN), start).unwrap().position, None);
+  }
+
+  #[test]
+  fn surface_and_airborne_halves_are_not_paired() {
+    let start = Instant::now();
+    let mut registry = AircraftRegistry::with_reference((51.990, 4.375));
+    let mut odd = message(POSITION_ODD);
+    // the same aircraft's odd airborne half, with its parity redone
+    odd.data[1..4].copy_from_slice(&[0x48, 0x41, 0x75]);
+    let parity = crate::crc::crc24(&odd.data[..11]);
+    odd.data[11..].copy_from_slice(&parity.to_be_bytes()[1..]);
+
+    registry.ingest(&odd, start);
+    assert_eq!(registry.decode(&message(SURFACE_EVEN), start).unwrap().position, None);
+  }
+
+  #[test]
+  fn surface_movement_steps_get_coarser_with_speed() {
+    let movement = |movement: u8| decode_surface_movement(&[0x38 | (movement >> 4), movement << 4, 0, 0, 0, 0, 0]);
+    assert_eq!(movement(0), None);
+    assert_eq!(movement(1), Some(0.0));
+    assert_eq!(movement(2), Some(0.125));
+    assert_eq!(movement(12), Some(1.75));
+    assert_eq!(movement(38), Some(14.5));
+    assert_eq!(movement(93), Some(69.0));
+    assert_eq!(movement(108), Some(98.0));
+    assert_eq!(movement(123), Some(170.0));
+    assert_eq!(movement(124), Some(175.0));
+    assert_eq!(movement(125), None);
+  }
+
+  #[test]
+  fn positions_parse_from_lat_lon() {
+    assert_eq!(parse_position("52.31,4.76"), Ok((52.31, 4.76)));
+    assert_eq!(parse_position(" -33.9 , 151.2 "), Ok((-33.9, 151.2)));
+    assert!(parse_position("52.31").is_err());
+    assert!(parse_position("91,0").is_err());
+    assert!(parse_position("0,181").is_err());
+  }
+}
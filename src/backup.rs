@@ -0,0 +1,77 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bladerf::bladerf::{bladerf_error, BLADERF_FLASH_PAGE_SIZE};
+use bladerf::BladeRFDevice;
+
+// Pages per bladerf_read_flash call, 64 KiB at a time
+pub const FLASH_READ_PAGES: u32 = 256;
+
+// e.g. "<dir>/bladerf-<serial>-flash-1700000000.bin". Anything in the serial that isn't
+// alphanumeric is dropped, so a garbled serial can't escape `dir`.
+pub fn backup_path(dir: &Path, serial: &str, what: &str, extension: &str, now: SystemTime) -> PathBuf {
+  let serial: String = serial.chars().filter(char::is_ascii_alphanumeric).collect();
+  let serial = if serial.is_empty() { String::from("unknown") } else { serial };
+  let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+  dir.join(format!("bladerf-{}-{}-{}.{}", serial, what, since_epoch.as_secs(), extension))
+}
+
+// Whether libbladeRF turned the request down because the board or its firmware can't do it,
+// rather than because something went wrong
+pub fn is_unsupported(err: isize) -> bool {
+  err == bladerf_error::BLADERF_ERR_UNSUPPORTED as isize
+}
+
+// Read the whole SPI flash: the FX3 firmware, the autoloaded FPGA image and the calibration
+// data, whatever is stored on this board. Returns the image and whether libbladeRF had to guess
+// the flash size, in which case the image may be short or padded.
+pub fn read_flash_image(dev: &BladeRFDevice) -> Result<(Vec<u8>, bool), isize> {
+  let (size, is_guess) = dev.get_flash_size()?;
+  let pages = size / BLADERF_FLASH_PAGE_SIZE;
+  let mut image = Vec::with_capacity(size as usize);
+  let mut page = 0;
+  while page < pages {
+    let count = FLASH_READ_PAGES.min(pages - page);
+    image.extend_from_slice(&dev.read_flash(page, count)?);
+    page += count;
+  }
+  Ok((image, is_guess))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use bladerf::mock::MockBladeRf;
+
+  #[test]
+  fn backup_paths_are_named_after_the_serial_and_time() {
+    let now = UNIX_EPOCH + Duration::from_secs(1700000000);
+    assert_eq!(backup_path(Path::new("backups"), "ab12cd34", "flash", "bin", now), PathBuf::from("backups/bladerf-ab12cd34-flash-1700000000.bin"));
+    assert_eq!(backup_path(Path::new("."), "../x/\0", "fw", "log", now), PathBuf::from("./bladerf-x-fw-1700000000.log"));
+    assert_eq!(backup_path(Path::new("."), "\0\0", "fw", "log", now), PathBuf::from("./bladerf-unknown-fw-1700000000.log"));
+  }
+
+  #[test]
+  fn the_flash_is_read_in_chunks() {
+    let mock = MockBladeRf::new();
+    let flash: Vec<u8> = (0..300 * BLADERF_FLASH_PAGE_SIZE).map(|i| (i / BLADERF_FLASH_PAGE_SIZE) as u8).collect();
+    mock.state().flash = flash.clone();
+    mock.state().flash_size_guess = true;
+    let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+    assert_eq!(read_flash_image(&dev), Ok((flash, true)));
+    assert_eq!(mock.calls(), vec!["get_flash_size()", "read_flash(0, 256)", "read_flash(256, 44)"]);
+  }
+
+  #[test]
+  fn flash_read_errors_are_passed_on() {
+    let mock = MockBladeRf::new();
+    mock.push_result("get_flash_size", bladerf_error::BLADERF_ERR_UNSUPPORTED as i32);
+    let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+    let err = read_flash_image(&dev).unwrap_err();
+    assert!(is_unsupported(err));
+    assert!(!is_unsupported(bladerf_error::BLADERF_ERR_IO as isize));
+  }
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+// How much sample data the USB pipeline should hold when the buffer parameters are left to us
+pub const TARGET_BUFFERING: Duration = Duration::from_millis(20);
+
+// Less than this and a busy host will overrun at the configured rate
+pub const MIN_BUFFERING: Duration = Duration::from_millis(5);
+
+// libbladeRF requires buffer sizes in multiples of 1024 samples
+pub const BUFFER_SIZE_MULTIPLE: u32 = 1024;
+
+// Size of each buffer when sizing automatically. Larger buffers mean fewer USB transfers
+// per second, smaller ones less latency; 8192 samples is 0.5ms at 16 MSPS.
+pub const AUTO_BUFFER_SIZE: u32 = 8192;
+
+// Timeouts handed to libbladeRF, in milliseconds. sync_config's applies to each USB transfer
+// filling a buffer, sync_rx's to a whole call waiting for samples.
+pub const DEFAULT_CONFIG_TIMEOUT_MS: u32 = 5000;
+pub const DEFAULT_RX_TIMEOUT_MS: u32 = 5000;
+
+// sync_rx needs at least this many buffer fill times before timing out is meaningful
+const MIN_RX_TIMEOUT_BUFFERS: u32 = 2;
+
+// Beyond this a sync_rx call noticeably holds up shutdown
+pub const SLOW_SHUTDOWN_RX_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Parameters passed to sync_config
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StreamBuffers {
+  pub num_buffers: u32,
+  // in samples
+  pub buffer_size: u32,
+  pub num_transfers: u32,
+}
+
+impl StreamBuffers {
+  // Enough buffers of AUTO_BUFFER_SIZE samples to cover TARGET_BUFFERING at the given rate,
+  // with half of them in flight. libbladeRF needs more buffers than transfers, so never fewer
+  // than 4 buffers.
+  pub fn for_sample_rate(sample_rate: u32) -> StreamBuffers {
+    let samples = (sample_rate as u64 * TARGET_BUFFERING.as_micros() as u64) / 1_000_000;
+    let num_buffers = (samples.div_ceil(AUTO_BUFFER_SIZE as u64) as u32).max(4);
+
+    StreamBuffers {
+      num_buffers,
+      buffer_size: AUTO_BUFFER_SIZE,
+      num_transfers: num_buffers / 2,
+    }
+  }
+
+  // Time it takes to fill every buffer at the given rate
+  pub fn depth(&self, sample_rate: u32) -> Duration {
+    let samples = self.num_buffers as u64 * self.buffer_size as u64;
+    Duration::from_micros(samples * 1_000_000 / sample_rate.max(1) as u64)
+  }
+
+  pub fn is_too_shallow(&self, sample_rate: u32) -> bool {
+    self.depth(sample_rate) < MIN_BUFFERING
+  }
+
+  // Time it takes to fill a single buffer at the given rate
+  pub fn buffer_duration(&self, sample_rate: u32) -> Duration {
+    Duration::from_micros(self.buffer_size as u64 * 1_000_000 / sample_rate.max(1) as u64)
+  }
+
+  // Check the sync_rx timeout leaves time for a buffer to arrive. A shorter one would time out
+  // on every call whether or not the stream is healthy.
+  pub fn validate_rx_timeout(&self, sample_rate: u32, rx_timeout_ms: u32) -> Result<(), String> {
+    let minimum = self.buffer_duration(sample_rate) * MIN_RX_TIMEOUT_BUFFERS;
+    if Duration::from_millis(rx_timeout_ms as u64) < minimum {
+      return Err(format!("--rx-timeout of {}ms is shorter than {} buffers of {} samples take at {} samples/s ({:?})",
+                         rx_timeout_ms, MIN_RX_TIMEOUT_BUFFERS, self.buffer_size, sample_rate, minimum));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn automatic_sizing_covers_the_target_buffering() {
+    let buffers = StreamBuffers::for_sample_rate(16000000);
+    assert_eq!(buffers, StreamBuffers { num_buffers: 40, buffer_size: AUTO_BUFFER_SIZE, num_transfers: 20 });
+    assert!(buffers.depth(16000000) >= TARGET_BUFFERING);
+  }
+
+  #[test]
+  fn automatic_sizing_never_goes_below_four_buffers() {
+    assert_eq!(StreamBuffers::for_sample_rate(1000000), StreamBuffers { num_buffers: 4, buffer_size: AUTO_BUFFER_SIZE, num_transfers: 2 });
+  }
+
+  #[test]
+  fn a_few_small_buffers_are_too_shallow() {
+    let buffers = StreamBuffers { num_buffers: 4, buffer_size: BUFFER_SIZE_MULTIPLE, num_transfers: 2 };
+    assert_eq!(buffers.depth(16000000), Duration::from_micros(256));
+    assert!(buffers.is_too_shallow(16000000));
+    assert!(!StreamBuffers::for_sample_rate(16000000).is_too_shallow(16000000));
+  }
+
+  #[test]
+  fn the_rx_timeout_has_to_cover_two_buffers() {
+    let buffers = StreamBuffers { num_buffers: 16, buffer_size: 8192, num_transfers: 8 };
+    assert_eq!(buffers.buffer_duration(16000000), Duration::from_micros(512));
+
+    assert_eq!(buffers.validate_rx_timeout(16000000, 2), Ok(()));
+    let err = buffers.validate_rx_timeout(16000000, 1).unwrap_err();
+    assert!(err.starts_with("--rx-timeout of 1ms is shorter than 2 buffers of 8192 samples"), "{}", err);
+  }
+}
@@ -0,0 +1,59 @@
+// Well known Mode S and ADS-B parameters, for the rest of the code and for users of the library
+// to refer to by name rather than as bare numbers
+
+// Mode S replies, ADS-B included, are all sent on 1090 MHz
+pub const ADSB_FREQUENCY_HZ: u32 = 1090000000;
+
+// Long (112 bit) frames: DF16, DF17/DF18 extended squitters and DF20/DF21 Comm-B replies
+pub const LONG_FRAME_BYTES: usize = 14;
+pub const LONG_FRAME_BITS: usize = LONG_FRAME_BYTES * 8;
+
+// Short (56 bit) frames: DF0, DF4, DF5 and DF11 all-call replies
+pub const SHORT_FRAME_BYTES: usize = 7;
+pub const SHORT_FRAME_BITS: usize = SHORT_FRAME_BYTES * 8;
+
+// Every frame starts with an 8us preamble of four 0.5us pulses
+pub const ADSB_PREAMBLE_US: usize = 8;
+
+// After the preamble every bit takes 1us, a 0.5us pulse in its first half for a 1 or its second
+// half for a 0, so the pulses need 2 MHz of bandwidth
+pub const ADSB_BIT_US: usize = 1;
+
+// Mode S CRC-24 generator polynomial (x^24 + x^23 + ... + 1, top bit implied)
+pub const MODES_GENERATOR_POLY: u32 = 0xfff409;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::DF17;
+
+  #[test]
+  fn a_long_frame_with_its_preamble_takes_120us() {
+    assert_eq!((LONG_FRAME_BITS, SHORT_FRAME_BITS), (112, 56));
+    assert_eq!(ADSB_PREAMBLE_US + LONG_FRAME_BITS * ADSB_BIT_US, 120);
+  }
+
+  #[test]
+  fn the_generator_polynomial_gives_the_parity_of_a_real_frame() {
+    // DF17's parity is 576098
+    let frame = DF17;
+    // bit by bit long division, without the lookup table crc24 uses
+    let mut remainder: u32 = 0;
+    for bit in 0..LONG_FRAME_BITS - 24 {
+      let top = (remainder >> 23) & 1 != 0;
+      let next = (frame[bit / 8] >> (7 - bit % 8)) as u32 & 1;
+      remainder = ((remainder << 1) | next) & 0xffffff;
+      if top {
+        remainder ^= MODES_GENERATOR_POLY;
+      }
+    }
+    for _ in 0..24 {
+      let top = (remainder >> 23) & 1 != 0;
+      remainder = (remainder << 1) & 0xffffff;
+      if top {
+        remainder ^= MODES_GENERATOR_POLY;
+      }
+    }
+    assert_eq!(remainder, 0x576098);
+  }
+}
@@ -0,0 +1,252 @@
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use bladerf::{gain_mode_from_name, BladeRFDevice};
+use bladerf::bladerf::bladerf_gain_mode;
+use bladerf::bladerf::bladerf_module::*;
+
+use crate::units::parse_frequency;
+
+// How often the listener checks for shutdown between connections
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// A live change to the receiver, one per line on the control socket:
+//
+//   gain 30
+//   gain-mode manual|default|fast|slow|hybrid
+//   frequency 1090000000|1090M
+//   bias-tee on|off
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlCommand {
+  Gain(i32),
+  GainMode(bladerf_gain_mode),
+  Frequency(u32),
+  BiasTee(bool),
+}
+
+// The gain mode for a --gain-mode name
+pub fn parse_gain_mode(name: &str) -> Option<bladerf_gain_mode> {
+  gain_mode_from_name(name)
+}
+
+impl FromStr for ControlCommand {
+  type Err = String;
+
+  fn from_str(line: &str) -> Result<ControlCommand, String> {
+    let mut words = line.split_whitespace();
+    let (command, value) = match (words.next(), words.next(), words.next()) {
+      (Some(command), Some(value), None) => (command, value),
+      _ => return Err(format!("expected a command and a value, got '{}'", line.trim())),
+    };
+
+    match command {
+      "gain" => value.parse().map(ControlCommand::Gain).map_err(|_| format!("invalid gain '{}'", value)),
+      "gain-mode" => parse_gain_mode(value).map(ControlCommand::GainMode)
+        .ok_or_else(|| format!("unknown gain mode '{}' (expected default, manual, fast, slow or hybrid)", value)),
+      "frequency" => parse_frequency(value).map(ControlCommand::Frequency),
+      "bias-tee" => match value {
+        "on" | "true" => Ok(ControlCommand::BiasTee(true)),
+        "off" | "false" => Ok(ControlCommand::BiasTee(false)),
+        _ => Err(format!("invalid bias tee state '{}' (expected on or off)", value)),
+      },
+      _ => Err(format!("unknown command '{}' (expected gain, gain-mode, frequency or bias-tee)", command)),
+    }
+  }
+}
+
+impl ControlCommand {
+  // Call the matching setter on the RX module
+  pub fn apply(&self, dev: &BladeRFDevice) -> Result<(), String> {
+    let (setter, result) = match *self {
+      ControlCommand::Gain(gain) => ("set_gain", dev.set_gain(BLADERF_MODULE_RX, gain).map(|_| ())),
+      ControlCommand::GainMode(mode) => ("set_gain_mode", dev.set_gain_mode(BLADERF_MODULE_RX, mode).map(|_| ())),
+      ControlCommand::Frequency(frequency) => ("set_frequency", dev.set_frequency(BLADERF_MODULE_RX, frequency).map(|_| ())),
+      ControlCommand::BiasTee(enable) => ("set_bias_tee", dev.set_bias_tee(BLADERF_MODULE_RX, enable).map(|_| ())),
+    };
+    result.map_err(|e| format!("{} failed (error {})", setter, e))
+  }
+}
+
+// A command waiting to be applied, and the client waiting for the outcome
+pub struct ControlRequest {
+  pub command: ControlCommand,
+  reply: Sender<Result<(), String>>,
+}
+
+impl ControlRequest {
+  pub fn new(command: ControlCommand) -> (ControlRequest, Receiver<Result<(), String>>) {
+    let (reply, outcome) = channel();
+    (ControlRequest { command, reply }, outcome)
+  }
+
+  pub fn reply(self, result: Result<(), String>) {
+    // the client may have hung up in the meantime
+    let _ = self.reply.send(result);
+  }
+}
+
+// Unix socket taking ControlCommands, one per line, each answered with "ok" or "error: ...".
+// Commands are handed over through the receiver returned by bind, for whoever owns the device
+// to apply between transfers. The socket file is removed when this is dropped.
+pub struct ControlSocket {
+  path: PathBuf,
+}
+
+impl ControlSocket {
+  // Listen on path and accept clients in the background until running is cleared
+  pub fn bind(path: &Path, running: Arc<AtomicBool>) -> io::Result<(ControlSocket, Receiver<ControlRequest>)> {
+    // a socket left behind by a crashed run blocks the bind, but one that's still answering
+    // belongs to another instance
+    if path.exists() && UnixStream::connect(path).is_err() {
+      fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    // non-blocking so the accept loop notices shutdown
+    listener.set_nonblocking(true)?;
+    info!("Accepting control commands on {}", path.display());
+
+    let (requests, receiver) = channel();
+    thread::Builder::new().name(String::from("adsb-control")).spawn(move || {
+      while running.load(Ordering::SeqCst) {
+        match listener.accept() {
+          Ok((stream, _)) => {
+            let requests = requests.clone();
+            let spawned = thread::Builder::new().name(String::from("adsb-control-client"))
+              .spawn(move || serve_client(stream, requests));
+            if let Err(e) = spawned {
+              warn!("Unable to start a thread for a control client: {}", e);
+            }
+          },
+          Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+          Err(e) => {
+            warn!("Error accepting control client: {}", e);
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+          },
+        }
+      }
+    })?;
+
+    Ok((ControlSocket { path: path.to_path_buf() }, receiver))
+  }
+}
+
+impl Drop for ControlSocket {
+  fn drop(&mut self) {
+    if let Err(e) = fs::remove_file(&self.path) {
+      debug!("Unable to remove control socket {}: {}", self.path.display(), e);
+    }
+  }
+}
+
+// Answer every command line until the client hangs up
+fn serve_client(stream: UnixStream, requests: Sender<ControlRequest>) {
+  // accepted sockets inherit non-blocking from the listener on some platforms
+  if let Err(e) = stream.set_nonblocking(false) {
+    warn!("Unable to set up control client: {}", e);
+    return;
+  }
+  let mut writer = match stream.try_clone() {
+    Ok(writer) => writer,
+    Err(e) => {
+      warn!("Unable to set up control client: {}", e);
+      return;
+    },
+  };
+
+  for line in BufReader::new(stream).lines() {
+    let line = match line {
+      Ok(line) => line,
+      Err(_) => break,
+    };
+    if line.trim().is_empty() {
+      continue;
+    }
+
+    let result = line.parse::<ControlCommand>().and_then(|command| {
+      info!("Control command: {:?}", command);
+      let (request, outcome) = ControlRequest::new(command);
+      requests.send(request).map_err(|_| String::from("receiver is shutting down"))?;
+      // applied between transfers, so this can take up to --rx-timeout
+      outcome.recv().unwrap_or_else(|_| Err(String::from("receiver is shutting down")))
+    });
+    let answer = match result {
+      Ok(()) => String::from("ok\n"),
+      Err(e) => {
+        warn!("Control command '{}' failed: {}", line.trim(), e);
+        format!("error: {}\n", e)
+      },
+    };
+    if writer.write_all(answer.as_bytes()).is_err() {
+      break;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::process;
+
+  use bladerf::bladerf::bladerf_error::BLADERF_ERR_INVAL;
+  use bladerf::mock::MockBladeRf;
+
+  #[test]
+  fn commands_parse_from_a_line() {
+    assert_eq!("gain 30".parse(), Ok(ControlCommand::Gain(30)));
+    assert_eq!(" gain-mode manual ".parse(), Ok(ControlCommand::GainMode(bladerf_gain_mode::BLADERF_GAIN_MGC)));
+    assert_eq!("frequency 1090M".parse(), Ok(ControlCommand::Frequency(1090000000)));
+    assert_eq!("bias-tee on".parse(), Ok(ControlCommand::BiasTee(true)));
+    assert_eq!("bias-tee false".parse(), Ok(ControlCommand::BiasTee(false)));
+  }
+
+  #[test]
+  fn malformed_commands_are_rejected() {
+    for line in ["gain", "gain 30 40", "gain loud", "gain-mode turbo", "frequency 1090", "bias-tee maybe", "volume 11", ""] {
+      assert!(line.parse::<ControlCommand>().is_err(), "{} parsed", line);
+    }
+  }
+
+  #[test]
+  fn commands_are_applied_to_the_rx_module() {
+    let mock = MockBladeRf::new();
+    let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+    assert_eq!(ControlCommand::Frequency(1090000000).apply(&dev), Ok(()));
+    assert_eq!(mock.state().frequency, 1090000000);
+    mock.push_result("set_gain", BLADERF_ERR_INVAL as i32);
+    assert_eq!(ControlCommand::Gain(30).apply(&dev), Err(format!("set_gain failed (error {})", BLADERF_ERR_INVAL as isize)));
+  }
+
+  #[test]
+  fn the_socket_answers_each_line_with_the_outcome() {
+    let path = std::env::temp_dir().join(format!("bladerf_adsb-test-{}-control.sock", process::id()));
+    let running = Arc::new(AtomicBool::new(true));
+    let (socket, requests) = ControlSocket::bind(&path, running.clone()).unwrap();
+
+    let mut client = UnixStream::connect(&path).unwrap();
+    let mut answers = BufReader::new(client.try_clone().unwrap()).lines();
+    client.write_all(b"gain 30\n").unwrap();
+    let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(request.command, ControlCommand::Gain(30));
+    request.reply(Ok(()));
+    assert_eq!(answers.next().unwrap().unwrap(), "ok");
+
+    client.write_all(b"\nvolume 11\n").unwrap();
+    assert!(answers.next().unwrap().unwrap().starts_with("error: unknown command 'volume'"));
+
+    running.store(false, Ordering::SeqCst);
+    drop(socket);
+    assert!(!path.exists());
+  }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use crate::consts::{LONG_FRAME_BITS, LONG_FRAME_BYTES};
+pub use crate::consts::MODES_GENERATOR_POLY;
+
+// CRC-24 of a byte string as used by Mode S
+pub fn crc24(data: &[u8]) -> u32 {
+  let mut crc: u32 = 0;
+
+  for &byte in data {
+    crc ^= (byte as u32) << 16;
+    for _ in 0..8 {
+      crc = if crc & 0x800000 != 0 {
+        (crc << 1) ^ MODES_GENERATOR_POLY
+      } else {
+        crc << 1
+      };
+      crc &= 0xffffff;
+    }
+  }
+
+  crc
+}
+
+// Remainder of the whole frame, parity field included. Zero for a valid DF17/18 frame, and
+// for any other frame whose parity is a plain CRC.
+pub fn syndrome(frame: &[u8]) -> u32 {
+  crc24(frame)
+}
+
+// The value overlaid on the CRC: the CRC of everything before the 24 bit parity field XORed
+// with that field. For address/parity formats (DF0/4/5/16/20/21) it's the ICAO address, for
+// DF11 the interrogator code, and zero for an intact DF17/18 frame.
+pub fn parity_overlay(frame: &[u8]) -> u32 {
+  let (data, parity) = frame.split_at(frame.len().saturating_sub(3));
+  let parity = parity.iter().fold(0u32, |acc, &byte| (acc << 8) | byte as u32);
+  crc24(data) ^ parity
+}
+
+// The first 5 bits carry the downlink format. An "error" there would turn the frame into a
+// different type altogether, so like dump1090 we never repair them.
+const FIX_FIRST_BIT: usize = 5;
+
+// How aggressively frames failing CRC are repaired
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CrcFixMode {
+  None,
+  Single,
+  // also two bit errors; more prone to "repairing" noise into a valid looking frame
+  Double,
+}
+
+impl CrcFixMode {
+  pub fn name(&self) -> &'static str {
+    match self {
+      CrcFixMode::None   => "none",
+      CrcFixMode::Single => "single",
+      CrcFixMode::Double => "double",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<CrcFixMode> {
+    match name {
+      "none"   => Some(CrcFixMode::None),
+      "single" => Some(CrcFixMode::Single),
+      "double" => Some(CrcFixMode::Double),
+      _        => None,
+    }
+  }
+}
+
+// Syndrome left by flipping the given bits of an otherwise all-zero long frame. The CRC is
+// linear, so this is also the change those flips make to any frame's syndrome.
+fn error_syndrome(bits: &[usize]) -> u32 {
+  let mut error = [0u8; LONG_FRAME_BYTES];
+  for &bit in bits {
+    error[bit / 8] ^= 0x80 >> (bit % 8);
+  }
+  crc24(&error)
+}
+
+// Repairs long frames with plain CRCs (DF17/18) by looking the syndrome up in a table of the
+// syndromes every correctable error pattern produces, the error-locator approach dump1090 uses
+pub struct ErrorCorrector {
+  mode: CrcFixMode,
+  // None marks a syndrome shared by several error patterns, which can't be fixed unambiguously
+  table: HashMap<u32, Option<Vec<usize>>>,
+}
+
+impl ErrorCorrector {
+  pub fn new(mode: CrcFixMode) -> ErrorCorrector {
+    let mut table: HashMap<u32, Option<Vec<usize>>> = HashMap::new();
+    let mut insert = |bits: Vec<usize>| {
+      let syndrome = error_syndrome(&bits);
+      table.entry(syndrome).and_modify(|entry| *entry = None).or_insert(Some(bits));
+    };
+
+    if mode != CrcFixMode::None {
+      for i in FIX_FIRST_BIT..LONG_FRAME_BITS {
+        insert(vec![i]);
+      }
+    }
+    if mode == CrcFixMode::Double {
+      for i in FIX_FIRST_BIT..LONG_FRAME_BITS {
+        for j in (i + 1)..LONG_FRAME_BITS {
+          insert(vec![i, j]);
+        }
+      }
+    }
+
+    ErrorCorrector { mode, table }
+  }
+
+  pub fn mode(&self) -> CrcFixMode {
+    self.mode
+  }
+
+  // Try to repair a frame in place. Returns the number of bits flipped (0 if it was already
+  // valid), or None if the frame can't be repaired unambiguously. Short frames are never touched.
+  pub fn fix(&self, frame: &mut [u8]) -> Option<usize> {
+    if frame.len() != LONG_FRAME_BYTES {
+      return None;
+    }
+
+    let syndrome = syndrome(frame);
+    if syndrome == 0 {
+      return Some(0);
+    }
+
+    match self.table.get(&syndrome) {
+      Some(Some(bits)) => {
+        for &bit in bits {
+          frame[bit / 8] ^= 0x80 >> (bit % 8);
+        }
+        Some(bits.len())
+      },
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::DF17;
+
+  fn flipped(bits: &[usize]) -> [u8; LONG_FRAME_BYTES] {
+    let mut frame = DF17;
+    for &bit in bits {
+      frame[bit / 8] ^= 0x80 >> (bit % 8);
+    }
+    frame
+  }
+
+  #[test]
+  fn an_intact_frame_has_no_syndrome() {
+    assert_eq!(syndrome(&DF17), 0);
+    assert_ne!(syndrome(&flipped(&[40])), 0);
+  }
+
+  #[test]
+  fn single_bit_errors_are_repaired_anywhere_past_the_downlink_format() {
+    let corrector = ErrorCorrector::new(CrcFixMode::Single);
+    for bit in FIX_FIRST_BIT..LONG_FRAME_BITS {
+      let mut frame = flipped(&[bit]);
+      assert_eq!(corrector.fix(&mut frame), Some(1), "bit {}", bit);
+      assert_eq!(frame, DF17);
+    }
+  }
+
+  #[test]
+  fn the_downlink_format_is_never_repaired() {
+    let mut frame = flipped(&[0]);
+    assert_eq!(ErrorCorrector::new(CrcFixMode::Double).fix(&mut frame), None);
+    assert_eq!(frame, flipped(&[0]));
+  }
+
+  #[test]
+  fn two_bit_errors_need_double_mode() {
+    let mut frame = flipped(&[20, 77]);
+    assert_eq!(ErrorCorrector::new(CrcFixMode::Single).fix(&mut frame), None);
+    assert_eq!(ErrorCorrector::new(CrcFixMode::Double).fix(&mut frame), Some(2));
+    assert_eq!(frame, DF17);
+  }
+
+  #[test]
+  fn none_mode_only_passes_intact_frames() {
+    let corrector = ErrorCorrector::new(CrcFixMode::None);
+    assert_eq!(corrector.fix(&mut DF17.clone()), Some(0));
+    assert_eq!(corrector.fix(&mut flipped(&[40])), None);
+  }
+
+  #[test]
+  fn short_frames_are_left_alone() {
+    let mut frame = [0x5d, 0x48, 0x40, 0xd6, 0x12, 0x34, 0x56];
+    assert_eq!(ErrorCorrector::new(CrcFixMode::Double).fix(&mut frame), None);
+    assert_eq!(frame, [0x5d, 0x48, 0x40, 0xd6, 0x12, 0x34, 0x56]);
+  }
+
+  #[test]
+  fn modes_round_trip_through_their_names() {
+    for mode in [CrcFixMode::None, CrcFixMode::Single, CrcFixMode::Double] {
+      assert_eq!(CrcFixMode::from_name(mode.name()), Some(mode));
+    }
+    assert_eq!(CrcFixMode::from_name("triple"), None);
+  }
+
+  #[test]
+  fn the_overlay_of_an_intact_squitter_is_zero() {
+    let mut frame = DF17.to_vec();
+    assert_eq!(parity_overlay(&frame), 0);
+    frame[13] ^= 0x5a;
+    assert_eq!(parity_overlay(&frame), 0x5a);
+  }
+}
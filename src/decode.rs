@@ -0,0 +1,302 @@
+use std::str::FromStr;
+
+use crate::consts::{LONG_FRAME_BYTES, SHORT_FRAME_BYTES};
+use crate::crc;
+
+// How much of each frame is looked at on its way through, from --decode-level. Each level does
+// everything the one before it does.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum DecodeLevel {
+  // forward frames as extracted, without looking at them
+  Raw,
+  // drop reserved downlink formats and frames failing their CRC or parity check
+  Crc,
+  // also decode positions, callsigns and velocities, for the output formats that include them
+  Full,
+}
+
+impl DecodeLevel {
+  pub fn name(&self) -> &'static str {
+    match self {
+      DecodeLevel::Raw  => "raw",
+      DecodeLevel::Crc  => "crc",
+      DecodeLevel::Full => "full",
+    }
+  }
+}
+
+impl FromStr for DecodeLevel {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<DecodeLevel, String> {
+    match s {
+      "raw"  => Ok(DecodeLevel::Raw),
+      "crc"  => Ok(DecodeLevel::Crc),
+      "full" => Ok(DecodeLevel::Full),
+      _      => Err(format!("'{}' isn't a decode level, expected raw, crc or full", s)),
+    }
+  }
+}
+
+// Downlink format, the first 5 bits of every Mode S frame. DF24 only uses 2 bits, so
+// anything starting with 0b11 is folded into 24.
+pub fn downlink_format(data: &[u8]) -> u8 {
+  let df = data[0] >> 3;
+  if df >= 24 { 24 } else { df }
+}
+
+// Downlink formats actually defined for Mode S. Everything else (1-3, 6-10, 12-15, 22-23)
+// is reserved or unassigned and only ever comes from noise.
+pub fn is_valid_df(df: u8) -> bool {
+  matches!(df, 0 | 4 | 5 | 11 | 16 | 17 | 18 | 19 | 20 | 21 | 24)
+}
+
+// DF17 (ADS-B from a Mode S transponder) and DF18 (non-transponder ADS-B / TIS-B / ADS-R)
+// share the same 112 bit layout and ME field
+pub fn is_extended_squitter(df: u8) -> bool {
+  df == 17 || df == 18
+}
+
+// DF18 control field values
+pub const CF_ADSB_ES_NT: u8 = 0;
+pub const CF_ADSB_ES_NT_NON_ICAO: u8 = 1;
+pub const CF_TISB_FINE: u8 = 2;
+pub const CF_TISB_COARSE: u8 = 3;
+pub const CF_TISB_MANAGEMENT: u8 = 4;
+pub const CF_TISB_NON_ICAO: u8 = 5;
+pub const CF_ADSR: u8 = 6;
+
+// Where an extended squitter came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SquitterSource {
+  // DF17, an aircraft's own transponder
+  Transponder,
+  // DF18 CF 0/1, ADS-B from a non-transponder device (ground vehicles, obstacles)
+  NonTransponder,
+  // DF18 CF 2/3/4/5, ground station rebroadcast of radar/secondary tracks
+  TisB,
+  // DF18 CF 6, rebroadcast of ADS-B received on another link (e.g. UAT)
+  AdsR,
+  // DF18 CF 7, reserved
+  Reserved,
+}
+
+// Fields common to DF17 and DF18 frames
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtendedSquitter {
+  pub df: u8,
+  // CA for DF17, CF for DF18
+  pub capability: u8,
+  pub icao: u32,
+  // whether `icao` is a real ICAO address rather than an anonymous/track address
+  pub icao_is_real: bool,
+  pub source: SquitterSource,
+  pub type_code: u8,
+  pub me: [u8; 7],
+}
+
+// Decode the header and ME field of a DF17/DF18 frame. Returns None for other formats, short
+// frames, or frames whose CRC doesn't check out.
+pub fn decode_extended_squitter(data: &[u8]) -> Option<ExtendedSquitter> {
+  if data.len() != LONG_FRAME_BYTES {
+    return None;
+  }
+
+  let df = downlink_format(data);
+  if !is_extended_squitter(df) || crc::syndrome(data) != 0 {
+    return None;
+  }
+
+  let capability = data[0] & 0x07;
+  let (source, icao_is_real) = if df == 17 {
+    (SquitterSource::Transponder, true)
+  } else {
+    match capability {
+      CF_ADSB_ES_NT          => (SquitterSource::NonTransponder, true),
+      CF_ADSB_ES_NT_NON_ICAO => (SquitterSource::NonTransponder, false),
+      CF_TISB_FINE | CF_TISB_COARSE | CF_TISB_MANAGEMENT => (SquitterSource::TisB, true),
+      CF_TISB_NON_ICAO       => (SquitterSource::TisB, false),
+      CF_ADSR                => (SquitterSource::AdsR, true),
+      _                      => (SquitterSource::Reserved, false),
+    }
+  };
+
+  let mut me = [0u8; 7];
+  me.copy_from_slice(&data[4..11]);
+
+  Some(ExtendedSquitter {
+    df,
+    capability,
+    icao: ((data[1] as u32) << 16) | ((data[2] as u32) << 8) | data[3] as u32,
+    icao_is_real,
+    source,
+    type_code: me[0] >> 3,
+    me,
+  })
+}
+
+// A DF11 all-call reply. It carries no position, but does announce the aircraft's address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AllCallReply {
+  // transponder capability, the same field as DF17's CA
+  pub capability: u8,
+  pub icao: u32,
+  // interrogator code the parity was overlaid with: 0 for acquisition squitters, otherwise
+  // the II (or SI) code of the radar that asked
+  pub interrogator: u8,
+}
+
+// The parity of a DF11 frame is the CRC overlaid with the interrogator's 7 bit code label and
+// identifier, so only the low 7 bits of the overlay may be set
+const ALL_CALL_PI_MASK: u32 = 0xffff80;
+
+// Decode a DF11 frame. Returns None for other formats, long frames, or an overlay that can't be
+// an interrogator code, which means the frame is corrupt.
+pub fn decode_all_call(data: &[u8]) -> Option<AllCallReply> {
+  if data.len() != SHORT_FRAME_BYTES || downlink_format(data) != 11 {
+    return None;
+  }
+
+  let overlay = crc::parity_overlay(data);
+  if overlay & ALL_CALL_PI_MASK != 0 {
+    return None;
+  }
+
+  Some(AllCallReply {
+    capability: data[0] & 0x07,
+    icao: ((data[1] as u32) << 16) | ((data[2] as u32) << 8) | data[3] as u32,
+    interrogator: overlay as u8,
+  })
+}
+
+// The real ICAO address a frame announces: from DF11 all-call replies and from DF17/DF18 frames
+// that carry one. Formats with the address folded into the parity aren't trusted, since any
+// corrupt frame yields some address.
+pub fn frame_icao(data: &[u8]) -> Option<u32> {
+  if let Some(reply) = decode_all_call(data) {
+    return Some(reply.icao);
+  }
+  decode_extended_squitter(data).filter(|squitter| squitter.icao_is_real).map(|squitter| squitter.icao)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::DF17;
+
+  // A DF18 frame with control field `cf` and the same address and ME field as DF17, with a
+  // valid CRC
+  fn df18(cf: u8) -> Vec<u8> {
+    let mut frame = DF17.to_vec();
+    frame[0] = (18 << 3) | cf;
+    let parity = crc::crc24(&frame[..11]);
+    frame[11..].copy_from_slice(&parity.to_be_bytes()[1..]);
+    frame
+  }
+
+  #[test]
+  fn df17_decodes_as_a_transponder_squitter() {
+    let squitter = decode_extended_squitter(&DF17).unwrap();
+    assert_eq!((squitter.df, squitter.icao, squitter.type_code), (17, 0x4840d6, 4));
+    assert_eq!(squitter.source, SquitterSource::Transponder);
+    assert!(squitter.icao_is_real);
+  }
+
+  #[test]
+  fn df18_control_field_says_where_it_came_from() {
+    let cases = [
+      (CF_ADSB_ES_NT, SquitterSource::NonTransponder, true),
+      (CF_ADSB_ES_NT_NON_ICAO, SquitterSource::NonTransponder, false),
+      (CF_TISB_FINE, SquitterSource::TisB, true),
+      (CF_TISB_COARSE, SquitterSource::TisB, true),
+      (CF_TISB_MANAGEMENT, SquitterSource::TisB, true),
+      (CF_TISB_NON_ICAO, SquitterSource::TisB, false),
+      (CF_ADSR, SquitterSource::AdsR, true),
+      (7, SquitterSource::Reserved, false),
+    ];
+    for (cf, source, icao_is_real) in cases {
+      let squitter = decode_extended_squitter(&df18(cf)).unwrap();
+      assert_eq!((squitter.df, squitter.capability, squitter.source, squitter.icao_is_real), (18, cf, source, icao_is_real), "CF {}", cf);
+      assert_eq!(squitter.icao, 0x4840d6);
+    }
+  }
+
+  #[test]
+  fn only_intact_long_extended_squitters_decode() {
+    let mut corrupt = DF17;
+    corrupt[6] ^= 0x01;
+    assert_eq!(decode_extended_squitter(&corrupt), None);
+    assert_eq!(decode_extended_squitter(&DF17[..7]), None);
+
+    // a DF20 frame with a zero remainder still isn't a squitter
+    let mut df20 = df18(0);
+    df20[0] = 20 << 3;
+    let parity = crc::crc24(&df20[..11]);
+    df20[11..].copy_from_slice(&parity.to_be_bytes()[1..]);
+    assert_eq!(decode_extended_squitter(&df20), None);
+  }
+
+  #[test]
+  fn is_valid_df_accepts_only_assigned_formats() {
+    for df in [0, 4, 5, 11, 16, 17, 18, 19, 20, 21, 24] {
+      assert!(is_valid_df(df), "DF{}", df);
+    }
+    for df in [1, 2, 3, 6, 7, 8, 9, 10, 12, 13, 14, 15, 22, 23] {
+      assert!(!is_valid_df(df), "DF{}", df);
+    }
+  }
+
+  #[test]
+  fn downlink_format_folds_everything_from_24_up() {
+    assert_eq!(downlink_format(&[0x8d]), 17);
+    assert_eq!(downlink_format(&[0x58]), 11);
+    assert_eq!(downlink_format(&[0xc0]), 24);
+    assert_eq!(downlink_format(&[0xff]), 24);
+  }
+
+  #[test]
+  fn df17_and_df18_are_extended_squitters() {
+    assert!(is_extended_squitter(17));
+    assert!(is_extended_squitter(18));
+    assert!(!is_extended_squitter(11));
+    assert!(!is_extended_squitter(19));
+  }
+
+  // A DF11 reply from 4840d6 with its parity overlaid with `overlay`
+  fn df11(overlay: u32) -> Vec<u8> {
+    let mut frame = vec![(11 << 3) | 5, 0x48, 0x40, 0xd6, 0, 0, 0];
+    let parity = crc::crc24(&frame[..4]) ^ overlay;
+    frame[4..].copy_from_slice(&parity.to_be_bytes()[1..]);
+    frame
+  }
+
+  #[test]
+  fn all_call_replies_give_the_address_and_interrogator() {
+    assert_eq!(decode_all_call(&df11(0)), Some(AllCallReply { capability: 5, icao: 0x4840d6, interrogator: 0 }));
+    assert_eq!(decode_all_call(&df11(0x4d)).map(|reply| reply.interrogator), Some(0x4d));
+  }
+
+  #[test]
+  fn an_overlay_that_cant_be_an_interrogator_is_corrupt() {
+    assert_eq!(decode_all_call(&df11(0x80)), None);
+    assert_eq!(decode_all_call(&DF17), None);
+    assert_eq!(decode_all_call(&df11(0)[..6]), None);
+  }
+
+  #[test]
+  fn only_all_calls_and_real_squitter_addresses_are_trusted() {
+    assert_eq!(frame_icao(&df11(0x12)), Some(0x4840d6));
+    assert_eq!(frame_icao(&DF17), Some(0x4840d6));
+    // DF4 with the address folded into its parity
+    assert_eq!(frame_icao(&[0x20, 0x00, 0x17, 0x18, 0x12, 0x34, 0x56]), None);
+  }
+
+  #[test]
+  fn decode_levels_parse_from_their_names_in_order() {
+    let levels = ["raw", "crc", "full"].map(|name| name.parse::<DecodeLevel>().unwrap());
+    assert_eq!(levels, [DecodeLevel::Raw, DecodeLevel::Crc, DecodeLevel::Full]);
+    assert_eq!(levels.map(|level| level.name()), ["raw", "crc", "full"]);
+    assert!(DecodeLevel::Raw < DecodeLevel::Crc && DecodeLevel::Crc < DecodeLevel::Full);
+    assert!("none".parse::<DecodeLevel>().is_err());
+  }
+}
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Drops repeats of a frame seen within a window, such as the same squitter received on both
+// channels or decoded twice around a retune. Frames are compared byte for byte.
+pub struct Deduplicator {
+  window: Duration,
+  seen: HashMap<Vec<u8>, Instant>,
+  last_prune: Instant,
+}
+
+impl Deduplicator {
+  pub fn new(window: Duration, now: Instant) -> Deduplicator {
+    Deduplicator { window, seen: HashMap::new(), last_prune: now }
+  }
+
+  // Whether the same frame was already let through within the window. A repeat doesn't extend
+  // the window, so a frame that keeps coming back is still let through once per window.
+  pub fn is_duplicate(&mut self, data: &[u8], now: Instant) -> bool {
+    if now.saturating_duration_since(self.last_prune) >= self.window {
+      let window = self.window;
+      self.seen.retain(|_, at| now.saturating_duration_since(*at) < window);
+      self.last_prune = now;
+    }
+
+    match self.seen.get(data) {
+      Some(at) if now.saturating_duration_since(*at) < self.window => true,
+      _ => {
+        self.seen.insert(data.to_vec(), now);
+        false
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn repeats_within_the_window_are_dropped() {
+    let start = Instant::now();
+    let mut dedup = Deduplicator::new(Duration::from_millis(100), start);
+    assert!(!dedup.is_duplicate(&[1, 2, 3], start));
+    assert!(dedup.is_duplicate(&[1, 2, 3], start + Duration::from_millis(50)));
+    assert!(!dedup.is_duplicate(&[1, 2, 4], start + Duration::from_millis(50)));
+  }
+
+  #[test]
+  fn a_repeat_does_not_extend_the_window() {
+    let start = Instant::now();
+    let mut dedup = Deduplicator::new(Duration::from_millis(100), start);
+    assert!(!dedup.is_duplicate(&[1, 2, 3], start));
+    assert!(dedup.is_duplicate(&[1, 2, 3], start + Duration::from_millis(90)));
+    assert!(!dedup.is_duplicate(&[1, 2, 3], start + Duration::from_millis(100)));
+    assert!(dedup.is_duplicate(&[1, 2, 3], start + Duration::from_millis(150)));
+  }
+
+  #[test]
+  fn old_frames_are_pruned() {
+    let start = Instant::now();
+    let mut dedup = Deduplicator::new(Duration::from_millis(100), start);
+    for i in 0..10u8 {
+      dedup.is_duplicate(&[i], start);
+    }
+    assert!(!dedup.is_duplicate(&[0xff], start + Duration::from_millis(200)));
+    assert_eq!(dedup.seen.len(), 1);
+  }
+}
@@ -0,0 +1,239 @@
+use crate::consts::{ADSB_BIT_US, ADSB_PREAMBLE_US, LONG_FRAME_BITS, SHORT_FRAME_BYTES};
+use crate::decode::{downlink_format, is_valid_df};
+
+// Minimum preamble score by default, see preamble_score. 6 dB means even the weakest pulse
+// averages twice the magnitude of the gaps between them.
+pub const DEFAULT_PREAMBLE_THRESHOLD: f32 = 6.0;
+
+// Weight each new stretch of quiet samples gets in the noise floor average. Small, so the floor
+// follows a changing noise level over a second or so of capture but not the odd weak frame the
+// demodulator missed.
+pub const NOISE_FLOOR_ALPHA: f32 = 0.05;
+
+// Preamble pulses start at 0, 1, 3.5 and 4.5us and last 0.5us, in half microseconds
+const PREAMBLE_PULSES: [usize; 4] = [0, 2, 7, 9];
+
+// Samples of a whole long frame, preamble included
+pub fn frame_samples(samples_per_us: usize) -> usize {
+  (ADSB_PREAMBLE_US + LONG_FRAME_BITS * ADSB_BIT_US) * samples_per_us
+}
+
+// Magnitude of every SC16_Q11 sample (I and Q as little endian i16). A trailing partial sample
+// is ignored.
+pub fn magnitudes(buf: &[u8]) -> Vec<f32> {
+  buf.chunks_exact(4).map(|sample| {
+    let i = i16::from_le_bytes([sample[0], sample[1]]) as f32;
+    let q = i16::from_le_bytes([sample[2], sample[3]]) as f32;
+    (i * i + q * q).sqrt()
+  }).collect()
+}
+
+// How much a preamble starting at `pos` stands out: the mean magnitude of its weakest pulse over
+// the mean magnitude of the gaps in the 8us preamble, in dB (20 log10 of the ratio). Going by
+// the weakest pulse means a lone pulse, or the pulses of a frame's data, can't pass for a
+// preamble. Noise scores at or below 0 dB, a clean preamble well above 10 dB.
+pub fn preamble_score(mag: &[f32], pos: usize, samples_per_us: usize) -> f32 {
+  let half = samples_per_us / 2;
+  let mut weakest_pulse = f32::MAX;
+  let mut gap = 0.0;
+  for slot in 0..ADSB_PREAMBLE_US * 2 {
+    let start = pos + slot * half;
+    let sum: f32 = mag[start..start + half].iter().sum();
+    if PREAMBLE_PULSES.contains(&slot) {
+      weakest_pulse = weakest_pulse.min(sum);
+    } else {
+      gap += sum;
+    }
+  }
+  let pulse = weakest_pulse / half as f32;
+  let gap = gap / ((ADSB_PREAMBLE_US * 2 - PREAMBLE_PULSES.len()) * half) as f32;
+  20.0 * (pulse.max(f32::MIN_POSITIVE) / gap.max(f32::MIN_POSITIVE)).log10()
+}
+
+// Mean power (magnitude squared) of the samples carrying a pulse in the frame at `pos`: the four
+// preamble pulses and the stronger half of each of its `bits` bits
+pub fn frame_power(mag: &[f32], pos: usize, samples_per_us: usize, bits: usize) -> f32 {
+  let half = samples_per_us / 2;
+  let power = |start: usize| -> f32 { mag[start..start + half].iter().map(|m| m * m).sum() };
+  let mut total: f32 = PREAMBLE_PULSES.iter().map(|&slot| power(pos + slot * half)).sum();
+  for bit in 0..bits {
+    let start = pos + (ADSB_PREAMBLE_US + bit * ADSB_BIT_US) * samples_per_us;
+    total += power(start).max(power(start + half));
+  }
+  total / ((PREAMBLE_PULSES.len() + bits) * half) as f32
+}
+
+// Slow moving average of the power between frames, to measure frames against
+#[derive(Clone, Debug)]
+pub struct NoiseFloor {
+  alpha: f32,
+  // None until the first quiet stretch
+  power: Option<f32>,
+}
+
+impl NoiseFloor {
+  pub fn new(alpha: f32) -> NoiseFloor {
+    NoiseFloor { alpha, power: None }
+  }
+
+  // Fold in the mean power of a stretch without frames. The first one seeds the average rather
+  // than ramping up from zero.
+  pub fn update(&mut self, quiet_power: f32) {
+    self.power = Some(match self.power {
+      Some(power) => power + self.alpha * (quiet_power - power),
+      None => quiet_power,
+    });
+  }
+
+  pub fn power(&self) -> Option<f32> {
+    self.power
+  }
+
+  // How far a frame's power stands above the floor, in dB. None before the floor is known, or
+  // while it's zero (a capture of digital silence).
+  pub fn snr(&self, frame_power: f32) -> Option<f32> {
+    let noise = self.power.filter(|&power| power > 0.0)?;
+    Some(10.0 * (frame_power.max(f32::MIN_POSITIVE) / noise).log10())
+  }
+}
+
+// Pulse position demodulation of the bits following a preamble at `pos`: a bit is 1 when the
+// first half of its microsecond carries more energy than the second
+fn demodulate_bits(mag: &[f32], pos: usize, samples_per_us: usize, bits: usize) -> Vec<u8> {
+  let half = samples_per_us / 2;
+  let mut data = vec![0u8; bits / 8];
+  for bit in 0..bits {
+    let start = pos + (ADSB_PREAMBLE_US + bit * ADSB_BIT_US) * samples_per_us;
+    let first: f32 = mag[start..start + half].iter().sum();
+    let second: f32 = mag[start + half..start + samples_per_us].iter().sum();
+    if first > second {
+      data[bit / 8] |= 0x80 >> (bit % 8);
+    }
+  }
+  data
+}
+
+// Software stand-in for the FPGA's demodulator, for raw baseband captures. Looks for preambles
+// scoring at least `threshold` dB in the first `search` samples of `mag` (which must hold a
+// whole frame past each of them) and returns the sample offset and bytes of every frame with a
+// real downlink format. A lower threshold finds weaker frames but lets more noise through as
+// frames with bad CRCs.
+pub fn demodulate(mag: &[f32], search: usize, samples_per_us: usize, threshold: f32) -> Vec<(usize, Vec<u8>)> {
+  assert!(samples_per_us >= 2 && samples_per_us % 2 == 0, "need an even number of samples per microsecond");
+  let frame_len = frame_samples(samples_per_us);
+  let end = search.min(mag.len().saturating_sub(frame_len - 1));
+  let half = samples_per_us / 2;
+
+  let mut frames = Vec::new();
+  let mut pos = 0;
+  while pos < end {
+    if preamble_score(mag, pos, samples_per_us) < threshold {
+      pos += 1;
+      continue;
+    }
+    // the score stays high for a few samples around the real start, take the best of them
+    let best = (pos..=(pos + half).min(end - 1))
+      .max_by(|&a, &b| preamble_score(mag, a, samples_per_us).total_cmp(&preamble_score(mag, b, samples_per_us)))
+      .unwrap_or(pos);
+
+    let mut data = demodulate_bits(mag, best, samples_per_us, LONG_FRAME_BITS);
+    let df = downlink_format(&data);
+    if !is_valid_df(df) {
+      pos += 1;
+      continue;
+    }
+    if df < 16 {
+      data.truncate(SHORT_FRAME_BYTES);
+    }
+    let bits = data.len() * 8;
+    frames.push((best, data));
+    pos = best + (ADSB_PREAMBLE_US + bits * ADSB_BIT_US) * samples_per_us;
+  }
+  frames
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::DF17;
+
+  const DF11: [u8; 7] = [0x5d, 0x48, 0x40, 0xd6, 0x12, 0x34, 0x56];
+
+  // Magnitudes of `data` sent at `pos` with pulses of `pulse` over a floor of `floor`, at 2
+  // samples per microsecond, padded out past a long frame
+  fn signal(data: &[u8], pos: usize, pulse: f32, floor: f32) -> Vec<f32> {
+    let mut mag = vec![floor; pos + frame_samples(2) + 16];
+    for slot in PREAMBLE_PULSES {
+      mag[pos + slot] = pulse;
+    }
+    for bit in 0..data.len() * 8 {
+      let one = data[bit / 8] & (0x80 >> (bit % 8)) != 0;
+      mag[pos + (ADSB_PREAMBLE_US + bit) * 2 + if one { 0 } else { 1 }] = pulse;
+    }
+    mag
+  }
+
+  #[test]
+  fn magnitudes_combine_i_and_q() {
+    let buf = [3i16, 4, -6, 8].iter().flat_map(|v| v.to_le_bytes()).chain([1u8]).collect::<Vec<_>>();
+    assert_eq!(magnitudes(&buf), vec![5.0, 10.0]);
+  }
+
+  #[test]
+  fn a_clean_preamble_scores_far_above_noise() {
+    let mag = signal(&DF17, 0, 1000.0, 10.0);
+    assert_eq!(preamble_score(&mag, 0, 2), 40.0);
+    assert!(preamble_score(&mag, 1, 2) < DEFAULT_PREAMBLE_THRESHOLD);
+  }
+
+  #[test]
+  fn frames_are_found_at_their_offset() {
+    let frames = demodulate(&signal(&DF17, 30, 1000.0, 10.0), 100, 2, DEFAULT_PREAMBLE_THRESHOLD);
+    assert_eq!(frames, vec![(30, DF17.to_vec())]);
+  }
+
+  #[test]
+  fn short_formats_keep_only_56_bits() {
+    let frames = demodulate(&signal(&DF11, 0, 1000.0, 10.0), 100, 2, DEFAULT_PREAMBLE_THRESHOLD);
+    assert_eq!(frames, vec![(0, DF11.to_vec())]);
+  }
+
+  #[test]
+  fn preambles_under_the_threshold_are_passed_over() {
+    // pulses 4 dB above the floor
+    let mag = signal(&DF17, 0, 1000.0, 631.0);
+    assert_eq!(demodulate(&mag, 100, 2, DEFAULT_PREAMBLE_THRESHOLD), Vec::new());
+    assert_eq!(demodulate(&mag, 100, 2, 3.0).len(), 1);
+  }
+
+  #[test]
+  fn flat_noise_demodulates_to_nothing() {
+    assert_eq!(demodulate(&vec![10.0; 1000], 1000, 2, DEFAULT_PREAMBLE_THRESHOLD), Vec::new());
+  }
+
+  #[test]
+  fn frame_power_counts_only_the_pulses() {
+    let mag = signal(&DF17, 30, 1000.0, 10.0);
+    assert_eq!(frame_power(&mag, 30, 2, 112), 1000000.0);
+  }
+
+  #[test]
+  fn the_noise_floor_is_seeded_then_moves_slowly() {
+    let mut floor = NoiseFloor::new(0.5);
+    assert_eq!(floor.power(), None);
+    assert_eq!(floor.snr(1000000.0), None);
+
+    floor.update(100.0);
+    assert_eq!(floor.power(), Some(100.0));
+    assert_eq!(floor.snr(1000000.0), Some(40.0));
+    floor.update(300.0);
+    assert_eq!(floor.power(), Some(200.0));
+  }
+
+  #[test]
+  fn silence_gives_no_snr() {
+    let mut floor = NoiseFloor::new(NOISE_FLOOR_ALPHA);
+    floor.update(0.0);
+    assert_eq!(floor.snr(1000000.0), None);
+  }
+}
@@ -0,0 +1,387 @@
+use std::time::{Duration, Instant};
+
+use bladerf::bladerf::bladerf_dev_speed;
+
+use crate::frame::BYTES_PER_SAMPLE;
+
+// Sample rates the ADS-B FPGA image can demodulate at. The preamble detector and bit slicer in
+// the image are built around 16 MSPS (8 samples per 0.5us Mode S chip pair), so frame extraction
+// is not rate independent and other rates produce no frames at all.
+pub const SUPPORTED_SAMPLE_RATES: &[u32] = &[16000000];
+
+pub fn validate_sample_rate(rate: u32) -> Result<(), String> {
+  if SUPPORTED_SAMPLE_RATES.contains(&rate) {
+    Ok(())
+  } else {
+    let supported: Vec<String> = SUPPORTED_SAMPLE_RATES.iter().map(|r| r.to_string()).collect();
+    Err(format!(
+      "sample rate {} is not supported by the ADS-B FPGA image (supported: {})",
+      rate, supported.join(", ")
+    ))
+  }
+}
+
+// USB 2.0 moves around 35 MB/s in practice, so above this many samples per second (of
+// BYTES_PER_SAMPLE each) the stream overruns constantly on a High speed connection
+pub const USB2_MAX_SAMPLE_RATE: u32 = 8000000;
+
+// Whether streaming `sample_rate` over a connection of `speed` should go ahead. USB 3.0 is
+// required with --require-usb3 or above USB2_MAX_SAMPLE_RATE, unless --allow-usb2 overrides it.
+// An unknown speed is let through, as there's nothing to go on.
+pub fn check_usb_speed(speed: bladerf_dev_speed, sample_rate: u32, require_usb3: bool, allow_usb2: bool) -> Result<(), String> {
+  let needs_usb3 = (require_usb3 || sample_rate > USB2_MAX_SAMPLE_RATE) && !allow_usb2;
+  if !needs_usb3 || speed != bladerf_dev_speed::BLADERF_DEVICE_SPEED_HIGH {
+    return Ok(());
+  }
+  if require_usb3 {
+    return Err(String::from("the bladeRF is connected at USB 2.0 (High) speed and --require-usb3 was given; plug it into a USB 3.0 port"));
+  }
+  Err(format!(
+    "the bladeRF is connected at USB 2.0 (High) speed, which can't carry {} samples/s ({} MB/s); plug it into a USB 3.0 port, \
+    or pass --allow-usb2 to stream anyway and put up with overruns", sample_rate, sample_rate as usize * BYTES_PER_SAMPLE / 1000000
+  ))
+}
+
+// Mode S pulses are 0.5us long, so anything narrower than this smears them into each other
+pub const MIN_ADSB_BANDWIDTH: u32 = 2000000;
+
+// Misconfigurations of the RX filter bandwidth that still work but lose frames. The board
+// accepts any bandwidth it can set, so these are only warned about, with a hint at the fix.
+pub fn bandwidth_warnings(sample_rate: u32, bandwidth: u32) -> Vec<String> {
+  let mut warnings = Vec::new();
+  // complex sampling covers sample_rate Hz of spectrum, anything wider folds back in band
+  if bandwidth > sample_rate {
+    warnings.push(format!(
+      "bandwidth {} Hz is wider than the {} samples/s sample rate, out of band signals will alias onto 1090 MHz; \
+       set --bandwidth to at most {}", bandwidth, sample_rate, sample_rate
+    ));
+  }
+  if bandwidth < MIN_ADSB_BANDWIDTH {
+    warnings.push(format!(
+      "bandwidth {} Hz is too narrow for ADS-B's 0.5us pulses, expect few or no frames; \
+       set --bandwidth to at least {}", bandwidth, MIN_ADSB_BANDWIDTH
+    ));
+  }
+  warnings
+}
+
+// The ADS-B FPGA image demodulates 4 MHz above the LO, so 1090 MHz is received with the LO
+// tuned to 1086 MHz
+pub const DEFAULT_LO_OFFSET: i32 = 4000000;
+
+// Where to tune the LO to receive `target`, `offset` Hz below it
+pub fn lo_frequency(target: u32, offset: i32) -> Result<u32, String> {
+  u32::try_from(target as i64 - offset as i64)
+    .map_err(|_| format!("frequency {} Hz with an LO offset of {} Hz puts the LO out of range", target, offset))
+}
+
+// Check a frequency against the board's tuning range, as reported by get_frequency_limits
+pub fn validate_frequency(frequency: u32, min: u64, max: u64) -> Result<(), String> {
+  if (min..=max).contains(&(frequency as u64)) {
+    Ok(())
+  } else {
+    Err(format!("frequency {} Hz is outside the board's tuning range of {} - {} Hz", frequency, min, max))
+  }
+}
+
+// Decimate interleaved SC16 I/Q samples by an integer factor, averaging each group of
+// `factor` complex samples (boxcar filter). Trailing samples that don't fill a group are dropped.
+// Only meant for recorded IQ; the FPGA frame path always runs at the full rate.
+pub fn decimate_iq(input: &[i16], factor: usize) -> Vec<i16> {
+  assert!(factor > 0, "decimation factor must be non-zero");
+
+  let mut output = Vec::with_capacity(input.len() / factor + 1);
+  for group in input.chunks_exact(factor * 2) {
+    let mut i_sum: i32 = 0;
+    let mut q_sum: i32 = 0;
+    for pair in group.chunks_exact(2) {
+      i_sum += pair[0] as i32;
+      q_sum += pair[1] as i32;
+    }
+    output.push((i_sum / factor as i32) as i16);
+    output.push((q_sum / factor as i32) as i16);
+  }
+
+  output
+}
+
+// Split a BLADERF_RX_X2 buffer into one buffer per channel. Multi-channel streams interleave
+// whole SC16 samples (4 bytes, I then Q) channel by channel:
+//
+//   | ch0 I | ch0 Q | ch1 I | ch1 Q | ch0 I | ch0 Q | ch1 I | ch1 Q | ...
+//
+// so each channel's buffer has the same layout as a single channel stream. A trailing partial
+// group of samples is dropped.
+pub fn deinterleave_channels(buf: &[u8], channels: usize) -> Vec<Vec<u8>> {
+  assert!(channels > 0, "channel count must be non-zero");
+
+  let mut outputs = vec![Vec::with_capacity(buf.len() / channels); channels];
+  for group in buf.chunks_exact(BYTES_PER_SAMPLE * channels) {
+    for (output, sample) in outputs.iter_mut().zip(group.chunks_exact(BYTES_PER_SAMPLE)) {
+      output.extend_from_slice(sample);
+    }
+  }
+
+  outputs
+}
+
+// Largest magnitude an SC16_Q11 component reaches; the ADC saturates at +-2047
+pub const SC16_Q11_FULL_SCALE: i16 = 2047;
+
+// SC16_Q11 components are 12 bit values in an i16, so dividing by this maps them onto [-1.0, 1.0)
+pub const SC16_Q11_SCALE: f32 = 2048.0;
+
+// Convert interleaved SC16_Q11 samples (little endian i16 I then Q) to interleaved f32 I and Q
+// in [-1.0, 1.0), for doing your own DSP on captures. Converts as many components as fit in both
+// `bytes` and `out`, ignoring a trailing odd byte, and returns how many floats were written.
+pub fn sc16q11_to_cf32(bytes: &[u8], out: &mut [f32]) -> usize {
+  let mut written = 0;
+  for (value, component) in out.iter_mut().zip(bytes.chunks_exact(2)) {
+    *value = i16::from_le_bytes([component[0], component[1]]) as f32 / SC16_Q11_SCALE;
+    written += 1;
+  }
+  written
+}
+
+// The inverse of sc16q11_to_cf32. Values are clamped to the 12 bit range, so anything at or
+// beyond full scale comes out as -2048 or 2047 rather than wrapping. Returns how many
+// components were written, as many as fit in both `samples` and `out`.
+pub fn cf32_to_sc16q11(samples: &[f32], out: &mut [u8]) -> usize {
+  let mut written = 0;
+  for (value, component) in samples.iter().zip(out.chunks_exact_mut(2)) {
+    let q11 = (value * SC16_Q11_SCALE).round().clamp(-SC16_Q11_SCALE, SC16_Q11_FULL_SCALE as f32) as i16;
+    component.copy_from_slice(&q11.to_le_bytes());
+    written += 1;
+  }
+  written
+}
+
+// Only every Nth sample is checked for clipping, which is plenty to spot a saturating front end
+pub const CLIP_CHECK_STRIDE: usize = 16;
+
+// Fraction of checked samples clipping above which the gain is probably too high
+pub const CLIP_WARN_RATIO: f64 = 0.01;
+
+// How often the clipping ratio is evaluated
+pub const CLIP_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Check every `stride`th SC16_Q11 sample in buf for an I or Q component at full scale. Returns
+// how many samples were checked and how many of those clipped.
+pub fn count_clipped(buf: &[u8], stride: usize) -> (u64, u64) {
+  assert!(stride > 0, "stride must be non-zero");
+
+  let mut checked = 0;
+  let mut clipped = 0;
+  for sample in buf.chunks_exact(BYTES_PER_SAMPLE).step_by(stride) {
+    let i = i16::from_le_bytes([sample[0], sample[1]]);
+    let q = i16::from_le_bytes([sample[2], sample[3]]);
+    checked += 1;
+    // saturating_abs so -2048 doesn't overflow
+    if i.saturating_abs() >= SC16_Q11_FULL_SCALE || q.saturating_abs() >= SC16_Q11_FULL_SCALE {
+      clipped += 1;
+    }
+  }
+
+  (checked, clipped)
+}
+
+// Turns running totals of checked and clipped samples into a clipping ratio once per interval
+pub struct ClipMonitor {
+  interval: Duration,
+  last_check: Instant,
+  // totals at the last check
+  checked: u64,
+  clipped: u64,
+}
+
+impl ClipMonitor {
+  pub fn new(interval: Duration, now: Instant) -> ClipMonitor {
+    ClipMonitor {
+      interval,
+      last_check: now,
+      checked: 0,
+      clipped: 0,
+    }
+  }
+
+  // The fraction of samples that clipped since the last check, once the interval is up and
+  // anything was checked
+  pub fn poll(&mut self, checked: u64, clipped: u64, now: Instant) -> Option<f64> {
+    if now.saturating_duration_since(self.last_check) < self.interval {
+      return None;
+    }
+
+    let window_checked = checked.saturating_sub(self.checked);
+    let window_clipped = clipped.saturating_sub(self.clipped);
+    self.last_check = now;
+    self.checked = checked;
+    self.clipped = clipped;
+
+    if window_checked == 0 {
+      None
+    } else {
+      Some(window_clipped as f64 / window_checked as f64)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn only_16_msps_is_supported() {
+    assert_eq!(validate_sample_rate(16000000), Ok(()));
+    let err = validate_sample_rate(8000000).unwrap_err();
+    assert!(err.contains("8000000") && err.contains("16000000"), "{}", err);
+  }
+
+  #[test]
+  fn frequencies_must_be_in_the_tuning_range_inclusive() {
+    assert_eq!(validate_frequency(1090000000, 280000000, 3800000000), Ok(()));
+    assert_eq!(validate_frequency(280000000, 280000000, 3800000000), Ok(()));
+    assert_eq!(validate_frequency(137000000, 280000000, 3800000000),
+               Err(String::from("frequency 137000000 Hz is outside the board's tuning range of 280000000 - 3800000000 Hz")));
+  }
+
+  #[test]
+  fn decimate_iq_averages_i_and_q_separately() {
+    let input = [10, -10, 20, -20, 30, 30, 50, 10];
+    assert_eq!(decimate_iq(&input, 2), vec![15, -15, 40, 20]);
+    assert_eq!(decimate_iq(&input, 1), input.to_vec());
+  }
+
+  #[test]
+  fn decimate_iq_drops_a_partial_group() {
+    assert_eq!(decimate_iq(&[4, 4, 8, 8, 100, 100], 2), vec![6, 6]);
+    assert_eq!(decimate_iq(&[1, 2], 2), Vec::<i16>::new());
+  }
+
+  #[test]
+  fn decimate_iq_does_not_overflow_near_full_scale() {
+    assert_eq!(decimate_iq(&[i16::MAX, i16::MIN, i16::MAX, i16::MIN], 2), vec![i16::MAX, i16::MIN]);
+  }
+
+  #[test]
+  #[should_panic]
+  fn decimate_iq_rejects_a_zero_factor() {
+    decimate_iq(&[1, 2], 0);
+  }
+
+  #[test]
+  fn deinterleave_channels_splits_whole_samples() {
+    let buf = [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 9, 9];
+    assert_eq!(deinterleave_channels(&buf, 2), vec![vec![0, 0, 0, 0, 2, 2, 2, 2], vec![1, 1, 1, 1, 3, 3, 3, 3]]);
+  }
+
+  #[test]
+  fn a_single_channel_passes_through() {
+    let buf: Vec<u8> = (0..16).collect();
+    assert_eq!(deinterleave_channels(&buf, 1), vec![buf]);
+  }
+
+  // SC16_Q11 samples as they come off the wire
+  fn samples(iq: &[(i16, i16)]) -> Vec<u8> {
+    iq.iter().flat_map(|&(i, q)| i.to_le_bytes().into_iter().chain(q.to_le_bytes())).collect()
+  }
+
+  #[test]
+  fn either_component_at_full_scale_counts_as_clipped() {
+    let buf = samples(&[(0, 0), (SC16_Q11_FULL_SCALE, 0), (0, -2048), (2046, -2046)]);
+    assert_eq!(count_clipped(&buf, 1), (4, 2));
+  }
+
+  #[test]
+  fn only_every_strideth_sample_is_checked() {
+    let buf = samples(&[(2047, 0), (2047, 0), (0, 0), (2047, 0), (0, 0)]);
+    assert_eq!(count_clipped(&buf, 2), (3, 1));
+  }
+
+  #[test]
+  fn the_clip_ratio_covers_only_the_last_interval() {
+    let start = Instant::now();
+    let mut monitor = ClipMonitor::new(CLIP_CHECK_INTERVAL, start);
+
+    assert_eq!(monitor.poll(100, 50, start + CLIP_CHECK_INTERVAL / 2), None);
+    assert_eq!(monitor.poll(100, 50, start + CLIP_CHECK_INTERVAL), Some(0.5));
+    assert_eq!(monitor.poll(300, 52, start + 2 * CLIP_CHECK_INTERVAL), Some(0.01));
+    // nothing checked in the interval, so there's no ratio to give
+    assert_eq!(monitor.poll(300, 52, start + 3 * CLIP_CHECK_INTERVAL), None);
+  }
+
+  #[test]
+  fn a_bandwidth_within_the_sample_rate_draws_no_warnings() {
+    assert!(bandwidth_warnings(16000000, 14000000).is_empty());
+    assert!(bandwidth_warnings(MIN_ADSB_BANDWIDTH, MIN_ADSB_BANDWIDTH).is_empty());
+  }
+
+  #[test]
+  fn a_bandwidth_too_wide_or_too_narrow_is_warned_about() {
+    let wide = bandwidth_warnings(8000000, 14000000);
+    assert_eq!(wide.len(), 1);
+    assert!(wide[0].contains("set --bandwidth to at most 8000000"), "{}", wide[0]);
+
+    let narrow = bandwidth_warnings(1000000, 1500000);
+    assert_eq!(narrow.len(), 2);
+    assert!(narrow[1].contains("set --bandwidth to at least 2000000"), "{}", narrow[1]);
+  }
+
+  #[test]
+  fn the_lo_is_tuned_the_offset_below_the_target() {
+    assert_eq!(lo_frequency(1090000000, DEFAULT_LO_OFFSET), Ok(1086000000));
+    assert_eq!(lo_frequency(1090000000, 0), Ok(1090000000));
+    assert_eq!(lo_frequency(1090000000, -4000000), Ok(1094000000));
+  }
+
+  #[test]
+  fn an_lo_out_of_range_is_an_error() {
+    assert!(lo_frequency(1000000, DEFAULT_LO_OFFSET).is_err());
+    assert!(lo_frequency(u32::MAX, -1).is_err());
+  }
+
+  #[test]
+  fn usb2_is_refused_above_what_it_can_carry() {
+    let high = bladerf_dev_speed::BLADERF_DEVICE_SPEED_HIGH;
+    let err = check_usb_speed(high, 16000000, false, false).unwrap_err();
+    assert!(err.contains("16000000 samples/s (64 MB/s)") && err.contains("--allow-usb2"), "{}", err);
+    assert_eq!(check_usb_speed(high, 16000000, false, true), Ok(()));
+    assert_eq!(check_usb_speed(high, USB2_MAX_SAMPLE_RATE, false, false), Ok(()));
+  }
+
+  #[test]
+  fn require_usb3_refuses_usb2_at_any_rate() {
+    let err = check_usb_speed(bladerf_dev_speed::BLADERF_DEVICE_SPEED_HIGH, 2000000, true, false).unwrap_err();
+    assert!(err.contains("--require-usb3"), "{}", err);
+  }
+
+  #[test]
+  fn usb3_and_unknown_speeds_go_ahead() {
+    assert_eq!(check_usb_speed(bladerf_dev_speed::BLADERF_DEVICE_SPEED_SUPER, 16000000, true, false), Ok(()));
+    assert_eq!(check_usb_speed(bladerf_dev_speed::BLADERF_DEVICE_SPEED_UNKNOWN, 16000000, true, false), Ok(()));
+  }
+
+  #[test]
+  fn sc16q11_converts_to_the_unit_range() {
+    let bytes: Vec<u8> = [0i16, 1024, -2048, 2047].iter().flat_map(|v| v.to_le_bytes()).chain([0x7f]).collect();
+    let mut out = [9.0f32; 6];
+    assert_eq!(sc16q11_to_cf32(&bytes, &mut out), 4);
+    assert_eq!(out, [0.0, 0.5, -1.0, 2047.0 / 2048.0, 9.0, 9.0]);
+
+    // and stops where the output does
+    let mut short = [0.0f32; 2];
+    assert_eq!(sc16q11_to_cf32(&bytes, &mut short), 2);
+  }
+
+  #[test]
+  fn cf32_converts_back_and_clamps_to_12_bits() {
+    let samples = [0.0f32, 0.5, -1.0, 2047.0 / 2048.0, 1.0, -3.0];
+    let mut out = [0u8; 12];
+    assert_eq!(cf32_to_sc16q11(&samples, &mut out), 6);
+    let values: Vec<i16> = out.chunks_exact(2).map(|c| i16::from_le_bytes([c[0], c[1]])).collect();
+    assert_eq!(values, vec![0, 1024, -2048, 2047, 2047, -2048]);
+
+    let mut back = [0.0f32; 4];
+    sc16q11_to_cf32(&out[..8], &mut back);
+    assert_eq!(back, samples[..4]);
+  }
+}
@@ -0,0 +1,101 @@
+use std::fmt;
+
+// Process exit codes, so supervisors can tell failure classes apart without scraping logs.
+// 0 is a clean shutdown.
+pub const EXIT_DEVICE: i32 = 1;
+pub const EXIT_DEVICE_OPEN: i32 = 2;
+pub const EXIT_FPGA_LOAD: i32 = 3;
+pub const EXIT_REMOTE_UNREACHABLE: i32 = 4;
+pub const EXIT_CONFIG: i32 = 5;
+pub const EXIT_NO_FRAMES: i32 = 6;
+// a second Ctrl+C cut the shutdown short, 128 + SIGINT as shells report it
+pub const EXIT_FORCE_QUIT: i32 = 130;
+// same code Rust uses for a panic on the main thread
+pub const EXIT_PANIC: i32 = 101;
+
+// Fatal errors that end a run
+#[derive(Clone, Debug, PartialEq)]
+pub enum AppError {
+  // the device failed while being configured or streaming, with the libbladeRF error code
+  Device(String, isize),
+  // no device could be opened, with the libbladeRF error code
+  DeviceOpen(isize),
+  // the FPGA image loaded but the device couldn't be opened again afterwards
+  FpgaReopen(isize),
+  // the FPGA image couldn't be found, verified or loaded
+  FpgaLoad(String),
+  // the remote server couldn't be reached, or was lost, after all retries
+  RemoteUnreachable(String),
+  // invalid command line or configuration
+  Config(String),
+  // the run ended cleanly without a single frame, only an error under --strict
+  NoFrames,
+  // a worker thread panicked, with the thread's name and the panic message
+  Panic(String, String),
+}
+
+impl AppError {
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      AppError::Device(..) => EXIT_DEVICE,
+      AppError::DeviceOpen(_) | AppError::FpgaReopen(_) => EXIT_DEVICE_OPEN,
+      AppError::FpgaLoad(_) => EXIT_FPGA_LOAD,
+      AppError::RemoteUnreachable(_) => EXIT_REMOTE_UNREACHABLE,
+      AppError::Config(_) => EXIT_CONFIG,
+      AppError::NoFrames => EXIT_NO_FRAMES,
+      AppError::Panic(..) => EXIT_PANIC,
+    }
+  }
+}
+
+impl fmt::Display for AppError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      AppError::Device(what, code) => write!(f, "{} failed (error {})", what, code),
+      AppError::DeviceOpen(code) => write!(f, "unable to open bladeRF device (error {})", code),
+      AppError::FpgaReopen(code) => write!(f, "the FPGA image loaded, but the device didn't come back on USB afterwards (error {}); \
+        check the USB cable and port, and try a port without a hub", code),
+      AppError::FpgaLoad(msg) => write!(f, "FPGA load failed: {}", msg),
+      AppError::RemoteUnreachable(msg) => write!(f, "remote server unreachable: {}", msg),
+      AppError::Config(msg) => write!(f, "invalid configuration: {}", msg),
+      AppError::NoFrames => write!(f, "no frames were received"),
+      AppError::Panic(thread, msg) => write!(f, "thread {} panicked: {}", thread, msg),
+    }
+  }
+}
+
+impl std::error::Error for AppError {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn each_failure_class_has_its_own_non_zero_exit_code() {
+    let errors = [
+      AppError::Device("sync_rx".to_string(), -1),
+      AppError::DeviceOpen(-3),
+      AppError::FpgaLoad("no image".to_string()),
+      AppError::RemoteUnreachable("127.0.0.1:30001".to_string()),
+      AppError::Config("bad".to_string()),
+    ];
+    let codes: Vec<i32> = errors.iter().map(AppError::exit_code).collect();
+
+    assert_eq!(codes, vec![EXIT_DEVICE, EXIT_DEVICE_OPEN, EXIT_FPGA_LOAD, EXIT_REMOTE_UNREACHABLE, EXIT_CONFIG]);
+    assert!(codes.iter().all(|&code| code != 0));
+  }
+
+  #[test]
+  fn messages_carry_the_detail() {
+    assert_eq!(AppError::Device("sync_rx".to_string(), -1).to_string(), "sync_rx failed (error -1)");
+    assert_eq!(AppError::DeviceOpen(-3).to_string(), "unable to open bladeRF device (error -3)");
+    assert_eq!(AppError::Config("--sample-rate is too low".to_string()).to_string(), "invalid configuration: --sample-rate is too low");
+  }
+
+  #[test]
+  fn a_device_lost_after_the_fpga_load_exits_as_unopened() {
+    let err = AppError::FpgaReopen(-7);
+    assert_eq!(err.exit_code(), EXIT_DEVICE_OPEN);
+    assert!(err.to_string().contains("didn't come back on USB afterwards (error -7)"), "{}", err);
+  }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+
+use crate::decode::frame_icao;
+
+// Parse a 24 bit ICAO address written as 6 hex digits, e.g. "4ca2b6"
+pub fn parse_icao(s: &str) -> Result<u32, String> {
+  let s = s.trim();
+  if s.len() != 6 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+    return Err(format!("'{}' isn't an ICAO address, expected 6 hex digits", s));
+  }
+  u32::from_str_radix(s, 16).map_err(|e| e.to_string())
+}
+
+// Forward or drop frames by the ICAO address of the aircraft that sent them. Only DF11 frames
+// and DF17/DF18 frames with a real ICAO address are matched against the list; everything else
+// (other downlink formats, anonymous and TIS-B track addresses) gets the `unknown` decision.
+pub enum IcaoFilter {
+  // forward everything
+  None,
+  // forward only the listed addresses
+  Allow { icaos: HashSet<u32>, unknown: bool },
+  // forward everything except the listed addresses
+  Block { icaos: HashSet<u32>, unknown: bool },
+}
+
+impl IcaoFilter {
+  // Build the filter from --icao-allow / --icao-block. The two are mutually exclusive, which the
+  // command line enforces; an empty list disables filtering.
+  pub fn new(allow: &[u32], block: &[u32], unknown: bool) -> IcaoFilter {
+    if !allow.is_empty() {
+      IcaoFilter::Allow { icaos: allow.iter().copied().collect(), unknown }
+    } else if !block.is_empty() {
+      IcaoFilter::Block { icaos: block.iter().copied().collect(), unknown }
+    } else {
+      IcaoFilter::None
+    }
+  }
+
+  // Whether a frame with this (possibly unknown) ICAO address should be forwarded
+  pub fn accepts(&self, icao: Option<u32>) -> bool {
+    match (self, icao) {
+      (IcaoFilter::None, _) => true,
+      (IcaoFilter::Allow { unknown, .. }, None) | (IcaoFilter::Block { unknown, .. }, None) => *unknown,
+      (IcaoFilter::Allow { icaos, .. }, Some(icao)) => icaos.contains(&icao),
+      (IcaoFilter::Block { icaos, .. }, Some(icao)) => !icaos.contains(&icao),
+    }
+  }
+
+  // Whether a raw frame should be forwarded
+  pub fn accepts_frame(&self, data: &[u8]) -> bool {
+    if let IcaoFilter::None = self {
+      return true;
+    }
+    self.accepts(frame_icao(data))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::DF17;
+
+  // DF4 surveillance altitude reply, address folded into the parity
+  const DF4: [u8; 7] = [0x20, 0x00, 0x17, 0x18, 0x12, 0x34, 0x56];
+
+  #[test]
+  fn icao_addresses_are_six_hex_digits() {
+    assert_eq!(parse_icao("4ca2b6"), Ok(0x4ca2b6));
+    assert_eq!(parse_icao(" 4CA2B6 "), Ok(0x4ca2b6));
+    for s in ["4ca2b", "4ca2b60", "4ca2bg", "0x4ca2b6", ""] {
+      assert!(parse_icao(s).is_err(), "{} parsed", s);
+    }
+  }
+
+  #[test]
+  fn no_lists_forward_everything() {
+    let filter = IcaoFilter::new(&[], &[], false);
+    assert!(filter.accepts(Some(0x4840d6)));
+    assert!(filter.accepts(None));
+    assert!(filter.accepts_frame(&DF4));
+  }
+
+  #[test]
+  fn an_allow_list_forwards_only_its_addresses() {
+    let filter = IcaoFilter::new(&[0x4840d6], &[], false);
+    assert!(filter.accepts_frame(&DF17));
+    assert!(!filter.accepts(Some(0x4ca2b6)));
+    assert!(!filter.accepts_frame(&DF4));
+    assert!(IcaoFilter::new(&[0x4840d6], &[], true).accepts_frame(&DF4));
+  }
+
+  #[test]
+  fn a_block_list_drops_only_its_addresses() {
+    let filter = IcaoFilter::new(&[], &[0x4840d6], true);
+    assert!(!filter.accepts_frame(&DF17));
+    assert!(filter.accepts(Some(0x4ca2b6)));
+    assert!(filter.accepts_frame(&DF4));
+    assert!(!IcaoFilter::new(&[], &[0x4840d6], false).accepts_frame(&DF4));
+  }
+}
@@ -0,0 +1,12 @@
+// Frames shared by the unit tests of the library and of the binary, which both declare this
+// module
+
+use bladerf_adsb::frame::AdsbFrame;
+
+// DF17 airborne identification from the Mode S literature, ICAO 4840d6 with a valid CRC
+pub const DF17: [u8; 14] = [0x8d, 0x48, 0x40, 0xd6, 0x20, 0x2c, 0xc3, 0x71, 0xc3, 0x2c, 0xe0, 0x57, 0x60, 0x98];
+
+// A frame of `data` read from the first slot, with nothing else known about it
+pub fn frame(data: &[u8]) -> AdsbFrame {
+  AdsbFrame { data: data.to_vec(), slot: 0, timestamp: None, frequency: None, snr: None }
+}
@@ -0,0 +1,414 @@
+use std::time::Instant;
+
+use crate::aircraft::{AircraftRegistry, FrameFields};
+use crate::consts::{LONG_FRAME_BYTES, SHORT_FRAME_BYTES};
+use crate::decode::is_extended_squitter;
+use crate::frame::AdsbFrame;
+
+// MLAT timestamps in AVR/Beast output are 48 bits wide
+const MLAT_TIMESTAMP_MASK: u64 = 0xffff_ffff_ffff;
+
+// Beast frames start with this byte, and any occurrence inside a frame is doubled
+pub const BEAST_ESCAPE: u8 = 0x1a;
+// Beast message types for 56 and 112 bit Mode S frames
+pub const BEAST_TYPE_SHORT: u8 = b'2';
+pub const BEAST_TYPE_LONG: u8 = b'3';
+
+// Wire formats frames can be serialized to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+  // "*<hex>;" lines (readsb --net-ri-port)
+  Avr,
+  // "@<12 hex digit 12 MHz timestamp><hex>;" lines (dump1090 --raw with mlat)
+  AvrMlat,
+  // binary Beast frames with MLAT timestamps (readsb --net-bi-port)
+  Beast,
+  // one JSON object per line with whatever could be decoded from the frame
+  Json,
+  // nothing at all, for benchmarking the receive and decode path with --stats-interval
+  None,
+}
+
+impl OutputFormat {
+  // Every format, in the order they're listed. Add new formats here so --output-format and
+  // --list-formats pick them up.
+  pub const ALL: &'static [OutputFormat] = &[OutputFormat::Avr, OutputFormat::AvrMlat, OutputFormat::Beast, OutputFormat::Json, OutputFormat::None];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      OutputFormat::Avr     => "avr",
+      OutputFormat::AvrMlat => "avr-mlat",
+      OutputFormat::Beast   => "beast",
+      OutputFormat::Json    => "json",
+      OutputFormat::None    => "none",
+    }
+  }
+
+  pub fn description(&self) -> &'static str {
+    match self {
+      OutputFormat::Avr     => "AVR text, one \"*<hex>;\" line per frame (readsb --net-ri-port)",
+      OutputFormat::AvrMlat => "AVR text with a 48 bit 12 MHz MLAT timestamp, \"@<timestamp><hex>;\" (dump1090 --raw)",
+      OutputFormat::Beast   => "Binary Beast frames with a 12 MHz MLAT timestamp, 0x1a escaped (readsb --net-bi-port)",
+      OutputFormat::Json    => "Newline delimited JSON with the raw frame plus ICAO, callsign, altitude, position and velocity when decodable",
+      OutputFormat::None    => "No output: frames are still decoded and counted, for benchmarking with --stats-interval",
+    }
+  }
+
+  // Binary formats can't be shown as text as-is
+  pub fn is_binary(&self) -> bool {
+    matches!(self, OutputFormat::Beast)
+  }
+
+  // Formats that include decoded fields, which need frames run through an AircraftRegistry
+  // first to resolve positions
+  pub fn is_decoded(&self) -> bool {
+    matches!(self, OutputFormat::Json)
+  }
+
+  pub fn from_name(name: &str) -> Option<OutputFormat> {
+    OutputFormat::ALL.iter().copied().find(|format| format.name() == name)
+  }
+
+  // `fields` is what the frame decoded to, only used by decoded formats. `tag` is the --tag
+  // naming the receiver, which only JSON has room for; AVR and Beast leave it out.
+  pub fn serialize(&self, frame: &AdsbFrame, fields: Option<&FrameFields>, tag: Option<&str>) -> Vec<u8> {
+    match self {
+      OutputFormat::Avr     => frame.to_avr().into_bytes(),
+      OutputFormat::AvrMlat => to_avr_mlat(frame).into_bytes(),
+      OutputFormat::Beast   => to_beast(frame),
+      OutputFormat::Json    => {
+        let mut line = to_json(frame, fields, tag);
+        line.push('\n');
+        line.into_bytes()
+      },
+      OutputFormat::None    => Vec::new(),
+    }
+  }
+}
+
+// AVR line prefixed with the frame's MLAT timestamp, zero when the timestamp is unknown
+pub fn to_avr_mlat(frame: &AdsbFrame) -> String {
+  let mut line = format!("@{:012x}", frame.timestamp.unwrap_or(0) & MLAT_TIMESTAMP_MASK);
+  for byte in &frame.data {
+    line.push_str(&format!("{:02x}", byte));
+  }
+  line.push_str(";\n");
+  line
+}
+
+// Beast frame: escape byte, message type, 6 byte MLAT timestamp, signal level, then the frame.
+// The FPGA doesn't report signal levels, so that byte is always 0. Every 0x1a after the leading
+// one is doubled so receivers can resynchronise on frame boundaries.
+pub fn to_beast(frame: &AdsbFrame) -> Vec<u8> {
+  let kind = if frame.is_long() { BEAST_TYPE_LONG } else { BEAST_TYPE_SHORT };
+  let timestamp = (frame.timestamp.unwrap_or(0) & MLAT_TIMESTAMP_MASK).to_be_bytes();
+
+  let mut out = Vec::with_capacity(2 + 2 * (6 + 1 + frame.data.len()));
+  out.push(BEAST_ESCAPE);
+  out.push(kind);
+  for &byte in timestamp[2..].iter().chain(std::iter::once(&0u8)).chain(frame.data.iter()) {
+    out.push(byte);
+    if byte == BEAST_ESCAPE {
+      out.push(byte);
+    }
+  }
+  out
+}
+
+// Frame bytes of an AVR line, with or without an MLAT timestamp ("*<hex>;" or
+// "@<timestamp><hex>;"). Surrounding whitespace is ignored. None unless the line holds a
+// whole 56 or 112 bit frame.
+pub fn parse_avr(line: &str) -> Option<Vec<u8>> {
+  let line = line.trim();
+  let hex = if let Some(rest) = line.strip_prefix('*') {
+    rest
+  } else {
+    line.strip_prefix('@')?.get(12..)?
+  };
+  let hex = hex.strip_suffix(';')?;
+  if hex.len() != SHORT_FRAME_BYTES * 2 && hex.len() != LONG_FRAME_BYTES * 2 {
+    return None;
+  }
+
+  (0..hex.len()).step_by(2)
+    .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+    .collect()
+}
+
+// MLAT timestamp of an "@<timestamp><hex>;" AVR line, None for plain AVR
+pub fn parse_avr_timestamp(line: &str) -> Option<u64> {
+  let digits = line.trim().strip_prefix('@')?.get(..12)?;
+  u64::from_str_radix(digits, 16).ok()
+}
+
+// MLAT timestamp, signal level and frame bytes of the Beast message at the start of `bytes`,
+// undoing the 0x1a escaping. Anything after the message is ignored. None if the message is
+// truncated, badly escaped or not a Mode S frame.
+pub fn parse_beast(bytes: &[u8]) -> Option<(u64, i8, Vec<u8>)> {
+  if bytes.len() < 2 || bytes[0] != BEAST_ESCAPE {
+    return None;
+  }
+  let frame_len = match bytes[1] {
+    BEAST_TYPE_SHORT => SHORT_FRAME_BYTES,
+    BEAST_TYPE_LONG => LONG_FRAME_BYTES,
+    _ => return None,
+  };
+
+  // timestamp, signal level and frame, unescaped
+  let mut fields = Vec::with_capacity(6 + 1 + frame_len);
+  let mut rest = bytes[2..].iter();
+  while fields.len() < 6 + 1 + frame_len {
+    let byte = *rest.next()?;
+    if byte == BEAST_ESCAPE && *rest.next()? != BEAST_ESCAPE {
+      // an unpaired 0x1a starts the next message
+      return None;
+    }
+    fields.push(byte);
+  }
+
+  let timestamp = fields[..6].iter().fold(0u64, |acc, &byte| (acc << 8) | byte as u64);
+  Some((timestamp, fields[6] as i8, fields[7..].to_vec()))
+}
+
+// One JSON object per frame, e.g.
+// {"hex":"8d4840d6202cc371c32ce0576098","df":17,"timestamp":305419896,"frequency":1090000000,
+//  "icao":"4840d6","type_code":4,"callsign":"KLM1023"}
+// timestamp (12 MHz MLAT ticks), frequency and snr (in dB) are null when unknown. The decoded
+// fields (icao, type_code, callsign, altitude in feet, lat/lon, ground_speed in knots, track in
+// degrees, vertical_rate in feet per minute) only appear when `fields` has them, and tag only
+// when there is one. The FPGA doesn't report signal levels, so only frames demodulated in
+// software have an snr, and there is no rssi yet.
+pub fn to_json(frame: &AdsbFrame, fields: Option<&FrameFields>, tag: Option<&str>) -> String {
+  let hex: String = frame.data.iter().map(|byte| format!("{:02x}", byte)).collect();
+  let mut json = serde_json::json!({
+    "hex": hex,
+    "df": frame.df(),
+    "timestamp": frame.timestamp.map(|t| t & MLAT_TIMESTAMP_MASK),
+    "frequency": frame.frequency,
+    "snr": frame.snr,
+  });
+
+  if let (Some(tag), Some(object)) = (tag, json.as_object_mut()) {
+    object.insert(String::from("tag"), tag.into());
+  }
+
+  if let (Some(fields), Some(object)) = (fields, json.as_object_mut()) {
+    object.insert(String::from("icao"), format!("{:06x}", fields.icao).into());
+    if is_extended_squitter(frame.df()) {
+      object.insert(String::from("type_code"), fields.type_code.into());
+    }
+    if let Some(ref callsign) = fields.callsign {
+      object.insert(String::from("callsign"), callsign.clone().into());
+    }
+    if let Some(altitude) = fields.altitude {
+      object.insert(String::from("altitude"), altitude.into());
+    }
+    if let Some((lat, lon)) = fields.position {
+      object.insert(String::from("lat"), lat.into());
+      object.insert(String::from("lon"), lon.into());
+    }
+    if let Some(speed) = fields.ground_speed {
+      object.insert(String::from("ground_speed"), speed.into());
+    }
+    if let Some(track) = fields.track {
+      object.insert(String::from("track"), track.into());
+    }
+    if let Some(rate) = fields.vertical_rate {
+      object.insert(String::from("vertical_rate"), rate.into());
+    }
+  }
+  json.to_string()
+}
+
+// Human readable listing of every format with a sample of its output, for --list-formats
+pub fn describe_formats() -> String {
+  // DF17 airborne identification from the Mode S literature
+  let sample = AdsbFrame {
+    data: vec![0x8d, 0x48, 0x40, 0xd6, 0x20, 0x2c, 0xc3, 0x71, 0xc3, 0x2c, 0xe0, 0x57, 0x60, 0x98],
+    slot: 0,
+    timestamp: Some(0x1234_5678),
+    frequency: None,
+    snr: None,
+  };
+
+  let mut listing = String::new();
+  for format in OutputFormat::ALL {
+    listing.push_str(&format!("{:<10} {}\n", format.name(), format.description()));
+    let fields = AircraftRegistry::new().decode(&sample, Instant::now());
+    let bytes = format.serialize(&sample, fields.as_ref(), None);
+    if bytes.is_empty() {
+      continue;
+    }
+    let line = if format.is_binary() {
+      bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" ")
+    } else {
+      String::from_utf8_lossy(&bytes).into_owned()
+    };
+    listing.push_str(&format!("{:<10} e.g. {}\n", "", line.trim_end()));
+  }
+  listing
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{frame, DF17};
+
+  // DF17 stamped with `timestamp`
+  fn timed(timestamp: u64) -> AdsbFrame {
+    AdsbFrame { timestamp: Some(timestamp), ..frame(&DF17) }
+  }
+
+  #[test]
+  fn avr_mlat_prefixes_a_12_digit_timestamp() {
+    assert_eq!(to_avr_mlat(&timed(0x1234_5678)), "@0000123456788d4840d6202cc371c32ce0576098;\n");
+  }
+
+  #[test]
+  fn avr_mlat_keeps_the_low_48_bits_and_zeroes_an_unknown_timestamp() {
+    assert_eq!(to_avr_mlat(&timed(0xab_cdef_0123_4567)), "@cdef012345678d4840d6202cc371c32ce0576098;\n");
+    assert_eq!(to_avr_mlat(&frame(&DF17)), "@0000000000008d4840d6202cc371c32ce0576098;\n");
+  }
+
+  #[test]
+  fn formats_are_found_by_name() {
+    for format in OutputFormat::ALL {
+      assert_eq!(OutputFormat::from_name(format.name()), Some(*format));
+    }
+    assert_eq!(OutputFormat::from_name("avr-mlat"), Some(OutputFormat::AvrMlat));
+    assert_eq!(OutputFormat::from_name("AVR"), None);
+  }
+
+  #[test]
+  fn the_listing_names_every_format() {
+    let listing = describe_formats();
+    for format in OutputFormat::ALL {
+      assert!(listing.lines().any(|line| line.starts_with(&format!("{:<10} ", format.name()))), "{} missing from\n{}", format.name(), listing);
+    }
+    assert!(listing.contains("e.g. *8d4840d6202cc371c32ce0576098;\n"));
+    assert!(listing.contains("e.g. @0000123456788d4840d6202cc371c32ce0576098;\n"));
+  }
+
+  #[test]
+  fn beast_frames_carry_type_timestamp_and_signal_before_the_frame() {
+    let beast = to_beast(&timed(0x0102_0304_0506));
+    assert_eq!(&beast[..9], &[BEAST_ESCAPE, BEAST_TYPE_LONG, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x00]);
+    assert_eq!(&beast[9..], &DF17[..]);
+  }
+
+  #[test]
+  fn beast_doubles_escape_bytes_after_the_first() {
+    let mut short = timed(0x1a);
+    short.data = vec![0x5d, 0x1a, 0x40, 0xd6, 0x12, 0x34, 0x56];
+    assert_eq!(to_beast(&short), vec![
+      BEAST_ESCAPE, BEAST_TYPE_SHORT, 0, 0, 0, 0, 0, 0x1a, 0x1a, 0,
+      0x5d, 0x1a, 0x1a, 0x40, 0xd6, 0x12, 0x34, 0x56,
+    ]);
+  }
+
+  #[test]
+  fn json_carries_hex_df_timestamp_and_frequency() {
+    let mut tuned = timed(0x1234_5678);
+    tuned.frequency = Some(1086000000);
+    assert_eq!(
+      serde_json::from_str::<serde_json::Value>(&to_json(&tuned, None, None)).unwrap(),
+      serde_json::json!({"hex": "8d4840d6202cc371c32ce0576098", "df": 17, "timestamp": 0x1234_5678u64, "frequency": 1086000000, "snr": null}),
+    );
+  }
+
+  #[test]
+  fn json_has_null_for_an_unknown_timestamp_or_frequency() {
+    let json: serde_json::Value = serde_json::from_str(&to_json(&frame(&DF17), None, None)).unwrap();
+    assert!(json["timestamp"].is_null());
+    assert!(json["frequency"].is_null());
+  }
+
+  #[test]
+  fn avr_lines_parse_back_to_the_frame() {
+    let framed = timed(0x1234_5678);
+    assert_eq!(parse_avr(&String::from_utf8(OutputFormat::Avr.serialize(&framed, None, None)).unwrap()), Some(framed.data.clone()));
+    let line = to_avr_mlat(&framed);
+    assert_eq!(parse_avr(&line), Some(framed.data.clone()));
+    assert_eq!(parse_avr_timestamp(&line), Some(0x1234_5678));
+    assert_eq!(parse_avr_timestamp("*8d4840d6202cc371c32ce0576098;"), None);
+  }
+
+  #[test]
+  fn malformed_avr_lines_are_rejected() {
+    for line in ["8d4840d6202cc371c32ce0576098;", "*8d4840d6202cc371c32ce0576098", "*8d4840d6202cc371c32ce05760;",
+      "*8d4840d6202cc371c32ce05760zz;", "@0000123456;", ""] {
+      assert_eq!(parse_avr(line), None, "{} parsed", line);
+    }
+  }
+
+  #[test]
+  fn beast_messages_parse_back_to_the_frame() {
+    let framed = timed(0x1a1a_0304_051a);
+    let mut beast = to_beast(&framed);
+    assert_eq!(parse_beast(&beast), Some((0x1a1a_0304_051a, 0, framed.data.clone())));
+
+    // the start of the next message is left alone
+    beast.extend_from_slice(&[BEAST_ESCAPE, BEAST_TYPE_SHORT]);
+    assert_eq!(parse_beast(&beast), Some((0x1a1a_0304_051a, 0, framed.data)));
+  }
+
+  #[test]
+  fn truncated_or_badly_escaped_beast_messages_are_rejected() {
+    let beast = to_beast(&timed(0x1a));
+    assert_eq!(parse_beast(&beast[..beast.len() - 1]), None);
+    assert_eq!(parse_beast(&[BEAST_ESCAPE, b'4', 0, 0]), None);
+    assert_eq!(parse_beast(&beast[1..]), None);
+
+    let mut unpaired = beast.clone();
+    unpaired.remove(8);
+    assert_eq!(parse_beast(&unpaired), None);
+  }
+
+  #[test]
+  fn json_adds_the_decoded_fields_that_are_known() {
+    let fields = AircraftRegistry::new().decode(&frame(&DF17), Instant::now()).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&to_json(&frame(&DF17), Some(&fields), None)).unwrap();
+
+    assert_eq!(json["icao"], "4840d6");
+    assert_eq!(json["type_code"], 4);
+    assert_eq!(json["callsign"], "KLM1023");
+    for absent in ["altitude", "lat", "lon", "ground_speed", "track", "vertical_rate"] {
+      assert!(json.get(absent).is_none(), "{} in {}", absent, json);
+    }
+  }
+
+  #[test]
+  fn json_leaves_out_the_type_code_of_frames_without_one() {
+    let mut all_call = frame(&DF17);
+    all_call.data = vec![0x5d, 0x48, 0x40, 0xd6, 0x12, 0x34, 0x56];
+    let fields = FrameFields { icao: 0x4840d6, ..FrameFields::default() };
+    let json: serde_json::Value = serde_json::from_str(&to_json(&all_call, Some(&fields), None)).unwrap();
+
+    assert_eq!(json["icao"], "4840d6");
+    assert!(json.get("type_code").is_none());
+  }
+
+  #[test]
+  fn the_json_format_writes_one_line_per_frame() {
+    let line = OutputFormat::Json.serialize(&frame(&DF17), None, None);
+    assert_eq!(line.iter().filter(|&&byte| byte == b'\n').count(), 1);
+    assert_eq!(line.last(), Some(&b'\n'));
+  }
+
+  #[test]
+  fn the_tag_is_added_to_json_only() {
+    let json: serde_json::Value = serde_json::from_str(&to_json(&frame(&DF17), None, Some("site-1"))).unwrap();
+    assert_eq!(json["tag"], "site-1");
+    let json: serde_json::Value = serde_json::from_str(&to_json(&frame(&DF17), None, None)).unwrap();
+    assert!(json.get("tag").is_none());
+
+    assert_eq!(OutputFormat::Avr.serialize(&frame(&DF17), None, Some("site-1")), OutputFormat::Avr.serialize(&frame(&DF17), None, None));
+    assert_eq!(OutputFormat::Beast.serialize(&timed(1), None, Some("site-1")), OutputFormat::Beast.serialize(&timed(1), None, None));
+  }
+
+  #[test]
+  fn the_none_format_serializes_to_nothing() {
+    assert_eq!(OutputFormat::from_name("none"), Some(OutputFormat::None));
+    assert!(OutputFormat::None.serialize(&timed(1), None, Some("site-1")).is_empty());
+  }
+}
@@ -0,0 +1,367 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use bladerf::BladeRFDevice;
+use bladerf::bladerf::bladerf_fpga_size;
+use bladerf::bladerf::bladerf_fpga_size::*;
+
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+
+// Where the ADS-B images are installed by default, the first place searched
+pub const DEFAULT_FPGA_DIR: &str = "/usr/share/Nuand/bladeRF";
+
+// Directories searched for the ADS-B image when no --fpga-path is given, in order: the system
+// and local install locations, the user's data directory, then next to the binary
+pub fn fpga_search_dirs() -> Vec<PathBuf> {
+  let mut dirs = vec![PathBuf::from(DEFAULT_FPGA_DIR), PathBuf::from("/usr/local/share/Nuand/bladeRF")];
+  if let Some(home) = std::env::var_os("HOME") {
+    dirs.push(PathBuf::from(home).join(".local/share/Nuand/bladeRF"));
+  }
+  if let Some(dir) = std::env::current_exe().ok().and_then(|exe| exe.parent().map(Path::to_path_buf)) {
+    dirs.push(dir);
+  }
+  dirs
+}
+
+// The ADS-B image file for an FPGA size. An unknown size (including ones newer than these
+// bindings) can't be matched to an image.
+pub fn fpga_image_name(size: bladerf_fpga_size) -> Option<&'static str> {
+  match size {
+    BLADERF_FPGA_40KLE   => Some("adsbx40.rbf"),
+    BLADERF_FPGA_115KLE  => Some("adsbx115.rbf"),
+    BLADERF_FPGA_A4      => Some("adsbxA4.rbf"),
+    BLADERF_FPGA_A5      => Some("adsbxA5.rbf"),
+    BLADERF_FPGA_A9      => Some("adsbxA9.rbf"),
+    BLADERF_FPGA_UNKNOWN => None,
+  }
+}
+
+// The first of `dirs` holding the image, either as is or gzip compressed
+pub fn find_fpga_image(name: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+  dirs.iter()
+    .flat_map(|dir| [dir.join(name), dir.join(format!("{}.gz", name))])
+    .find(|path| path.is_file())
+}
+
+// The ADS-B image for an FPGA size, searched for in fpga_search_dirs. When the size is unknown
+// or the image isn't in any of them, the error explains how to pick one by hand.
+pub fn default_fpga_path(size: bladerf_fpga_size) -> Result<String, String> {
+  let name = match fpga_image_name(size) {
+    Some(name) => name,
+    None => return Err(format!(
+      "unable to determine the FPGA size of this board, so no default image can be picked; pass \
+       --fpga-path with the ADS-B image for your board (adsbx40.rbf, adsbx115.rbf, adsbxA4.rbf, \
+       adsbxA5.rbf or adsbxA9.rbf, normally in {})", DEFAULT_FPGA_DIR)),
+  };
+
+  let dirs = fpga_search_dirs();
+  match find_fpga_image(name, &dirs) {
+    Some(path) => Ok(path.to_string_lossy().into_owned()),
+    None => {
+      let searched: Vec<String> = dirs.iter().map(|dir| dir.display().to_string()).collect();
+      Err(format!("{} not found in {}; download it from https://www.nuand.com/fpga/ or pass --fpga-path",
+                  name, searched.join(", ")))
+    },
+  }
+}
+
+// Hex encoded SHA-256 of a file
+pub fn sha256_file(path: &str) -> io::Result<String> {
+  let mut file = File::open(path)?;
+  let mut hasher = Sha256::new();
+  let mut buf = [0u8; 64 * 1024];
+
+  loop {
+    let n = file.read(&mut buf)?;
+    if n == 0 {
+      break;
+    }
+    hasher.update(&buf[..n]);
+  }
+
+  Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// Reference checksum for an image: the user supplied value if any, otherwise a
+// sha256sum style "<path>.sha256" file shipped next to the image
+pub fn expected_sha256(path: &str, user_sha256: Option<&str>) -> Option<String> {
+  if let Some(sha256) = user_sha256 {
+    return Some(sha256.trim().to_lowercase());
+  }
+
+  let mut sidecar = String::new();
+  File::open(format!("{}.sha256", path)).ok()?.read_to_string(&mut sidecar).ok()?;
+  sidecar.split_whitespace().next().map(|s| s.to_lowercase())
+}
+
+// Checksum the image and compare it against the reference, returning the computed checksum on a match
+pub fn verify_fpga(path: &str, user_sha256: Option<&str>) -> Result<String, String> {
+  let expected = match expected_sha256(path, user_sha256) {
+    Some(expected) => expected,
+    None => return Err(format!("no reference checksum for {} (pass --fpga-sha256 or provide {}.sha256)", path, path)),
+  };
+
+  let actual = sha256_file(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+  if actual != expected {
+    return Err(format!("checksum mismatch for {}: expected {}, got {}", path, expected, actual));
+  }
+
+  Ok(actual)
+}
+
+// Times the FPGA state is polled after reopening the device, and the pause between polls.
+// load_fpga can return before the FPGA reports configured over USB.
+pub const FPGA_CONFIGURED_ATTEMPTS: u32 = 5;
+pub const FPGA_CONFIGURED_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+// Poll is_fpga_configured until it reports configured or `attempts` polls have been made.
+// Returns whether the FPGA ended up configured; errors from libbladeRF end the wait early.
+pub fn wait_for_fpga_configured(dev: &BladeRFDevice, attempts: u32, delay: Duration) -> Result<bool, isize> {
+  for attempt in 1..=attempts {
+    if dev.is_fpga_configured()? {
+      return Ok(true);
+    }
+    if attempt < attempts {
+      thread::sleep(delay);
+    }
+  }
+  Ok(false)
+}
+
+// Every gzip stream starts with these two bytes
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Whether an image is gzip compressed, going by its extension or, failing that, its first bytes
+pub fn is_gzip(path: &str) -> io::Result<bool> {
+  if path.ends_with(".gz") {
+    return Ok(true);
+  }
+
+  let mut magic = [0u8; 2];
+  match File::open(path)?.read_exact(&mut magic) {
+    Ok(()) => Ok(magic == GZIP_MAGIC),
+    // too short to be gzip, let load_fpga complain about it
+    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+    Err(e) => Err(e),
+  }
+}
+
+// An FPGA image ready to hand to load_fpga. Compressed images are decompressed into a temporary
+// file, removed again when this is dropped, since libbladeRF only loads from a plain file.
+pub struct FpgaImage {
+  path: PathBuf,
+  temporary: bool,
+}
+
+impl FpgaImage {
+  pub fn open(path: &str) -> io::Result<FpgaImage> {
+    if !is_gzip(path)? {
+      return Ok(FpgaImage { path: PathBuf::from(path), temporary: false });
+    }
+
+    let name = Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or("fpga.rbf.gz");
+    let temp_path = std::env::temp_dir().join(format!("bladerf_adsb-{}-{}", process::id(), name.trim_end_matches(".gz")));
+    let image = FpgaImage { path: temp_path, temporary: true };
+
+    // written through `image` so a failed decompression still removes the partial file
+    let mut decoder = GzDecoder::new(File::open(path)?);
+    io::copy(&mut decoder, &mut File::create(&image.path)?)?;
+    Ok(image)
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  pub fn is_decompressed(&self) -> bool {
+    self.temporary
+  }
+}
+
+impl Drop for FpgaImage {
+  fn drop(&mut self) {
+    if self.temporary {
+      let _ = fs::remove_file(&self.path);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bladerf::bladerf::bladerf_error::BLADERF_ERR_NODEV;
+  use bladerf::mock::MockBladeRf;
+
+  // sha256("abc")
+  const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+  // A file under the temp directory named for this process and test, removed when dropped
+  struct TempFile(PathBuf);
+
+  impl TempFile {
+    fn new(name: &str, contents: &[u8]) -> TempFile {
+      let path = std::env::temp_dir().join(format!("bladerf_adsb-test-{}-{}", process::id(), name));
+      fs::write(&path, contents).unwrap();
+      TempFile(path)
+    }
+
+    fn path(&self) -> &str {
+      self.0.to_str().unwrap()
+    }
+  }
+
+  impl Drop for TempFile {
+    fn drop(&mut self) {
+      let _ = fs::remove_file(&self.0);
+    }
+  }
+
+  #[test]
+  fn sha256_file_hashes_the_contents() {
+    let image = TempFile::new("sha256.rbf", b"abc");
+    assert_eq!(sha256_file(image.path()).unwrap(), ABC_SHA256);
+  }
+
+  #[test]
+  fn verify_fpga_accepts_a_matching_user_checksum_in_any_case() {
+    let image = TempFile::new("user.rbf", b"abc");
+    let given = format!(" {} \n", ABC_SHA256.to_uppercase());
+    assert_eq!(verify_fpga(image.path(), Some(&given)), Ok(String::from(ABC_SHA256)));
+  }
+
+  #[test]
+  fn verify_fpga_falls_back_to_a_sidecar_file() {
+    let image = TempFile::new("sidecar.rbf", b"abc");
+    let _sidecar = TempFile::new("sidecar.rbf.sha256", format!("{}  sidecar.rbf\n", ABC_SHA256).as_bytes());
+    assert_eq!(verify_fpga(image.path(), None), Ok(String::from(ABC_SHA256)));
+  }
+
+  #[test]
+  fn verify_fpga_rejects_a_mismatch() {
+    let image = TempFile::new("mismatch.rbf", b"abd");
+    let err = verify_fpga(image.path(), Some(ABC_SHA256)).unwrap_err();
+    assert!(err.contains("checksum mismatch"), "{}", err);
+  }
+
+  #[test]
+  fn verify_fpga_needs_a_reference() {
+    let image = TempFile::new("noref.rbf", b"abc");
+    let err = verify_fpga(image.path(), None).unwrap_err();
+    assert!(err.contains("no reference checksum"), "{}", err);
+  }
+
+  #[test]
+  fn waiting_stops_once_the_fpga_reports_configured() {
+    let mock = MockBladeRf::new();
+    mock.state().fpga_configured = false;
+    mock.push_result("is_fpga_configured", 0);
+    mock.push_result("is_fpga_configured", 0);
+    mock.push_result("is_fpga_configured", 1);
+    let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+    assert_eq!(wait_for_fpga_configured(&dev, 5, Duration::ZERO), Ok(true));
+    assert_eq!(mock.calls().len(), 3);
+  }
+
+  #[test]
+  fn waiting_gives_up_after_the_attempts() {
+    let mock = MockBladeRf::new();
+    mock.state().fpga_configured = false;
+    let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+    assert_eq!(wait_for_fpga_configured(&dev, 3, Duration::ZERO), Ok(false));
+    assert_eq!(mock.calls().len(), 3);
+  }
+
+  #[test]
+  fn an_error_ends_the_wait() {
+    let mock = MockBladeRf::new();
+    mock.push_result("is_fpga_configured", BLADERF_ERR_NODEV as i32);
+    let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+    assert_eq!(wait_for_fpga_configured(&dev, 5, Duration::ZERO), Err(BLADERF_ERR_NODEV as isize));
+    assert_eq!(mock.calls().len(), 1);
+  }
+
+  fn gzip(contents: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    io::Write::write_all(&mut encoder, contents).unwrap();
+    encoder.finish().unwrap()
+  }
+
+  #[test]
+  fn plain_images_are_loaded_from_where_they_are() {
+    let file = TempFile::new("plain.rbf", b"abc");
+    let image = FpgaImage::open(file.path()).unwrap();
+
+    assert!(!image.is_decompressed());
+    assert_eq!(image.path(), file.0.as_path());
+    drop(image);
+    assert!(file.0.exists());
+  }
+
+  #[test]
+  fn gzip_images_are_decompressed_to_a_temporary_file() {
+    let file = TempFile::new("hostedx40.rbf.gz", &gzip(b"abc"));
+    let image = FpgaImage::open(file.path()).unwrap();
+    let decompressed = image.path().to_path_buf();
+
+    assert!(image.is_decompressed());
+    assert!(decompressed.to_str().unwrap().ends_with("hostedx40.rbf"));
+    assert_eq!(fs::read(&decompressed).unwrap(), b"abc");
+    drop(image);
+    assert!(!decompressed.exists());
+  }
+
+  #[test]
+  fn a_corrupt_gzip_image_leaves_nothing_behind() {
+    let mut corrupt = gzip(b"abc");
+    corrupt.truncate(12);
+    let file = TempFile::new("corrupt.rbf.gz", &corrupt);
+
+    assert!(FpgaImage::open(file.path()).is_err());
+    assert!(!std::env::temp_dir().join(format!("bladerf_adsb-{}-corrupt.rbf", process::id())).exists());
+  }
+
+  #[test]
+  fn every_known_size_has_an_image() {
+    assert_eq!(fpga_image_name(BLADERF_FPGA_40KLE), Some("adsbx40.rbf"));
+    assert_eq!(fpga_image_name(BLADERF_FPGA_A9), Some("adsbxA9.rbf"));
+    assert_eq!(fpga_image_name(BLADERF_FPGA_UNKNOWN), None);
+  }
+
+  #[test]
+  fn an_unknown_size_asks_for_fpga_path() {
+    let err = default_fpga_path(BLADERF_FPGA_UNKNOWN).unwrap_err();
+    assert!(err.contains("pass --fpga-path"), "{}", err);
+  }
+
+  #[test]
+  fn the_first_directory_holding_the_image_wins() {
+    let root = std::env::temp_dir().join(format!("bladerf_adsb-test-{}-search", process::id()));
+    let (empty, gzipped, plain) = (root.join("empty"), root.join("gzipped"), root.join("plain"));
+    for dir in [&empty, &gzipped, &plain] {
+      fs::create_dir_all(dir).unwrap();
+    }
+    fs::write(gzipped.join("adsbxA4.rbf.gz"), b"").unwrap();
+    fs::write(plain.join("adsbxA4.rbf"), b"").unwrap();
+
+    let found = find_fpga_image("adsbxA4.rbf", &[empty.clone(), gzipped.clone(), plain.clone()]);
+    let missing = find_fpga_image("adsbxA9.rbf", &[empty.clone(), gzipped.clone(), plain.clone()]);
+    let _ = fs::remove_dir_all(&root);
+    assert_eq!(found, Some(gzipped.join("adsbxA4.rbf.gz")));
+    assert_eq!(missing, None);
+  }
+
+  #[test]
+  fn the_system_directory_is_searched_first() {
+    assert_eq!(fpga_search_dirs()[0], PathBuf::from(DEFAULT_FPGA_DIR));
+  }
+}
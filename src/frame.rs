@@ -0,0 +1,394 @@
+use crate::consts::{LONG_FRAME_BYTES, SHORT_FRAME_BYTES};
+use crate::crc;
+use crate::decode::{downlink_format, is_extended_squitter, is_valid_df};
+
+// Each message from the ADS-B FPGA image occupies a fixed 16 byte slot in the RX buffer
+pub const SLOT_BYTES: usize = 16;
+
+// Slots are carried in SC16_Q11 samples of 4 bytes each. The same holds for SC16_Q11_META, as
+// libbladeRF's sync interface strips the metadata headers before handing buffers over.
+pub const BYTES_PER_SAMPLE: usize = 4;
+pub const SAMPLES_PER_SLOT: u64 = (SLOT_BYTES / BYTES_PER_SAMPLE) as u64;
+
+// Offset of the Mode S payload within a slot
+const PAYLOAD_OFFSET: usize = 2;
+
+// Beast/AVR MLAT timestamps count a 12 MHz clock, as dump1090 and readsb expect
+pub const MLAT_CLOCK_HZ: u64 = 12000000;
+
+// Convert a sample count at the given sample rate into 12 MHz MLAT ticks
+pub fn samples_to_mlat_ticks(samples: u64, sample_rate: u32) -> u64 {
+  ((samples as u128 * MLAT_CLOCK_HZ as u128) / sample_rate as u128) as u64
+}
+
+// A single Mode S frame pulled out of an FPGA buffer
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdsbFrame {
+  pub data: Vec<u8>,
+  // index of the slot within the buffer it was read from
+  pub slot: usize,
+  // 12 MHz MLAT clock, when known
+  pub timestamp: Option<u64>,
+  // frequency the receiver was tuned to when the frame was read
+  pub frequency: Option<u32>,
+  // signal to noise ratio in dB, only known for frames demodulated in software
+  pub snr: Option<f32>,
+}
+
+impl AdsbFrame {
+  // Sample offset of this frame from the start of its buffer
+  pub fn sample_offset(&self) -> u64 {
+    self.slot as u64 * SAMPLES_PER_SLOT
+  }
+
+  pub fn is_long(&self) -> bool {
+    self.data.len() == LONG_FRAME_BYTES
+  }
+
+  pub fn df(&self) -> u8 {
+    downlink_format(&self.data)
+  }
+
+  // AVR (raw) representation, e.g. "*8d4840d6202cc371c32ce0576098;\n"
+  pub fn to_avr(&self) -> String {
+    let mut ascii_buf = String::from("*");
+    for byte in &self.data {
+      ascii_buf.push_str(&format!("{:02x}", byte));
+    }
+    ascii_buf.push_str(";\n");
+    ascii_buf
+  }
+
+  // One line debugging summary for --ascii-dump-frames, e.g.
+  // "slot 3 DF17 crc ok 8d4840d6202cc371c32ce0576098 rssi n/a". Only extended squitters have a
+  // checkable CRC, the other formats overlay their parity with an address. The FPGA image doesn't
+  // report signal levels, so RSSI is always n/a for now.
+  pub fn annotate(&self) -> String {
+    let df = self.df();
+    let crc = if !is_extended_squitter(df) {
+      "n/a"
+    } else if crc::syndrome(&self.data) == 0 {
+      "ok"
+    } else {
+      "bad"
+    };
+    let hex: String = self.data.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    format!("slot {} DF{} crc {} {} rssi n/a", self.slot, df, crc, hex)
+  }
+}
+
+// The part of an RX buffer filled in by a sync_rx that returned `samples` samples. Anything
+// past it is left over from an earlier transfer and must not be parsed again. A count larger
+// than the buffer can hold is a bug in the wrapper, caught in debug builds and clamped otherwise.
+pub fn received(buf: &[u8], samples: usize) -> &[u8] {
+  debug_assert!(samples * BYTES_PER_SAMPLE <= buf.len(),
+    "sync_rx reported {} samples, more than the {} byte buffer holds", samples, buf.len());
+  &buf[..(samples * BYTES_PER_SAMPLE).min(buf.len())]
+}
+
+// Zero whatever a short sync_rx left unfilled, so stale messages from an earlier transfer can't
+// be picked up again by anything that looks at the whole buffer. Zeroed slots aren't flagged as
+// valid, so they never parse as frames.
+pub fn clear_unfilled(buf: &mut [u8], samples: usize) {
+  let filled = (samples * BYTES_PER_SAMPLE).min(buf.len());
+  buf[filled..].fill(0);
+}
+
+// The frame in one FPGA message, if it's flagged as valid
+fn parse_slot(slot: &[u8], index: usize) -> Option<AdsbFrame> {
+  if (slot[0] & 0x01) != 1 {
+    return None;
+  }
+  // the top bit of the first payload byte distinguishes long (112 bit) from short (56 bit) frames
+  let end = if (slot[PAYLOAD_OFFSET] & 0x80) == 0x80 { LONG_FRAME_BYTES } else { SHORT_FRAME_BYTES };
+
+  Some(AdsbFrame {
+    data: slot[PAYLOAD_OFFSET..PAYLOAD_OFFSET + end].to_vec(),
+    slot: index,
+    timestamp: None,
+    frequency: None,
+    snr: None,
+  })
+}
+
+// Walk an FPGA buffer slot by slot and return every frame flagged as valid
+pub fn extract_frames(buf: &[u8]) -> Vec<AdsbFrame> {
+  buf.chunks_exact(SLOT_BYTES).enumerate()
+    .filter_map(|(index, slot)| parse_slot(slot, index))
+    .collect()
+}
+
+// The frame in a packet read with BLADERF_FORMAT_PACKET_META. Every packet carries a single
+// message laid out like a slot, and libbladeRF reports its length, so there's no walking a
+// buffer of slots. A packet too short for a whole message is dropped.
+pub fn extract_packet_frame(packet: &[u8]) -> Option<AdsbFrame> {
+  if packet.len() < SLOT_BYTES {
+    return None;
+  }
+  parse_slot(&packet[..SLOT_BYTES], 0)
+}
+
+// Slots looked at before judging whether the FPGA image is the ADS-B one, 16 buffers' worth
+pub const IMAGE_CHECK_SLOTS: u64 = 4096;
+// The ADS-B image flags a tiny share of slots even in busy airspace, while with any other image
+// the flag is the low bit of a sample and set about half the time
+const WRONG_IMAGE_FLAGGED_RATIO: f64 = 0.2;
+// Flagged slots needed before the share holding a real downlink format says anything. Noise
+// hits one a little over half the time.
+const MIN_FLAGGED_FOR_DF_CHECK: u64 = 32;
+const WRONG_IMAGE_VALID_DF_RATIO: f64 = 0.8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageVerdict {
+  Adsb,
+  WrongImage,
+  // too few slots seen yet
+  Unknown,
+}
+
+// Tally of the slots at the start of a stream, to tell the ADS-B image's framing apart from the
+// plain samples any other image delivers
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SlotCensus {
+  pub slots: u64,
+  pub flagged: u64,
+  // flagged slots holding a downlink format that exists
+  pub valid_df: u64,
+}
+
+impl SlotCensus {
+  pub fn new() -> SlotCensus {
+    SlotCensus::default()
+  }
+
+  pub fn add(&mut self, buf: &[u8]) {
+    for slot in buf.chunks_exact(SLOT_BYTES) {
+      self.slots += 1;
+      if (slot[0] & 0x01) == 1 {
+        self.flagged += 1;
+        if is_valid_df(downlink_format(&slot[PAYLOAD_OFFSET..])) {
+          self.valid_df += 1;
+        }
+      }
+    }
+  }
+
+  pub fn flagged_ratio(&self) -> f64 {
+    if self.slots == 0 {
+      0.0
+    } else {
+      self.flagged as f64 / self.slots as f64
+    }
+  }
+
+  pub fn verdict(&self) -> ImageVerdict {
+    if self.slots < IMAGE_CHECK_SLOTS {
+      return ImageVerdict::Unknown;
+    }
+    if self.flagged_ratio() > WRONG_IMAGE_FLAGGED_RATIO {
+      return ImageVerdict::WrongImage;
+    }
+    if self.flagged >= MIN_FLAGGED_FOR_DF_CHECK && (self.valid_df as f64) < self.flagged as f64 * WRONG_IMAGE_VALID_DF_RATIO {
+      return ImageVerdict::WrongImage;
+    }
+    ImageVerdict::Adsb
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::DF17;
+
+  const SHORT: [u8; 7] = [0x5d, 0x48, 0x40, 0xd6, 0x12, 0x34, 0x56];
+
+  // A slot as the FPGA writes it, flagged as holding `payload`
+  fn slot(payload: &[u8]) -> [u8; SLOT_BYTES] {
+    let mut slot = [0u8; SLOT_BYTES];
+    slot[0] = 0x01;
+    slot[PAYLOAD_OFFSET..PAYLOAD_OFFSET + payload.len()].copy_from_slice(payload);
+    slot
+  }
+
+  #[test]
+  fn extract_frames_takes_flagged_slots_of_either_length() {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&slot(&DF17));
+    buf.extend_from_slice(&[0u8; SLOT_BYTES]);
+    buf.extend_from_slice(&slot(&SHORT));
+
+    let frames = extract_frames(&buf);
+    assert_eq!(frames.iter().map(|frame| frame.data.clone()).collect::<Vec<_>>(), vec![DF17.to_vec(), SHORT.to_vec()]);
+    assert_eq!(frames.iter().map(|frame| frame.slot).collect::<Vec<_>>(), vec![0, 2]);
+    assert!(frames[0].is_long());
+    assert!(!frames[1].is_long());
+  }
+
+  #[test]
+  fn extract_frames_ignores_unflagged_slots_and_a_partial_one() {
+    let mut unflagged = slot(&DF17);
+    unflagged[0] = 0x00;
+    let mut buf = unflagged.to_vec();
+    buf.extend_from_slice(&slot(&DF17)[..SLOT_BYTES - 1]);
+
+    assert_eq!(extract_frames(&buf), Vec::new());
+  }
+
+  #[test]
+  fn to_avr_is_hex_between_star_and_semicolon() {
+    let frame = extract_frames(&slot(&DF17)).remove(0);
+    assert_eq!(frame.to_avr(), "*8d4840d6202cc371c32ce0576098;\n");
+  }
+
+  #[test]
+  fn mlat_ticks_count_a_12_mhz_clock() {
+    assert_eq!(samples_to_mlat_ticks(16000000, 16000000), MLAT_CLOCK_HZ);
+    assert_eq!(samples_to_mlat_ticks(4, 16000000), 3);
+    // large enough that multiplying by the clock would overflow a u64
+    assert_eq!(samples_to_mlat_ticks(1 << 60, 16000000), 3 << 58);
+  }
+
+  #[test]
+  fn sample_offset_counts_whole_slots() {
+    let mut buf = vec![0u8; 3 * SLOT_BYTES];
+    buf[2 * SLOT_BYTES..].copy_from_slice(&slot(&SHORT));
+    assert_eq!(extract_frames(&buf)[0].sample_offset(), 2 * SAMPLES_PER_SLOT);
+  }
+
+  #[test]
+  fn only_the_received_part_of_a_buffer_is_parsed() {
+    let mut buf = slot(&DF17).to_vec();
+    buf.extend_from_slice(&slot(&SHORT));
+
+    assert_eq!(extract_frames(received(&buf, SAMPLES_PER_SLOT as usize)).len(), 1);
+    assert_eq!(extract_frames(received(&buf, 2 * SAMPLES_PER_SLOT as usize)).len(), 2);
+  }
+
+  #[test]
+  fn clear_unfilled_zeroes_the_stale_tail() {
+    let mut buf = slot(&DF17).to_vec();
+    buf.extend_from_slice(&slot(&SHORT));
+
+    clear_unfilled(&mut buf, SAMPLES_PER_SLOT as usize);
+    assert_eq!(&buf[..SLOT_BYTES], &slot(&DF17));
+    assert!(buf[SLOT_BYTES..].iter().all(|&b| b == 0));
+    assert_eq!(extract_frames(&buf).len(), 1);
+  }
+
+  #[test]
+  fn a_busy_buffer_gives_up_every_frame_in_slot_order() {
+    // the benchmark's buffer: a long frame in every 16th slot and a short one halfway between
+    let mut buf = Vec::new();
+    for index in 0..4096 / SLOT_BYTES {
+      match index % 16 {
+        0 => buf.extend_from_slice(&slot(&DF17)),
+        8 => buf.extend_from_slice(&slot(&SHORT)),
+        _ => buf.extend_from_slice(&[0u8; SLOT_BYTES]),
+      }
+    }
+
+    let frames = extract_frames(&buf);
+    assert_eq!(frames.len(), 32);
+    assert!(frames.windows(2).all(|pair| pair[1].slot == pair[0].slot + 8));
+    assert_eq!(frames.iter().filter(|frame| frame.is_long()).count(), 16);
+  }
+
+  #[test]
+  fn annotate_shows_the_crc_only_for_extended_squitters() {
+    let mut buf = vec![0u8; 3 * SLOT_BYTES];
+    buf[..SLOT_BYTES].copy_from_slice(&slot(&DF17));
+    buf[2 * SLOT_BYTES..].copy_from_slice(&slot(&SHORT));
+    let mut frames = extract_frames(&buf);
+
+    assert_eq!(frames[0].annotate(), "slot 0 DF17 crc ok 8d4840d6202cc371c32ce0576098 rssi n/a");
+    assert_eq!(frames[1].annotate(), "slot 2 DF11 crc n/a 5d4840d6123456 rssi n/a");
+    frames[0].data[13] ^= 0x01;
+    assert!(frames[0].annotate().contains(" crc bad "));
+  }
+
+  #[test]
+  fn a_packet_holds_a_single_frame() {
+    assert_eq!(extract_packet_frame(&slot(&DF17)).map(|frame| frame.data), Some(DF17.to_vec()));
+    // anything past the first message is ignored
+    let mut packet = slot(&SHORT).to_vec();
+    packet.extend_from_slice(&slot(&DF17));
+    assert_eq!(extract_packet_frame(&packet).map(|frame| frame.data), Some(SHORT.to_vec()));
+  }
+
+  #[test]
+  fn short_or_unflagged_packets_hold_no_frame() {
+    assert_eq!(extract_packet_frame(&slot(&DF17)[..SLOT_BYTES - 1]), None);
+    let mut unflagged = slot(&DF17);
+    unflagged[0] = 0x00;
+    assert_eq!(extract_packet_frame(&unflagged), None);
+  }
+
+  // `slots` slots, every `every`th of them flagged as holding `payload`
+  fn census(slots: u64, every: u64, payload: &[u8]) -> SlotCensus {
+    let mut census = SlotCensus::new();
+    for i in 0..slots {
+      if i % every == 0 {
+        census.add(&slot(payload));
+      } else {
+        census.add(&[0u8; SLOT_BYTES]);
+      }
+    }
+    census
+  }
+
+  #[test]
+  fn the_census_waits_for_enough_slots() {
+    assert_eq!(census(IMAGE_CHECK_SLOTS - 1, 2, &DF17).verdict(), ImageVerdict::Unknown);
+    assert_eq!(SlotCensus::new().flagged_ratio(), 0.0);
+  }
+
+  #[test]
+  fn sparse_flagged_frames_look_like_the_adsb_image() {
+    let census = census(IMAGE_CHECK_SLOTS, 64, &DF17);
+    assert_eq!(census.flagged, IMAGE_CHECK_SLOTS / 64);
+    assert_eq!(census.valid_df, census.flagged);
+    assert_eq!(census.verdict(), ImageVerdict::Adsb);
+  }
+
+  #[test]
+  fn too_many_flagged_slots_look_like_another_image() {
+    let census = census(IMAGE_CHECK_SLOTS, 2, &DF17);
+    assert_eq!(census.flagged_ratio(), 0.5);
+    assert_eq!(census.verdict(), ImageVerdict::WrongImage);
+  }
+
+  #[test]
+  fn flagged_slots_without_real_downlink_formats_look_like_another_image() {
+    // DF1 doesn't exist
+    assert_eq!(census(IMAGE_CHECK_SLOTS, 64, &[0x08, 0x48, 0x40, 0xd6, 0x12, 0x34, 0x56]).verdict(), ImageVerdict::WrongImage);
+    // but a handful of them says nothing yet
+    assert_eq!(census(IMAGE_CHECK_SLOTS, 1024, &[0x08, 0x48, 0x40, 0xd6, 0x12, 0x34, 0x56]).verdict(), ImageVerdict::Adsb);
+  }
+
+  #[test]
+  fn a_short_read_leaves_no_stale_slots() {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&slot(&DF17));
+    buf.extend_from_slice(&slot(&SHORT));
+    clear_unfilled(&mut buf, SAMPLES_PER_SLOT as usize);
+
+    assert_eq!(&buf[..SLOT_BYTES], &slot(&DF17));
+    assert!(buf[SLOT_BYTES..].iter().all(|&b| b == 0));
+    assert_eq!(extract_frames(&buf).len(), 1);
+  }
+
+  #[test]
+  fn clearing_a_full_read_changes_nothing() {
+    let mut buf = slot(&DF17).to_vec();
+    clear_unfilled(&mut buf, 2 * SAMPLES_PER_SLOT as usize);
+    assert_eq!(buf, slot(&DF17));
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  #[should_panic(expected = "more than the 16 byte buffer holds")]
+  fn an_overlong_count_is_caught_in_debug_builds() {
+    received(&slot(&DF17), 2 * SAMPLES_PER_SLOT as usize);
+  }
+}
@@ -0,0 +1,204 @@
+use std::time::{Duration, Instant};
+
+use bladerf::bladerf::bladerf_gain_mode;
+use bladerf::bladerf::bladerf_gain_mode::*;
+
+// Minimum time between two gain adjustments, long enough for the message rate to settle
+pub const GAIN_ADJUST_INTERVAL: Duration = Duration::from_secs(5);
+
+// Size of a single adjustment in dB
+pub const GAIN_ADJUST_STEP: i32 = 3;
+
+// Below this many messages per second there's probably signal left on the table
+pub const LOW_MESSAGE_RATE: f64 = 50.0;
+
+// Fraction of extended squitters failing CRC above which the front end is assumed to be
+// saturating (strong signals overloading the LNA, or noise swamping the preamble detector)
+pub const NOISE_CRC_RATIO: f64 = 0.2;
+
+// What the receiver saw over one adjustment interval. The ADS-B FPGA image doesn't report
+// signal levels, so the CRC failure ratio stands in for RSSI headroom.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GainObservation {
+  pub message_rate: f64,
+  pub crc_failure_ratio: f64,
+  // short reads and timeouts from sync_rx
+  pub overruns: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GainStep {
+  Up,
+  Down,
+  Hold,
+}
+
+// Slow feedback loop stepping the manual gain between the device's limits
+pub struct GainController {
+  gain: i32,
+  min: i32,
+  max: i32,
+  interval: Duration,
+  last_adjust: Instant,
+  // counter totals at the last adjustment
+  messages: u64,
+  crc_failures: u64,
+  overruns: u64,
+}
+
+impl GainController {
+  pub fn new(gain: i32, min: i32, max: i32, interval: Duration, now: Instant) -> GainController {
+    GainController {
+      gain: gain.clamp(min, max),
+      min,
+      max,
+      interval,
+      last_adjust: now,
+      messages: 0,
+      crc_failures: 0,
+      overruns: 0,
+    }
+  }
+
+  pub fn gain(&self) -> i32 {
+    self.gain
+  }
+
+  // Trouble always wins: overruns or saturation step down, otherwise a quiet receiver with
+  // headroom steps up
+  pub fn decide(&self, observation: &GainObservation) -> GainStep {
+    if observation.overruns > 0 || observation.crc_failure_ratio >= NOISE_CRC_RATIO {
+      if self.gain > self.min { GainStep::Down } else { GainStep::Hold }
+    } else if observation.message_rate < LOW_MESSAGE_RATE {
+      if self.gain < self.max { GainStep::Up } else { GainStep::Hold }
+    } else {
+      GainStep::Hold
+    }
+  }
+
+  // Feed the running counter totals. Once per interval this returns the new gain to apply,
+  // or None if the gain should stay where it is.
+  pub fn poll(&mut self, messages: u64, crc_failures: u64, overruns: u64, now: Instant) -> Option<i32> {
+    let elapsed = now.saturating_duration_since(self.last_adjust);
+    if elapsed < self.interval {
+      return None;
+    }
+
+    let new_messages = messages.saturating_sub(self.messages);
+    let new_failures = crc_failures.saturating_sub(self.crc_failures);
+    let checked = new_messages + new_failures;
+    let observation = GainObservation {
+      message_rate: new_messages as f64 / elapsed.as_secs_f64(),
+      crc_failure_ratio: if checked == 0 { 0.0 } else { new_failures as f64 / checked as f64 },
+      overruns: overruns.saturating_sub(self.overruns),
+    };
+
+    self.last_adjust = now;
+    self.messages = messages;
+    self.crc_failures = crc_failures;
+    self.overruns = overruns;
+
+    let gain = match self.decide(&observation) {
+      GainStep::Up => (self.gain + GAIN_ADJUST_STEP).min(self.max),
+      GainStep::Down => (self.gain - GAIN_ADJUST_STEP).max(self.min),
+      GainStep::Hold => return None,
+    };
+    self.gain = gain;
+    Some(gain)
+  }
+}
+
+// Gain modes each board supports on RX, for libbladeRF builds where bladerf_get_gain_modes
+// isn't available. The bladeRF 1 only has the LMS6002D's manual gain and libbladeRF's own AGC.
+pub fn board_gain_modes(board: &str) -> &'static [bladerf_gain_mode] {
+  match board {
+    "bladerf2" => &[
+      BLADERF_GAIN_DEFAULT,
+      BLADERF_GAIN_MGC,
+      BLADERF_GAIN_FASTATTACK_AGC,
+      BLADERF_GAIN_SLOWATTACK_AGC,
+      BLADERF_GAIN_HYBRID_AGC,
+    ],
+    _ => &[BLADERF_GAIN_DEFAULT, BLADERF_GAIN_MGC],
+  }
+}
+
+// The requested mode if the board supports it, otherwise BLADERF_GAIN_DEFAULT
+pub fn resolve_gain_mode(requested: bladerf_gain_mode, supported: &[bladerf_gain_mode]) -> bladerf_gain_mode {
+  if supported.contains(&requested) {
+    requested
+  } else {
+    BLADERF_GAIN_DEFAULT
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn observation(message_rate: f64, crc_failure_ratio: f64, overruns: u64) -> GainObservation {
+    GainObservation { message_rate, crc_failure_ratio, overruns }
+  }
+
+  #[test]
+  fn trouble_steps_down_before_a_quiet_band_steps_up() {
+    let controller = GainController::new(30, 0, 60, GAIN_ADJUST_INTERVAL, Instant::now());
+
+    assert_eq!(controller.decide(&observation(10.0, 0.0, 1)), GainStep::Down);
+    assert_eq!(controller.decide(&observation(10.0, NOISE_CRC_RATIO, 0)), GainStep::Down);
+    assert_eq!(controller.decide(&observation(10.0, 0.0, 0)), GainStep::Up);
+    assert_eq!(controller.decide(&observation(LOW_MESSAGE_RATE, 0.0, 0)), GainStep::Hold);
+  }
+
+  #[test]
+  fn decide_holds_at_the_limits() {
+    let at_min = GainController::new(0, 0, 60, GAIN_ADJUST_INTERVAL, Instant::now());
+    let at_max = GainController::new(60, 0, 60, GAIN_ADJUST_INTERVAL, Instant::now());
+
+    assert_eq!(at_min.decide(&observation(10.0, 0.0, 1)), GainStep::Hold);
+    assert_eq!(at_max.decide(&observation(10.0, 0.0, 0)), GainStep::Hold);
+  }
+
+  #[test]
+  fn poll_adjusts_at_most_once_per_interval() {
+    let start = Instant::now();
+    let mut controller = GainController::new(30, 0, 60, GAIN_ADJUST_INTERVAL, start);
+
+    assert_eq!(controller.poll(0, 0, 0, start + GAIN_ADJUST_INTERVAL / 2), None);
+    assert_eq!(controller.poll(0, 0, 0, start + GAIN_ADJUST_INTERVAL), Some(30 + GAIN_ADJUST_STEP));
+    assert_eq!(controller.poll(0, 0, 0, start + GAIN_ADJUST_INTERVAL + GAIN_ADJUST_INTERVAL / 2), None);
+    assert_eq!(controller.gain(), 30 + GAIN_ADJUST_STEP);
+  }
+
+  #[test]
+  fn poll_only_counts_what_changed_since_the_last_adjustment() {
+    let start = Instant::now();
+    let mut controller = GainController::new(30, 0, 60, Duration::from_secs(1), start);
+
+    // one overrun steps down once; the same total next time isn't a new overrun
+    assert_eq!(controller.poll(1000, 0, 1, start + Duration::from_secs(1)), Some(30 - GAIN_ADJUST_STEP));
+    assert_eq!(controller.poll(2000, 0, 1, start + Duration::from_secs(2)), None);
+  }
+
+  #[test]
+  fn steps_are_clamped_to_the_limits() {
+    let start = Instant::now();
+    let mut controller = GainController::new(58, 0, 60, Duration::from_secs(1), start);
+
+    assert_eq!(controller.poll(0, 0, 0, start + Duration::from_secs(1)), Some(60));
+    assert_eq!(GainController::new(90, 0, 60, GAIN_ADJUST_INTERVAL, start).gain(), 60);
+  }
+
+  #[test]
+  fn only_the_bladerf2_has_the_agc_modes() {
+    assert!(board_gain_modes("bladerf2").contains(&BLADERF_GAIN_FASTATTACK_AGC));
+    assert_eq!(board_gain_modes("bladerf1"), &[BLADERF_GAIN_DEFAULT, BLADERF_GAIN_MGC]);
+  }
+
+  #[test]
+  fn unsupported_gain_modes_fall_back_to_the_default() {
+    let supported = board_gain_modes("bladerf1");
+    assert_eq!(resolve_gain_mode(BLADERF_GAIN_MGC, supported), BLADERF_GAIN_MGC);
+    assert_eq!(resolve_gain_mode(BLADERF_GAIN_HYBRID_AGC, supported), BLADERF_GAIN_DEFAULT);
+  }
+}
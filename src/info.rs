@@ -0,0 +1,102 @@
+use bladerf::bladerf::bladerf_module::*;
+use bladerf::bladerf::{Struct_bladerf_range, Struct_bladerf_version};
+use bladerf::{library_version, BladeRFDevice};
+
+// One labelled line of the device summary
+pub type InfoRow = (&'static str, String);
+
+fn version_string(version: Result<Struct_bladerf_version, isize>) -> String {
+  match version {
+    Ok(v) => format!("{}.{}.{}", v.major, v.minor, v.patch),
+    Err(e) => format!("unavailable (error {})", e),
+  }
+}
+
+fn range_string(range: Result<Struct_bladerf_range, isize>, unit: &str) -> String {
+  match range {
+    Ok(r) => {
+      let scale = if r.scale == 0.0 { 1.0 } else { r.scale as f64 };
+      format!("{} - {} {}", r.min as f64 * scale, r.max as f64 * scale, unit)
+    },
+    Err(e) => format!("unavailable (error {})", e),
+  }
+}
+
+// Everything worth pasting into a bug report about the attached device. A failing query shows up
+// as "unavailable" in its row rather than aborting the whole summary.
+pub fn device_info_rows(dev: &BladeRFDevice) -> Vec<InfoRow> {
+  let serial = match dev.get_serial() {
+    Ok(serial) => serial.trim_end_matches('\0').to_string(),
+    Err(e) => format!("unavailable (error {})", e),
+  };
+  let fpga_size = match dev.get_fpga_size() {
+    Ok(size) => format!("{:?}", size),
+    Err(e) => format!("unavailable (error {})", e),
+  };
+
+  vec![
+    ("libbladeRF version", version_string(Ok(library_version()))),
+    ("Serial", serial),
+    ("Board", dev.get_board_name()),
+    ("Firmware version", version_string(dev.fw_version())),
+    ("FPGA version", version_string(dev.fpga_version())),
+    ("FPGA size", fpga_size),
+    ("USB speed", format!("{:?}", dev.device_speed())),
+    ("Expansion board", match dev.get_attached_expansion() {
+      Ok(board) => format!("{:?}", board),
+      Err(e) => format!("unavailable (error {})", e),
+    }),
+    ("Frequency range", range_string(dev.get_frequency_range(BLADERF_MODULE_RX), "Hz")),
+    ("Sample rate range", range_string(dev.get_sample_rate_range(BLADERF_MODULE_RX), "samples/s")),
+    ("Gain range", range_string(dev.get_gain_range(BLADERF_MODULE_RX), "dB")),
+  ]
+}
+
+// Render rows as a two column table with the labels padded to the same width
+pub fn render_table(rows: &[InfoRow]) -> String {
+  let width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+
+  let mut table = String::new();
+  for (label, value) in rows {
+    table.push_str(&format!("{:<width$}  {}\n", label, value, width = width));
+  }
+  table
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bladerf::bladerf::bladerf_error::BLADERF_ERR_UNSUPPORTED;
+  use bladerf::mock::MockBladeRf;
+
+  fn value<'a>(rows: &'a [InfoRow], label: &str) -> &'a str {
+    &rows.iter().find(|(l, _)| *l == label).unwrap().1
+  }
+
+  #[test]
+  fn rows_describe_the_device() {
+    let dev = BladeRFDevice::with_api(Box::new(MockBladeRf::new()));
+    let rows = device_info_rows(&dev);
+
+    assert_eq!(value(&rows, "Board"), "bladerf1");
+    assert_eq!(value(&rows, "Frequency range"), "280000000 - 3800000000 Hz");
+    assert_eq!(value(&rows, "Gain range"), "5 - 60 dB");
+  }
+
+  #[test]
+  fn a_failing_query_only_blanks_its_own_row() {
+    let mock = MockBladeRf::new();
+    mock.push_result("get_gain_range", BLADERF_ERR_UNSUPPORTED as i32);
+    let dev = BladeRFDevice::with_api(Box::new(mock));
+    let rows = device_info_rows(&dev);
+
+    assert_eq!(value(&rows, "Gain range"), format!("unavailable (error {})", BLADERF_ERR_UNSUPPORTED as isize));
+    assert_eq!(value(&rows, "Sample rate range"), "160000 - 40000000 samples/s");
+  }
+
+  #[test]
+  fn labels_are_padded_to_line_up() {
+    let rows: Vec<InfoRow> = vec![("Board", String::from("bladerf1")), ("USB speed", String::from("Super"))];
+    assert_eq!(render_table(&rows), "Board      bladerf1\nUSB speed  Super\n");
+  }
+}
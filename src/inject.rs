@@ -0,0 +1,115 @@
+use std::io::{self, BufRead};
+use std::str::FromStr;
+
+use crate::crc::crc24;
+use crate::decode::downlink_format;
+use crate::format::parse_avr;
+
+// What --inject-crc does about the parity of injected frames
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InjectCrc {
+  // the same CRC checks and filters as received frames, so corrupt ones are dropped (or repaired
+  // under --fix-crc)
+  Check,
+  // give DF11, DF17 and DF18 frames the right parity first, then check and filter them as above
+  Compute,
+  // straight to the sinks byte for byte, skipping every check and filter
+  AsIs,
+}
+
+impl FromStr for InjectCrc {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<InjectCrc, String> {
+    match s {
+      "check"   => Ok(InjectCrc::Check),
+      "compute" => Ok(InjectCrc::Compute),
+      "as-is"   => Ok(InjectCrc::AsIs),
+      _         => Err(format!("'{}' isn't a CRC mode, expected check, compute or as-is", s)),
+    }
+  }
+}
+
+// The frames of an --inject file, one per line as 14 or 28 hex digits, optionally written as
+// AVR ("*<hex>;" or "@<timestamp><hex>;"). Blank lines and lines starting with '#' are skipped;
+// any other line that isn't a whole frame is an error naming the line.
+pub fn read_injected<R: BufRead>(reader: R) -> io::Result<Vec<Vec<u8>>> {
+  let mut frames = Vec::new();
+  for (number, line) in reader.lines().enumerate() {
+    let line = line?;
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let frame = if line.starts_with('*') || line.starts_with('@') { parse_avr(line) } else { parse_avr(&format!("*{};", line)) };
+    match frame {
+      Some(frame) => frames.push(frame),
+      None => {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+          format!("line {} isn't a Mode S frame (14 or 28 hex digits): {}", number + 1, line)));
+      },
+    }
+  }
+  Ok(frames)
+}
+
+// Overwrite the parity of a DF11, DF17 or DF18 frame with the CRC of the rest of it. Those carry
+// their CRC in the clear (DF11 as if replying to interrogator code 0); the other formats overlay
+// it with the aircraft's address, which isn't known here, so they're left alone.
+pub fn compute_parity(data: &mut [u8]) {
+  if !matches!(downlink_format(data), 11 | 17 | 18) {
+    return;
+  }
+  let split = data.len() - 3;
+  let parity = crc24(&data[..split]);
+  data[split..].copy_from_slice(&parity.to_be_bytes()[1..]);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crc::syndrome;
+  use crate::fixtures::DF17;
+
+  #[test]
+  fn injected_frames_are_read_as_hex_or_avr() {
+    let file = "# edge cases\n\n8d4840d6202cc371c32ce0576098\n*5d4840d6123456;\n  @0000000000005d4840d6123456;  \n";
+    assert_eq!(read_injected(file.as_bytes()).unwrap(), vec![
+      DF17.to_vec(),
+      vec![0x5d, 0x48, 0x40, 0xd6, 0x12, 0x34, 0x56],
+      vec![0x5d, 0x48, 0x40, 0xd6, 0x12, 0x34, 0x56],
+    ]);
+  }
+
+  #[test]
+  fn a_line_that_isnt_a_frame_is_an_error_naming_it() {
+    let err = read_injected("8d4840d6202cc371c32ce0576098\n8d4840d6\n".as_bytes()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "line 2 isn't a Mode S frame (14 or 28 hex digits): 8d4840d6");
+  }
+
+  #[test]
+  fn parity_is_computed_only_where_it_is_in_the_clear() {
+    let mut df17 = DF17;
+    df17[11..].copy_from_slice(&[0, 0, 0]);
+    compute_parity(&mut df17);
+    assert_eq!(df17, DF17);
+
+    let mut df11 = [0x5d, 0x48, 0x40, 0xd6, 0, 0, 0];
+    compute_parity(&mut df11);
+    assert_eq!(syndrome(&df11), 0);
+
+    // DF4 overlays its parity with the address
+    let mut df4 = [0x20, 0x00, 0x17, 0x18, 0, 0, 0];
+    compute_parity(&mut df4);
+    assert_eq!(df4, [0x20, 0x00, 0x17, 0x18, 0, 0, 0]);
+  }
+
+  #[test]
+  fn crc_modes_parse_from_their_names() {
+    assert_eq!("check".parse::<InjectCrc>(), Ok(InjectCrc::Check));
+    assert_eq!("compute".parse::<InjectCrc>(), Ok(InjectCrc::Compute));
+    assert_eq!("as-is".parse::<InjectCrc>(), Ok(InjectCrc::AsIs));
+    assert!("fix".parse::<InjectCrc>().is_err());
+  }
+}
@@ -0,0 +1,67 @@
+use std::time::{Duration, Instant};
+
+// A second Ctrl+C within this long of the first force-quits
+pub const FORCE_QUIT_WINDOW: Duration = Duration::from_secs(5);
+
+// What a Ctrl+C should do
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterruptAction {
+  // ask the threads to wind down
+  Shutdown,
+  // graceful shutdown is stuck, e.g. in a sync_rx that never returns, so exit right away
+  ForceQuit,
+}
+
+// Counts Ctrl+C presses. The first asks for a graceful shutdown; a second one within the window
+// force-quits. A press after the window has passed starts over, so a slow but progressing
+// shutdown isn't cut short by a stray second press much later.
+pub struct InterruptCounter {
+  window: Duration,
+  first: Option<Instant>,
+}
+
+impl InterruptCounter {
+  pub fn new(window: Duration) -> InterruptCounter {
+    InterruptCounter { window, first: None }
+  }
+
+  pub fn press(&mut self, now: Instant) -> InterruptAction {
+    match self.first {
+      Some(first) if now.saturating_duration_since(first) <= self.window => InterruptAction::ForceQuit,
+      _ => {
+        self.first = Some(now);
+        InterruptAction::Shutdown
+      },
+    }
+  }
+}
+
+impl Default for InterruptCounter {
+  fn default() -> Self {
+    InterruptCounter::new(FORCE_QUIT_WINDOW)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn a_second_press_within_the_window_force_quits() {
+    let mut counter = InterruptCounter::default();
+    let start = Instant::now();
+
+    assert_eq!(counter.press(start), InterruptAction::Shutdown);
+    assert_eq!(counter.press(start + FORCE_QUIT_WINDOW), InterruptAction::ForceQuit);
+  }
+
+  #[test]
+  fn a_press_after_the_window_starts_over() {
+    let mut counter = InterruptCounter::new(Duration::from_secs(1));
+    let start = Instant::now();
+
+    assert_eq!(counter.press(start), InterruptAction::Shutdown);
+    assert_eq!(counter.press(start + Duration::from_secs(2)), InterruptAction::Shutdown);
+    assert_eq!(counter.press(start + Duration::from_millis(2500)), InterruptAction::ForceQuit);
+  }
+}
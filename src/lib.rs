@@ -0,0 +1,48 @@
+extern crate bladerf;
+// so modules shared with the binary can name this crate the way it does
+extern crate self as bladerf_adsb;
+
+pub mod aircraft;
+pub mod backup;
+pub mod buffers;
+pub mod consts;
+pub mod control;
+pub mod crc;
+pub mod decode;
+pub mod dedup;
+pub mod demod;
+pub mod dsp;
+pub mod error;
+pub mod filter;
+pub mod format;
+pub mod fpga;
+pub mod frame;
+pub mod gain;
+pub mod info;
+pub mod inject;
+pub mod interrupt;
+pub mod ratelimit;
+pub mod rawdump;
+pub mod reorder;
+pub mod replay;
+pub mod rx;
+pub mod scan;
+pub mod shard;
+pub mod simulate;
+pub mod sink;
+pub mod spool;
+pub mod stats;
+pub mod throughput;
+pub mod units;
+
+#[cfg(feature = "cli")]
+pub mod affinity;
+
+#[cfg(feature = "async")]
+pub mod stream;
+
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+#[cfg(test)]
+mod fixtures;
@@ -1,47 +1,217 @@
-use clap::{command, Parser};
+use clap::{command, CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap::builder::TypedValueParser;
+use clap_complete::Shell;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::io::{Write};
-use std::net::TcpStream;
 use std::str::FromStr;
-use indicatif::{ProgressBar, ProgressStyle};
-use std::time::Duration;
+use std::io;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use signal_hook::consts::{SIGUSR1, SIGUSR2};
+use std::time::{Duration, Instant, SystemTime};
 
 extern crate log;
 
 extern crate bladerf;
-use bladerf::{BladeRFModuleConfig};
-use bladerf::bladerf::bladerf_fpga_size::*;
+extern crate bladerf_adsb;
+use bladerf::{is_disconnect, library_version, retry, retry_with_backoff, BladeRFDevice, BladeRFModuleConfig, DeviceSelector, GlobalConfig, TRANSIENT_RETRY_ATTEMPTS, TRANSIENT_RETRY_DELAY};
 use bladerf::bladerf::bladerf_module::*;
 use bladerf::bladerf::bladerf_format::*;
 use bladerf::bladerf::bladerf_gain_mode::*;
-use bladerf::bladerf::Struct_bladerf_devinfo;
+use bladerf::bladerf::bladerf_error::*;
+use bladerf::bladerf::bladerf_channel_layout::*;
+use bladerf::bladerf::bladerf_xb::*;
+use bladerf::bladerf::bladerf_rx_mux::*;
+use bladerf::bladerf::bladerf_channel_rx;
+use bladerf::bladerf::{bladerf_xb, Struct_bladerf_devinfo, Struct_bladerf_metadata, Struct_bladerf_version, BLADERF_META_FLAG_RX_NOW, BLADERF_META_STATUS_OVERRUN};
+use bladerf_adsb::affinity::pin_current_thread;
+use bladerf_adsb::aircraft::{parse_position, AircraftRegistry, AIRCRAFT_MAX_AGE};
+use bladerf_adsb::backup::{backup_path, is_unsupported, read_flash_image};
+use bladerf_adsb::buffers::{StreamBuffers, BUFFER_SIZE_MULTIPLE, DEFAULT_CONFIG_TIMEOUT_MS, DEFAULT_RX_TIMEOUT_MS, SLOW_SHUTDOWN_RX_TIMEOUT};
+use bladerf_adsb::consts::ADSB_FREQUENCY_HZ;
+use bladerf_adsb::control::{ControlCommand, ControlSocket};
+use bladerf_adsb::crc;
+use bladerf_adsb::crc::{CrcFixMode, ErrorCorrector};
+use bladerf_adsb::decode::{decode_all_call, is_extended_squitter, is_valid_df, DecodeLevel};
+use bladerf_adsb::demod::DEFAULT_PREAMBLE_THRESHOLD;
+use bladerf_adsb::dsp::{bandwidth_warnings, check_usb_speed, count_clipped, deinterleave_channels, lo_frequency, validate_frequency, validate_sample_rate, ClipMonitor, CLIP_CHECK_INTERVAL, CLIP_CHECK_STRIDE, CLIP_WARN_RATIO, DEFAULT_LO_OFFSET};
+use bladerf_adsb::error::{AppError, EXIT_FORCE_QUIT};
+use bladerf_adsb::fpga::{default_fpga_path, sha256_file, FpgaImage, verify_fpga, wait_for_fpga_configured, FPGA_CONFIGURED_ATTEMPTS, FPGA_CONFIGURED_RETRY_DELAY};
+use bladerf_adsb::filter::{parse_icao, IcaoFilter};
+use bladerf_adsb::format::{describe_formats, OutputFormat};
+#[cfg(feature = "websocket")]
+use bladerf_adsb::format::to_json;
+#[cfg(feature = "websocket")]
+use bladerf_adsb::websocket::WebSocketSink;
+use bladerf_adsb::frame::{clear_unfilled, extract_frames, extract_packet_frame, received, samples_to_mlat_ticks, AdsbFrame, ImageVerdict, SlotCensus, BYTES_PER_SAMPLE};
+use bladerf_adsb::gain::{board_gain_modes, resolve_gain_mode, GainController, GAIN_ADJUST_INTERVAL};
+use bladerf_adsb::info::{device_info_rows, render_table};
+use bladerf_adsb::inject::{compute_parity, read_injected, InjectCrc};
+use bladerf_adsb::interrupt::{InterruptAction, InterruptCounter, FORCE_QUIT_WINDOW};
+use bladerf_adsb::dedup::Deduplicator;
+use bladerf_adsb::ratelimit::{FramePriority, FrameRateLimiter, RateLimitedLogger};
+use bladerf_adsb::rawdump::{DumpTrigger, RawRing, RAW_RING_BUFFERS};
+use bladerf_adsb::reorder::{ReorderBuffer, DEFAULT_REORDER_WINDOW_MS};
+use bladerf_adsb::replay::{CaptureFormat, FrameLogSource, ReplayPacer, ReplaySource, DEFAULT_META_MESSAGE_BYTES, META_HEADER_BYTES};
+use bladerf_adsb::scan::{FrequencyScheduler, WarmupWindow, DEFAULT_WARMUP_DISCARD_MS, RETUNE_SETTLE};
+use bladerf_adsb::shard::{Sharder, UnknownShard};
+use bladerf_adsb::simulate::{Simulator, DEFAULT_SIMULATE_RATE, SIMULATED_AIRCRAFT};
+use bladerf_adsb::sink::{parse_addr, systemd_listen_fds, FdSink, FrameSink, NullSink, OutputPlan, ReconnectPolicy, RemoteMode, SinkBuffer, SinkSpec, StatsSender, TcpSink, DEFAULT_FLUSH_INTERVAL_MS};
+use bladerf_adsb::spool::{Spool, DEFAULT_SPOOL_MAX_BYTES};
+use bladerf_adsb::stats;
+use bladerf_adsb::stats::{Activity, RateMeter, SinkStats, Stats, IDLE_AFTER};
+use bladerf_adsb::throughput::{CounterCheck, DEFAULT_THROUGHPUT_DURATION};
+use bladerf_adsb::units::{parse_bandwidth, parse_frequency, parse_sample_rate};
 
 use std::sync::atomic::Ordering;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::thread;
-use std::sync::mpsc::{channel, Sender};
-use log::{LevelFilter};
-use log::{trace, debug, info, warn, error};
-use env_logger::Builder;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use log::{Level, LevelFilter};
+use log::{log_enabled, trace, debug, info, warn, error};
+use env_logger::{Builder, Target};
 use std::ffi::CStr;
 use thousands::Separable;
 
+#[cfg(test)]
+mod fixtures;
+
+// Number of back-to-back sync_rx timeouts tolerated before the stream is considered dead
+const MAX_CONSECUTIVE_TIMEOUTS: u32 = 10;
+
+// Back-to-back sync_rx timeouts. They happen transiently under USB contention, so a few are
+// retried, but MAX_CONSECUTIVE_TIMEOUTS in a row means the stream has stopped.
+#[derive(Debug, Default)]
+struct TimeoutCounter {
+  consecutive: u32,
+}
+
+impl TimeoutCounter {
+  // A call timed out. How many have in a row, or Err with that once it's too many.
+  fn timed_out(&mut self) -> Result<u32, u32> {
+    self.consecutive += 1;
+    if self.consecutive >= MAX_CONSECUTIVE_TIMEOUTS { Err(self.consecutive) } else { Ok(self.consecutive) }
+  }
+
+  // A call returned samples. How many timeouts it recovered from, if any.
+  fn succeeded(&mut self) -> Option<u32> {
+    let recovered = std::mem::take(&mut self.consecutive);
+    if recovered > 0 { Some(recovered) } else { None }
+  }
+
+  // Start over, on a fresh stream
+  fn reset(&mut self) {
+    self.consecutive = 0;
+  }
+}
+
+// How often the spinner animates while frames are arriving
+const SPINNER_TICK: Duration = Duration::from_millis(120);
+
+// How often the spinner's message rate is refreshed, and how heavily it's smoothed
+const RATE_DISPLAY_INTERVAL: Duration = Duration::from_millis(500);
+const RATE_TIME_CONSTANT: Duration = Duration::from_secs(5);
+
+// After a device reset, how long to leave it to re-enumerate before opening it again, and how
+// hard to try when it isn't back yet
+const RESET_SETTLE: Duration = Duration::from_secs(2);
+const RESET_REOPEN_ATTEMPTS: u32 = 10;
+const RESET_REOPEN_DELAY: Duration = Duration::from_millis(500);
+
+// The device drops off USB briefly while it reconfigures after an FPGA load. Reopening backs off
+// from the first delay to the second, about 10s in all before giving up.
+const FPGA_REOPEN_ATTEMPTS: u32 = 8;
+const FPGA_REOPEN_INITIAL_DELAY: Duration = Duration::from_millis(250);
+const FPGA_REOPEN_MAX_DELAY: Duration = Duration::from_secs(2);
+
+// How often to look for a device that was unplugged mid-stream
+const DEVICE_RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+
+// How long the output thread waits for a frame before checking whether it should shut down
+const OUTPUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  // Print a summary of the attached device
+  #[command(about = "Print a summary table of the attached device (serial, versions, ranges) and exit")]
+  Info,
+  // Check that the USB link keeps up with the sample rate
+  #[command(about = "Stream the FPGA's sample counter at --sample-rate and report achieved throughput and dropped samples")]
+  Throughput {
+    #[arg(short = None, long = "duration", value_name = "SECONDS", action, default_value_t = DEFAULT_THROUGHPUT_DURATION.as_secs(), help = "How long to stream for")]
+    duration: u64,
+  },
+  // Save what can be read back off the device before reflashing it
+  #[command(about = "Save the device's SPI flash (firmware, autoloaded FPGA, calibration) and firmware log to files named after its serial")]
+  Backup {
+    #[arg(short = None, long = "dir", value_name = "DIR", action, default_value = ".", help = "Directory to write the backup files to")]
+    dir: PathBuf,
+  },
+  // Shell completion scripts, for packaging rather than everyday use
+  #[command(hide = true, about = "Print a completion script for the given shell to stdout")]
+  Completions {
+    #[arg(value_name = "SHELL", value_enum)]
+    shell: Shell,
+  },
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+  #[command(subcommand)]
+  command: Option<Command>,
+
   // Sets the target bandwidth
-  #[arg(short = 'b', long = "bandwidth", value_name = "VALUE", default_value_t = 14000000, action, env = "BLADERF_ADSB_BANDWIDTH", help = "Bandwidth")]
+  #[arg(short = 'b', long = "bandwidth", value_name = "HZ", default_value_t = 14000000, action, value_parser = parse_bandwidth, env = "BLADERF_ADSB_BANDWIDTH", help = "Bandwidth in Hz, optionally with a k, M or G suffix (14M)")]
   bandwidth: u32,
   
   // Sets the FPGA path
   #[arg(short = None, long = "fpga-path", value_name = "PATH", alias = "fpgapath", action, default_value_t = String::new(), env = "BLADERF_ADSB_FPGA_PATH", help = "FPGA path")]
   fpga_path: String,
 
+  // Keep the RX thread on one core
+  #[arg(short = None, long = "cpu-affinity", value_name = "CORE", action, env = "BLADERF_ADSB_CPU_AFFINITY", help = "Pin the RX thread to this CPU core (numbered from 0) to cut scheduler jitter; ignored with a warning where unsupported")]
+  cpu_affinity: Option<usize>,
+
+  // Reset the device before configuring it
+  #[arg(short = None, long = "reset-on-start", action = clap::ArgAction::SetTrue, default_value_t = false, env = "BLADERF_ADSB_RESET_ON_START", help = "Reset the device before configuring it, clearing an FPGA left in a bad state by a crashed run. Adds a few seconds to startup")]
+  reset_on_start: bool,
+
+  // Verify the FPGA image checksum before loading it
+  #[arg(short = None, long = "verify-fpga", action = clap::ArgAction::SetTrue, default_value_t = false, env = "BLADERF_ADSB_VERIFY_FPGA", help = "Refuse to load an FPGA image whose SHA-256 doesn't match --fpga-sha256 or <image>.sha256")]
+  verify_fpga: bool,
+
+  // Expected FPGA image checksum
+  #[arg(short = None, long = "fpga-sha256", value_name = "HEX", action, env = "BLADERF_ADSB_FPGA_SHA256", help = "Expected SHA-256 of the FPGA image (implies --verify-fpga)")]
+  fpga_sha256: Option<String>,
+
   // Sets the target frequency
-  #[arg(short = None, long = "frequency", value_name = "hz", action, default_value_t = 1086000000, env = "BLADERF_ADSB_FREQUENCY", help = "Frequency")]
+  #[arg(short = None, long = "frequency", value_name = "HZ", action, default_value_t = ADSB_FREQUENCY_HZ, value_parser = parse_frequency, env = "BLADERF_ADSB_FREQUENCY", help = "Frequency to receive in Hz, optionally with a k, M or G suffix (1090M). The LO is tuned --lo-offset below it")]
   frequency: u32,
 
+  // The FPGA image demodulates away from the LO
+  #[arg(short = None, long = "lo-offset", value_name = "hz", action, default_value_t = DEFAULT_LO_OFFSET, allow_negative_numbers = true, env = "BLADERF_ADSB_LO_OFFSET", help = "How far below the received frequency to tune the LO, where the FPGA image expects it")]
+  lo_offset: i32,
+
+  // Cycle through several frequencies
+  #[arg(short = None, long = "frequency-list", value_name = "HZ,HZ,...", action, value_delimiter = ',', value_parser = parse_frequency, env = "BLADERF_ADSB_FREQUENCY_LIST", help = "Comma separated frequencies to cycle through round-robin (overrides --frequency)")]
+  frequency_list: Vec<u32>,
+
+  // Time spent on each frequency of the frequency list
+  #[arg(short = None, long = "dwell-ms", value_name = "ms", action, default_value_t = 1000, env = "BLADERF_ADSB_DWELL_MS", help = "Time spent on each --frequency-list entry before retuning")]
+  dwell_ms: u64,
+
+  // Frames decoded while the gain settles are mostly noise
+  #[arg(short = None, long = "warmup-discard-ms", value_name = "ms", action, default_value_t = DEFAULT_WARMUP_DISCARD_MS, env = "BLADERF_ADSB_WARMUP_DISCARD_MS", help = "Drop frames from the first ms of the stream and after every retune, 0 to keep them all")]
+  warmup_discard_ms: u64,
+
   // Set the gain mode
   #[arg(short = None, long = "gain-mode", value_name = "mode", alias = "lnagain", action, default_value_t = String::from("default"), env = "BLADERF_ADSB_GAIN_MODE", help = "Gain mode", value_parser = ["default", "manual", "fast", "slow", "hybrid"])]
   gain_mode: String,
@@ -50,13 +220,106 @@ struct Cli {
   #[arg(short = None, long = "gain", action, value_name = "db", default_value_t = 35, env = "BLADERF_ADSB_GAIN", help = "Gain in dB")]
   gain: i32,
 
+  // Let the tool pick the gain
+  #[arg(short = None, long = "gain-auto-adjust", action = clap::ArgAction::SetTrue, default_value_t = false, env = "BLADERF_ADSB_GAIN_AUTO_ADJUST", help = "Start at a conservative manual gain and step it up or down based on message rate, CRC failures and overruns, which are only seen with --meta-timestamps or --packet-meta (implies --gain-mode manual)")]
+  gain_auto_adjust: bool,
+
   // Set the sample rate
-  #[arg(short = 'u', long = "sample-rate", alias = "samplerate", action, value_name = "RATE", default_value_t = 16000000, env = "BLADERF_ADSB_SAMPLE_RATE", help = "Sample rate")]
+  #[arg(short = 'u', long = "sample-rate", alias = "samplerate", action, value_name = "HZ", default_value_t = 16000000, value_parser = parse_sample_rate, env = "BLADERF_ADSB_SAMPLE_RATE", help = "Sample rate in Hz, optionally with a k, M or G suffix (the ADS-B FPGA image only supports 16M)")]
   sample_rate: u32,
 
-  // Enable or disable streaming to remote port
-  #[arg(short = None, long = "remote", value_name = "BOOL", action = clap::ArgAction::Set, default_value_t = true, env = "BLADERF_ADSB_REMOTE", help = "Send data to remote server")]
-  remote: bool,
+  // Take timestamps from the device's sample counter
+  #[arg(short = None, long = "meta-timestamps", action = clap::ArgAction::SetTrue, default_value_t = false, env = "BLADERF_ADSB_META_TIMESTAMPS", help = "Stream in SC16_Q11_META format and timestamp frames from the device's sample counter instead of counting samples on the host")]
+  meta_timestamps: bool,
+
+  // Refuse to stream over USB 2.0
+  #[arg(short = None, long = "require-usb3", action = clap::ArgAction::SetTrue, default_value_t = false, env = "BLADERF_ADSB_REQUIRE_USB3", help = "Refuse to stream if the device is connected at USB 2.0 speed. Always the case above 8000000 samples/s unless --allow-usb2 is given")]
+  require_usb3: bool,
+
+  // Stream over USB 2.0 regardless
+  #[arg(short = None, long = "allow-usb2", action = clap::ArgAction::SetTrue, default_value_t = false, conflicts_with = "require_usb3", env = "BLADERF_ADSB_ALLOW_USB2", help = "Stream at USB 2.0 speed even at sample rates it can't keep up with, expecting overruns")]
+  allow_usb2: bool,
+
+  // Read frames as packets, for FPGA images that support it
+  #[arg(short = None, long = "packet-meta", action = clap::ArgAction::SetTrue, default_value_t = false, conflicts_with_all = ["meta_timestamps", "channels"], env = "BLADERF_ADSB_PACKET_META", help = "Stream in PACKET_META format, one FPGA message per sync_rx timestamped by the device, instead of parsing fixed slots. Needs an FPGA image with packet support")]
+  packet_meta: bool,
+
+  // Stream both RX channels of a bladeRF 2.0
+  #[arg(short = None, long = "channels", value_name = "COUNT", action, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=2), env = "BLADERF_ADSB_CHANNELS", help = "Number of RX channels to stream; 2 uses the BLADERF_RX_X2 layout and extracts frames from both channels")]
+  channels: u8,
+
+  // Capture the raw buffers leading up to a fault
+  #[arg(short = None, long = "dump-raw-on-error", value_name = "DIR", action, env = "BLADERF_ADSB_DUMP_RAW_ON_ERROR", help = "Keep the last few raw sync_rx buffers and write them to a timestamped file in DIR on a burst of overruns or CRC failures; the file can be fed back with --replay")]
+  dump_raw_on_error: Option<PathBuf>,
+
+  // Read samples from a capture instead of the device
+  #[arg(short = None, long = "replay", value_name = "PATH", action, env = "BLADERF_ADSB_REPLAY", help = "Replay a raw sample capture instead of opening the device")]
+  replay: Option<PathBuf>,
+
+  // Layout of the replayed capture
+  #[arg(short = None, long = "replay-format", value_name = "FORMAT", action, default_value_t = String::from("sc16q11"), value_parser = ["sc16q11", "sc16q11-meta", "iq"], env = "BLADERF_ADSB_REPLAY_FORMAT", help = "Format of the --replay capture; sc16q11-meta captures keep their recorded sample timestamps, iq captures are raw baseband samples demodulated in software")]
+  replay_format: String,
+
+  // Sensitivity of the software demodulator
+  #[arg(short = None, long = "preamble-threshold", value_name = "DB", action, default_value_t = DEFAULT_PREAMBLE_THRESHOLD, env = "BLADERF_ADSB_PREAMBLE_THRESHOLD", help = "With --replay-format iq, how far in dB the weakest preamble pulse must stand above the gaps between the pulses; lower finds weaker frames but lets through more noise")]
+  preamble_threshold: f32,
+
+  // Message size of SC16_Q11_META captures
+  #[arg(short = None, long = "replay-message-size", value_name = "BYTES", action, default_value_t = DEFAULT_META_MESSAGE_BYTES, env = "BLADERF_ADSB_REPLAY_MESSAGE_SIZE", help = "USB message size of a sc16q11-meta capture, 2048 for SuperSpeed or 1024 for HighSpeed")]
+  replay_message_size: usize,
+
+  // Replay previously forwarded frames
+  #[arg(short = None, long = "replay-frames", value_name = "PATH", action, conflicts_with = "replay", env = "BLADERF_ADSB_REPLAY_FRAMES", help = "Forward the frames of an AVR or Beast log (detected automatically) instead of opening the device")]
+  replay_frames: Option<PathBuf>,
+
+  // Make up frames instead of receiving them
+  #[arg(short = None, long = "simulate", action = clap::ArgAction::SetTrue, default_value_t = false, conflicts_with_all = ["replay", "replay_frames"], env = "BLADERF_ADSB_SIMULATE", help = "Forward synthetic DF17 frames (callsigns, positions and velocities of a few made up aircraft) instead of opening the device, for testing the outputs")]
+  simulate: bool,
+
+  // How fast --simulate makes frames
+  #[arg(short = None, long = "simulate-rate", value_name = "FRAMES_PER_SEC", action, default_value_t = DEFAULT_SIMULATE_RATE, value_parser = clap::value_parser!(u32).range(1..), env = "BLADERF_ADSB_SIMULATE_RATE", help = "Frames per second forwarded by --simulate")]
+  simulate_rate: u32,
+
+  // Push test frames through the sinks
+  #[arg(short = None, long = "inject", value_name = "PATH", action, env = "BLADERF_ADSB_INJECT", help = "Forward the frames in PATH, one per line as hex or AVR, once at startup ahead of the received ones, for testing downstream consumers")]
+  inject: Option<PathBuf>,
+
+  // What to do about the parity of injected frames
+  #[arg(short = None, long = "inject-crc", value_name = "MODE", action, default_value = "check", value_parser = clap::builder::PossibleValuesParser::new(["check", "compute", "as-is"]).map(|mode| mode.parse::<InjectCrc>().unwrap()), env = "BLADERF_ADSB_INJECT_CRC", help = "check puts --inject frames through the usual CRC checks and filters, compute fills in the parity of DF11/17/18 frames first, as-is sends them to the sinks unchanged")]
+  inject_crc: InjectCrc,
+
+  // Only forward the injected frames
+  #[arg(short = None, long = "inject-only", action = clap::ArgAction::SetTrue, default_value_t = false, requires = "inject", conflicts_with_all = ["replay", "replay_frames", "simulate"], env = "BLADERF_ADSB_INJECT_ONLY", help = "Forward the --inject frames and exit, without opening the device")]
+  inject_only: bool,
+
+  // Pace replays like the original reception
+  #[arg(short = None, long = "replay-realtime", action = clap::ArgAction::SetTrue, default_value_t = false, env = "BLADERF_ADSB_REPLAY_REALTIME", help = "Forward replayed frames as far apart as their MLAT timestamps say they were received, rather than as fast as possible")]
+  replay_realtime: bool,
+
+  // Stream buffering, sized from the sample rate unless given
+  #[arg(short = None, long = "num-buffers", value_name = "COUNT", action, env = "BLADERF_ADSB_NUM_BUFFERS", help = "Number of sample buffers (default: enough for ~20ms at the sample rate)")]
+  num_buffers: Option<u32>,
+
+  #[arg(short = None, long = "buffer-size", value_name = "SAMPLES", action, env = "BLADERF_ADSB_BUFFER_SIZE", help = "Samples per buffer, a multiple of 1024 (default: 8192)")]
+  buffer_size: Option<u32>,
+
+  #[arg(short = None, long = "num-transfers", value_name = "COUNT", action, env = "BLADERF_ADSB_NUM_TRANSFERS", help = "Number of USB transfers in flight, less than --num-buffers (default: half of the buffers)")]
+  num_transfers: Option<u32>,
+
+  // The two libbladeRF stream timeouts, which limit different things
+  #[arg(short = None, long = "config-timeout", value_name = "MS", action, default_value_t = DEFAULT_CONFIG_TIMEOUT_MS, value_parser = clap::value_parser!(u32).range(1..), env = "BLADERF_ADSB_CONFIG_TIMEOUT", help = "Timeout of each USB transfer filling a sample buffer, passed to sync_config")]
+  config_timeout: u32,
+
+  #[arg(short = None, long = "rx-timeout", value_name = "MS", action, default_value_t = DEFAULT_RX_TIMEOUT_MS, value_parser = clap::value_parser!(u32).range(1..), env = "BLADERF_ADSB_RX_TIMEOUT", help = "Timeout of each sync_rx call. Too short and quiet spells cause spurious timeouts, too long and shutdown is delayed")]
+  rx_timeout: u32,
+
+  // Enable or disable streaming to remote port, left unset it's used only when it's there
+  #[arg(short = None, long = "remote", value_name = "BOOL", action = clap::ArgAction::Set, env = "BLADERF_ADSB_REMOTE", help = "Send frames to the remote server. true waits for it (see --reconnect-attempts), false disables it; by default the server is tried once and frames go to stdout if it isn't there")]
+  remote: Option<bool>,
+
+  // Decode locally without any networking
+  #[arg(short = None, long = "no-remote", action = clap::ArgAction::SetTrue, default_value_t = false, conflicts_with_all = ["remote", "sinks"], env = "BLADERF_ADSB_NO_REMOTE", help = "Don't connect to any server and write frames to stdout instead, same as --remote false --output-stdout")]
+  no_remote: bool,
 
   // Set the stream IP address
   #[arg(short = None, long = "remote-ip", action = clap::ArgAction::Set, default_value_t = Ipv4Addr::new(127, 0, 0, 1), env = "BLADERF_ADSB_REMOTE_IP", help = "Remote IP")]
@@ -66,22 +329,688 @@ struct Cli {
   #[arg(short, long = "remote-port", action, default_value_t = 30001, env = "BLADERF_ADSB_REMOTE_PORT", help = "Remove port (matches readsb \"--net-ri-port\")")]
   remote_port: u16,
 
+  // Send to several servers, each in its own format
+  #[arg(short = None, long = "sink", value_name = "HOST:PORT[:FORMAT]", action = clap::ArgAction::Append, value_delimiter = ',', env = "BLADERF_ADSB_SINKS", help = "Remote server to send frames to, in FORMAT if given or --output-format otherwise. Repeatable; replaces --remote-ip/--remote-port")]
+  sinks: Vec<SinkSpec>,
+
+  // Split the frames between the sinks instead of sending every frame to all of them
+  #[arg(short = None, long = "shard", action = clap::ArgAction::SetTrue, default_value_t = false, requires = "sinks", env = "BLADERF_ADSB_SHARD", help = "Send each frame to just one --sink, picked by a hash of its ICAO address, so every frame from an aircraft goes to the same server")]
+  shard: bool,
+
+  // Where --shard sends frames it can't get an ICAO address from
+  #[arg(short = None, long = "shard-unknown", value_name = "round-robin|N", action, default_value = "round-robin", env = "BLADERF_ADSB_SHARD_UNKNOWN", help = "With --shard, where frames without an ICAO address go: round-robin across the sinks, or always to the Nth --sink")]
+  shard_unknown: UnknownShard,
+
+  // Write to descriptors set up by whoever started us, e.g. systemd socket activation
+  #[arg(short = None, long = "inherit-fd", value_name = "FD", action = clap::ArgAction::Append, value_delimiter = ',', conflicts_with = "no_remote", env = "BLADERF_ADSB_INHERIT_FD", help = "Send frames in --output-format to this already open descriptor (a pipe, a connected socket, or a listening socket whose clients each get every frame). Repeatable; defaults to the sockets systemd passes in LISTEN_FDS")]
+  inherit_fds: Vec<RawFd>,
+
+  // Serve frames to browsers over WebSocket
+  #[cfg(feature = "websocket")]
+  #[arg(short = None, long = "ws-port", value_name = "PORT", action, env = "BLADERF_ADSB_WS_PORT", help = "Accept WebSocket connections on PORT and broadcast every frame to them as JSON")]
+  ws_port: Option<u16>,
+
+  // How much decoding to do
+  #[arg(short = None, long = "decode-level", value_name = "LEVEL", action, default_value = "full", value_parser = clap::builder::PossibleValuesParser::new(["raw", "crc", "full"]).map(|level| level.parse::<DecodeLevel>().unwrap()), env = "BLADERF_ADSB_DECODE_LEVEL", help = "raw forwards frames unchecked, crc drops reserved DFs and frames failing CRC or parity, full also decodes positions, callsigns and velocities for the json format and --ws-port")]
+  decode_level: DecodeLevel,
+
+  // Repair frames failing CRC
+  #[arg(short = None, long = "fix-crc", value_name = "MODE", action, default_value_t = String::from("none"), value_parser = ["none", "single", "double"], env = "BLADERF_ADSB_FIX_CRC", help = "Repair extended squitters with single (or single and double) bit CRC errors instead of dropping them")]
+  fix_crc: String,
+
+  // Forward DF18 frames
+  #[arg(short = None, long = "include-tisb", value_name = "BOOL", action = clap::ArgAction::Set, default_value_t = true, env = "BLADERF_ADSB_INCLUDE_TISB", help = "Forward DF18 frames (TIS-B, ADS-R and non-transponder ADS-B such as ground vehicles)")]
+  include_tisb: bool,
+
+  // Log every extracted frame with its slot, DF and CRC status
+  #[arg(short = None, long = "ascii-dump-frames", action = clap::ArgAction::SetTrue, default_value_t = false, env = "BLADERF_ADSB_ASCII_DUMP_FRAMES", help = "Log each extracted frame, before filtering, with its slot, DF, CRC status and hex at debug level")]
+  ascii_dump_frames: bool,
+
+  // Drop frames with reserved downlink formats
+  #[arg(short = None, long = "drop-invalid-df", value_name = "BOOL", action = clap::ArgAction::Set, default_value_t = true, env = "BLADERF_ADSB_DROP_INVALID_DF", help = "Drop frames whose downlink format isn't a real Mode S format")]
+  drop_invalid_df: bool,
+
+  // Only forward frames from these aircraft
+  #[arg(short = None, long = "icao-allow", value_name = "ICAO,...", action = clap::ArgAction::Append, value_delimiter = ',', value_parser = parse_icao, conflicts_with = "icao_block", env = "BLADERF_ADSB_ICAO_ALLOW", help = "Only forward frames from these comma separated ICAO addresses (6 hex digits)")]
+  icao_allow: Vec<u32>,
+
+  // Never forward frames from these aircraft
+  #[arg(short = None, long = "icao-block", value_name = "ICAO,...", action = clap::ArgAction::Append, value_delimiter = ',', value_parser = parse_icao, env = "BLADERF_ADSB_ICAO_BLOCK", help = "Drop frames from these comma separated ICAO addresses (6 hex digits)")]
+  icao_block: Vec<u32>,
+
+  // What --icao-allow/--icao-block do with frames that carry no ICAO address
+  #[arg(short = None, long = "icao-unknown", value_name = "BOOL", action = clap::ArgAction::Set, default_value_t = true, env = "BLADERF_ADSB_ICAO_UNKNOWN", help = "With --icao-allow or --icao-block, forward frames whose ICAO address can't be decoded (DFs other than 11, 17 and 18, anonymous and TIS-B track addresses)")]
+  icao_unknown: bool,
+
+  // Write frames to stdout
+  #[arg(short = None, long = "output-stdout", action = clap::ArgAction::SetTrue, default_value_t = false, env = "BLADERF_ADSB_OUTPUT_STDOUT", help = "Write frames to stdout in --output-format, alongside any remote servers. Hides the spinner; logs always go to stderr")]
+  output_stdout: bool,
+
+  // Set the output format
+  #[arg(short = None, long = "output-format", value_name = "FORMAT", action, default_value_t = String::from("avr"), value_parser = clap::builder::PossibleValuesParser::new(OutputFormat::ALL.iter().map(|f| f.name())), env = "BLADERF_ADSB_OUTPUT_FORMAT", help = "Format of messages sent to the remote server. none sends nothing anywhere but still decodes and counts frames, for benchmarking with --stats-interval")]
+  output_format: String,
+
+  // Name this receiver in the output
+  #[arg(short = None, long = "tag", value_name = "STRING", action, env = "BLADERF_ADSB_TAG", help = "Include STRING (e.g. a site name) as \"tag\" in every JSON frame, on stdout, the sinks and --ws-port, so aggregated streams can be told apart. AVR and Beast have no room for it and leave it out")]
+  tag: Option<String>,
+
+  // Describe the output formats and exit
+  #[arg(short = None, long = "list-formats", action = clap::ArgAction::SetTrue, default_value_t = false, help = "List the supported output formats with a sample line each, then exit")]
+  list_formats: bool,
+
+  // Where the receiver is
+  #[arg(short = None, long = "receiver-position", value_name = "LAT,LON", action, value_parser = parse_position, env = "BLADERF_ADSB_RECEIVER_POSITION", help = "Latitude and longitude of the receiver in decimal degrees, for decoding surface positions of aircraft whose position isn't known yet")]
+  receiver_position: Option<(f64, f64)>,
+
+  // Cap the frames forwarded per second
+  #[arg(short = None, long = "max-frame-rate", value_name = "FRAMES", action, default_value_t = 0, env = "BLADERF_ADSB_MAX_FRAME_RATE", help = "Forward at most this many frames per second across all outputs, shedding other formats before CRC checked DF17/DF18 (0 = unlimited)")]
+  max_frame_rate: u32,
+
+  // Drop repeated frames
+  #[arg(short = None, long = "dedup-window-ms", value_name = "ms", action, default_value_t = 0, env = "BLADERF_ADSB_DEDUP_WINDOW_MS", help = "Drop frames identical to one forwarded within this many ms, once for all outputs so they all get the same stream (0 = keep repeats)")]
+  dedup_window_ms: u64,
+
+  // Forward frames in timestamp order
+  #[arg(short = None, long = "reorder-window-ms", value_name = "ms", action, default_value_t = DEFAULT_REORDER_WINDOW_MS, env = "BLADERF_ADSB_REORDER_WINDOW_MS", help = "Hold frames back this many ms to forward them in timestamp order, for MLAT (0 = forward as received)")]
+  reorder_window_ms: u64,
+
+  // Persist statistics across restarts
+  #[arg(short = None, long = "stats-file", value_name = "PATH", action, env = "BLADERF_ADSB_STATS_FILE", help = "JSON file cumulative statistics are loaded from at startup and saved to periodically and on shutdown")]
+  stats_file: Option<PathBuf>,
+
+  // How often the statistics file is written
+  #[arg(short = None, long = "stats-file-interval", value_name = "SECONDS", action, default_value_t = 60, env = "BLADERF_ADSB_STATS_FILE_INTERVAL", help = "Seconds between statistics file writes")]
+  stats_file_interval: u64,
+
+  // Send statistics to a monitoring collector
+  #[arg(short = None, long = "stats-remote", value_name = "HOST:PORT", action, value_parser = parse_addr, env = "BLADERF_ADSB_STATS_REMOTE", help = "Server to send a JSON line of statistics to every --stats-interval seconds, separately from the frames")]
+  stats_remote: Option<SocketAddr>,
+
+  // How often statistics are sent to --stats-remote
+  #[arg(short = None, long = "stats-interval", value_name = "SECONDS", action, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..), env = "BLADERF_ADSB_STATS_INTERVAL", help = "Seconds between statistics sent to --stats-remote, or logged with --output-format none")]
+  stats_interval: u64,
+
+  // Number of attempts when (re)connecting to the remote server
+  #[arg(short = None, long = "reconnect-attempts", value_name = "COUNT", action, default_value_t = 0, env = "BLADERF_ADSB_RECONNECT_ATTEMPTS", help = "Connection attempts before giving up on the remote server (0 = retry forever)")]
+  reconnect_attempts: u32,
+
+  // Batch frames into fewer TCP segments
+  #[arg(short = None, long = "sink-buffer-bytes", value_name = "BYTES", action, default_value_t = 0, env = "BLADERF_ADSB_SINK_BUFFER_BYTES", help = "Buffer up to this many bytes per server before sending, instead of sending every frame on its own (0 = no buffering)")]
+  sink_buffer_bytes: usize,
+
+  // Bounds how long buffered frames wait
+  #[arg(short = None, long = "flush-interval-ms", value_name = "ms", action, default_value_t = DEFAULT_FLUSH_INTERVAL_MS, value_parser = clap::value_parser!(u64).range(1..), env = "BLADERF_ADSB_FLUSH_INTERVAL_MS", help = "Send buffered frames at least this often with --sink-buffer-bytes, however few there are")]
+  flush_interval_ms: u64,
+
+  // Keep frames on disk while a server is down
+  #[arg(short = None, long = "spool-dir", value_name = "PATH", action, env = "BLADERF_ADSB_SPOOL_DIR", help = "While a server is unreachable, append its frames to a spool file in PATH instead of waiting on it, and send them in order before any live frames once it's back")]
+  spool_dir: Option<PathBuf>,
+
+  // Bounds the spool
+  #[arg(short = None, long = "spool-max-bytes", value_name = "BYTES", action, default_value_t = DEFAULT_SPOOL_MAX_BYTES, requires = "spool_dir", env = "BLADERF_ADSB_SPOOL_MAX_BYTES", help = "Largest a spool file may grow to, frames past it are dropped")]
+  spool_max_bytes: u64,
+
+  // Adjust the receiver while it runs
+  #[arg(short = None, long = "control-socket", value_name = "PATH", action, env = "BLADERF_ADSB_CONTROL_SOCKET", help = "Unix socket taking live 'gain <dB>', 'gain-mode <mode>', 'frequency <hz>' and 'bias-tee on|off' commands, one per line")]
+  control_socket: Option<PathBuf>,
+
   // Set the bias tee
   #[arg(short = None, long = "bias-tee", alias = "biastee", action = clap::ArgAction::SetTrue, default_value_t = false, env = "BLADERF_ADSB_BIAS_TEE", help = "State of bias tee")]
   bias_tee: bool,
 
+  // Which bladeRF to open when several are attached
+  #[arg(short = 'd', long = "device", value_name = "SELECTOR", action, env = "BLADERF_ADSB_DEVICE", help = "Device to open, as comma separated serial=<serial>, usb=<bus>:<addr>, instance=<n> or backend=<name> criteria; defaults to the first device found")]
+  device: Option<DeviceSelector>,
+
+  // RFIC port on boards with several RX inputs
+  #[arg(short = None, long = "rx-port", value_name = "PORT", action, env = "BLADERF_ADSB_RX_PORT", help = "RF port to receive on, e.g. A_BALANCED (bladeRF 2.0 only); defaults to the board's standard RX port")]
+  rx_port: Option<String>,
+
+  // Expansion board to attach during setup
+  #[arg(short = None, long = "expansion", value_name = "BOARD", action, default_value_t = String::from("none"), value_parser = ["none", "xb200", "xb300"], env = "BLADERF_ADSB_EXPANSION", help = "Expansion board to attach; the XB-200 transverter extends the tuning range below 300 MHz")]
+  expansion: String,
+
+  // Reset the USB device when opening it
+  #[arg(short = None, long = "usb-reset-on-open", action = clap::ArgAction::SetTrue, default_value_t = false, env = "BLADERF_ADSB_USB_RESET_ON_OPEN", help = "Have libbladeRF reset the USB device on open")]
+  usb_reset_on_open: bool,
+
   // Manage debugging information
   #[arg(short = 'v', long = "log-level", alias = "loglevel", action = clap::ArgAction::Set, default_value_t = String::from("info"), value_parser = ["off", "error", "warn", "info", "debug", "trace"], env = "BLADERF_ADSB_LOG_LEVEL", help = "Log level")]
   log_level: String,
+  #[arg(short = None, long = "strict", action = clap::ArgAction::SetTrue, default_value_t = false, env = "BLADERF_ADSB_STRICT", help = "Exit with a non-zero code if the run ends without receiving a single frame")]
+  strict: bool,
+  #[arg(short = 'q', long = "quiet", action = clap::ArgAction::SetTrue, default_value_t = false, env = "BLADERF_ADSB_QUIET", help = "Only log errors and hide the spinner, so nothing but frames is written to the data sinks; overrides --log-level")]
+  quiet: bool,
   #[arg(short = None, long = "log-style", alias = "logstyle", action = clap::ArgAction::Set, default_value_t = String::from("auto"), value_parser = ["auto", "always", "never"], env = "BLADERF_ADSB_LOG_STYLE", help = "Manage color for log messages")]
   log_style: String,
 }
 
-fn produce(sender: Sender<String>, cli: Cli, running: Arc<AtomicBool>) {
+// Where frames go, from --remote, --no-remote, --sink, --inherit-fd and --output-stdout.
+// --output-format none goes nowhere, the frames end up in a NullSink instead.
+fn output_plan(cli: &Cli) -> OutputPlan {
+  if cli.output_format == OutputFormat::None.name() {
+    return OutputPlan { remote: RemoteMode::Off, stdout: false };
+  }
+  OutputPlan::new(cli.remote, cli.no_remote, !cli.sinks.is_empty() || !inherited_fds(cli).is_empty(), cli.output_stdout)
+}
+
+// --inherit-fd, or failing that whatever systemd passed us
+fn inherited_fds(cli: &Cli) -> Vec<RawFd> {
+  if cli.inherit_fds.is_empty() { systemd_listen_fds() } else { cli.inherit_fds.clone() }
+}
+
+// Whether anything consumes frames, otherwise the RX thread doesn't bother handing them over.
+// It reads the environment, so the producers work it out once rather than per frame.
+fn forwards_frames(cli: &Cli) -> bool {
+  #[cfg(feature = "websocket")]
+  let websocket = cli.ws_port.is_some();
+  #[cfg(not(feature = "websocket"))]
+  let websocket = false;
+
+  let plan = output_plan(cli);
+  plan.remote != RemoteMode::Off || plan.stdout || websocket || cli.output_format == OutputFormat::None.name()
+}
+
+// --output-format none is for measuring the receive path alone, so it can't be combined with
+// options that send frames somewhere
+fn check_output_format(cli: &Cli) -> Result<(), String> {
+  if cli.output_format != OutputFormat::None.name() {
+    return Ok(());
+  }
+  let option = if !cli.sinks.is_empty() {
+    Some("--sink")
+  } else if !cli.inherit_fds.is_empty() {
+    Some("--inherit-fd")
+  } else if cli.output_stdout {
+    Some("--output-stdout")
+  } else if cli.remote == Some(true) {
+    Some("--remote true")
+  } else {
+    None
+  };
+  match option {
+    Some(option) => Err(format!("--output-format none writes no output, so it can't be used with {}", option)),
+    None => Ok(()),
+  }
+}
+
+// What the spinner says, given whether frames are arriving, how many there have been and how fast
+fn spinner_message(activity: Activity, count: u64, rate: f64) -> String {
+  match activity {
+    Activity::Waiting => String::from("Waiting for messages"),
+    Activity::Active => format!("Processed {} messages ({:.1} msg/s)", count.separate_with_commas(), rate),
+    Activity::Idle(age) => format!("Idle, last message {}s ago (processed {})", age.as_secs(), count.separate_with_commas()),
+  }
+}
+
+// --quiet leaves only errors, whatever --log-level says
+fn log_level(cli: &Cli) -> LevelFilter {
+  if cli.quiet { LevelFilter::Error } else { LevelFilter::from_str(cli.log_level.as_str()).unwrap() }
+}
+
+// The spinner is hidden to keep the terminal clear for whatever stdout is piped into
+fn shows_spinner(cli: &Cli) -> bool {
+  !output_plan(cli).stdout && !cli.quiet
+}
+
+// Options that need more decoding than --decode-level does
+fn check_decode_level(cli: &Cli) -> Result<(), String> {
+  #[cfg(feature = "websocket")]
+  let websocket = cli.ws_port.is_some();
+  #[cfg(not(feature = "websocket"))]
+  let websocket = false;
+
+  let level = cli.decode_level;
+  if level < DecodeLevel::Full {
+    let output_format = OutputFormat::from_name(&cli.output_format).unwrap();
+    if let Some(format) = cli.sinks.iter().filter_map(|sink| sink.format).chain([output_format]).find(|format| format.is_decoded()) {
+      return Err(format!("the {} output format needs --decode-level full, not {}", format.name(), level.name()));
+    }
+    if websocket {
+      return Err(format!("--ws-port needs --decode-level full, not {}", level.name()));
+    }
+  }
+  if level < DecodeLevel::Crc {
+    let needs_crc = if !cli.icao_allow.is_empty() || !cli.icao_block.is_empty() {
+      Some("--icao-allow and --icao-block need")
+    } else if cli.fix_crc != "none" {
+      Some("--fix-crc needs")
+    } else if !cli.include_tisb {
+      Some("--include-tisb false needs")
+    } else {
+      None
+    };
+    if let Some(option) = needs_crc {
+      return Err(format!("{} --decode-level crc or full, not {}", option, level.name()));
+    }
+  }
+  Ok(())
+}
+
+// Filters applied to every frame before it's forwarded, whatever it was read from
+fn accept_frame(frame: &mut AdsbFrame, cli: &Cli, stats: &Stats, corrector: &ErrorCorrector, icao_filter: &IcaoFilter) -> bool {
+  // checked first so the annotation is only built when it's going to be logged
+  if cli.ascii_dump_frames && log_enabled!(Level::Debug) {
+    debug!("Frame: {}", frame.annotate());
+  }
+  if cli.decode_level == DecodeLevel::Raw {
+    return true;
+  }
+
+  // reserved downlink formats are noise; short frames especially can slip through with a weak parity check
+  let df = frame.df();
+  if cli.drop_invalid_df && !is_valid_df(df) {
+    debug!("Dropping frame with invalid DF{}: {}", df, frame.to_avr().trim_end());
+    return false;
+  }
+
+  // extended squitters carry a plain CRC, so anything with a remainder is corrupt
+  if is_extended_squitter(df) {
+    if crc::syndrome(&frame.data) != 0 {
+      let received = frame.to_avr();
+      match corrector.fix(&mut frame.data) {
+        Some(bits) => {
+          debug!("Repaired {} bit error(s) in DF{} frame: {} -> {}", bits, df, received.trim_end(), frame.to_avr().trim_end());
+          stats.crc_corrected.fetch_add(1, Ordering::Relaxed);
+        },
+        None => {
+          debug!("Dropping DF{} frame with bad CRC: {}", df, received.trim_end());
+          stats.crc_failures.fetch_add(1, Ordering::Relaxed);
+          return false;
+        },
+      }
+    }
+    if df == 18 && !cli.include_tisb {
+      trace!("Dropping DF18 frame, TIS-B forwarding disabled");
+      return false;
+    }
+  }
+
+  // DF11 parity is overlaid with the interrogator code, so only the low 7 bits of the
+  // syndrome may be set. Short frames aren't repaired.
+  if df == 11 && decode_all_call(&frame.data).is_none() {
+    debug!("Dropping DF11 frame with bad parity: {}", frame.to_avr().trim_end());
+    stats.crc_failures.fetch_add(1, Ordering::Relaxed);
+    return false;
+  }
+
+  // after CRC repair, so a fixed frame is matched against its real address
+  if !icao_filter.accepts_frame(&frame.data) {
+    trace!("Dropping frame filtered by ICAO: {}", frame.to_avr().trim_end());
+    return false;
+  }
+
+  true
+}
+
+// Open the device, riding out the USB errors it sometimes gives right after enumerating
+fn open_device(device_string: Option<String>) -> Result<BladeRFDevice, isize> {
+  retry("bladerf_open", TRANSIENT_RETRY_ATTEMPTS, TRANSIENT_RETRY_DELAY, || bladerf::open(device_string.clone()))
+}
+
+// Expansion board named by --expansion
+fn expansion_board(name: &str) -> bladerf_xb {
+  match name {
+    "xb200" => BLADERF_XB_200,
+    "xb300" => BLADERF_XB_300,
+    _       => BLADERF_XB_NONE,
+  }
+}
+
+// Load the FPGA image, then close and reopen the device so libbladeRF picks it up
+fn load_fpga_and_reopen(dev: BladeRFDevice, fpga_path: &str, device_string: Option<String>) -> Result<BladeRFDevice, AppError> {
+  info!("Loading FPGA image: {}", fpga_path);
+  let image = FpgaImage::open(fpga_path)
+    .map_err(|e| AppError::FpgaLoad(format!("unable to read {}: {}", fpga_path, e)))?;
+  if image.is_decompressed() {
+    debug!("Decompressed {} to {}", fpga_path, image.path().display());
+  }
+  match dev.load_fpga(image.path().to_string_lossy().into_owned()) {
+    Ok(_) => {
+      info!("Successfully loaded image");
+    },
+    Err(e) => return Err(AppError::FpgaLoad(format!("unable to load {} (error {})", fpga_path, e))),
+  };
+  drop(image);
+
+  info!("Closing and opening device for new FPGA image");
+  dev.close();
+  let reopened = retry_with_backoff("bladerf_open", FPGA_REOPEN_ATTEMPTS, FPGA_REOPEN_INITIAL_DELAY, FPGA_REOPEN_MAX_DELAY, || {
+    bladerf::open(device_string.clone())
+  });
+  match reopened {
+    Ok(dev) => {
+      info!("Successfully re-loaded BladeRF device");
+      Ok(dev)
+    },
+    Err(e) => Err(AppError::FpgaReopen(e)),
+  }
+}
+
+// Reset the device and open it again once it has re-enumerated, which takes a few seconds
+fn reset_and_reopen(dev: BladeRFDevice, device_string: Option<String>) -> Result<BladeRFDevice, AppError> {
+  info!("Resetting device, this takes a few seconds");
+  let reset = dev.device_reset();
+  // the handle is no good after a reset, successful or not
+  dev.close();
+  reset.map_err(|e| AppError::Device(String::from("device_reset"), e))?;
+
+  thread::sleep(RESET_SETTLE);
+  let mut attempt = 1;
+  loop {
+    match open_device(device_string.clone()) {
+      Ok(dev) => {
+        info!("Successfully re-opened BladeRF device after reset");
+        return Ok(dev);
+      },
+      Err(e) if attempt < RESET_REOPEN_ATTEMPTS => {
+        debug!("Device not back after reset yet (error {}), attempt {}/{}", e, attempt, RESET_REOPEN_ATTEMPTS);
+        thread::sleep(RESET_REOPEN_DELAY);
+        attempt += 1;
+      },
+      Err(e) => return Err(AppError::DeviceOpen(e)),
+    }
+  }
+}
+
+// Stream the 32 bit counter instead of RF samples for `duration` and check every sample arrived.
+// The RX mux is put back the way it was afterwards, whether or not streaming succeeded.
+fn throughput(cli: &Cli, duration: Duration) -> Result<(), AppError> {
+  let device_string = cli.device.as_ref().map(DeviceSelector::to_device_string);
+  let mut dev = open_device(device_string.clone()).map_err(AppError::DeviceOpen)?;
+  if !cli.fpga_path.is_empty() {
+    dev = load_fpga_and_reopen(dev, &cli.fpga_path, device_string)?;
+  }
+  let configured = wait_for_fpga_configured(&dev, FPGA_CONFIGURED_ATTEMPTS, FPGA_CONFIGURED_RETRY_DELAY)
+    .map_err(|e| AppError::Device(String::from("is_fpga_configured"), e))?;
+  if !configured {
+    dev.close();
+    return Err(AppError::FpgaLoad(String::from("no FPGA image is loaded, pass --fpga-path")));
+  }
+
+  let previous_mux = dev.get_rx_mux().map_err(|e| AppError::Device(String::from("get_rx_mux"), e))?;
+  let result = stream_counter(&dev, cli.sample_rate, duration, cli.config_timeout, cli.rx_timeout);
+  if let Err(e) = dev.set_rx_mux(previous_mux) {
+    warn!("Unable to restore RX mux {:?} (error {})", previous_mux, e);
+  }
+  if let Err(e) = dev.shutdown(BLADERF_MODULE_RX) {
+    warn!("Device shutdown failed (error {}), the device may need to be reset", e);
+  }
+  let (check, elapsed) = result?;
+
+  let achieved = check.achieved_rate(elapsed);
+  println!("Sample rate:  {} samples/s", cli.sample_rate.separate_with_commas());
+  println!("Achieved:     {} samples/s ({:.1}%)", (achieved as u64).separate_with_commas(), achieved * 100.0 / cli.sample_rate as f64);
+  println!("Received:     {} samples in {:.1}s", check.samples.separate_with_commas(), elapsed.as_secs_f64());
+  println!("Gaps:         {} ({} samples lost)", check.gaps.separate_with_commas(), check.missing.separate_with_commas());
+  Ok(())
+}
+
+// The flash image first, then the firmware log. Boards or firmware that can't do one of them
+// get a warning and the other is still saved; it's only an error if neither could be.
+fn backup(cli: &Cli, dir: &Path) -> Result<(), AppError> {
+  let dev = open_device(cli.device.as_ref().map(DeviceSelector::to_device_string)).map_err(AppError::DeviceOpen)?;
+  let result = write_backup(&dev, dir);
+  dev.close();
+  let saved = result?;
+  for path in &saved {
+    println!("{}", path.display());
+  }
+  Ok(())
+}
+
+fn write_backup(dev: &BladeRFDevice, dir: &Path) -> Result<Vec<PathBuf>, AppError> {
+  let serial = dev.get_serial().map_err(|e| AppError::Device(String::from("get_serial"), e))?;
+  let serial = serial.trim_end_matches('\0');
+  let now = SystemTime::now();
+  let mut saved = Vec::new();
+
+  info!("Reading the SPI flash, this takes a while");
+  match read_flash_image(dev) {
+    Ok((image, is_guess)) => {
+      if is_guess {
+        warn!("libbladeRF guessed the flash size ({} bytes) from the FPGA size, the image may be incomplete", image.len());
+      }
+      let path = backup_path(dir, serial, "flash", "bin", now);
+      fs::write(&path, &image).map_err(|e| AppError::Config(format!("unable to write {}: {}", path.display(), e)))?;
+      info!("Saved {} bytes of flash to {}", image.len(), path.display());
+      saved.push(path);
+    },
+    Err(e) if is_unsupported(e) => warn!("Reading the flash isn't supported on this device, saving the firmware log only"),
+    Err(e) => return Err(AppError::Device(String::from("read_flash"), e)),
+  }
+
+  let path = backup_path(dir, serial, "fw", "log", now);
+  match dev.get_fw_log(path.to_string_lossy().into_owned()) {
+    Ok(_) => {
+      info!("Saved the firmware log to {}", path.display());
+      saved.push(path);
+    },
+    Err(e) if is_unsupported(e) && !saved.is_empty() => warn!("The firmware is too old to report its log (needs 1.10 or later), skipping it"),
+    Err(e) => return Err(AppError::Device(String::from("get_fw_log"), e)),
+  }
+  Ok(saved)
+}
+
+fn stream_counter(dev: &BladeRFDevice, sample_rate: u32, duration: Duration, config_timeout: u32, rx_timeout: u32)
+                  -> Result<(CounterCheck, Duration), AppError> {
+  dev.set_rx_mux(BLADERF_RX_MUX_32BIT_COUNTER).map_err(|e| AppError::Device(String::from("set_rx_mux"), e))?;
+  dev.set_sample_rate(BLADERF_MODULE_RX, sample_rate).map_err(|e| AppError::Device(String::from("set_sample_rate"), e))?;
+  let buffers = StreamBuffers::for_sample_rate(sample_rate);
+  retry("sync_config", TRANSIENT_RETRY_ATTEMPTS, TRANSIENT_RETRY_DELAY, || {
+    dev.sync_config(BLADERF_MODULE_RX, BLADERF_FORMAT_SC16_Q11, buffers.num_buffers, buffers.buffer_size, Some(buffers.num_transfers), config_timeout)
+  }).map_err(|e| AppError::Device(String::from("sync_config"), e))?;
+  retry("enable_module", TRANSIENT_RETRY_ATTEMPTS, TRANSIENT_RETRY_DELAY, || dev.enable_module(BLADERF_MODULE_RX, true))
+    .map_err(|e| AppError::Device(String::from("enable_module"), e))?;
+
+  info!("Streaming the sample counter at {} samples/s for {:?}", sample_rate, duration);
+  let mut buf = vec![0u8; buffers.buffer_size as usize * BYTES_PER_SAMPLE];
+  let mut check = CounterCheck::new();
+  let start = Instant::now();
+  while start.elapsed() < duration {
+    let samples = dev.sync_rx(&mut buf, buffers.buffer_size, None, rx_timeout)
+      .map_err(|e| AppError::Device(String::from("sync_rx"), e))?;
+    check.feed(received(&buf, samples as usize));
+  }
+  Ok((check, start.elapsed()))
+}
+
+// Feed frames from a capture file through the same path as live ones
+fn replay(sender: Sender<AdsbFrame>, cli: Cli, running: Arc<AtomicBool>, stats: Arc<Stats>) -> Result<(), AppError> {
+  let forward = forwards_frames(&cli);
+  let path = cli.replay.clone().unwrap();
+  let format = match cli.replay_format.as_str() {
+    "sc16q11-meta" => CaptureFormat::Sc16Q11Meta { message_bytes: cli.replay_message_size },
+    "iq" => CaptureFormat::Iq { preamble_threshold: cli.preamble_threshold },
+    _ => CaptureFormat::Sc16Q11,
+  };
+  let file = File::open(&path)
+    .map_err(|e| AppError::Config(format!("unable to open replay file {}: {}", path.display(), e)))?;
+
+  let corrector = ErrorCorrector::new(CrcFixMode::from_name(&cli.fix_crc).unwrap());
+  let icao_filter = IcaoFilter::new(&cli.icao_allow, &cli.icao_block, cli.icao_unknown);
+  info!("Replaying {} ({})", path.display(), cli.replay_format);
+  let mut source = ReplaySource::new(BufReader::new(file), format, cli.sample_rate);
+  let mut pacer = if cli.replay_realtime { Some(ReplayPacer::new()) } else { None };
+  while running.load(Ordering::SeqCst) {
+    let frames = match source.next_frames() {
+      Ok(Some(frames)) => frames,
+      Ok(None) => break,
+      Err(e) => return Err(AppError::Config(format!("error reading replay file {}: {}", path.display(), e))),
+    };
+
+    for frame in frames {
+      if let Some(pacer) = pacer.as_mut() {
+        thread::sleep(pacer.delay(frame.timestamp, Instant::now()));
+      }
+      if !forward_replayed(frame, &sender, forward, &cli, &stats, &corrector, &icao_filter) {
+        running.store(false, Ordering::SeqCst);
+        break;
+      }
+    }
+  }
+
+  info!("Replay finished");
+  let snr = stats.snapshot().snr;
+  if let (Some(min), Some(mean), Some(max)) = (snr.min, snr.mean, snr.max) {
+    info!("SNR over {} frames: min {:.1} dB, mean {:.1} dB, max {:.1} dB", snr.frames, min, mean, max);
+  }
+  Ok(())
+}
+
+// Feed the frames of an AVR or Beast log through the same filters and sinks as live ones
+fn replay_frames(sender: Sender<AdsbFrame>, cli: Cli, running: Arc<AtomicBool>, stats: Arc<Stats>) -> Result<(), AppError> {
+  let forward = forwards_frames(&cli);
+  let path = cli.replay_frames.clone().unwrap();
+  let file = File::open(&path)
+    .map_err(|e| AppError::Config(format!("unable to open replay file {}: {}", path.display(), e)))?;
+  let mut source = FrameLogSource::new(BufReader::new(file))
+    .map_err(|e| AppError::Config(format!("unable to replay {}: {}", path.display(), e)))?;
+
+  let corrector = ErrorCorrector::new(CrcFixMode::from_name(&cli.fix_crc).unwrap());
+  let icao_filter = IcaoFilter::new(&cli.icao_allow, &cli.icao_block, cli.icao_unknown);
+  let mut pacer = if cli.replay_realtime { Some(ReplayPacer::new()) } else { None };
+  info!("Replaying frames from {} ({:?})", path.display(), source.format());
+  while running.load(Ordering::SeqCst) {
+    let frame = match source.next_frame() {
+      Ok(Some(frame)) => frame,
+      Ok(None) => break,
+      Err(e) => return Err(AppError::Config(format!("error reading replay file {}: {}", path.display(), e))),
+    };
+    if let Some(pacer) = pacer.as_mut() {
+      thread::sleep(pacer.delay(frame.timestamp, Instant::now()));
+    }
+    if !forward_replayed(frame, &sender, forward, &cli, &stats, &corrector, &icao_filter) {
+      running.store(false, Ordering::SeqCst);
+      break;
+    }
+  }
+
+  if source.skipped > 0 {
+    info!("Skipped {} entries of {} that weren't Mode S frames", source.skipped, path.display());
+  }
+  info!("Replay finished");
+  Ok(())
+}
+
+// Feed made up frames through the same filters and sinks as live ones, paced by their timestamps
+fn simulate(sender: Sender<AdsbFrame>, cli: Cli, running: Arc<AtomicBool>, stats: Arc<Stats>) -> Result<(), AppError> {
+  let forward = forwards_frames(&cli);
+  let corrector = ErrorCorrector::new(CrcFixMode::from_name(&cli.fix_crc).unwrap());
+  let icao_filter = IcaoFilter::new(&cli.icao_allow, &cli.icao_block, cli.icao_unknown);
+  let mut simulator = Simulator::new(cli.simulate_rate);
+  let mut pacer = ReplayPacer::new();
+  info!("Simulating {} aircraft at {} frames/s", SIMULATED_AIRCRAFT, cli.simulate_rate);
+  while running.load(Ordering::SeqCst) {
+    let frame = simulator.next_frame();
+    thread::sleep(pacer.delay(frame.timestamp, Instant::now()));
+    if !forward_replayed(frame, &sender, forward, &cli, &stats, &corrector, &icao_filter) {
+      running.store(false, Ordering::SeqCst);
+    }
+  }
+
+  info!("Simulation stopped");
+  Ok(())
+}
+
+// Forward the --inject frames, before anything is received. Returns false once the output
+// thread has gone away.
+fn inject(frames: Vec<Vec<u8>>, sender: &Sender<AdsbFrame>, cli: &Cli, stats: &Stats) -> bool {
+  let forward = forwards_frames(cli);
+  let corrector = ErrorCorrector::new(CrcFixMode::from_name(&cli.fix_crc).unwrap());
+  let icao_filter = IcaoFilter::new(&cli.icao_allow, &cli.icao_block, cli.icao_unknown);
+  info!("Injecting {} frames", frames.len());
+  for data in frames {
+    let mut frame = AdsbFrame { data, slot: 0, timestamp: None, frequency: None, snr: None };
+    if cli.inject_crc == InjectCrc::Compute {
+      compute_parity(&mut frame.data);
+    }
+    if cli.inject_crc != InjectCrc::AsIs {
+      if !forward_replayed(frame, sender, forward, cli, stats, &corrector, &icao_filter) {
+        return false;
+      }
+      continue;
+    }
+    debug!("Injecting as is: {}", frame.to_avr());
+    if forward && sender.send(frame).is_err() {
+      debug!("Output thread has stopped, stopping injection");
+      return false;
+    }
+    stats.messages.fetch_add(1, Ordering::Relaxed);
+  }
+  true
+}
+
+// Filter and forward one replayed or simulated frame. Returns false once the output thread has
+// gone away.
+fn forward_replayed(mut frame: AdsbFrame, sender: &Sender<AdsbFrame>, forward: bool, cli: &Cli, stats: &Stats,
+                    corrector: &ErrorCorrector, icao_filter: &IcaoFilter) -> bool {
+  if !accept_frame(&mut frame, cli, stats, corrector, icao_filter) {
+    return true;
+  }
+  debug!("ADS-B message is: {}", frame.to_avr());
+  let snr = frame.snr;
+  if forward && sender.send(frame).is_err() {
+    debug!("Output thread has stopped, stopping replay");
+    return false;
+  }
+  stats.messages.fetch_add(1, Ordering::Relaxed);
+  if let Some(snr) = snr {
+    stats.record_snr(snr);
+  }
+  true
+}
+
+// SIGUSR1 pausing as the output thread sees it, logging each switch and counting the frames
+// dropped while paused
+#[derive(Default)]
+struct Pause {
+  paused: bool,
+  dropped: u64,
+}
+
+impl Pause {
+  // Pass `received` on unless `paused`, the SIGUSR1 flag as it stands now
+  fn filter(&mut self, paused: bool, received: Option<AdsbFrame>) -> Option<AdsbFrame> {
+    if paused != self.paused {
+      if paused {
+        warn!("Paused by SIGUSR1, dropping received frames until the next SIGUSR1");
+      } else {
+        if self.dropped > 0 {
+          info!("Resumed by SIGUSR1 after dropping {} frames", self.dropped);
+        } else {
+          info!("Resumed by SIGUSR1");
+        }
+        self.dropped = 0;
+      }
+      self.paused = paused;
+    }
+    match received {
+      Some(_) if paused => {
+        self.dropped += 1;
+        None
+      },
+      received => received,
+    }
+  }
+}
+
+// The device as configure_device leaves it, streaming RX
+struct ConfiguredDevice {
+  dev: BladeRFDevice,
+  rx_config: BladeRFModuleConfig,
+  gain_controller: Option<GainController>,
+  sample_rate: u32,
+}
+
+// Open the device and set it up from the command line, through to enabling RX. Also brings the
+// device back after it was unplugged, with `reset` false since --reset-on-start is for the
+// first open only.
+fn configure_device(cli: &Cli, reset: bool) -> Result<ConfiguredDevice, AppError> {
     let fpga_path: String;
 
     let mut rx_config: BladeRFModuleConfig = BladeRFModuleConfig {
-        frequency: cli.frequency.clone(),
+        frequency: lo_frequency(cli.frequency_list.first().cloned().unwrap_or(cli.frequency), cli.lo_offset).map_err(AppError::Config)?,
         bandwidth: cli.bandwidth.clone(),
         sample_rate: cli.sample_rate.clone(),
         lna_gain: BLADERF_GAIN_DEFAULT,
@@ -91,7 +1020,8 @@ fn produce(sender: Sender<String>, cli: Cli, running: Arc<AtomicBool>) {
     let bias_tee: bool = cli.bias_tee.clone();
 
     // set lna gain
-    match cli.gain_mode.as_str() {
+    let gain_mode = if cli.gain_auto_adjust { "manual" } else { cli.gain_mode.as_str() };
+    match gain_mode {
       "default" => {
         info!("set lna gain to BLADERF_GAIN_DEFAULT");
         rx_config.lna_gain = BLADERF_GAIN_DEFAULT;
@@ -115,102 +1045,294 @@ fn produce(sender: Sender<String>, cli: Cli, running: Arc<AtomicBool>) {
       _        => {}
     };
 
-  let mut dev = match bladerf::open(None) {
+  let device_string = cli.device.as_ref().map(DeviceSelector::to_device_string);
+  if let Some(ref device_string) = device_string {
+    info!("Opening bladeRF device \"{}\"", device_string);
+  }
+  let mut dev = match open_device(device_string.clone()) {
     Ok(dev) => {
       info!("Successfully loaded BladeRF device");
       dev
     },
-    Err(_) => panic!("bladerf::open error"),
+    Err(e) => return Err(AppError::DeviceOpen(e)),
   };
+  if reset && cli.reset_on_start {
+    dev = reset_and_reopen(dev, device_string.clone())?;
+  }
   let dev_fpga_size = match dev.get_fpga_size() {
     Ok(size) => {
       info!("FPGA size is {:?}", size);
       size
     },
-    Err(_) => panic!("dev.get_fpga_size()")
+    Err(e) => return Err(AppError::Device(String::from("get_fpga_size"), e)),
   };
   if !cli.fpga_path.is_empty() {
-    fpga_path = cli.fpga_path;
+    fpga_path = cli.fpga_path.clone();
   } else {
-    info!("FPGA path not specified. Falling back to default value.");
-    fpga_path = match dev_fpga_size {
-      BLADERF_FPGA_UNKNOWN => panic!("Unable to determine FPGA size"),
-      BLADERF_FPGA_40KLE   => String::from("/usr/share/Nuand/bladeRF/adsbx40.rbf"),
-      BLADERF_FPGA_115KLE  => String::from("/usr/share/Nuand/bladeRF/adsbx115.rbf"),
-      BLADERF_FPGA_A4      => String::from("/usr/share/Nuand/bladeRF/adsbxA4.rbf"),
-      BLADERF_FPGA_A5      => String::from("/usr/share/Nuand/bladeRF/adsbxA5.rbf"),
-      BLADERF_FPGA_A9      => String::from("/usr/share/Nuand/bladeRF/adsbxA9.rbf"),
-    }
+    info!("FPGA path not specified, searching the usual install locations");
+    fpga_path = default_fpga_path(dev_fpga_size).map_err(AppError::FpgaLoad)?;
+    info!("Found FPGA image {}", fpga_path);
   }
 
-  info!("Loading FPGA image: {}", fpga_path);
-  match dev.load_fpga(fpga_path) {
-    Ok(_) => {
-      info!("Successfully loaded image");
-    },
-    Err(_) => panic!("bladerf::load_fpga() error"),
+  let fpga_sha256 = if cli.verify_fpga || cli.fpga_sha256.is_some() {
+    match verify_fpga(&fpga_path, cli.fpga_sha256.as_deref()) {
+      Ok(sha256) => {
+        info!("FPGA image checksum verified");
+        sha256
+      },
+      Err(msg) => return Err(AppError::FpgaLoad(format!("refusing to load {}: {}", fpga_path, msg))),
+    }
+  } else {
+    // only used for the info output, load_fpga reports unreadable images itself
+    sha256_file(&fpga_path).unwrap_or_else(|_| String::from("unknown"))
   };
 
-  info!("Closing and opening device for new FPGA image");
-  dev.close();
-  dev = match bladerf::open(None) {
-    Ok(dev) => {
-      info!("Successfully re-loaded BladeRF device");
-      dev
+  dev = load_fpga_and_reopen(dev, &fpga_path, device_string.clone())?;
+
+  // a successful load_fpga doesn't guarantee the FPGA came up, so check before streaming and
+  // give it one more load if it didn't
+  let configured = wait_for_fpga_configured(&dev, FPGA_CONFIGURED_ATTEMPTS, FPGA_CONFIGURED_RETRY_DELAY)
+    .map_err(|e| AppError::Device(String::from("is_fpga_configured"), e))?;
+  if !configured {
+    warn!("FPGA not configured after loading {}, loading it again", fpga_path);
+    dev = load_fpga_and_reopen(dev, &fpga_path, device_string)?;
+    let configured = wait_for_fpga_configured(&dev, FPGA_CONFIGURED_ATTEMPTS, FPGA_CONFIGURED_RETRY_DELAY)
+      .map_err(|e| AppError::Device(String::from("is_fpga_configured"), e))?;
+    if !configured {
+      return Err(AppError::FpgaLoad(format!("FPGA still not configured after loading {} twice", fpga_path)));
+    }
+  }
+
+  // checked on the device as reopened after the FPGA load, the one that will actually stream
+  check_usb_speed(dev.device_speed(), rx_config.sample_rate, cli.require_usb3, cli.allow_usb2).map_err(AppError::Config)?;
+
+  // the expansion board is driven through the FPGA, so it can only be attached once that's loaded
+  let expansion = expansion_board(&cli.expansion);
+  if expansion != BLADERF_XB_NONE {
+    info!("Attaching expansion board {:?}", expansion);
+    dev.attach_expansion(expansion).map_err(|e| AppError::Device(String::from("expansion_attach"), e))?;
+  }
+
+  // reject frequencies the board can't tune now, rather than with a RANGE error mid-setup. Done
+  // after attaching the expansion board, which changes the range.
+  match dev.get_frequency_limits(BLADERF_MODULE_RX) {
+    Ok((min, max, _)) => {
+      let requested = if cli.frequency_list.is_empty() { vec![cli.frequency] } else { cli.frequency_list.clone() };
+      for frequency in requested {
+        validate_frequency(lo_frequency(frequency, cli.lo_offset).map_err(AppError::Config)?, min, max).map_err(AppError::Config)?;
+      }
     },
-    Err(_) => panic!("bladerf::open error"),
-  };
+    Err(e) => warn!("Unable to query the frequency range (error {}), not validating --frequency", e),
+  }
+
+  let rx_channel = bladerf_channel_rx(0);
+  if let Some(ref port) = cli.rx_port {
+    set_rx_port(&dev, port)?;
+  }
+  if let Ok(port) = dev.get_rf_port(rx_channel) {
+    info!("Receiving on RF port {}", port);
+  }
 
   debug!("Configure module");
   // Configure RX
-  dev.set_bias_tee(BLADERF_MODULE_RX, bias_tee).unwrap();
+  set_bias_tee(&dev, bias_tee)?;
 
+  for warning in bandwidth_warnings(rx_config.sample_rate, rx_config.bandwidth) {
+    warn!("{}", warning);
+  }
   dev.configure_module(BLADERF_MODULE_RX, rx_config.clone());
 
-  dev.set_gain_mode(BLADERF_MODULE_RX, rx_config.lna_gain).unwrap();
-  match rx_config.lna_gain {
-    BLADERF_GAIN_MGC => {
-      info!("Setting LNA gain to {}dB", cli.gain);
-      dev.set_gain(BLADERF_MODULE_RX, cli.gain).unwrap();
+  // ask the board which gain modes it has, falling back to what its model is known to support
+  let board = dev.get_board_name();
+  let supported_modes = match dev.get_gain_modes(BLADERF_MODULE_RX) {
+    Ok(modes) if !modes.is_empty() => modes,
+    _ => board_gain_modes(&board).to_vec(),
+  };
+  let gain_mode = resolve_gain_mode(rx_config.lna_gain, &supported_modes);
+  if gain_mode != rx_config.lna_gain {
+    warn!("{} doesn't support gain mode {:?} (supported: {:?}), using BLADERF_GAIN_DEFAULT", board, rx_config.lna_gain, supported_modes);
+    rx_config.lna_gain = gain_mode;
+  }
+
+  match dev.set_gain_mode(BLADERF_MODULE_RX, rx_config.lna_gain) {
+    Ok(_) => {},
+    Err(e) if rx_config.lna_gain != BLADERF_GAIN_DEFAULT => {
+      warn!("failed to set gain mode {:?} (error {}), using BLADERF_GAIN_DEFAULT", rx_config.lna_gain, e);
+      rx_config.lna_gain = BLADERF_GAIN_DEFAULT;
+      dev.set_gain_mode(BLADERF_MODULE_RX, rx_config.lna_gain).map_err(|e| AppError::Device(String::from("set_gain_mode"), e))?;
     },
-    _ => {}
+    Err(e) => return Err(AppError::Device(String::from("set_gain_mode"), e)),
+  }
+  let mut gain_controller = None;
+  if cli.gain_auto_adjust {
+    let range = dev.get_gain_range(BLADERF_MODULE_RX).map_err(|e| AppError::Device(String::from("get_gain_range"), e))?;
+    let min = (range.min as f32 * range.scale) as i32;
+    let max = (range.max as f32 * range.scale) as i32;
+    // start conservative, no higher than the middle of the range
+    let controller = GainController::new(cli.gain.min((min + max) / 2), min, max, GAIN_ADJUST_INTERVAL, Instant::now());
+    info!("Automatic gain adjustment between {}dB and {}dB, starting at {}dB", min, max, controller.gain());
+    dev.set_gain(BLADERF_MODULE_RX, controller.gain()).map_err(|e| AppError::Device(String::from("set_gain"), e))?;
+    gain_controller = Some(controller);
+  } else {
+    match rx_config.lna_gain {
+      BLADERF_GAIN_MGC => {
+        info!("Setting LNA gain to {}dB", cli.gain);
+        dev.set_gain(BLADERF_MODULE_RX, cli.gain).map_err(|e| AppError::Device(String::from("set_gain"), e))?;
+      },
+      _ => {}
+    }
   }
 
-  // Configure RX sample stream
-  dev.sync_config(BLADERF_MODULE_RX, BLADERF_FORMAT_SC16_Q11, 2, 1024, Some(1), 5000).unwrap();
+  // Configure RX sample stream, explicit flags override the automatic sizing field by field
+  let auto_buffers = StreamBuffers::for_sample_rate(rx_config.sample_rate);
+  let buffers = StreamBuffers {
+    num_buffers: cli.num_buffers.unwrap_or(auto_buffers.num_buffers),
+    buffer_size: cli.buffer_size.unwrap_or(auto_buffers.buffer_size),
+    num_transfers: cli.num_transfers.unwrap_or(auto_buffers.num_transfers),
+  };
+  info!("Using {} buffers of {} samples ({:?} of buffering), {} transfers",
+        buffers.num_buffers, buffers.buffer_size, buffers.depth(rx_config.sample_rate), buffers.num_transfers);
+  if buffers.is_too_shallow(rx_config.sample_rate) {
+    warn!("{:?} of buffering is shallow for {} samples/s, expect overruns on a busy host",
+          buffers.depth(rx_config.sample_rate), rx_config.sample_rate);
+  }
+  buffers.validate_rx_timeout(rx_config.sample_rate, cli.rx_timeout).map_err(AppError::Config)?;
+  if Duration::from_millis(cli.rx_timeout as u64) > SLOW_SHUTDOWN_RX_TIMEOUT {
+    warn!("With --rx-timeout {}ms, shutting down can take up to that long while no frames arrive", cli.rx_timeout);
+  }
+  let format = if cli.packet_meta {
+    BLADERF_FORMAT_PACKET_META
+  } else if cli.meta_timestamps {
+    BLADERF_FORMAT_SC16_Q11_META
+  } else {
+    BLADERF_FORMAT_SC16_Q11
+  };
+  let layout = if cli.channels == 2 { BLADERF_RX_X2 } else { BLADERF_RX_X1 };
+  retry("sync_config", TRANSIENT_RETRY_ATTEMPTS, TRANSIENT_RETRY_DELAY, || {
+    dev.sync_config_layout(layout, format, buffers.num_buffers, buffers.buffer_size, Some(buffers.num_transfers), cli.config_timeout)
+  }).map_err(|e| AppError::Device(String::from("sync_config"), e))?;
 
   // Enable RX
-  dev.enable_module(BLADERF_MODULE_RX, true).unwrap();
+  retry("enable_module", TRANSIENT_RETRY_ATTEMPTS, TRANSIENT_RETRY_DELAY, || dev.enable_module(BLADERF_MODULE_RX, true))
+    .map_err(|e| AppError::Device(String::from("enable_module"), e))?;
+  if cli.channels == 2 {
+    dev.enable_channel(bladerf_channel_rx(1), true).map_err(|e| AppError::Device(String::from("enable_channel"), e))?;
+  }
+
+  let sample_rate = dev.get_sample_rate(BLADERF_MODULE_RX).map_err(|e| AppError::Device(String::from("get_sample_rate"), e))?;
 
   // based on https://github.com/wiedehopf/readsb/blob/dev/sdr_ubladerf.c
-  info!("bladeRF: sampling rate:    {:.1} MHz", dev.get_sample_rate(BLADERF_MODULE_RX).unwrap() as f32 / 1e6);
-  info!("bladeRF: frequency:        {:.1} MHz", dev.get_frequency(BLADERF_MODULE_RX).unwrap() as f32 / 1e6);
-  info!("bladeRF: gain mode:        {:?}",      dev.get_gain_mode(BLADERF_MODULE_RX).unwrap());
-  info!("bladeRF: gain:             {}dB",      dev.get_gain(BLADERF_MODULE_RX).unwrap());
-  info!("bladeRF: biastee:          {}",        dev.get_bias_tee(BLADERF_MODULE_RX).unwrap());
-
-  let fw_version = dev.fw_version().unwrap();
-  let fpga_version = dev.fpga_version().unwrap();
-  let devinfo: Struct_bladerf_devinfo = dev.get_devinfo().unwrap();
-  trace!("bladeRF: firmware version: {}.{}.{}", fw_version.major, fw_version.minor, fw_version.patch);
-  trace!("bladeRF: fpga version:     {}.{}.{}", fpga_version.major, fpga_version.minor, fpga_version.patch);
-  trace!("bladeRF: fpga size:        {:?}", dev.get_fpga_size().unwrap());
-  info!("bladeRF: serial number:    {}", unsafe { CStr::from_ptr(devinfo.serial.as_ptr()).to_str().unwrap() });
-  trace!("bladeRF: usb bus:          {}", devinfo.usb_bus);
-  trace!("bladeRF: usb addr:         {}", devinfo.usb_addr);
+  info!("bladeRF: sampling rate:    {:.1} MHz", sample_rate as f32 / 1e6);
+  info!("bladeRF: frequency:        {:.1} MHz (LO at {} MHz, {} Hz below)",
+        cli.frequency_list.first().cloned().unwrap_or(cli.frequency) as f32 / 1e6,
+        or_unknown("frequency", dev.get_frequency(BLADERF_MODULE_RX), |lo| format!("{:.1}", lo as f32 / 1e6)), cli.lo_offset);
+  info!("bladeRF: gain mode:        {}",        or_unknown("gain mode", dev.get_gain_mode(BLADERF_MODULE_RX), |mode| format!("{:?}", mode)));
+  info!("bladeRF: gain:             {}dB",      or_unknown("gain", dev.get_gain(BLADERF_MODULE_RX), |gain| gain.to_string()));
+  match dev.get_bias_tee(BLADERF_MODULE_RX) {
+    Ok(enabled) => info!("bladeRF: biastee:          {}", enabled),
+    Err(_) => info!("bladeRF: biastee:          unsupported"),
+  }
+
+  // the device may be coming back from a reconnect, so none of this is worth failing over
+  trace!("bladeRF: firmware version: {}", or_unknown("firmware version", dev.fw_version(), |v| format!("{}.{}.{}", v.major, v.minor, v.patch)));
+  trace!("bladeRF: fpga version:     {}", or_unknown("FPGA version", dev.fpga_version(), |v| format!("{}.{}.{}", v.major, v.minor, v.patch)));
+  trace!("bladeRF: fpga size:        {:?}", dev.get_fpga_size());
+  info!("bladeRF: fpga sha256:      {}", fpga_sha256);
+  let devinfo = dev.get_devinfo()
+    .inspect_err(|e| warn!("Unable to read the device info (error {})", e))
+    .ok();
+  let field = |show: fn(&Struct_bladerf_devinfo) -> String| devinfo.as_ref().map_or_else(|| String::from("unknown"), show);
+  fn text(chars: &[std::ffi::c_char]) -> String {
+    unsafe { CStr::from_ptr(chars.as_ptr()) }.to_string_lossy().into_owned()
+  }
+  info!("bladeRF: serial number:    {}", field(|d| text(&d.serial)));
+  trace!("bladeRF: usb bus:          {}", field(|d| d.usb_bus.to_string()));
+  trace!("bladeRF: usb addr:         {}", field(|d| d.usb_addr.to_string()));
   info!("bladeRF: usb speed:        {:?}", dev.device_speed());
-  info!("bladeRF: instance:         {}", devinfo.instance);
-  info!("bladeRF: manufacturer:     {}", unsafe { CStr::from_ptr(devinfo.manufacturer.as_ptr()).to_str().unwrap() });
-  info!("bladeRF: product:          {}", unsafe { CStr::from_ptr(devinfo.product.as_ptr()).to_str().unwrap() });
+  info!("bladeRF: instance:         {}", field(|d| d.instance.to_string()));
+  info!("bladeRF: manufacturer:     {}", field(|d| text(&d.manufacturer)));
+  info!("bladeRF: product:          {}", field(|d| text(&d.product)));
+
+  Ok(ConfiguredDevice { dev, rx_config, gain_controller, sample_rate })
+}
+
+// A value for the startup printout, or "unknown" with a warning when the device couldn't
+// report it
+fn or_unknown<T>(what: &str, result: Result<T, isize>, show: impl FnOnce(T) -> String) -> String {
+  match result {
+    Ok(value) => show(value),
+    Err(e) => {
+      warn!("Unable to read the {} (error {})", what, e);
+      String::from("unknown")
+    },
+  }
+}
+
+// Tune the LO for receiving `target`, `lo_offset` below it
+fn tune_to(dev: &BladeRFDevice, target: u32, lo_offset: i32) -> Result<(), String> {
+  let lo = lo_frequency(target, lo_offset)?;
+  dev.set_frequency(BLADERF_MODULE_RX, lo)
+    .map(|_| ())
+    .map_err(|e| format!("set_frequency to {} Hz failed (error {})", lo, e))
+}
+
+// Wait for an unplugged device to come back and set it up again. None if the run was stopped
+// in the meantime.
+fn reconnect_device(cli: &Cli, running: &AtomicBool) -> Option<ConfiguredDevice> {
+  let mut attempt = 1;
+  while running.load(Ordering::SeqCst) {
+    thread::sleep(DEVICE_RECONNECT_INTERVAL);
+    match configure_device(cli, false) {
+      Ok(configured) => return Some(configured),
+      Err(e) => debug!("Device not back yet ({}), attempt {}", e, attempt),
+    }
+    attempt += 1;
+  }
+  None
+}
+
+fn produce(sender: Sender<AdsbFrame>, cli: Cli, running: Arc<AtomicBool>, stats: Arc<Stats>, paused: Arc<AtomicBool>) -> Result<(), AppError> {
+  let forward = forwards_frames(&cli);
+  let ConfiguredDevice { mut dev, mut gain_controller, sample_rate, .. } = configure_device(&cli, true)?;
 
-  let mut k: i32;
-  let mut end: i32;
-  let mut ascii_buf: String;
   let mut messages: [u8; 4096] = [0; 4096];
-  let mut message_count: u64 = 0;
+  let mut samples_received: u64 = 0;
+  let mut meta = Struct_bladerf_metadata::default();
+  let corrector = ErrorCorrector::new(CrcFixMode::from_name(&cli.fix_crc).unwrap());
+  let icao_filter = IcaoFilter::new(&cli.icao_allow, &cli.icao_block, cli.icao_unknown);
+  let mut timeouts = TimeoutCounter::default();
+  // only what the metadata flags as an overrun; timeouts and short reads don't mean samples
+  // were lost, and counting them would have --gain-auto-adjust back off on a quiet band
+  let mut overruns: u64 = 0;
+  let mut clip_monitor = ClipMonitor::new(CLIP_CHECK_INTERVAL, Instant::now());
+  let mut rx_log = RateLimitedLogger::default();
+  let mut raw_ring = cli.dump_raw_on_error.as_ref().map(|_| RawRing::new(RAW_RING_BUFFERS, messages.len()));
+  let mut dump_trigger = DumpTrigger::new(Instant::now());
+  let mut outcome: Result<(), AppError> = Ok(());
+  // the first slots of the stream tell whether the ADS-B image is actually loaded. Packets and
+  // interleaved channels don't come in plain slots, so those aren't checked.
+  let mut image_census = if cli.packet_meta || cli.channels > 1 { None } else { Some(SlotCensus::new()) };
+  // cleared if the device was unplugged and the run stopped before it came back
+  let mut device_open = true;
+
+  let frequencies = if cli.frequency_list.is_empty() { vec![cli.frequency] } else { cli.frequency_list.clone() };
+  if frequencies.len() > 1 {
+    info!("Cycling through {:?} Hz, {}ms per frequency", frequencies, cli.dwell_ms);
+  }
+  let mut scheduler = FrequencyScheduler::new(frequencies, Duration::from_millis(cli.dwell_ms), RETUNE_SETTLE, Instant::now());
+  let mut warmup = WarmupWindow::new(Duration::from_millis(cli.warmup_discard_ms), sample_rate);
+
+  // kept alive for the whole stream, dropping it removes the socket
+  let control = match cli.control_socket {
+    Some(ref path) => Some(ControlSocket::bind(path, running.clone())
+      .map_err(|e| AppError::Config(format!("unable to listen on control socket {}: {}", path.display(), e)))?),
+    None => None,
+  };
 
   let pb = ProgressBar::new_spinner();
-  pb.enable_steady_tick(Duration::from_millis(120));
+  if !shows_spinner(&cli) {
+    pb.set_draw_target(ProgressDrawTarget::hidden());
+  }
   pb.set_style(
     ProgressStyle::with_template("{spinner:40..white} {msg}")
       .unwrap()
@@ -314,114 +1436,1354 @@ fn produce(sender: Sender<String>, cli: Cli, running: Arc<AtomicBool>) {
       ]),
     );
 
-  while running.load(Ordering::SeqCst) {
-    dev.sync_rx(&mut messages, 1024, None, 5000).unwrap();
+  // Refresh the spinner on a timer rather than per frame, so it stays readable at high rates.
+  // It only animates while frames are arriving, so a stalled receiver is obvious at a glance.
+  let display = {
+    let pb = pb.clone();
+    let running = running.clone();
+    let stats = stats.clone();
+    thread::Builder::new().name(String::from("adsb-display")).spawn(move || {
+      let mut meter = RateMeter::new(RATE_TIME_CONSTANT, 0, Instant::now());
+      let mut last_count = 0;
+      let mut last_frame: Option<Instant> = None;
+      let mut last_refresh: Option<Instant> = None;
+      while running.load(Ordering::SeqCst) {
+        thread::sleep(SPINNER_TICK);
+        let now = Instant::now();
+        let count = stats.messages.load(Ordering::Relaxed);
+        if count != last_count {
+          last_count = count;
+          last_frame = Some(now);
+        }
+        let activity = Activity::from_last_frame(last_frame.map(|at| now - at), IDLE_AFTER);
+        if activity == Activity::Active {
+          pb.tick();
+        }
 
-    for i in (0..4096).step_by(16) {
-      if (messages[i as usize] & 0x01) == 1 {
-        if (messages[i as usize + 2] & 0x80) == 0x80 {
-          end = 14;
-        } else {
-          end = 7;
+        if !matches!(last_refresh, Some(at) if now - at < RATE_DISPLAY_INTERVAL) {
+          last_refresh = Some(now);
+          let rate = meter.update(count, now);
+          pb.set_message(spinner_message(activity, count, rate));
         }
+      }
+    }).expect("failed to spawn display thread")
+  };
 
-        ascii_buf = String::from("*");
-        k = 0;
-        while k < end {
-          ascii_buf.push_str(&format!("{:02x}", messages[i as usize + 2 + k as usize]));
-          k += 1;
+  while running.load(Ordering::SeqCst) {
+    // with metadata, read whatever is available now rather than waiting for a scheduled timestamp
+    meta.flags = BLADERF_META_FLAG_RX_NOW;
+    let meta_arg = if cli.meta_timestamps || cli.packet_meta { Some(&mut meta) } else { None };
+    let samples = match dev.sync_rx(&mut messages, 1024, meta_arg, cli.rx_timeout) {
+      Ok(samples) => {
+        if let Some(recovered) = timeouts.succeeded() {
+          info!("sync_rx recovered after {} timeout(s)", recovered);
+        }
+        if (cli.meta_timestamps || cli.packet_meta) && (meta.status & BLADERF_META_STATUS_OVERRUN) != 0 {
+          let timestamp = meta.timestamp;
+          if let Some(message) = rx_log.event("overrun", Instant::now(), || {
+            format!("sync_rx reported an overrun, samples were lost before timestamp {}", timestamp)
+          }) {
+            warn!("{}", message);
+          }
+          overruns += 1;
+        }
+        // packets are as long as the message they carry
+        if samples < 1024 && !cli.packet_meta {
+          if let Some(message) = rx_log.event("short_read", Instant::now(), || format!("Short read from sync_rx, {} of 1024 samples", samples)) {
+            warn!("{}", message);
+          }
+        }
+        samples as usize
+      },
+      Err(e) if e == BLADERF_ERR_TIMEOUT as isize => {
+        // Timeouts happen transiently under USB contention, so retry until the threshold is hit
+        stats.rx_timeouts.fetch_add(1, Ordering::Relaxed);
+        let consecutive_timeouts = match timeouts.timed_out() {
+          Ok(consecutive) => consecutive,
+          Err(consecutive) => {
+            error!("sync_rx timed out {} times in a row, stopping RX", consecutive);
+            running.store(false, Ordering::SeqCst);
+            outcome = Err(AppError::Device(String::from("sync_rx"), e));
+            break;
+          },
+        };
+        if let Some(message) = rx_log.event("sync_rx_timeout", Instant::now(), || {
+          format!("sync_rx timed out ({}/{}), retrying", consecutive_timeouts, MAX_CONSECUTIVE_TIMEOUTS)
+        }) {
+          warn!("{}", message);
+        }
+        continue;
+      },
+      Err(e) if is_disconnect(e) => {
+        // the sinks and the output thread carry on, they just see no frames for a while
+        error!("bladeRF device disappeared (sync_rx error {}), waiting for it to come back", e);
+        dev.close();
+        let configured = match reconnect_device(&cli, &running) {
+          Some(configured) => configured,
+          None => {
+            device_open = false;
+            break;
+          },
+        };
+        dev = configured.dev;
+        gain_controller = configured.gain_controller;
+        // carry on with the frequency the schedule or a control command was on
+        if Ok(configured.rx_config.frequency) != lo_frequency(scheduler.current(), cli.lo_offset) {
+          if let Err(e) = tune_to(&dev, scheduler.current(), cli.lo_offset) {
+            warn!("Unable to retune to {} Hz: {}", scheduler.current(), e);
+          }
         }
-        ascii_buf.push_str(";\n") ;
+        timeouts.reset();
+        warmup.restart(samples_received);
+        info!("bladeRF device is back, resuming RX");
+        continue;
+      },
+      Err(e) => {
+        error!("sync_rx failed with error {}, stopping RX", e);
+        running.store(false, Ordering::SeqCst);
+        outcome = Err(AppError::Device(String::from("sync_rx"), e));
+        break;
+      },
+    };
+
+    // a short read leaves the tail of the buffer as the last transfer wrote it
+    clear_unfilled(&mut messages, samples);
+
+    // packets arrive whenever the FPGA has a message, so only the device counter tells the time
+    if cli.packet_meta {
+      samples_received = meta.timestamp;
+    }
+
+    // paused by SIGUSR1: the device is still drained so it doesn't overrun, but nothing read is
+    // demodulated, so neither the statistics nor the output thread's dedup see it
+    let is_paused = paused.load(Ordering::SeqCst);
+
+    if let Some(census) = image_census.as_mut().filter(|_| !is_paused) {
+      census.add(received(&messages, samples));
+      match census.verdict() {
+        ImageVerdict::Unknown => {},
+        ImageVerdict::Adsb => image_census = None,
+        ImageVerdict::WrongImage => {
+          error!("This doesn't look like the ADS-B FPGA image: {:.0}% of slots are flagged as frames, {} of {} with a valid downlink format",
+                 census.flagged_ratio() * 100.0, census.valid_df, census.flagged);
+          error!("Frames will be garbage, check that --fpga-path is the ADS-B image (adsbx*.rbf) for this board");
+          image_census = None;
+        },
+      }
+    }
+
+    // Anything read while the LO settles after a retune is garbage
+    let frames = if is_paused {
+      Vec::new()
+    } else if scheduler.settling(Instant::now()) {
+      trace!("Discarding buffer read while settling on {} Hz", scheduler.current());
+      Vec::new()
+    } else if cli.packet_meta {
+      extract_packet_frame(received(&messages, samples)).into_iter().collect()
+    } else if cli.channels > 1 {
+      // each channel's frames are timed against that channel's own samples
+      deinterleave_channels(received(&messages, samples), cli.channels as usize).iter()
+        .flat_map(|channel| extract_frames(channel))
+        .collect()
+    } else {
+      // only parse what this transfer filled in, the rest is stale
+      extract_frames(received(&messages, samples))
+    };
+
+    for mut frame in frames {
+      // the device's counter stays right across overruns, our own count doesn't
+      let first_sample = if cli.meta_timestamps || cli.packet_meta { meta.timestamp } else { samples_received };
+      frame.timestamp = Some(samples_to_mlat_ticks(first_sample + frame.sample_offset(), sample_rate));
+      frame.frequency = Some(scheduler.current());
+
+      // timed by our own count, which the window is restarted against
+      if warmup.discards(samples_received + frame.sample_offset()) {
+        trace!("Dropping frame from the warmup window: {}", frame.to_avr().trim_end());
+        continue;
+      }
+
+      if !accept_frame(&mut frame, &cli, &stats, &corrector, &icao_filter) {
+        continue;
+      }
+
+      trace!("Thread 1");
+      debug!("ADS-B message is: {}", frame.to_avr());
+
+      // only send to other thread if destined for remote socket
+      if forward && sender.send(frame).is_err() {
+        debug!("Output thread has stopped, stopping RX");
+        running.store(false, Ordering::SeqCst);
+        break;
+      }
+
+      // update counter
+      stats.messages.fetch_add(1, Ordering::Relaxed);
+    }
+    // with several channels, sync_rx counts the samples of all of them
+    if !cli.packet_meta {
+      samples_received += (samples / cli.channels as usize) as u64;
+    }
 
-        trace!("Thread 1");
-        debug!("ADS-B message is: {}", ascii_buf);
+    // a cheap look at every Nth sample for a saturating ADC, which corrupts frames silently
+    let (checked, clipped) = count_clipped(received(&messages, samples), CLIP_CHECK_STRIDE);
+    let checked = stats.samples_checked.fetch_add(checked, Ordering::Relaxed) + checked;
+    let clipped = stats.samples_clipped.fetch_add(clipped, Ordering::Relaxed) + clipped;
+    if let Some(ratio) = clip_monitor.poll(checked, clipped, Instant::now()) {
+      if ratio > CLIP_WARN_RATIO {
+        warn!("ADC clipping on {:.1}% of samples, consider lowering --gain", ratio * 100.0);
+      }
+    }
 
-        // only send to other thread if destined for remote socket
-        if cli.remote {
-          sender.send(ascii_buf).unwrap();
+    if let Some(controller) = gain_controller.as_mut() {
+      let messages = stats.messages.load(Ordering::Relaxed);
+      let crc_failures = stats.crc_failures.load(Ordering::Relaxed);
+      if let Some(gain) = controller.poll(messages, crc_failures, overruns, Instant::now()) {
+        info!("Adjusting gain to {}dB", gain);
+        if let Err(e) = dev.set_gain(BLADERF_MODULE_RX, gain) {
+          warn!("Unable to set gain to {}dB (error {})", gain, e);
         }
+      }
+    }
 
-        // update counter
-        message_count = message_count + 1;
-        pb.set_message(format!("Processing message {}", message_count.separate_with_commas()));
+    if let (Some(ring), Some(dir)) = (raw_ring.as_mut(), cli.dump_raw_on_error.as_ref()) {
+      ring.push(received(&messages, samples));
+      if let Some(reason) = dump_trigger.poll(overruns, stats.crc_failures.load(Ordering::Relaxed), Instant::now()) {
+        match ring.dump(dir, SystemTime::now()) {
+          Ok(path) => warn!("{:?}, wrote the last {} raw buffers to {}", reason, ring.buffers().count(), path.display()),
+          Err(e) => warn!("{:?}, but unable to write raw buffers to {}: {}", reason, dir.display(), e),
+        }
       }
     }
-  }
 
+    // report bursts of overruns that have since stopped
+    for summary in rx_log.flush(Instant::now()) {
+      warn!("{}", summary);
+    }
+
+    // Retune between transfers once the dwell is up
+    if let Some(frequency) = scheduler.poll(Instant::now()) {
+      debug!("Retuning to {} Hz", frequency);
+      if let Err(e) = tune_to(&dev, frequency, cli.lo_offset) {
+        warn!("Unable to retune to {} Hz: {}", frequency, e);
+      }
+      warmup.restart(samples_received);
+    }
 
+    // Live changes from --control-socket, also between transfers so nothing else touches the device
+    if let Some((_, ref requests)) = control {
+      while let Ok(request) = requests.try_recv() {
+        let result = match request.command {
+          ControlCommand::Gain(_) if gain_controller.is_some() => Err(String::from("gain is adjusted automatically (--gain-auto-adjust)")),
+          ControlCommand::Frequency(frequency) => tune_to(&dev, frequency, cli.lo_offset),
+          command => command.apply(&dev),
+        };
+        if result.is_ok() {
+          info!("Applied control command {:?}", request.command);
+          // a frequency set by hand replaces --frequency-list
+          if let ControlCommand::Frequency(frequency) = request.command {
+            scheduler.retune(frequency, Instant::now());
+            warmup.restart(samples_received);
+          }
+        }
+        request.reply(result);
+      }
+    }
+  }
+
+  display.join().unwrap();
   pb.finish_with_message("Done");
+  info!("ADC clipping on {:.2}% of checked samples", stats.snapshot().clipping_percent());
   info!("Closing bladeRF device");
-  // Disable RX, shutting down our underlying RX stream
-  dev.enable_module(BLADERF_MODULE_RX, false).unwrap();
-  dev.close();
+  // Disable RX, shutting down our underlying RX stream. Failing that mustn't mask whatever
+  // ended the run, and the device is closed either way.
+  if device_open {
+    if let Err(e) = dev.shutdown(BLADERF_MODULE_RX) {
+      warn!("Unable to disable RX during shutdown (error {}), closed the device anyway", e);
+    }
+  }
+  outcome
+}
+
+// The wrong port means no signal at all, so only accept ports the board lists
+fn set_rx_port(dev: &BladeRFDevice, port: &str) -> Result<(), AppError> {
+  let rx_channel = bladerf_channel_rx(0);
+  let port = port.to_uppercase();
+  let ports = match dev.get_rf_ports(rx_channel) {
+    Ok(ports) => ports,
+    Err(e) if e == BLADERF_ERR_UNSUPPORTED as isize => {
+      return Err(AppError::Config(format!("--rx-port {} was requested but this board has no selectable RX ports", port)));
+    },
+    Err(e) => return Err(AppError::Device(String::from("get_rf_ports"), e)),
+  };
+  if !ports.contains(&port) {
+    return Err(AppError::Config(format!("RX port {} isn't available on this board (available: {})", port, ports.join(", "))));
+  }
+  dev.set_rf_port(rx_channel, &port).map(|_| ()).map_err(|e| AppError::Device(String::from("set_rf_port"), e))
+}
+
+// bladeRF 1.x boards have no bias tee, which only matters if one was asked for
+fn set_bias_tee(dev: &BladeRFDevice, bias_tee: bool) -> Result<(), AppError> {
+  match dev.set_bias_tee(BLADERF_MODULE_RX, bias_tee) {
+    Ok(_) => Ok(()),
+    Err(e) if e == BLADERF_ERR_UNSUPPORTED as isize && !bias_tee => {
+      warn!("This board has no bias tee, continuing without one");
+      Ok(())
+    },
+    Err(e) if e == BLADERF_ERR_UNSUPPORTED as isize => {
+      error!("--bias-tee was requested but this board has no bias tee");
+      Err(AppError::Device(String::from("set_bias_tee"), e))
+    },
+    Err(e) => Err(AppError::Device(String::from("set_bias_tee"), e)),
+  }
+}
+
+// Wait up to `timeout` for the next frame for the output thread, and whether it should stop:
+// once the producers are gone, or when a quiet spell finds the run has been stopped
+fn next_output(rx: &Receiver<AdsbFrame>, timeout: Duration, running: &AtomicBool) -> (Option<AdsbFrame>, bool) {
+  match rx.recv_timeout(timeout) {
+    Ok(frame) => (Some(frame), false),
+    Err(RecvTimeoutError::Timeout) => (None, !running.load(Ordering::SeqCst)),
+    Err(RecvTimeoutError::Disconnected) => (None, true),
+  }
+}
+
+// Run `body` on a named thread. A panic is caught and logged with the thread's name instead of
+// silently dropping whatever channel the thread owned, and any failure clears `running` so the
+// other threads wind down rather than waiting on input that will never come. With the release
+// profile's panic = 'abort' there's nothing to catch, but the panic message still names the thread.
+fn spawn_worker<F>(name: &str, running: Arc<AtomicBool>, body: F) -> thread::JoinHandle<Result<(), AppError>>
+where
+  F: FnOnce() -> Result<(), AppError> + Send + 'static,
+{
+  let thread_name = String::from(name);
+  thread::Builder::new().name(thread_name.clone()).spawn(move || {
+    let result = match panic::catch_unwind(AssertUnwindSafe(body)) {
+      Ok(result) => result,
+      Err(payload) => {
+        let msg = match payload.downcast_ref::<&str>() {
+          Some(msg) => msg.to_string(),
+          None => payload.downcast_ref::<String>().cloned().unwrap_or_else(|| String::from("unknown panic")),
+        };
+        error!("Thread {} panicked: {}, shutting down", thread_name, msg);
+        Err(AppError::Panic(thread_name, msg))
+      },
+    };
+
+    if result.is_err() {
+      running.store(false, Ordering::SeqCst);
+    }
+    result
+  }).expect("failed to spawn thread")
+}
+
+// The completion script for `shell`, for the completions subcommand
+fn write_completions(shell: Shell, out: &mut dyn io::Write) {
+  let mut command = Cli::command();
+  let name = command.get_name().to_string();
+  clap_complete::generate(shell, &mut command, name, out);
+}
+
+// What --version prints: this crate's version and the libbladeRF it's running against
+fn long_version(lib_version: &Struct_bladerf_version) -> String {
+  format!("{} (libbladeRF {}.{}.{})", env!("CARGO_PKG_VERSION"), lib_version.major, lib_version.minor, lib_version.patch)
+}
+
+// Exit codes are documented in the README and defined in bladerf_adsb::error
+fn main() {
+  if let Err(e) = run() {
+    eprintln!("Error: {}", e);
+    process::exit(e.exit_code());
+  }
 }
 
 // References:
 // https://docs.rs/clap/latest/clap/enum.ArgAction.html
-fn main() {
+fn run() -> Result<(), AppError> {
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
 
 
-    let cli = Cli::parse();
+    // --version also reports the libbladeRF we're linked against, which has to be looked up at runtime
+    let lib_version = library_version();
+    let parsed = Cli::command()
+      .long_version(long_version(&lib_version))
+      .try_get_matches()
+      .and_then(|matches| Cli::from_arg_matches(&matches));
+    let cli = match parsed {
+      Ok(cli) => cli,
+      // --help and --version
+      Err(e) if !e.use_stderr() => e.exit(),
+      Err(e) => {
+        let _ = e.print();
+        return Err(AppError::Config(String::from("invalid command line arguments")));
+      },
+    };
+    if cli.list_formats {
+      print!("{}", describe_formats());
+      return Ok(());
+    }
+    if let Some(Command::Completions { shell }) = cli.command {
+      write_completions(shell, &mut io::stdout());
+      return Ok(());
+    }
+    validate_sample_rate(cli.sample_rate).map_err(AppError::Config)?;
+    check_decode_level(&cli).map_err(AppError::Config)?;
+    check_output_format(&cli).map_err(AppError::Config)?;
+    if cli.replay_message_size <= META_HEADER_BYTES {
+      return Err(AppError::Config(format!("--replay-message-size must be larger than the {} byte header", META_HEADER_BYTES)));
+    }
+    if let Some(size) = cli.buffer_size {
+      if size == 0 || size % BUFFER_SIZE_MULTIPLE != 0 {
+        return Err(AppError::Config(format!("--buffer-size must be a multiple of {}", BUFFER_SIZE_MULTIPLE)));
+      }
+    }
+    if let UnknownShard::Sink(index) = cli.shard_unknown {
+      let outputs = cli.sinks.len() + inherited_fds(&cli).len();
+      if index >= outputs {
+        return Err(AppError::Config(format!("--shard-unknown {} but only {} --sink and --inherit-fd outputs given", index + 1, outputs)));
+      }
+    }
  
-    // setup logging
+    // setup logging, always on stderr so stdout only ever carries frames
+    let log_level = log_level(&cli);
     let mut builder = Builder::new();
-    builder.filter_level(LevelFilter::from_str(cli.log_level.as_str()).unwrap());
+    builder.target(Target::Stderr);
+    builder.filter_level(log_level);
     builder.parse_write_style(cli.log_style.as_str());
     builder.init();
 
-    let remote: bool = cli.remote.clone();
-
-    let addr: SocketAddr;
-    let mut stream: Option<TcpStream> = None;
-    if remote {
-      addr = SocketAddr::new(
-        IpAddr::V4(cli.remote_ip.clone()),
-        cli.remote_port.clone()
-      );
-      info!("Connecting to {}", addr);
-      stream = match TcpStream::connect(addr) {
-        Ok(stream) => Some(stream),
-        Err(_) => panic!("Unable to connect to socket"),
-      };
+    info!("libbladeRF version:        {}.{}.{} ({})", lib_version.major, lib_version.minor, lib_version.patch,
+          if lib_version.describe.is_null() { "" } else { unsafe { CStr::from_ptr(lib_version.describe).to_str().unwrap_or("") } });
+
+    GlobalConfig::new()
+      .usb_reset_on_open(cli.usb_reset_on_open)
+      .log_level(log_level.into())
+      .apply();
+
+    match cli.command {
+      Some(Command::Info) => {
+        let dev = open_device(cli.device.as_ref().map(DeviceSelector::to_device_string)).map_err(AppError::DeviceOpen)?;
+        print!("{}", render_table(&device_info_rows(&dev)));
+        dev.close();
+        return Ok(());
+      },
+      Some(Command::Throughput { duration }) => return throughput(&cli, Duration::from_secs(duration)),
+      Some(Command::Backup { ref dir }) => return backup(&cli, dir),
+      Some(Command::Completions { .. }) | None => {},
     }
 
+    let plan = output_plan(&cli);
+    let mut output_stdout = plan.stdout;
+    #[cfg(feature = "websocket")]
+    let ws_sink = match cli.ws_port {
+      Some(port) => Some(WebSocketSink::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port), running.clone())
+        .map_err(|e| AppError::Config(format!("unable to listen for WebSocket clients on port {}: {}", port, e)))?),
+      None => None,
+    };
+    #[cfg(feature = "websocket")]
+    let websocket = ws_sink.is_some();
+    #[cfg(not(feature = "websocket"))]
+    let websocket = false;
+    let output_format = OutputFormat::from_name(cli.output_format.as_str()).unwrap();
+    let stats_file = cli.stats_file.clone();
+    let strict = cli.strict;
+    let stats_file_interval = Duration::from_secs(cli.stats_file_interval);
+    let stats_remote = cli.stats_remote;
+    let max_frame_rate = cli.max_frame_rate;
+    let dedup_window = Duration::from_millis(cli.dedup_window_ms);
+    let receiver_position = cli.receiver_position;
+    let tag = cli.tag.clone();
+    let decode_level = cli.decode_level;
+    let reorder_window = Duration::from_millis(cli.reorder_window_ms);
+    let stats_interval = Duration::from_secs(cli.stats_interval);
+    // --sink (and --inherit-fd) replace the single --remote-ip/--remote-port server
+    let inherit_fds = inherited_fds(&cli);
+    let sink_specs: Vec<(SocketAddr, OutputFormat)> = if cli.sinks.is_empty() && !inherit_fds.is_empty() {
+      Vec::new()
+    } else if cli.sinks.is_empty() {
+      vec![(SocketAddr::new(IpAddr::V4(cli.remote_ip.clone()), cli.remote_port.clone()), output_format)]
+    } else {
+      cli.sinks.iter().map(|spec| (spec.addr, spec.format.unwrap_or(output_format))).collect()
+    };
+    // one spool per server, opened up front so a bad --spool-dir is a configuration error
+    let spools: Vec<Option<Spool>> = match cli.spool_dir {
+      Some(ref dir) if plan.remote != RemoteMode::Off => sink_specs.iter()
+        .map(|(addr, format)| Spool::open(dir, &format!("tcp-{}-{}", addr, format.name()), cli.spool_max_bytes).map(Some))
+        .collect::<io::Result<_>>()
+        .map_err(|e| AppError::Config(format!("unable to spool to {}: {}", dir.display(), e)))?,
+      _ => sink_specs.iter().map(|_| None).collect(),
+    };
+    let spooling = spools.iter().any(Option::is_some);
+    let policy = ReconnectPolicy {
+      max_attempts: cli.reconnect_attempts.clone(),
+      ..ReconnectPolicy::default()
+    };
+    let sink_buffer = SinkBuffer {
+      bytes: cli.sink_buffer_bytes,
+      flush_interval: Duration::from_millis(cli.flush_interval_ms),
+    };
+    let mut sharder = if cli.shard { Some(Sharder::new(sink_specs.len() + inherit_fds.len(), cli.shard_unknown)) } else { None };
+
+    let mut interrupts = InterruptCounter::default();
     ctrlc::set_handler(move || {
-      debug!("received Ctrl+C!");
-      r.store(false, Ordering::SeqCst);
+      match interrupts.press(Instant::now()) {
+        InterruptAction::Shutdown => {
+          warn!("Received Ctrl+C, shutting down; press it again within {}s to force quit", FORCE_QUIT_WINDOW.as_secs());
+          r.store(false, Ordering::SeqCst);
+        },
+        InterruptAction::ForceQuit => {
+          error!("Received a second Ctrl+C, force quitting");
+          process::exit(EXIT_FORCE_QUIT);
+        },
+      }
     })
-    .expect("Error setting Ctrl-C handler");
+    .map_err(|e| AppError::Config(format!("unable to set Ctrl-C handler: {}", e)))?;
+
+    // SIGUSR1 pauses and resumes forwarding, SIGUSR2 logs the statistics. Like Ctrl+C they only
+    // flip a flag, for the RX and output threads to act on.
+    let paused = Arc::new(AtomicBool::new(false));
+    let dump_stats = Arc::new(AtomicBool::new(false));
+    let toggle = paused.clone();
+    // safe: the handler only touches an atomic
+    unsafe { signal_hook::low_level::register(SIGUSR1, move || { toggle.fetch_xor(true, Ordering::SeqCst); }) }
+      .map_err(|e| AppError::Config(format!("unable to set SIGUSR1 handler: {}", e)))?;
+    signal_hook::flag::register(SIGUSR2, dump_stats.clone())
+      .map_err(|e| AppError::Config(format!("unable to set SIGUSR2 handler: {}", e)))?;
+
+  // Carry on from the totals of previous runs
+  let stats = Arc::new(match stats_file {
+    Some(ref path) => match stats::load(path) {
+      Ok(base) => {
+        info!("Loaded statistics from {}", path.display());
+        Stats::with_base(base)
+      },
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => Stats::new(),
+      Err(e) => {
+        warn!("Unable to load statistics from {} ({}), starting from zero", path.display(), e);
+        Stats::new()
+      },
+    },
+    None => Stats::new(),
+  });
+
+  if let Some(ref path) = stats_file {
+    let path = path.clone();
+    let saver_stats = stats.clone();
+    let saver_running = running.clone();
+    thread::Builder::new().name(String::from("adsb-stats")).spawn(move || {
+      let mut last_save = Instant::now();
+      while saver_running.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(250));
+        if last_save.elapsed() >= stats_file_interval {
+          if let Err(e) = stats::save(&path, &saver_stats.snapshot()) {
+            warn!("Unable to save statistics to {}: {}", path.display(), e);
+          }
+          last_save = Instant::now();
+        }
+      }
+    }).expect("failed to spawn stats thread");
+  }
 
-  // Read messages and print them out
+  // Statistics go over their own connection, so a slow or missing collector never holds up frames
+  if let Some(addr) = stats_remote {
+    let remote_stats = stats.clone();
+    let remote_running = running.clone();
+    let remote_policy = policy.clone();
+    let mut sender = StatsSender::new(addr, remote_policy);
+    remote_stats.add_sink(sender.name(), sender.stats());
+    thread::Builder::new().name(String::from("adsb-stats-remote")).spawn(move || {
+      let mut last_send = Instant::now();
+      while remote_running.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(250));
+        if last_send.elapsed() >= stats_interval {
+          last_send = Instant::now();
+          if let Err(e) = sender.send(stats::to_json_line(&remote_stats.snapshot()).as_bytes(), last_send) {
+            warn!("Unable to connect to stats server {} ({}), giving up on sending statistics", addr, e);
+            return;
+          }
+        }
+      }
+    }).expect("failed to spawn stats thread");
+  }
+
+  // read up front, so a bad --inject file is a configuration error rather than a failed worker
+  let injected = match cli.inject {
+    Some(ref path) => {
+      let file = File::open(path)
+        .map_err(|e| AppError::Config(format!("unable to open inject file {}: {}", path.display(), e)))?;
+      Some(read_injected(BufReader::new(file)).map_err(|e| AppError::Config(format!("unable to inject {}: {}", path.display(), e)))?)
+    },
+    None => None,
+  };
+
+  // Start configuring the device straight away so it comes up while we wait for the server
   let (tx, rx) = channel();
-  thread::spawn(move || produce(tx, cli, running));
-  loop {
-    match rx.recv() {
-      Ok(a) => {
-        trace!("Thread 2");
-        if remote {
-          // Send ASCII hex message to socket
-          if let Some(ref mut stream) = stream {
-            let bytes_written = match stream.write(a.as_bytes()) {
-              Ok(b) => b,
-              Err(_) => panic!("Error sending buffer to server"),
+  let producer_running = running.clone();
+  let producer_stats = stats.clone();
+  let producer_paused = paused.clone();
+  let producer = spawn_worker("adsb-rx", running.clone(), move || {
+    if let Some(core) = cli.cpu_affinity {
+      match pin_current_thread(core) {
+        Ok(()) => info!("Pinned the RX thread to CPU core {}", core),
+        Err(e) => warn!("Not pinning the RX thread: {}", e),
+      }
+    }
+    if let Some(frames) = injected {
+      if !inject(frames, &tx, &cli, &producer_stats) {
+        producer_running.store(false, Ordering::SeqCst);
+        return Ok(());
+      }
+    }
+    if cli.inject_only {
+      info!("Injection finished");
+      Ok(())
+    } else if cli.replay.is_some() {
+      replay(tx, cli, producer_running, producer_stats)
+    } else if cli.replay_frames.is_some() {
+      replay_frames(tx, cli, producer_running, producer_stats)
+    } else if cli.simulate {
+      simulate(tx, cli, producer_running, producer_stats)
+    } else {
+      produce(tx, cli, producer_running, producer_stats, producer_paused)
+    }
+  });
+
+  let output_running = running.clone();
+  let output_stats = stats.clone();
+  let output = spawn_worker("adsb-out", running.clone(), move || {
+    let running = output_running;
+    let stats = output_stats;
+    let mut outcome: Result<(), AppError> = Ok(());
+    let mut output_log = RateLimitedLogger::default();
+
+    // each with its position in sink_specs then inherit_fds, which is its shard under --shard
+    let mut sinks: Vec<(Box<dyn FrameSink>, OutputFormat, usize)> = Vec::new();
+    let mut last_error = String::new();
+    if plan.remote == RemoteMode::Optional {
+      // nobody asked for the server, so don't wait around for it
+      let (addr, format) = sink_specs[0];
+      match TcpSink::try_connect(addr, policy.clone()) {
+        Ok(sink) => {
+          info!("Sending {} to {}", format.name(), addr);
+          let sink = sink.with_buffer(sink_buffer.clone());
+          let sink = match spools.into_iter().next().flatten() {
+            Some(spool) => sink.with_spool(spool),
+            None => sink,
+          };
+          sinks.push((Box::new(sink), format, 0));
+        },
+        Err(e) => {
+          if !output_stdout {
+            warn!("Nothing listening on {} ({}), decoding locally and writing frames to stdout; pass --remote true to wait for the server", addr, e);
+            output_stdout = true;
+          } else {
+            warn!("Nothing listening on {} ({}), only writing frames to stdout", addr, e);
+          }
+        },
+      }
+    } else if plan.remote == RemoteMode::Required {
+      let fd_shards = sink_specs.len();
+      for (shard, ((addr, format), spool)) in sink_specs.into_iter().zip(spools).enumerate() {
+        match TcpSink::connect(addr, policy.clone(), &running) {
+          Ok(sink) => {
+            info!("Sending {} to {}", format.name(), addr);
+            let sink = sink.with_buffer(sink_buffer.clone());
+            let sink = match spool {
+              Some(spool) => sink.with_spool(spool),
+              None => sink,
             };
+            sinks.push((Box::new(sink), format, shard));
+          },
+          Err(e) => {
+            error!("Unable to connect to {}: {}", addr, e);
+            last_error = format!("{}: {}", addr, e);
+            // still reported, so it's clear which server never came up
+            let unreachable = Arc::new(SinkStats::new());
+            unreachable.failed(e.to_string());
+            stats.add_sink(format!("tcp:{}", addr), unreachable);
+          },
+        }
+      }
+      for (i, fd) in inherit_fds.into_iter().enumerate() {
+        match FdSink::inherit(fd) {
+          Ok(sink) => sinks.push((Box::new(sink), output_format, fd_shards + i)),
+          Err(e) => {
+            error!("Unable to use inherited descriptor {}: {}", fd, e);
+            last_error = format!("fd {}: {}", fd, e);
+            let unusable = Arc::new(SinkStats::new());
+            unusable.failed(e.to_string());
+            stats.add_sink(format!("fd:{}", fd), unusable);
+          },
+        }
+      }
+      if sinks.is_empty() && !output_stdout && !websocket {
+        running.store(false, Ordering::SeqCst);
+        outcome = Err(AppError::RemoteUnreachable(last_error.clone()));
+      }
+    }
+
+    if output_format == OutputFormat::None {
+      info!("Discarding frames for --output-format none");
+      sinks.push((Box::new(NullSink::new()), output_format, 0));
+    }
+    for (sink, _, _) in &sinks {
+      stats.add_sink(sink.name(), sink.stats());
+    }
+
+    // Stdout is line buffered, and flushed after every frame for the binary formats
+    let mut stdout = if output_stdout { Some(io::stdout()) } else { None };
+    let stdout_stats = Arc::new(SinkStats::new());
+    if output_stdout {
+      stats.add_sink(String::from("stdout"), stdout_stats.clone());
+    }
+
+    // decoded formats need every frame to go through the registry so positions can be paired.
+    // none decodes too, as far as --decode-level goes, so benchmarks include the decoding.
+    let decode = (output_stdout && output_format.is_decoded()) || sinks.iter().any(|(_, format, _)| format.is_decoded()) || websocket
+      || (output_format == OutputFormat::None && decode_level == DecodeLevel::Full);
+    let mut registry = match receiver_position {
+      Some(position) => AircraftRegistry::with_reference(position),
+      None => AircraftRegistry::new(),
+    };
+    let mut last_prune = Instant::now();
+    let mut dedup = if !dedup_window.is_zero() { Some(Deduplicator::new(dedup_window, Instant::now())) } else { None };
+    let mut reorder = if !reorder_window.is_zero() { Some(ReorderBuffer::new(reorder_window)) } else { None };
+    let mut frame_limiter = if max_frame_rate > 0 { Some(FrameRateLimiter::new(max_frame_rate, Instant::now())) } else { None };
+    let mut pause = Pause::default();
+    let mut last_stats_log = Instant::now();
+
+    // Read messages and print them out, waking up regularly so a shutdown during a quiet
+    // spell takes effect straight away rather than when the producer drops its sender
+    loop {
+      let mut timeout = reorder.as_ref()
+        .and_then(|reorder| reorder.next_due(Instant::now()))
+        .map_or(OUTPUT_POLL_INTERVAL, |due| due.min(OUTPUT_POLL_INTERVAL));
+      if sink_buffer.bytes > 0 {
+        timeout = timeout.min(sink_buffer.flush_interval);
+      }
+      let (received, finished) = next_output(&rx, timeout, &running);
+      if finished {
+        debug!("Shutting down output");
+      }
+
+      if dump_stats.swap(false, Ordering::SeqCst) {
+        info!("Statistics (SIGUSR2): {}", stats::to_json_line(&stats.snapshot()).trim_end());
+      }
+      // with no output to watch, the statistics are the point of --output-format none
+      if output_format == OutputFormat::None && last_stats_log.elapsed() >= stats_interval {
+        info!("Statistics: {}", stats::to_json_line(&stats.snapshot()).trim_end());
+        last_stats_log = Instant::now();
+      }
+      // while paused the RX thread stops demodulating what it reads; what is handed over anyway,
+      // replayed or already on its way, is dropped here before any decoding
+      let received = pause.filter(paused.load(Ordering::SeqCst), received);
 
-            debug!("Sent {}/{} bytes (\"{}\") to server", bytes_written, a.len(), a.replace("\n", ""));
-            if bytes_written < a.len() {
-              warn!("Sent {}/{} bytes to server", bytes_written, a.len());
+      // with --reorder-window-ms frames go out in timestamp order, held back for the window
+      let ready: Vec<AdsbFrame> = match reorder.as_mut() {
+        Some(reorder) => {
+          let mut ready: Vec<AdsbFrame> = received.and_then(|frame| reorder.push(frame, Instant::now())).into_iter().collect();
+          ready.extend(if finished { reorder.flush() } else { reorder.pop_ready(Instant::now()) });
+          ready
+        },
+        None => received.into_iter().collect(),
+      };
+
+      for frame in ready {
+        trace!("Thread 2");
+        if sinks.is_empty() && stdout.is_none() && !websocket {
+          stats.dropped.fetch_add(1, Ordering::Relaxed);
+          if let Some(message) = output_log.event("dropped", Instant::now(), || String::from("No sink to send to, dropping frames")) {
+            warn!("{}", message);
+          }
+          continue;
+        }
+
+        // once here rather than per output, so every sink sees the same deduplicated stream,
+        // and before decoding so repeats don't count as fresh positions
+        if let Some(dedup) = dedup.as_mut() {
+          if dedup.is_duplicate(&frame.data, Instant::now()) {
+            stats.duplicates.fetch_add(1, Ordering::Relaxed);
+            continue;
+          }
+        }
+
+        let fields = if decode { registry.decode(&frame, Instant::now()) } else { None };
+        if decode && last_prune.elapsed() >= AIRCRAFT_MAX_AGE {
+          registry.prune(AIRCRAFT_MAX_AGE, Instant::now());
+          last_prune = Instant::now();
+        }
+
+        // shed after decoding, so dropping a frame doesn't break up a position pair
+        if let Some(limiter) = frame_limiter.as_mut() {
+          if !limiter.admit(FramePriority::of(&frame.data), Instant::now()) {
+            stats.shed.fetch_add(1, Ordering::Relaxed);
+            if let Some(message) = output_log.event("shed", Instant::now(), || format!("Over --max-frame-rate {}, shedding frames", max_frame_rate)) {
+              warn!("{}", message);
             }
+            continue;
+          }
+        }
 
-            // Tell TCP to send the buffered data on the wire
-            trace!("flush server stream");
-            stream.flush().unwrap();
+        if let Some(ref out) = stdout {
+          let mut handle = out.lock();
+          let line = output_format.serialize(&frame, fields.as_ref(), tag.as_deref());
+          if let Err(e) = handle.write_all(&line).and_then(|_| handle.flush()) {
+            // most likely whatever we were piped into has exited
+            warn!("Unable to write to stdout ({}), no longer writing frames there", e);
+            stdout_stats.failed(e.to_string());
+            drop(handle);
+            stdout = None;
+            if sinks.is_empty() && !websocket {
+              running.store(false, Ordering::SeqCst);
+            }
+          } else {
+            stdout_stats.sent(line.len());
           }
         }
-      },
-      Err(_) => break,
+
+        #[cfg(feature = "websocket")]
+        if let Some(ref ws) = ws_sink {
+          ws.broadcast(&to_json(&frame, fields.as_ref(), tag.as_deref()));
+        }
+
+        // with --shard only one sink gets the frame. If that sink is gone for good its share is
+        // dropped rather than moved, so the other sinks keep seeing the same aircraft.
+        let target = sharder.as_mut().map(|sharder| sharder.shard_for(&frame.data));
+        if let Some(target) = target {
+          if !sinks.iter().any(|(_, _, shard)| *shard == target) {
+            stats.dropped.fetch_add(1, Ordering::Relaxed);
+            if let Some(message) = output_log.event("shard_lost", Instant::now(), || format!("Sink {} is gone, dropping the frames sharded to it", target + 1)) {
+              warn!("{}", message);
+            }
+          }
+        }
+
+        // Serialize once per format in use, not once per sink
+        let mut encoded: Vec<(OutputFormat, Vec<u8>)> = Vec::new();
+        for (_, format, shard) in &sinks {
+          if target.is_some_and(|target| target != *shard) {
+            continue;
+          }
+          if !encoded.iter().any(|(f, _)| f == format) {
+            encoded.push((*format, format.serialize(&frame, fields.as_ref(), tag.as_deref())));
+          }
+        }
+
+        // Send serialized message to each socket, dropping the ones that are gone for good
+        let had_sinks = !sinks.is_empty();
+        sinks.retain_mut(|(s, format, shard)| {
+          if target.is_some_and(|target| target != *shard) {
+            return true;
+          }
+          let a = &encoded.iter().find(|(f, _)| f == format).unwrap().1;
+          debug!("Sending {} bytes to {}", a.len(), s.name());
+          let reconnects = s.reconnects();
+          let result = s.send(a, &running);
+          stats.reconnects.fetch_add(s.reconnects() - reconnects, Ordering::Relaxed);
+          match result {
+            Ok(()) => true,
+            Err(e) => {
+              error!("Lost connection to {}: {}", s.name(), e);
+              last_error = format!("{}: {}", s.name(), e);
+              false
+            },
+          }
+        });
+        if had_sinks && sinks.is_empty() && stdout.is_none() && !websocket {
+          running.store(false, Ordering::SeqCst);
+          outcome = Err(AppError::RemoteUnreachable(last_error.clone()));
+        }
+      }
+
+      // buffered frames mustn't wait for more traffic to push them out, nor spooled ones for
+      // traffic to notice the server is back
+      if (sink_buffer.bytes > 0 || spooling) && !finished {
+        let had_sinks = !sinks.is_empty();
+        sinks.retain_mut(|(s, _, _)| match s.flush_if_due(Instant::now(), &running) {
+          Ok(()) => true,
+          Err(e) => {
+            error!("Lost connection to {}: {}", s.name(), e);
+            last_error = format!("{}: {}", s.name(), e);
+            false
+          },
+        });
+        if had_sinks && sinks.is_empty() && stdout.is_none() && !websocket {
+          running.store(false, Ordering::SeqCst);
+          outcome = Err(AppError::RemoteUnreachable(last_error.clone()));
+        }
+      }
+
+      if finished {
+        for (s, _, _) in sinks.iter_mut() {
+          if let Err(e) = s.finish() {
+            warn!("Unable to send the last buffered frames to {}: {}", s.name(), e);
+          }
+        }
+        break;
+      }
+    }
+
+    outcome
+  });
+
+  // A device failure is the root cause of anything that went wrong on the output side
+  let outcome = producer.join().unwrap().and(output.join().unwrap());
+
+  if let Some(ref path) = stats_file {
+    match stats::save(path, &stats.snapshot()) {
+      Ok(_) => info!("Saved statistics to {}", path.display()),
+      Err(e) => warn!("Unable to save statistics to {}: {}", path.display(), e),
+    }
+  }
+
+  if outcome.is_ok() {
+    check_frames_received(stats.messages.load(Ordering::Relaxed), strict)?;
+  }
+
+  outcome
+}
+
+// A silent run otherwise looks just like a successful one, so point out the usual causes. Only
+// an error under --strict.
+fn check_frames_received(messages: u64, strict: bool) -> Result<(), AppError> {
+  if messages > 0 {
+    return Ok(());
+  }
+  warn!("No frames were received during this run. Check that:");
+  warn!("  - the antenna is connected and suited to 1090 MHz");
+  warn!("  - --frequency is 1090 MHz and --lo-offset is what the FPGA image expects (the default, {} Hz)", DEFAULT_LO_OFFSET);
+  warn!("  - the gain isn't too low, or so high the ADC clips (see the clipping warnings)");
+  warn!("  - the FPGA image is the ADS-B image for this board");
+  if strict { Err(AppError::NoFrames) } else { Ok(()) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bladerf_adsb::decode;
+  use bladerf::bladerf::bladerf_error::BLADERF_ERR_IO;
+  use bladerf::mock::MockBladeRf;
+  use bladerf_adsb::error::EXIT_PANIC;
+  use crate::fixtures::{frame, DF17};
+
+  fn cli(args: &[&str]) -> Cli {
+    Cli::try_parse_from(std::iter::once("bladerf_adsb").chain(args.iter().copied())).unwrap()
+  }
+
+  // `data` with its parity replaced by a valid CRC
+  fn with_crc(data: &[u8]) -> Vec<u8> {
+    let mut data = data.to_vec();
+    let len = data.len();
+    let parity = crc::crc24(&data[..len - 3]);
+    data[len - 3..].copy_from_slice(&parity.to_be_bytes()[1..]);
+    data
+  }
+
+  // Whether `data` makes it through accept_frame with the given options
+  fn accepted(args: &[&str], data: &[u8]) -> bool {
+    let cli = cli(args);
+    let corrector = ErrorCorrector::new(CrcFixMode::from_name(&cli.fix_crc).unwrap());
+    let icao_filter = IcaoFilter::new(&cli.icao_allow, &cli.icao_block, cli.icao_unknown);
+    accept_frame(&mut frame(data), &cli, &Stats::new(), &corrector, &icao_filter)
+  }
+
+  #[test]
+  fn a_few_timeouts_are_retried_until_samples_arrive() {
+    let mut timeouts = TimeoutCounter::default();
+    for consecutive in 1..=3 {
+      assert_eq!(timeouts.timed_out(), Ok(consecutive));
     }
+    assert_eq!(timeouts.succeeded(), Some(3));
+
+    // the count starts over after a good read
+    assert_eq!(timeouts.succeeded(), None);
+    assert_eq!(timeouts.timed_out(), Ok(1));
+  }
+
+  #[test]
+  fn a_source_that_stays_silent_is_given_up_on() {
+    let mut timeouts = TimeoutCounter::default();
+    for _ in 1..MAX_CONSECUTIVE_TIMEOUTS {
+      assert!(timeouts.timed_out().is_ok());
+    }
+    assert_eq!(timeouts.timed_out(), Err(MAX_CONSECUTIVE_TIMEOUTS));
+  }
+
+  #[test]
+  fn reset_forgets_earlier_timeouts() {
+    let mut timeouts = TimeoutCounter::default();
+    for _ in 1..MAX_CONSECUTIVE_TIMEOUTS {
+      assert!(timeouts.timed_out().is_ok());
+    }
+    timeouts.reset();
+    assert_eq!(timeouts.timed_out(), Ok(1));
+  }
+
+  #[test]
+  fn frames_are_dropped_while_paused_and_counted_until_resumed() {
+    let received = || Some(frame(&DF17));
+    let mut pause = Pause::default();
+    assert_eq!(pause.filter(false, received()), received());
+
+    assert_eq!(pause.filter(true, received()), None);
+    assert_eq!(pause.filter(true, None), None);
+    assert_eq!(pause.filter(true, received()), None);
+    assert_eq!(pause.dropped, 2);
+
+    assert_eq!(pause.filter(false, received()), received());
+    assert_eq!((pause.paused, pause.dropped), (false, 0));
   }
 
-  return;
+  #[test]
+  fn df18_is_forwarded_unless_tisb_is_turned_off() {
+    let mut df18 = DF17;
+    df18[0] = (18 << 3) | decode::CF_TISB_FINE;
+    let df18 = with_crc(&df18);
+
+    assert!(accepted(&[], &df18));
+    assert!(!accepted(&["--include-tisb", "false"], &df18));
+    // DF17 is unaffected
+    assert!(accepted(&["--include-tisb", "false"], &DF17));
+  }
+
+  #[test]
+  fn reserved_downlink_formats_are_dropped_unless_asked_not_to() {
+    // DF7 doesn't exist, so this can only be noise
+    let df7 = [7 << 3, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc];
+
+    assert!(!accepted(&[], &df7));
+    assert!(accepted(&["--drop-invalid-df", "false"], &df7));
+  }
+
+  #[test]
+  fn version_includes_libbladerf() {
+    let lib_version = Struct_bladerf_version { major: 2, minor: 5, patch: 1, ..Struct_bladerf_version::default() };
+    assert_eq!(long_version(&lib_version), format!("{} (libbladeRF 2.5.1)", env!("CARGO_PKG_VERSION")));
+  }
+
+  #[test]
+  fn a_panicking_worker_stops_the_run_with_its_name_and_message() {
+    let running = Arc::new(AtomicBool::new(true));
+    let worker = spawn_worker("producer", running.clone(), || panic!("sync_rx went away"));
+
+    let err = worker.join().unwrap().unwrap_err();
+    assert_eq!(err, AppError::Panic(String::from("producer"), String::from("sync_rx went away")));
+    assert_eq!(err.exit_code(), EXIT_PANIC);
+    assert!(!running.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn a_worker_runs_under_its_name_and_a_clean_exit_leaves_the_others_running() {
+    let running = Arc::new(AtomicBool::new(true));
+    let worker = spawn_worker("output", running.clone(), || {
+      assert_eq!(thread::current().name(), Some("output"));
+      Ok(())
+    });
+
+    assert_eq!(worker.join().unwrap(), Ok(()));
+    assert!(running.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn a_missing_bias_tee_only_matters_when_one_was_asked_for() {
+    let unsupported = || {
+      let mock = MockBladeRf::new();
+      mock.push_result("set_bias_tee", BLADERF_ERR_UNSUPPORTED as i32);
+      BladeRFDevice::with_api(Box::new(mock))
+    };
+
+    assert_eq!(set_bias_tee(&unsupported(), false), Ok(()));
+    assert_eq!(set_bias_tee(&unsupported(), true), Err(AppError::Device(String::from("set_bias_tee"), BLADERF_ERR_UNSUPPORTED as isize)));
+  }
+
+  #[test]
+  fn other_bias_tee_failures_are_errors_either_way() {
+    let mock = MockBladeRf::new();
+    mock.push_result("set_bias_tee", BLADERF_ERR_IO as i32);
+    let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+    assert_eq!(set_bias_tee(&dev, false), Err(AppError::Device(String::from("set_bias_tee"), BLADERF_ERR_IO as isize)));
+    assert_eq!(set_bias_tee(&dev, true), Ok(()));
+    assert!(mock.state().bias_tee);
+  }
+
+  #[test]
+  fn damaged_extended_squitters_are_only_kept_with_fix_crc() {
+    let mut damaged = DF17;
+    damaged[6] ^= 0x10;
+
+    assert!(!accepted(&[], &damaged));
+    assert!(accepted(&["--fix-crc", "single"], &damaged));
+  }
+
+  #[test]
+  fn a_quiet_output_thread_notices_a_shutdown() {
+    let (sender, rx) = channel();
+    let running = AtomicBool::new(true);
+
+    assert_eq!(next_output(&rx, Duration::from_millis(1), &running), (None, false));
+    running.store(false, Ordering::SeqCst);
+    assert_eq!(next_output(&rx, Duration::from_millis(1), &running), (None, true));
+    drop(sender);
+  }
+
+  #[test]
+  fn output_stops_once_every_producer_is_gone() {
+    let (sender, rx) = channel();
+    let running = AtomicBool::new(true);
+    sender.send(frame(&DF17)).unwrap();
+    drop(sender);
+
+    assert_eq!(next_output(&rx, Duration::from_millis(1), &running), (Some(frame(&DF17)), false));
+    assert_eq!(next_output(&rx, Duration::from_millis(1), &running), (None, true));
+  }
+
+  #[test]
+  fn replay_stops_when_the_output_thread_has_gone() {
+    let cli = cli(&[]);
+    let (sender, rx) = channel();
+    drop(rx);
+    let corrector = ErrorCorrector::new(CrcFixMode::None);
+    let icao_filter = IcaoFilter::new(&cli.icao_allow, &cli.icao_block, cli.icao_unknown);
+
+    assert!(!forward_replayed(frame(&DF17), &sender, true, &cli, &Stats::new(), &corrector, &icao_filter));
+  }
+
+  #[test]
+  fn output_stdout_forwards_frames_without_a_remote_server() {
+    assert!(!forwards_frames(&cli(&["--remote", "false"])));
+    assert!(forwards_frames(&cli(&["--remote", "false", "--output-stdout"])));
+
+    let plan = output_plan(&cli(&["--sink", "127.0.0.1:30005:beast", "--output-stdout"]));
+    assert_eq!((plan.remote, plan.stdout), (RemoteMode::Required, true));
+  }
+
+  #[test]
+  fn quiet_overrides_the_log_level_and_hides_the_spinner() {
+    assert_eq!(log_level(&cli(&["--log-level", "debug"])), LevelFilter::Debug);
+    assert_eq!(log_level(&cli(&["--log-level", "debug", "--quiet"])), LevelFilter::Error);
+
+    assert!(shows_spinner(&cli(&[])));
+    assert!(!shows_spinner(&cli(&["-q"])));
+    assert!(!shows_spinner(&cli(&["--output-stdout"])));
+  }
+
+  #[test]
+  fn expansion_boards_are_named_on_the_command_line() {
+    assert_eq!(expansion_board(&cli(&["--expansion", "xb200"]).expansion), BLADERF_XB_200);
+    assert_eq!(expansion_board(&cli(&["--expansion", "xb300"]).expansion), BLADERF_XB_300);
+    assert_eq!(expansion_board(&cli(&[]).expansion), BLADERF_XB_NONE);
+    assert!(Cli::try_parse_from(["bladerf_adsb", "--expansion", "xb100"]).is_err());
+  }
+
+  #[test]
+  fn a_run_without_frames_only_fails_under_strict() {
+    assert_eq!(check_frames_received(0, false), Ok(()));
+    assert_eq!(check_frames_received(0, true), Err(AppError::NoFrames));
+    assert_eq!(check_frames_received(1, true), Ok(()));
+    assert_eq!(AppError::NoFrames.exit_code(), 6);
+  }
+
+  #[test]
+  fn rx_ports_are_matched_case_insensitively_against_the_boards_list() {
+    let mock = MockBladeRf::new();
+    mock.state().rf_ports = vec![String::from("A_BALANCED"), String::from("B_BALANCED")];
+    let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+    assert_eq!(set_rx_port(&dev, "b_balanced"), Ok(()));
+    assert_eq!(mock.state().rf_port, "B_BALANCED");
+    assert_eq!(set_rx_port(&dev, "C_BALANCED"),
+               Err(AppError::Config(String::from("RX port C_BALANCED isn't available on this board (available: A_BALANCED, B_BALANCED)"))));
+  }
+
+  #[test]
+  fn boards_without_rx_ports_reject_rx_port() {
+    let mock = MockBladeRf::new();
+    mock.push_result("get_rf_ports", BLADERF_ERR_UNSUPPORTED as i32);
+    let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+    assert!(matches!(set_rx_port(&dev, "A_BALANCED"), Err(AppError::Config(_))));
+    assert!(!mock.calls().iter().any(|call| call.starts_with("set_rf_port")));
+  }
+
+  #[test]
+  fn completions_cover_the_options_and_stay_out_of_help() {
+    assert!(matches!(cli(&["completions", "bash"]).command, Some(Command::Completions { shell: Shell::Bash })));
+
+    let mut script = Vec::new();
+    write_completions(Shell::Bash, &mut script);
+    let script = String::from_utf8(script).unwrap();
+    assert!(script.contains("--frequency"), "{}", script);
+    assert!(script.contains("throughput"));
+    assert!(!Cli::command().render_help().to_string().contains("completions"));
+  }
+
+  #[test]
+  fn no_remote_turns_the_server_off_and_prints_frames() {
+    assert_eq!(output_plan(&cli(&[])).remote, RemoteMode::Optional);
+    assert_eq!(output_plan(&cli(&["--no-remote"])), OutputPlan { remote: RemoteMode::Off, stdout: true });
+    assert!(forwards_frames(&cli(&["--no-remote"])));
+  }
+
+  #[test]
+  fn a_failed_reset_still_closes_the_handle() {
+    let mock = MockBladeRf::new();
+    mock.push_result("device_reset", BLADERF_ERR_IO as i32);
+    let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+    assert_eq!(reset_and_reopen(dev, None).err(), Some(AppError::Device(String::from("device_reset"), BLADERF_ERR_IO as isize)));
+    assert_eq!(mock.calls(), vec!["device_reset()", "close()"]);
+    assert!(cli(&["--reset-on-start"]).reset_on_start);
+  }
+
+  #[test]
+  fn the_spinner_says_whether_messages_are_arriving() {
+    assert_eq!(spinner_message(Activity::Waiting, 0, 0.0), "Waiting for messages");
+    assert_eq!(spinner_message(Activity::Active, 12345, 20.25), "Processed 12,345 messages (20.2 msg/s)");
+    assert_eq!(spinner_message(Activity::Idle(Duration::from_millis(7900)), 12345, 0.0), "Idle, last message 7s ago (processed 12,345)");
+  }
+
+  #[test]
+  fn the_stream_timeouts_are_set_separately() {
+    let defaults = cli(&[]);
+    assert_eq!((defaults.config_timeout, defaults.rx_timeout), (DEFAULT_CONFIG_TIMEOUT_MS, DEFAULT_RX_TIMEOUT_MS));
+    let set = cli(&["--config-timeout", "1000", "--rx-timeout", "250"]);
+    assert_eq!((set.config_timeout, set.rx_timeout), (1000, 250));
+    assert!(Cli::try_parse_from(["bladerf_adsb", "--rx-timeout", "0"]).is_err());
+  }
+
+  #[test]
+  fn a_query_the_device_cant_answer_prints_as_unknown() {
+    assert_eq!(or_unknown("serial", Ok(42), |value| value.to_string()), "42");
+    assert_eq!(or_unknown("serial", Err(BLADERF_ERR_IO as isize), |value: i32| value.to_string()), "unknown");
+  }
+
+  #[test]
+  fn a_stopped_run_stops_waiting_for_the_device() {
+    assert!(reconnect_device(&cli(&[]), &AtomicBool::new(false)).is_none());
+  }
+
+  #[test]
+  fn tuning_sets_the_lo_below_the_target() {
+    let mock = MockBladeRf::new();
+    let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+    assert_eq!(tune_to(&dev, 1090000000, DEFAULT_LO_OFFSET), Ok(()));
+    assert_eq!(mock.state().frequency, 1086000000);
+    assert!(tune_to(&dev, 1000000, DEFAULT_LO_OFFSET).is_err());
+    assert_eq!(mock.state().frequency, 1086000000);
+  }
+
+  #[test]
+  fn backups_save_the_flash_and_the_firmware_log() {
+    let dir = std::env::temp_dir().join(format!("bladerf_adsb-test-{}-backup", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mock = MockBladeRf::new();
+    mock.state().flash = vec![0x5a; 1024];
+    mock.state().fw_log = String::from("boot\n");
+    let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+    let saved = write_backup(&dev, &dir).unwrap();
+    assert_eq!(saved.len(), 2);
+    assert_eq!(fs::read(&saved[0]).unwrap(), vec![0x5a; 1024]);
+    assert_eq!(fs::read_to_string(&saved[1]).unwrap(), "boot\n");
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn backups_fall_back_to_the_firmware_log() {
+    let dir = std::env::temp_dir().join(format!("bladerf_adsb-test-{}-backup-log", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mock = MockBladeRf::new();
+    mock.push_result("get_flash_size", BLADERF_ERR_UNSUPPORTED as i32);
+    let dev = BladeRFDevice::with_api(Box::new(mock.clone()));
+
+    let saved = write_backup(&dev, &dir).unwrap();
+    assert_eq!(saved.len(), 1);
+    assert!(saved[0].to_string_lossy().ends_with(".log"));
+
+    // with neither, there's nothing to show for it
+    mock.push_result("get_flash_size", BLADERF_ERR_UNSUPPORTED as i32);
+    mock.push_result("get_fw_log", BLADERF_ERR_UNSUPPORTED as i32);
+    assert!(matches!(write_backup(&dev, &dir), Err(AppError::Device(_, _))));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn inherited_descriptors_stand_in_for_the_server_like_sinks() {
+    let inherited = cli(&["--inherit-fd", "5,6"]);
+    assert_eq!(inherited_fds(&inherited), vec![5, 6]);
+    assert_eq!(output_plan(&inherited), output_plan(&cli(&["--sink", "127.0.0.1:30001"])));
+    assert!(Cli::try_parse_from(["bladerf_adsb", "--inherit-fd", "5", "--no-remote"]).is_err());
+  }
+
+  #[test]
+  fn raw_forwards_frames_the_crc_check_drops() {
+    let mut corrupt = DF17;
+    corrupt[5] ^= 0x10;
+    assert!(!accepted(&[], &corrupt));
+    assert!(!accepted(&["--decode-level", "crc"], &corrupt));
+    assert!(accepted(&["--decode-level", "raw"], &corrupt));
+  }
+
+  #[test]
+  fn lower_decode_levels_rule_out_the_options_that_need_more() {
+    assert_eq!(check_decode_level(&cli(&["--decode-level", "crc"])), Ok(()));
+    assert_eq!(check_decode_level(&cli(&["--decode-level", "crc", "--output-format", "json"])),
+               Err(String::from("the json output format needs --decode-level full, not crc")));
+    assert_eq!(check_decode_level(&cli(&["--decode-level", "raw", "--sink", "127.0.0.1:30001:json"])),
+               Err(String::from("the json output format needs --decode-level full, not raw")));
+    assert_eq!(check_decode_level(&cli(&["--decode-level", "raw", "--icao-block", "4840d6"])),
+               Err(String::from("--icao-allow and --icao-block need --decode-level crc or full, not raw")));
+    assert_eq!(check_decode_level(&cli(&["--decode-level", "crc", "--icao-block", "4840d6"])), Ok(()));
+    assert!(Cli::try_parse_from(["bladerf_adsb", "--decode-level", "everything"]).is_err());
+  }
+
+  // What inject sends on for a frame with a corrupt parity, with the given options
+  fn injected(args: &[&str]) -> Vec<AdsbFrame> {
+    let mut corrupt = DF17.to_vec();
+    corrupt[13] ^= 0x01;
+    let (sender, rx) = channel();
+    assert!(inject(vec![corrupt], &sender, &cli(args), &Stats::new()));
+    drop(sender);
+    rx.iter().collect()
+  }
+
+  #[test]
+  fn injected_parity_is_checked_computed_or_left_alone() {
+    assert!(injected(&[]).is_empty());
+    assert_eq!(injected(&["--inject-crc", "compute"]).iter().map(|frame| frame.data.clone()).collect::<Vec<_>>(), vec![DF17.to_vec()]);
+    let as_is = injected(&["--inject-crc", "as-is"]);
+    assert_eq!(as_is.len(), 1);
+    assert_eq!(as_is[0].data[13], DF17[13] ^ 0x01);
+  }
+
+  #[test]
+  fn injection_stops_when_the_output_thread_has_gone() {
+    let (sender, rx) = channel();
+    drop(rx);
+    assert!(!inject(vec![DF17.to_vec()], &sender, &cli(&["--inject-crc", "as-is"]), &Stats::new()));
+    assert!(Cli::try_parse_from(["bladerf_adsb", "--inject-only"]).is_err());
+  }
+
+  #[test]
+  fn output_format_none_goes_nowhere_but_still_forwards() {
+    let none = cli(&["--output-format", "none"]);
+    assert_eq!(output_plan(&none), OutputPlan { remote: RemoteMode::Off, stdout: false });
+    assert!(forwards_frames(&none));
+    assert_eq!(check_output_format(&none), Ok(()));
+    assert_eq!(check_output_format(&cli(&["--output-format", "none", "--output-stdout"])),
+               Err(String::from("--output-format none writes no output, so it can't be used with --output-stdout")));
+    assert_eq!(check_output_format(&cli(&["--output-format", "none", "--sink", "127.0.0.1:30002"])),
+               Err(String::from("--output-format none writes no output, so it can't be used with --sink")));
+    assert_eq!(check_output_format(&cli(&["--sink", "127.0.0.1:30002"])), Ok(()));
+  }
+
+  #[test]
+  fn frequencies_and_rates_take_suffixes() {
+    let cli = cli(&["--frequency", "1090M", "--sample-rate", "16M", "--bandwidth", "14M", "--frequency-list", "1090M,1.09G"]);
+    assert_eq!((cli.frequency, cli.sample_rate, cli.bandwidth), (1090000000, 16000000, 14000000));
+    assert_eq!(cli.frequency_list, vec![1090000000, 1090000000]);
+    assert!(Cli::try_parse_from(["bladerf_adsb", "--frequency", "1090"]).is_err());
+  }
 }
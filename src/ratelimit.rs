@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::crc;
+use crate::decode::{downlink_format, is_extended_squitter};
+
+// Default window repeated messages are collapsed over
+pub const LOG_SUPPRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Window {
+  started: Instant,
+  // events swallowed since the window started
+  suppressed: u64,
+}
+
+// Collapses bursts of the same kind of message so an unplugged server or a run of overruns
+// doesn't flood the journal. The first event of each category in a window is logged as usual,
+// the rest are only counted and reported as "N more in the last 5s". It returns the text to log
+// rather than logging itself, so callers keep their own level and target.
+pub struct RateLimitedLogger {
+  interval: Duration,
+  windows: HashMap<&'static str, Window>,
+}
+
+impl RateLimitedLogger {
+  pub fn new(interval: Duration) -> RateLimitedLogger {
+    RateLimitedLogger {
+      interval,
+      windows: HashMap::new(),
+    }
+  }
+
+  // Record an event of `category`. Returns the message to log, with a count of what was
+  // suppressed in the previous window appended, or None if it should be swallowed. The message
+  // is only built when it's going to be logged.
+  pub fn event<F: FnOnce() -> String>(&mut self, category: &'static str, now: Instant, message: F) -> Option<String> {
+    let interval = self.interval;
+    match self.windows.get_mut(category) {
+      Some(window) if now.saturating_duration_since(window.started) < interval => {
+        window.suppressed += 1;
+        None
+      },
+      Some(window) => {
+        let suppressed = window.suppressed;
+        window.started = now;
+        window.suppressed = 0;
+        if suppressed > 0 {
+          Some(format!("{} ({} more in the last {}s)", message(), suppressed, interval.as_secs()))
+        } else {
+          Some(message())
+        }
+      },
+      None => {
+        self.windows.insert(category, Window { started: now, suppressed: 0 });
+        Some(message())
+      },
+    }
+  }
+
+  // Summaries for categories whose window has ended with events still unreported, so a burst
+  // that stops is still accounted for. Call periodically.
+  pub fn flush(&mut self, now: Instant) -> Vec<String> {
+    let interval = self.interval;
+    let mut summaries = Vec::new();
+    for (category, window) in self.windows.iter_mut() {
+      if window.suppressed > 0 && now.saturating_duration_since(window.started) >= interval {
+        summaries.push(format!("{}: {} more in the last {}s", category, window.suppressed, interval.as_secs()));
+        window.started = now;
+        window.suppressed = 0;
+      }
+    }
+    summaries.sort();
+    summaries
+  }
+}
+
+impl Default for RateLimitedLogger {
+  fn default() -> Self {
+    RateLimitedLogger::new(LOG_SUPPRESS_INTERVAL)
+  }
+}
+
+// Share of the frame rate budget held back for high priority frames: low priority frames are
+// only let through while the bucket is more than this full
+const LOW_PRIORITY_RESERVE: f64 = 0.5;
+
+// Which frames go first when the frame rate has to be capped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FramePriority {
+  // CRC checked extended squitters, the frames carrying positions and identities
+  High,
+  Low,
+}
+
+impl FramePriority {
+  pub fn of(data: &[u8]) -> FramePriority {
+    if is_extended_squitter(downlink_format(data)) && crc::syndrome(data) == 0 {
+      FramePriority::High
+    } else {
+      FramePriority::Low
+    }
+  }
+}
+
+// Token bucket capping the frames forwarded per second across all sinks, for uplinks that are
+// metered or slow. The bucket holds a second's worth of frames, so short bursts get through;
+// under sustained overload low priority frames are shed first.
+pub struct FrameRateLimiter {
+  rate: f64,
+  tokens: f64,
+  last: Instant,
+}
+
+impl FrameRateLimiter {
+  pub fn new(max_per_sec: u32, now: Instant) -> FrameRateLimiter {
+    FrameRateLimiter { rate: max_per_sec as f64, tokens: max_per_sec as f64, last: now }
+  }
+
+  // Whether a frame may be forwarded now, taking a token if so
+  pub fn admit(&mut self, priority: FramePriority, now: Instant) -> bool {
+    let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+    self.last = now;
+
+    let floor = match priority {
+      FramePriority::High => 1.0,
+      FramePriority::Low => 1.0 + self.rate * LOW_PRIORITY_RESERVE,
+    };
+    if self.tokens >= floor {
+      self.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::DF17;
+
+  #[test]
+  fn repeats_within_the_window_are_swallowed_and_counted_on_the_next() {
+    let start = Instant::now();
+    let mut logger = RateLimitedLogger::new(Duration::from_secs(5));
+    let overrun = || String::from("overrun");
+
+    assert_eq!(logger.event("overrun", start, overrun), Some(String::from("overrun")));
+    assert_eq!(logger.event("overrun", start + Duration::from_secs(1), overrun), None);
+    assert_eq!(logger.event("overrun", start + Duration::from_secs(2), overrun), None);
+    assert_eq!(logger.event("overrun", start + Duration::from_secs(5), overrun),
+               Some(String::from("overrun (2 more in the last 5s)")));
+    assert_eq!(logger.event("overrun", start + Duration::from_secs(10), overrun), Some(String::from("overrun")));
+  }
+
+  #[test]
+  fn categories_are_limited_separately() {
+    let now = Instant::now();
+    let mut logger = RateLimitedLogger::default();
+
+    assert!(logger.event("overrun", now, String::new).is_some());
+    assert!(logger.event("timeout", now, String::new).is_some());
+    assert!(logger.event("overrun", now, String::new).is_none());
+  }
+
+  #[test]
+  fn swallowed_messages_are_only_built_when_logged() {
+    let now = Instant::now();
+    let mut logger = RateLimitedLogger::default();
+    logger.event("send", now, String::new);
+
+    assert_eq!(logger.event("send", now, || panic!("built a suppressed message")), None);
+  }
+
+  #[test]
+  fn flush_reports_a_burst_that_stopped() {
+    let start = Instant::now();
+    let mut logger = RateLimitedLogger::new(Duration::from_secs(5));
+    logger.event("timeout", start, String::new);
+    logger.event("timeout", start + Duration::from_secs(1), String::new);
+    logger.event("drop", start, String::new);
+
+    assert!(logger.flush(start + Duration::from_secs(4)).is_empty());
+    assert_eq!(logger.flush(start + Duration::from_secs(5)), vec![String::from("timeout: 1 more in the last 5s")]);
+    assert!(logger.flush(start + Duration::from_secs(10)).is_empty());
+  }
+
+  fn admitted(limiter: &mut FrameRateLimiter, priority: FramePriority, now: Instant) -> usize {
+    (0..100).take_while(|_| limiter.admit(priority, now)).count()
+  }
+
+  #[test]
+  fn only_intact_extended_squitters_are_high_priority() {
+    assert_eq!(FramePriority::of(&DF17), FramePriority::High);
+    let mut damaged = DF17;
+    damaged[6] ^= 0x10;
+    assert_eq!(FramePriority::of(&damaged), FramePriority::Low);
+    assert_eq!(FramePriority::of(&[0x20, 0x00, 0x17, 0x18, 0x12, 0x34, 0x56]), FramePriority::Low);
+  }
+
+  #[test]
+  fn low_priority_frames_leave_half_the_budget_to_high_priority_ones() {
+    let start = Instant::now();
+    let mut limiter = FrameRateLimiter::new(10, start);
+
+    assert_eq!(admitted(&mut limiter, FramePriority::Low, start), 5);
+    assert_eq!(admitted(&mut limiter, FramePriority::High, start), 5);
+    assert!(!limiter.admit(FramePriority::High, start));
+  }
+
+  #[test]
+  fn the_bucket_refills_at_the_rate_up_to_a_seconds_worth() {
+    let start = Instant::now();
+    let mut limiter = FrameRateLimiter::new(10, start);
+    admitted(&mut limiter, FramePriority::High, start);
+
+    assert_eq!(admitted(&mut limiter, FramePriority::High, start + Duration::from_millis(300)), 3);
+    assert_eq!(admitted(&mut limiter, FramePriority::High, start + Duration::from_secs(60)), 10);
+  }
+}
@@ -0,0 +1,189 @@
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// sync_rx buffers kept for --dump-raw-on-error, about 1 ms of samples at 16 MSPS
+pub const RAW_RING_BUFFERS: usize = 16;
+
+// Events within one TRIGGER_WINDOW that count as a burst worth capturing
+pub const OVERRUN_BURST: u64 = 3;
+pub const CRC_FAILURE_BURST: u64 = 50;
+pub const TRIGGER_WINDOW: Duration = Duration::from_secs(1);
+
+// Minimum time between two dumps, so a persistent fault doesn't fill the disk
+pub const DUMP_COOLDOWN: Duration = Duration::from_secs(60);
+
+// The last few raw sync_rx buffers. Slots are allocated once up front, so keeping it costs one
+// copy per buffer and nothing else until a dump is written.
+pub struct RawRing {
+  slots: Vec<Vec<u8>>,
+  // next slot to overwrite
+  next: usize,
+  // slots holding data, until the ring first wraps
+  filled: usize,
+}
+
+impl RawRing {
+  pub fn new(buffers: usize, buffer_bytes: usize) -> RawRing {
+    assert!(buffers > 0, "ring needs at least one buffer");
+    RawRing {
+      slots: (0..buffers).map(|_| Vec::with_capacity(buffer_bytes)).collect(),
+      next: 0,
+      filled: 0,
+    }
+  }
+
+  pub fn push(&mut self, buf: &[u8]) {
+    let slot = &mut self.slots[self.next];
+    slot.clear();
+    slot.extend_from_slice(buf);
+    self.next = (self.next + 1) % self.slots.len();
+    self.filled = (self.filled + 1).min(self.slots.len());
+  }
+
+  // Buffers from oldest to newest
+  pub fn buffers(&self) -> impl Iterator<Item = &[u8]> {
+    let start = (self.next + self.slots.len() - self.filled) % self.slots.len();
+    (0..self.filled).map(move |i| self.slots[(start + i) % self.slots.len()].as_slice())
+  }
+
+  // Write the buffers back to back, oldest first. The result is a plain SC16_Q11 capture that
+  // --replay can read.
+  pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+    for buf in self.buffers() {
+      out.write_all(buf)?;
+    }
+    out.flush()
+  }
+
+  // Write the ring to a new timestamped file in `dir`, returning its path
+  pub fn dump(&self, dir: &Path, now: SystemTime) -> io::Result<PathBuf> {
+    let path = dump_path(dir, now);
+    let mut file = File::create(&path)?;
+    self.write_to(&mut file)?;
+    Ok(path)
+  }
+}
+
+// e.g. "<dir>/raw-1700000000.123.sc16q11"
+pub fn dump_path(dir: &Path, now: SystemTime) -> PathBuf {
+  let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+  dir.join(format!("raw-{}.{:03}.sc16q11", since_epoch.as_secs(), since_epoch.subsec_millis()))
+}
+
+// Why a dump was triggered
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DumpReason {
+  OverrunBurst,
+  CrcFailureBurst,
+}
+
+// Watches the overrun and CRC failure totals for bursts, in fixed windows
+pub struct DumpTrigger {
+  window_start: Instant,
+  // totals at the start of the window
+  overruns: u64,
+  crc_failures: u64,
+  last_dump: Option<Instant>,
+}
+
+impl DumpTrigger {
+  pub fn new(now: Instant) -> DumpTrigger {
+    DumpTrigger {
+      window_start: now,
+      overruns: 0,
+      crc_failures: 0,
+      last_dump: None,
+    }
+  }
+
+  // Feed the current totals. Returns the reason to dump now, if a burst has happened in this
+  // window and the last dump is at least DUMP_COOLDOWN ago.
+  pub fn poll(&mut self, overruns: u64, crc_failures: u64, now: Instant) -> Option<DumpReason> {
+    if now.saturating_duration_since(self.window_start) >= TRIGGER_WINDOW {
+      self.window_start = now;
+      self.overruns = overruns;
+      self.crc_failures = crc_failures;
+    }
+
+    let reason = if overruns.saturating_sub(self.overruns) >= OVERRUN_BURST {
+      DumpReason::OverrunBurst
+    } else if crc_failures.saturating_sub(self.crc_failures) >= CRC_FAILURE_BURST {
+      DumpReason::CrcFailureBurst
+    } else {
+      return None;
+    };
+
+    if let Some(last) = self.last_dump {
+      if now.saturating_duration_since(last) < DUMP_COOLDOWN {
+        return None;
+      }
+    }
+    self.last_dump = Some(now);
+    // start a fresh window so the same burst doesn't count towards the next dump
+    self.window_start = now;
+    self.overruns = overruns;
+    self.crc_failures = crc_failures;
+    Some(reason)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn the_ring_keeps_the_newest_buffers_oldest_first() {
+    let mut ring = RawRing::new(3, 2);
+    ring.push(&[1, 1]);
+    ring.push(&[2, 2]);
+    assert_eq!(ring.buffers().collect::<Vec<_>>(), vec![&[1, 1][..], &[2, 2]]);
+
+    ring.push(&[3, 3]);
+    ring.push(&[4, 4]);
+    let mut out = Vec::new();
+    ring.write_to(&mut out).unwrap();
+    assert_eq!(out, vec![2, 2, 3, 3, 4, 4]);
+  }
+
+  #[test]
+  fn dumps_are_named_for_when_they_were_taken() {
+    let now = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+    assert_eq!(dump_path(Path::new("/var/tmp"), now), PathBuf::from("/var/tmp/raw-1700000000.123.sc16q11"));
+  }
+
+  #[test]
+  fn a_burst_within_the_window_triggers_a_dump() {
+    let start = Instant::now();
+    let mut trigger = DumpTrigger::new(start);
+
+    assert_eq!(trigger.poll(OVERRUN_BURST - 1, 0, start + Duration::from_millis(100)), None);
+    assert_eq!(trigger.poll(OVERRUN_BURST, 0, start + Duration::from_millis(200)), Some(DumpReason::OverrunBurst));
+  }
+
+  #[test]
+  fn events_spread_over_windows_are_not_a_burst() {
+    let start = Instant::now();
+    let mut trigger = DumpTrigger::new(start);
+
+    assert_eq!(trigger.poll(0, CRC_FAILURE_BURST - 1, start + Duration::from_millis(900)), None);
+    assert_eq!(trigger.poll(0, CRC_FAILURE_BURST - 1, start + TRIGGER_WINDOW), None);
+    assert_eq!(trigger.poll(0, 2 * CRC_FAILURE_BURST - 2, start + TRIGGER_WINDOW + Duration::from_millis(500)), None);
+    assert_eq!(trigger.poll(0, 2 * CRC_FAILURE_BURST - 1, start + TRIGGER_WINDOW + Duration::from_millis(600)),
+               Some(DumpReason::CrcFailureBurst));
+  }
+
+  #[test]
+  fn dumps_are_spaced_by_the_cooldown() {
+    let start = Instant::now();
+    let mut trigger = DumpTrigger::new(start);
+
+    assert!(trigger.poll(OVERRUN_BURST, 0, start).is_some());
+    assert_eq!(trigger.poll(2 * OVERRUN_BURST, 0, start + Duration::from_millis(500)), None);
+    let later = start + DUMP_COOLDOWN;
+    assert_eq!(trigger.poll(3 * OVERRUN_BURST, 0, later), None);
+    assert_eq!(trigger.poll(4 * OVERRUN_BURST, 0, later + Duration::from_millis(1)), Some(DumpReason::OverrunBurst));
+  }
+}
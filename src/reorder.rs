@@ -0,0 +1,115 @@
+use std::time::{Duration, Instant};
+
+use crate::frame::AdsbFrame;
+
+// Default for --reorder-window-ms
+pub const DEFAULT_REORDER_WINDOW_MS: u64 = 5;
+
+// Holds frames for a short while so they can be forwarded in timestamp order, for MLAT
+// consumers that expect timestamps to only go up. A frame is released once it has been held for
+// the window, along with every held frame timestamped before it. Frames without a timestamp, or
+// timestamped before one already released, can't be put in order and are released straight away.
+pub struct ReorderBuffer {
+  window: Duration,
+  // sorted by timestamp, with the time each frame arrived
+  held: Vec<(u64, Instant, AdsbFrame)>,
+  last_released: Option<u64>,
+}
+
+impl ReorderBuffer {
+  pub fn new(window: Duration) -> ReorderBuffer {
+    ReorderBuffer { window, held: Vec::new(), last_released: None }
+  }
+
+  // Take a frame, returning it straight back if it can't be put in order
+  pub fn push(&mut self, frame: AdsbFrame, now: Instant) -> Option<AdsbFrame> {
+    let timestamp = match frame.timestamp {
+      Some(timestamp) if !matches!(self.last_released, Some(last) if timestamp < last) => timestamp,
+      _ => return Some(frame),
+    };
+    // after any frames with the same timestamp, so those keep their order of arrival
+    let index = self.held.partition_point(|(held, _, _)| *held <= timestamp);
+    self.held.insert(index, (timestamp, now, frame));
+    None
+  }
+
+  // Frames due for release, in timestamp order
+  pub fn pop_ready(&mut self, now: Instant) -> Vec<AdsbFrame> {
+    let due = self.held.iter().rposition(|(_, arrived, _)| now.saturating_duration_since(*arrived) >= self.window);
+    match due {
+      Some(index) => self.release(index + 1),
+      None => Vec::new(),
+    }
+  }
+
+  // Every held frame, in timestamp order, for shutdown
+  pub fn flush(&mut self) -> Vec<AdsbFrame> {
+    self.release(self.held.len())
+  }
+
+  // How long until the next frame is due, None while nothing is held
+  pub fn next_due(&self, now: Instant) -> Option<Duration> {
+    self.held.iter()
+      .map(|(_, arrived, _)| (*arrived + self.window).saturating_duration_since(now))
+      .min()
+  }
+
+  fn release(&mut self, count: usize) -> Vec<AdsbFrame> {
+    if let Some((timestamp, _, _)) = self.held[..count].last() {
+      self.last_released = Some(*timestamp);
+    }
+    self.held.drain(..count).map(|(_, _, frame)| frame).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{frame, DF17};
+
+  // DF17 stamped with `timestamp`
+  fn timed(timestamp: Option<u64>) -> AdsbFrame {
+    AdsbFrame { timestamp, ..frame(&DF17) }
+  }
+
+  fn timestamps(frames: &[AdsbFrame]) -> Vec<Option<u64>> {
+    frames.iter().map(|frame| frame.timestamp).collect()
+  }
+
+  #[test]
+  fn frames_are_released_in_timestamp_order_after_the_window() {
+    let start = Instant::now();
+    let mut buffer = ReorderBuffer::new(Duration::from_millis(5));
+    assert!(buffer.push(timed(Some(30)), start).is_none());
+    assert!(buffer.push(timed(Some(10)), start + Duration::from_millis(1)).is_none());
+    assert!(buffer.push(timed(Some(20)), start + Duration::from_millis(2)).is_none());
+
+    assert!(buffer.pop_ready(start + Duration::from_millis(4)).is_empty());
+    assert_eq!(buffer.next_due(start + Duration::from_millis(4)), Some(Duration::from_millis(1)));
+    // 30 is due, and takes the earlier frames with it
+    assert_eq!(timestamps(&buffer.pop_ready(start + Duration::from_millis(5))), vec![Some(10), Some(20), Some(30)]);
+    assert_eq!(buffer.next_due(start), None);
+  }
+
+  #[test]
+  fn only_frames_up_to_the_last_due_one_are_released() {
+    let start = Instant::now();
+    let mut buffer = ReorderBuffer::new(Duration::from_millis(5));
+    buffer.push(timed(Some(10)), start);
+    buffer.push(timed(Some(20)), start + Duration::from_millis(3));
+    assert_eq!(timestamps(&buffer.pop_ready(start + Duration::from_millis(5))), vec![Some(10)]);
+    assert_eq!(timestamps(&buffer.flush()), vec![Some(20)]);
+  }
+
+  #[test]
+  fn frames_that_cant_be_ordered_pass_straight_through() {
+    let start = Instant::now();
+    let mut buffer = ReorderBuffer::new(Duration::from_millis(5));
+    assert_eq!(buffer.push(timed(None), start).map(|frame| frame.timestamp), Some(None));
+
+    buffer.push(timed(Some(20)), start);
+    buffer.pop_ready(start + Duration::from_millis(5));
+    assert_eq!(buffer.push(timed(Some(10)), start).map(|frame| frame.timestamp), Some(Some(10)));
+    assert!(buffer.push(timed(Some(20)), start).is_none());
+  }
+}
@@ -0,0 +1,513 @@
+use std::io::{self, BufRead, Read};
+use std::time::{Duration, Instant};
+
+use crate::consts::{LONG_FRAME_BYTES, SHORT_FRAME_BYTES};
+use crate::demod::{demodulate, frame_power, frame_samples, magnitudes, NoiseFloor, NOISE_FLOOR_ALPHA};
+use crate::format::{parse_avr, parse_avr_timestamp, parse_beast, BEAST_ESCAPE, BEAST_TYPE_LONG, BEAST_TYPE_SHORT};
+use crate::frame::{extract_frames, samples_to_mlat_ticks, AdsbFrame, BYTES_PER_SAMPLE, MLAT_CLOCK_HZ};
+
+// SC16_Q11_META streams are split into fixed size USB messages, each starting with a 16 byte
+// header: 4 reserved bytes, the little endian 64 bit sample timestamp of the message's first
+// sample, then 32 bits of flags. libbladeRF strips these when streaming, a raw capture of the
+// wire format still has them.
+pub const META_HEADER_BYTES: usize = 16;
+const META_TIMESTAMP_OFFSET: usize = 4;
+const META_FLAGS_OFFSET: usize = 12;
+
+// USB message size on SuperSpeed links, 1024 on HighSpeed
+pub const DEFAULT_META_MESSAGE_BYTES: usize = 2048;
+
+// Bytes read per step from a capture without metadata, matching one sync_rx buffer
+const RAW_CHUNK_BYTES: usize = 4096;
+
+// Bytes read per step from a baseband capture, 4 ms at 16 MSPS
+const IQ_CHUNK_BYTES: usize = 262144;
+
+// Layout of a capture file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaptureFormat {
+  // bare SC16_Q11 samples, timestamps are counted from the start of the file
+  Sc16Q11,
+  // SC16_Q11_META messages of the given size, timestamps come from the message headers
+  Sc16Q11Meta { message_bytes: usize },
+  // baseband SC16_Q11 samples as the stock FPGA streams them, demodulated in software with
+  // preambles scoring at least preamble_threshold dB (see demod::preamble_score)
+  Iq { preamble_threshold: f32 },
+}
+
+// One message of a SC16_Q11_META capture
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetaMessage<'a> {
+  pub timestamp: u64,
+  pub flags: u32,
+  pub payload: &'a [u8],
+}
+
+// Split a buffer of whole SC16_Q11_META messages into headers and payloads. A trailing partial
+// message is ignored.
+pub fn parse_meta_messages(buf: &[u8], message_bytes: usize) -> Vec<MetaMessage<'_>> {
+  assert!(message_bytes > META_HEADER_BYTES, "message size must leave room for the header");
+
+  buf.chunks_exact(message_bytes).map(|message| {
+    let mut timestamp = [0u8; 8];
+    timestamp.copy_from_slice(&message[META_TIMESTAMP_OFFSET..META_TIMESTAMP_OFFSET + 8]);
+    let mut flags = [0u8; 4];
+    flags.copy_from_slice(&message[META_FLAGS_OFFSET..META_FLAGS_OFFSET + 4]);
+
+    MetaMessage {
+      timestamp: u64::from_le_bytes(timestamp),
+      flags: u32::from_le_bytes(flags),
+      payload: &message[META_HEADER_BYTES..],
+    }
+  }).collect()
+}
+
+// Reads a capture back in chunks and extracts the frames in it, timestamped as if they had just
+// been received
+pub struct ReplaySource<R: Read> {
+  reader: R,
+  format: CaptureFormat,
+  sample_rate: u32,
+  // samples read so far, the time base for captures without metadata
+  samples_read: u64,
+  // Iq only: the end of the previous chunk, not yet searched because a frame starting there
+  // would run into the next chunk
+  carry: Vec<u8>,
+  // Iq only: the power between frames, for their SNR
+  noise_floor: NoiseFloor,
+}
+
+impl<R: Read> ReplaySource<R> {
+  pub fn new(reader: R, format: CaptureFormat, sample_rate: u32) -> ReplaySource<R> {
+    ReplaySource {
+      reader,
+      format,
+      sample_rate,
+      samples_read: 0,
+      carry: Vec::new(),
+      noise_floor: NoiseFloor::new(NOISE_FLOOR_ALPHA),
+    }
+  }
+
+  // Frames from the next chunk of the capture, or None at the end of the file
+  pub fn next_frames(&mut self) -> io::Result<Option<Vec<AdsbFrame>>> {
+    let chunk_bytes = match self.format {
+      CaptureFormat::Sc16Q11 => RAW_CHUNK_BYTES,
+      CaptureFormat::Sc16Q11Meta { message_bytes } => message_bytes,
+      CaptureFormat::Iq { .. } => IQ_CHUNK_BYTES,
+    };
+
+    let mut buf = vec![0u8; chunk_bytes];
+    let filled = read_full(&mut self.reader, &mut buf)?;
+    // an Iq capture still has its carried over tail to search
+    if filled == 0 && self.carry.is_empty() {
+      return Ok(None);
+    }
+    buf.truncate(filled);
+
+    let mut frames = Vec::new();
+    match self.format {
+      CaptureFormat::Sc16Q11 => {
+        for mut frame in extract_frames(&buf) {
+          frame.timestamp = Some(samples_to_mlat_ticks(self.samples_read + frame.sample_offset(), self.sample_rate));
+          frames.push(frame);
+        }
+        self.samples_read += (filled / BYTES_PER_SAMPLE) as u64;
+      },
+      CaptureFormat::Sc16Q11Meta { message_bytes } => {
+        // a partial message at the end of the file has no trustworthy payload
+        for message in parse_meta_messages(&buf, message_bytes) {
+          for mut frame in extract_frames(message.payload) {
+            frame.timestamp = Some(samples_to_mlat_ticks(message.timestamp + frame.sample_offset(), self.sample_rate));
+            frames.push(frame);
+          }
+          self.samples_read += (message.payload.len() / BYTES_PER_SAMPLE) as u64;
+        }
+      },
+      CaptureFormat::Iq { preamble_threshold } => {
+        let samples_per_us = (self.sample_rate / 1_000_000) as usize;
+        let mut samples = std::mem::take(&mut self.carry);
+        samples.extend_from_slice(&buf);
+        let mag = magnitudes(&samples);
+
+        // at the end of the file there's nothing left to wait for
+        let at_end = filled < chunk_bytes;
+        let search = if at_end { mag.len() } else { mag.len().saturating_sub(frame_samples(samples_per_us)) };
+        let mut next = search;
+        let demodulated = demodulate(&mag, search, samples_per_us, preamble_threshold);
+
+        // the noise floor comes from the searched samples the frames don't cover
+        let mut quiet_power = 0.0;
+        let mut quiet_samples = 0;
+        let mut quiet_start = 0;
+        for (offset, data) in &demodulated {
+          let end = offset + (data.len() * 8 + 8) * samples_per_us;
+          for m in &mag[quiet_start.min(*offset)..*offset] {
+            quiet_power += m * m;
+          }
+          quiet_samples += offset.saturating_sub(quiet_start);
+          quiet_start = quiet_start.max(end);
+        }
+        for m in &mag[quiet_start.min(search)..search] {
+          quiet_power += m * m;
+        }
+        quiet_samples += search.saturating_sub(quiet_start);
+        if quiet_samples > 0 {
+          self.noise_floor.update(quiet_power / quiet_samples as f32);
+        }
+
+        for (offset, data) in demodulated {
+          let end = offset + (data.len() * 8 + 8) * samples_per_us;
+          // don't search the tail of a frame that runs into the carried over samples again
+          next = next.max(end);
+          let snr = self.noise_floor.snr(frame_power(&mag, offset, samples_per_us, data.len() * 8));
+          frames.push(AdsbFrame {
+            data,
+            slot: 0,
+            timestamp: Some(samples_to_mlat_ticks(self.samples_read + offset as u64, self.sample_rate)),
+            frequency: None,
+            snr,
+          });
+        }
+
+        let next = next.min(mag.len());
+        self.samples_read += next as u64;
+        if !at_end {
+          self.carry = samples[next * BYTES_PER_SAMPLE..].to_vec();
+        }
+      },
+    }
+
+    Ok(Some(frames))
+  }
+}
+
+// Fill as much of buf as the reader can provide, short only at the end of the input
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+  let mut filled = 0;
+  while filled < buf.len() {
+    match reader.read(&mut buf[filled..]) {
+      Ok(0) => break,
+      Ok(n) => filled += n,
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(filled)
+}
+
+// Serialized frame logs --replay-frames can read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameLogFormat {
+  // AVR lines, with or without MLAT timestamps
+  Avr,
+  // binary Beast messages
+  Beast,
+}
+
+impl FrameLogFormat {
+  // Tell the format from the first byte of the log: Beast messages start with 0x1a, AVR lines
+  // with '*' or '@'
+  pub fn detect(start: &[u8]) -> Option<FrameLogFormat> {
+    match start.iter().find(|byte| !byte.is_ascii_whitespace())? {
+      &BEAST_ESCAPE => Some(FrameLogFormat::Beast),
+      b'*' | b'@' => Some(FrameLogFormat::Avr),
+      _ => None,
+    }
+  }
+}
+
+// Reads frames back from an AVR or Beast log, bypassing sample decoding entirely. Lines and
+// messages that don't hold a Mode S frame (Mode A/C, status messages, garbage) are skipped and
+// counted. A timestamp of 0 means the writer didn't know it, so such frames get none.
+pub struct FrameLogSource<R: BufRead> {
+  reader: R,
+  format: FrameLogFormat,
+  // type byte of a Beast message whose leading 0x1a has already been read
+  pending_type: Option<u8>,
+  pub skipped: u64,
+}
+
+impl<R: BufRead> FrameLogSource<R> {
+  // Detect the format from the start of the log. Errors with InvalidData if it's neither.
+  pub fn new(mut reader: R) -> io::Result<FrameLogSource<R>> {
+    let format = FrameLogFormat::detect(reader.fill_buf()?)
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an AVR or Beast log"))?;
+    Ok(FrameLogSource { reader, format, pending_type: None, skipped: 0 })
+  }
+
+  pub fn format(&self) -> FrameLogFormat {
+    self.format
+  }
+
+  // The next frame in the log, or None at the end of it
+  pub fn next_frame(&mut self) -> io::Result<Option<AdsbFrame>> {
+    loop {
+      let parsed = match self.format {
+        FrameLogFormat::Avr => {
+          let mut line = String::new();
+          if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+          }
+          if line.trim().is_empty() {
+            continue;
+          }
+          parse_avr(&line).map(|data| (parse_avr_timestamp(&line).unwrap_or(0), data))
+        },
+        FrameLogFormat::Beast => {
+          let message = match self.next_beast_message()? {
+            Some(message) => message,
+            None => return Ok(None),
+          };
+          parse_beast(&message).map(|(timestamp, _, data)| (timestamp, data))
+        },
+      };
+
+      match parsed {
+        Some((timestamp, data)) => {
+          return Ok(Some(AdsbFrame {
+            data,
+            slot: 0,
+            timestamp: if timestamp == 0 { None } else { Some(timestamp) },
+            frequency: None,
+            snr: None,
+          }));
+        },
+        None => self.skipped += 1,
+      }
+    }
+  }
+
+  // Raw bytes of the next Beast message, still escaped, for parse_beast. Messages that aren't
+  // Mode S frames come back as just their escape and type byte. None at the end of the log.
+  fn next_beast_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+    let kind = match self.pending_type.take() {
+      Some(kind) => kind,
+      // resynchronise on the next 0x1a that isn't an escaped data byte
+      None => loop {
+        match self.next_byte()? {
+          Some(BEAST_ESCAPE) => match self.next_byte()? {
+            Some(BEAST_ESCAPE) => continue,
+            Some(kind) => break kind,
+            None => return Ok(None),
+          },
+          Some(_) => continue,
+          None => return Ok(None),
+        }
+      },
+    };
+
+    let mut message = vec![BEAST_ESCAPE, kind];
+    // timestamp, signal level and frame
+    let fields = match kind {
+      BEAST_TYPE_SHORT => 6 + 1 + SHORT_FRAME_BYTES,
+      BEAST_TYPE_LONG => 6 + 1 + LONG_FRAME_BYTES,
+      _ => return Ok(Some(message)),
+    };
+    let mut unescaped = 0;
+    while unescaped < fields {
+      match self.next_byte()? {
+        Some(BEAST_ESCAPE) => match self.next_byte()? {
+          Some(BEAST_ESCAPE) => message.extend_from_slice(&[BEAST_ESCAPE, BEAST_ESCAPE]),
+          // the message was cut short by the start of the next one
+          Some(next) => {
+            self.pending_type = Some(next);
+            break;
+          },
+          None => break,
+        },
+        Some(byte) => message.push(byte),
+        None => break,
+      }
+      unescaped += 1;
+    }
+    Ok(Some(message))
+  }
+
+  fn next_byte(&mut self) -> io::Result<Option<u8>> {
+    let byte = self.reader.fill_buf()?.first().copied();
+    if byte.is_some() {
+      self.reader.consume(1);
+    }
+    Ok(byte)
+  }
+}
+
+// Spaces replayed frames out the way they were received, using their MLAT timestamps. Frames
+// without a timestamp go out straight away. A timestamp going backwards (a new capture appended,
+// or the 48 bit counter wrapping) restarts the timeline from that frame.
+pub struct ReplayPacer {
+  // first timestamp of the current timeline and when it was replayed
+  start: Option<(u64, Instant)>,
+}
+
+impl ReplayPacer {
+  pub fn new() -> ReplayPacer {
+    ReplayPacer { start: None }
+  }
+
+  // How long to wait before forwarding a frame with this timestamp
+  pub fn delay(&mut self, timestamp: Option<u64>, now: Instant) -> Duration {
+    let timestamp = match timestamp {
+      Some(timestamp) => timestamp,
+      None => return Duration::ZERO,
+    };
+    match self.start {
+      Some((first, started)) if timestamp >= first => {
+        let offset = Duration::from_nanos(((timestamp - first) as u128 * 1_000_000_000 / MLAT_CLOCK_HZ as u128) as u64);
+        (started + offset).saturating_duration_since(now)
+      },
+      _ => {
+        self.start = Some((timestamp, now));
+        Duration::ZERO
+      },
+    }
+  }
+}
+
+impl Default for ReplayPacer {
+  fn default() -> Self {
+    ReplayPacer::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+  use crate::fixtures::{frame, DF17};
+
+  // A 16 byte FPGA slot flagged as holding DF17
+  fn slot() -> Vec<u8> {
+    let mut slot = vec![0u8; 16];
+    slot[0] = 0x01;
+    slot[2..2 + DF17.len()].copy_from_slice(&DF17);
+    slot
+  }
+
+  // A SC16_Q11_META message as it is on the wire, its payload zero padded to message_bytes
+  fn meta_message(timestamp: u64, flags: u32, payload: &[u8], message_bytes: usize) -> Vec<u8> {
+    let mut message = vec![0u8; META_HEADER_BYTES];
+    message[META_TIMESTAMP_OFFSET..META_TIMESTAMP_OFFSET + 8].copy_from_slice(&timestamp.to_le_bytes());
+    message[META_FLAGS_OFFSET..META_FLAGS_OFFSET + 4].copy_from_slice(&flags.to_le_bytes());
+    message.extend_from_slice(payload);
+    message.resize(message_bytes, 0);
+    message
+  }
+
+  fn timestamps<R: Read>(mut source: ReplaySource<R>) -> Vec<Option<u64>> {
+    let mut timestamps = Vec::new();
+    while let Some(frames) = source.next_frames().unwrap() {
+      timestamps.extend(frames.iter().map(|frame| frame.timestamp));
+    }
+    timestamps
+  }
+
+  #[test]
+  fn meta_headers_are_split_from_their_payloads() {
+    let mut buf = meta_message(0x0102_0304_0506_0708, 0x8000_0001, &[0xaa; 8], 32);
+    buf.extend(meta_message(42, 0, &[0xbb; 8], 32));
+    // a partial message at the end is left out
+    buf.extend_from_slice(&[0xcc; 20]);
+
+    let messages = parse_meta_messages(&buf, 32);
+    assert_eq!(messages.len(), 2);
+    assert_eq!((messages[0].timestamp, messages[0].flags), (0x0102_0304_0506_0708, 0x8000_0001));
+    assert_eq!(&messages[0].payload[..8], &[0xaa; 8]);
+    assert_eq!(messages[0].payload.len(), 32 - META_HEADER_BYTES);
+    assert_eq!(messages[1].timestamp, 42);
+  }
+
+  #[test]
+  fn raw_captures_count_samples_from_the_start_of_the_file() {
+    // the second frame is one slot into the second chunk, 1024 + 4 samples in
+    let mut capture = slot();
+    capture.resize(RAW_CHUNK_BYTES, 0);
+    capture.extend(vec![0u8; 16]);
+    capture.extend(slot());
+
+    let source = ReplaySource::new(Cursor::new(capture), CaptureFormat::Sc16Q11, 16000000);
+    assert_eq!(timestamps(source), vec![Some(0), Some(samples_to_mlat_ticks(1028, 16000000))]);
+  }
+
+  #[test]
+  fn meta_captures_take_timestamps_from_the_headers() {
+    // 3 slots per message; the device counter jumps between them as it would after an overrun
+    let mut first = vec![0u8; 32];
+    first.extend(slot());
+    let mut capture = meta_message(1000, 0, &first, 64);
+    capture.extend(meta_message(5000, 0, &slot(), 64));
+    capture.extend_from_slice(&[0u8; 20]);
+
+    let source = ReplaySource::new(Cursor::new(capture), CaptureFormat::Sc16Q11Meta { message_bytes: 64 }, 16000000);
+    assert_eq!(timestamps(source), vec![
+      Some(samples_to_mlat_ticks(1008, 16000000)),
+      Some(samples_to_mlat_ticks(5000, 16000000)),
+    ]);
+  }
+
+  fn logged(timestamp: Option<u64>) -> AdsbFrame {
+    AdsbFrame { timestamp, ..frame(&DF17) }
+  }
+
+  fn frames<R: BufRead>(mut source: FrameLogSource<R>) -> (Vec<AdsbFrame>, u64) {
+    let mut frames = Vec::new();
+    while let Some(frame) = source.next_frame().unwrap() {
+      frames.push(frame);
+    }
+    (frames, source.skipped)
+  }
+
+  #[test]
+  fn log_formats_are_told_apart_by_their_first_byte() {
+    assert_eq!(FrameLogFormat::detect(b"\n*8d4840d6"), Some(FrameLogFormat::Avr));
+    assert_eq!(FrameLogFormat::detect(b"@000012345678"), Some(FrameLogFormat::Avr));
+    assert_eq!(FrameLogFormat::detect(&[BEAST_ESCAPE, BEAST_TYPE_LONG]), Some(FrameLogFormat::Beast));
+    assert_eq!(FrameLogFormat::detect(b"hello"), None);
+    assert!(FrameLogSource::new(Cursor::new(b"hello".to_vec())).is_err());
+  }
+
+  #[test]
+  fn avr_logs_skip_lines_without_a_frame() {
+    let log = format!("{}\n*8d4840d6;\n{}", crate::format::to_avr_mlat(&logged(Some(42))), crate::format::to_avr_mlat(&logged(None)));
+    let (frames, skipped) = frames(FrameLogSource::new(Cursor::new(log.into_bytes())).unwrap());
+
+    assert_eq!(frames.iter().map(|frame| frame.timestamp).collect::<Vec<_>>(), vec![Some(42), None]);
+    assert!(frames.iter().all(|frame| frame.data == DF17));
+    assert_eq!(skipped, 1);
+  }
+
+  #[test]
+  fn beast_logs_resynchronise_after_other_messages() {
+    let mut log = crate::format::to_beast(&logged(Some(0x1a1a)));
+    // a Mode A/C message, then a Mode S frame cut short by the next message
+    log.extend_from_slice(&[BEAST_ESCAPE, b'1', 0, 0, 0, 0, 0, 0, 0, 0x12, 0x34]);
+    log.extend_from_slice(&crate::format::to_beast(&logged(Some(7)))[..12]);
+    log.extend_from_slice(&crate::format::to_beast(&logged(Some(9))));
+    let (frames, skipped) = frames(FrameLogSource::new(Cursor::new(log)).unwrap());
+
+    assert_eq!(frames.iter().map(|frame| frame.timestamp).collect::<Vec<_>>(), vec![Some(0x1a1a), Some(9)]);
+    assert_eq!(skipped, 2);
+  }
+
+  #[test]
+  fn the_pacer_spaces_frames_by_their_timestamps() {
+    let mut pacer = ReplayPacer::new();
+    let start = Instant::now();
+
+    assert_eq!(pacer.delay(Some(1000), start), Duration::ZERO);
+    assert_eq!(pacer.delay(Some(1000 + MLAT_CLOCK_HZ / 2), start), Duration::from_millis(500));
+    assert_eq!(pacer.delay(Some(1000 + MLAT_CLOCK_HZ / 2), start + Duration::from_secs(1)), Duration::ZERO);
+    assert_eq!(pacer.delay(None, start), Duration::ZERO);
+  }
+
+  #[test]
+  fn the_pacer_restarts_when_timestamps_go_backwards() {
+    let mut pacer = ReplayPacer::new();
+    let start = Instant::now();
+
+    pacer.delay(Some(MLAT_CLOCK_HZ), start);
+    assert_eq!(pacer.delay(Some(5), start + Duration::from_secs(3)), Duration::ZERO);
+    assert_eq!(pacer.delay(Some(5 + MLAT_CLOCK_HZ), start + Duration::from_secs(3)), Duration::from_secs(1));
+  }
+}
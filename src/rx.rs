@@ -0,0 +1,194 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+
+use log::{debug, warn};
+
+use bladerf::BladeRFDevice;
+use bladerf::bladerf::bladerf_module::*;
+use bladerf::bladerf::bladerf_error::*;
+
+use crate::buffers::DEFAULT_RX_TIMEOUT_MS;
+use crate::error::AppError;
+use crate::frame::{clear_unfilled, extract_frames, received, AdsbFrame};
+use crate::replay::{CaptureFormat, ReplaySource};
+
+// Frames buffered for the consumer before the reader blocks
+pub const DEFAULT_RX_QUEUE: usize = 256;
+
+// Where run_rx reads frames from
+pub enum RxSource {
+  // A device opened by the closure, which runs on the reader thread and returns it with the
+  // FPGA loaded, the RX module configured and sync_config applied, as for AsyncRxStream::spawn
+  Device(Box<dyn FnOnce() -> Result<BladeRFDevice, isize> + Send>),
+  // A raw sample capture, read as with --replay
+  Replay { path: PathBuf, format: CaptureFormat, sample_rate: u32 },
+}
+
+pub struct RxConfig {
+  pub source: RxSource,
+  // frames buffered between the reader thread and the consumer
+  pub queue: usize,
+}
+
+impl RxConfig {
+  pub fn new(source: RxSource) -> RxConfig {
+    RxConfig { source, queue: DEFAULT_RX_QUEUE }
+  }
+}
+
+// Read frames on a background thread and hand them over through a channel, for embedding the
+// frame extraction without any of the binary's sinks. Frames arrive unfiltered, the consumer
+// decides what to keep. Reading ends when `stop` is set, the source runs out or the receiver is
+// dropped; the thread's result says whether it ended on an error.
+pub fn run_rx(config: RxConfig, stop: Arc<AtomicBool>) -> (Receiver<AdsbFrame>, thread::JoinHandle<Result<(), AppError>>) {
+  let (sender, receiver) = sync_channel(config.queue);
+  let source = config.source;
+  let handle = thread::Builder::new().name(String::from("adsb-rx")).spawn(move || {
+    match source {
+      RxSource::Device(setup) => rx_device(setup, sender, stop),
+      RxSource::Replay { path, format, sample_rate } => rx_replay(path, format, sample_rate, sender, stop),
+    }
+  }).expect("failed to spawn RX thread");
+
+  (receiver, handle)
+}
+
+fn rx_device(setup: Box<dyn FnOnce() -> Result<BladeRFDevice, isize> + Send>, sender: SyncSender<AdsbFrame>,
+             stop: Arc<AtomicBool>) -> Result<(), AppError> {
+  let dev = setup().map_err(AppError::DeviceOpen)?;
+  if let Err(e) = dev.enable_module(BLADERF_MODULE_RX, true) {
+    dev.close();
+    return Err(AppError::Device(String::from("enable_module"), e));
+  }
+
+  let mut messages: [u8; 4096] = [0; 4096];
+  let mut result = Ok(());
+  'rx: while !stop.load(Ordering::SeqCst) {
+    let samples = match dev.sync_rx(&mut messages, 1024, None, DEFAULT_RX_TIMEOUT_MS) {
+      Ok(samples) => samples as usize,
+      Err(e) if e == BLADERF_ERR_TIMEOUT as isize => {
+        debug!("run_rx: sync_rx timed out, retrying");
+        continue;
+      },
+      Err(e) => {
+        result = Err(AppError::Device(String::from("sync_rx"), e));
+        break;
+      },
+    };
+
+    clear_unfilled(&mut messages, samples);
+    for frame in extract_frames(received(&messages, samples)) {
+      // receiver dropped, nobody is listening any more
+      if sender.send(frame).is_err() {
+        break 'rx;
+      }
+    }
+  }
+
+  if let Err(e) = dev.shutdown(BLADERF_MODULE_RX) {
+    warn!("run_rx: device shutdown failed (error {})", e);
+  }
+  result
+}
+
+fn rx_replay(path: PathBuf, format: CaptureFormat, sample_rate: u32, sender: SyncSender<AdsbFrame>,
+             stop: Arc<AtomicBool>) -> Result<(), AppError> {
+  let file = File::open(&path)
+    .map_err(|e| AppError::Config(format!("unable to open replay file {}: {}", path.display(), e)))?;
+  let mut source = ReplaySource::new(BufReader::new(file), format, sample_rate);
+
+  while !stop.load(Ordering::SeqCst) {
+    let frames = match source.next_frames() {
+      Ok(Some(frames)) => frames,
+      Ok(None) => break,
+      Err(e) => return Err(AppError::Config(format!("error reading replay file {}: {}", path.display(), e))),
+    };
+    for frame in frames {
+      if sender.send(frame).is_err() {
+        return Ok(());
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::process;
+  use std::time::Duration;
+  use crate::fixtures::DF17;
+
+  use bladerf::mock::MockBladeRf;
+
+  // One sync_rx buffer whose first slot holds DF17
+  fn buffer() -> Vec<u8> {
+    let mut buf = vec![0u8; 4096];
+    buf[0] = 0x01;
+    buf[2..2 + DF17.len()].copy_from_slice(&DF17);
+    buf
+  }
+
+  fn device(mock: &MockBladeRf) -> RxSource {
+    let mock = mock.clone();
+    RxSource::Device(Box::new(move || Ok(BladeRFDevice::with_api(Box::new(mock)))))
+  }
+
+  #[test]
+  fn device_frames_arrive_until_stopped_then_the_device_is_closed() {
+    let mock = MockBladeRf::new();
+    mock.push_rx_buffer(buffer());
+    let stop = Arc::new(AtomicBool::new(false));
+    let (frames, handle) = run_rx(RxConfig::new(device(&mock)), stop.clone());
+
+    assert_eq!(frames.recv_timeout(Duration::from_secs(5)).unwrap().data, DF17.to_vec());
+    stop.store(true, Ordering::SeqCst);
+    assert_eq!(handle.join().unwrap(), Ok(()));
+    assert_eq!(mock.calls().last().map(String::as_str), Some("close()"));
+  }
+
+  #[test]
+  fn a_sync_rx_error_ends_reading() {
+    let mock = MockBladeRf::new();
+    mock.push_result("sync_rx", BLADERF_ERR_IO as i32);
+    let (_frames, handle) = run_rx(RxConfig::new(device(&mock)), Arc::new(AtomicBool::new(false)));
+
+    assert_eq!(handle.join().unwrap(), Err(AppError::Device(String::from("sync_rx"), BLADERF_ERR_IO as isize)));
+    assert_eq!(mock.calls().last().map(String::as_str), Some("close()"));
+  }
+
+  #[test]
+  fn a_device_that_fails_to_open_is_reported() {
+    let source = RxSource::Device(Box::new(|| Err(BLADERF_ERR_NODEV as isize)));
+    let (_frames, handle) = run_rx(RxConfig::new(source), Arc::new(AtomicBool::new(false)));
+
+    assert_eq!(handle.join().unwrap(), Err(AppError::DeviceOpen(BLADERF_ERR_NODEV as isize)));
+  }
+
+  #[test]
+  fn a_replay_is_read_to_the_end() {
+    let path = std::env::temp_dir().join(format!("bladerf_adsb-test-{}-rx.sc16q11", process::id()));
+    fs::write(&path, buffer()).unwrap();
+    let source = RxSource::Replay { path: path.clone(), format: CaptureFormat::Sc16Q11, sample_rate: 16000000 };
+    let (frames, handle) = run_rx(RxConfig::new(source), Arc::new(AtomicBool::new(false)));
+
+    let result = handle.join().unwrap();
+    let _ = fs::remove_file(&path);
+    assert_eq!(result, Ok(()));
+    assert_eq!(frames.iter().map(|frame| frame.data).collect::<Vec<_>>(), vec![DF17.to_vec()]);
+  }
+
+  #[test]
+  fn a_missing_replay_file_is_a_config_error() {
+    let source = RxSource::Replay { path: PathBuf::from("/nonexistent/capture.sc16q11"), format: CaptureFormat::Sc16Q11, sample_rate: 16000000 };
+    let (_frames, handle) = run_rx(RxConfig::new(source), Arc::new(AtomicBool::new(false)));
+
+    assert!(matches!(handle.join().unwrap(), Err(AppError::Config(_))));
+  }
+}
@@ -0,0 +1,148 @@
+use std::time::{Duration, Instant};
+
+// Samples right after a retune are garbage while the LO settles, frames inside this window are dropped
+pub const RETUNE_SETTLE: Duration = Duration::from_millis(5);
+
+// Frames decoded while the gain settles after the stream starts or moves to a new frequency are
+// mostly noise, default length of the window they are dropped in
+pub const DEFAULT_WARMUP_DISCARD_MS: u64 = 20;
+
+// Round-robin schedule over a fixed set of frequencies, dwelling on each in turn
+pub struct FrequencyScheduler {
+  frequencies: Vec<u32>,
+  dwell: Duration,
+  settle: Duration,
+  index: usize,
+  tuned_at: Instant,
+}
+
+impl FrequencyScheduler {
+  pub fn new(frequencies: Vec<u32>, dwell: Duration, settle: Duration, now: Instant) -> FrequencyScheduler {
+    assert!(!frequencies.is_empty(), "frequency list must not be empty");
+
+    FrequencyScheduler {
+      frequencies,
+      dwell,
+      settle,
+      index: 0,
+      tuned_at: now,
+    }
+  }
+
+  pub fn current(&self) -> u32 {
+    self.frequencies[self.index]
+  }
+
+  // Advance to the next frequency once the dwell has elapsed, returning it so the caller can retune.
+  // A single entry list never needs retuning.
+  pub fn poll(&mut self, now: Instant) -> Option<u32> {
+    if self.frequencies.len() < 2 || now.duration_since(self.tuned_at) < self.dwell {
+      return None;
+    }
+
+    self.index = (self.index + 1) % self.frequencies.len();
+    self.tuned_at = now;
+    Some(self.current())
+  }
+
+  // Stay on a single frequency from now on, replacing the list
+  pub fn retune(&mut self, frequency: u32, now: Instant) {
+    self.frequencies = vec![frequency];
+    self.index = 0;
+    self.tuned_at = now;
+  }
+
+  // Whether we are still inside the settling window after the last retune. The first tune at
+  // startup is covered by the usual stream start, so this only applies once we have moved.
+  pub fn settling(&self, now: Instant) -> bool {
+    self.frequencies.len() > 1 && now.duration_since(self.tuned_at) < self.settle
+  }
+}
+
+// Drops frames for a fixed number of samples after every (re)start of the stream. Counting
+// samples rather than time keeps the window exact however late buffers are read.
+pub struct WarmupWindow {
+  length: u64,
+  until: u64,
+}
+
+impl WarmupWindow {
+  // A window starting at sample 0, the start of the stream
+  pub fn new(duration: Duration, sample_rate: u32) -> WarmupWindow {
+    let length = (duration.as_secs_f64() * sample_rate as f64) as u64;
+    WarmupWindow { length, until: length }
+  }
+
+  // Start the window again from `sample`, after a retune or a restart of the stream
+  pub fn restart(&mut self, sample: u64) {
+    self.until = sample + self.length;
+  }
+
+  // Whether a frame starting at `sample` is still inside the window
+  pub fn discards(&self, sample: u64) -> bool {
+    sample < self.until
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const DWELL: Duration = Duration::from_millis(1000);
+
+  #[test]
+  fn scheduler_cycles_through_the_list_once_each_dwell_is_up() {
+    let start = Instant::now();
+    let mut scheduler = FrequencyScheduler::new(vec![1090000000, 978000000, 1030000000], DWELL, RETUNE_SETTLE, start);
+    assert_eq!(scheduler.current(), 1090000000);
+
+    assert_eq!(scheduler.poll(start + DWELL / 2), None);
+    assert_eq!(scheduler.poll(start + DWELL), Some(978000000));
+    assert_eq!(scheduler.poll(start + DWELL * 2), Some(1030000000));
+    // the dwell counts from the last retune
+    assert_eq!(scheduler.poll(start + DWELL * 2 + DWELL / 2), None);
+    assert_eq!(scheduler.poll(start + DWELL * 3), Some(1090000000));
+  }
+
+  #[test]
+  fn scheduler_never_retunes_a_single_frequency() {
+    let start = Instant::now();
+    let mut scheduler = FrequencyScheduler::new(vec![1090000000], DWELL, RETUNE_SETTLE, start);
+
+    assert_eq!(scheduler.poll(start + DWELL * 10), None);
+    assert!(!scheduler.settling(start));
+  }
+
+  #[test]
+  fn scheduler_is_settling_right_after_a_retune() {
+    let start = Instant::now();
+    let mut scheduler = FrequencyScheduler::new(vec![1090000000, 978000000], DWELL, RETUNE_SETTLE, start);
+    scheduler.poll(start + DWELL);
+
+    assert!(scheduler.settling(start + DWELL));
+    assert!(!scheduler.settling(start + DWELL + RETUNE_SETTLE));
+  }
+
+  #[test]
+  #[should_panic]
+  fn scheduler_needs_a_frequency() {
+    FrequencyScheduler::new(Vec::new(), DWELL, RETUNE_SETTLE, Instant::now());
+  }
+
+  #[test]
+  fn the_warmup_window_covers_its_length_in_samples() {
+    let window = WarmupWindow::new(Duration::from_millis(50), 16000000);
+    assert!(window.discards(0));
+    assert!(window.discards(799999));
+    assert!(!window.discards(800000));
+  }
+
+  #[test]
+  fn a_restart_opens_the_window_again() {
+    let mut window = WarmupWindow::new(Duration::from_millis(1), 16000000);
+    window.restart(1000000);
+    assert!(window.discards(1015999));
+    assert!(!window.discards(1016000));
+    assert!(!WarmupWindow::new(Duration::ZERO, 16000000).discards(0));
+  }
+}
@@ -0,0 +1,108 @@
+use std::str::FromStr;
+
+use crate::decode::frame_icao;
+
+// Where --shard sends frames that carry no ICAO address, from --shard-unknown
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnknownShard {
+  // take turns across all the sinks
+  RoundRobin,
+  // always the same one, an index into the --sink list
+  Sink(usize),
+}
+
+impl FromStr for UnknownShard {
+  type Err = String;
+
+  // "round-robin", or the 1 based position of a --sink
+  fn from_str(s: &str) -> Result<UnknownShard, String> {
+    if s == "round-robin" {
+      return Ok(UnknownShard::RoundRobin);
+    }
+    match s.parse::<usize>() {
+      Ok(n) if n > 0 => Ok(UnknownShard::Sink(n - 1)),
+      _ => Err(format!("'{}' isn't a sink, expected round-robin or the position of a --sink (1, 2, ...)", s)),
+    }
+  }
+}
+
+// The shard for an ICAO address. Mixed with a fixed multiplier rather than a seeded hasher, so
+// an aircraft lands on the same sink on every run, and so addresses handed out in consecutive
+// blocks still spread evenly.
+pub fn icao_shard(icao: u32, shards: usize) -> usize {
+  let mixed = (icao as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+  (mixed % shards as u64) as usize
+}
+
+// Picks the one sink each frame goes to under --shard, so every frame from an aircraft ends up
+// at the same downstream processor
+pub struct Sharder {
+  shards: usize,
+  unknown: UnknownShard,
+  // next shard for round robin
+  next: usize,
+}
+
+impl Sharder {
+  pub fn new(shards: usize, unknown: UnknownShard) -> Sharder {
+    assert!(shards > 0, "sharding needs at least one sink");
+    Sharder { shards, unknown, next: 0 }
+  }
+
+  pub fn shard_for(&mut self, data: &[u8]) -> usize {
+    match (frame_icao(data), self.unknown) {
+      (Some(icao), _) => icao_shard(icao, self.shards),
+      (None, UnknownShard::Sink(index)) => index,
+      (None, UnknownShard::RoundRobin) => {
+        let shard = self.next;
+        self.next = (self.next + 1) % self.shards;
+        shard
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::DF17;
+
+  // DF4 surveillance reply, its address folded into the parity
+  const DF4: [u8; 7] = [0x20, 0x00, 0x17, 0x18, 0x29, 0x4c, 0x57];
+
+  #[test]
+  fn unknown_shards_parse_from_round_robin_or_a_position() {
+    assert_eq!("round-robin".parse::<UnknownShard>(), Ok(UnknownShard::RoundRobin));
+    assert_eq!("2".parse::<UnknownShard>(), Ok(UnknownShard::Sink(1)));
+    assert!("0".parse::<UnknownShard>().is_err());
+    assert!("first".parse::<UnknownShard>().is_err());
+  }
+
+  #[test]
+  fn frames_from_an_aircraft_always_go_to_the_same_shard() {
+    let mut sharder = Sharder::new(3, UnknownShard::RoundRobin);
+    let shard = icao_shard(0x4840d6, 3);
+    for _ in 0..5 {
+      assert_eq!(sharder.shard_for(&DF17), shard);
+    }
+  }
+
+  #[test]
+  fn consecutive_addresses_spread_evenly() {
+    let mut counts = [0; 3];
+    for icao in 0x400000..0x400000 + 3000 {
+      counts[icao_shard(icao, 3)] += 1;
+    }
+    assert!(counts.iter().all(|count| (900..=1100).contains(count)), "{:?}", counts);
+  }
+
+  #[test]
+  fn frames_without_an_address_go_round_robin_or_to_the_chosen_sink() {
+    let mut sharder = Sharder::new(3, UnknownShard::RoundRobin);
+    assert_eq!((0..4).map(|_| sharder.shard_for(&DF4)).collect::<Vec<_>>(), vec![0, 1, 2, 0]);
+
+    let mut sharder = Sharder::new(3, UnknownShard::Sink(1));
+    assert_eq!(sharder.shard_for(&DF4), 1);
+    assert_eq!(sharder.shard_for(&DF4), 1);
+  }
+}
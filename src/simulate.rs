@@ -0,0 +1,193 @@
+use crate::aircraft::{cpr_nl, CALLSIGN_CHARSET, CPR_NZ, CPR_SCALE};
+use crate::crc::crc24;
+use crate::frame::{AdsbFrame, MLAT_CLOCK_HZ};
+
+// Default for --simulate-rate, in frames per second
+pub const DEFAULT_SIMULATE_RATE: u32 = 100;
+
+// Aircraft --simulate flies, each sending its own callsign, positions and velocity
+pub const SIMULATED_AIRCRAFT: u32 = 8;
+
+// Simulated aircraft get consecutive addresses from here
+const SIMULATED_ICAO_BASE: u32 = 0xc0ffe0;
+
+// Where they start out from, roughly Amsterdam
+const SIMULATED_ORIGIN: (f64, f64) = (52.3, 4.76);
+
+const DF17_CAPABILITY: u8 = 0x8d;
+const TC_IDENTIFICATION: u64 = 4;
+const TC_AIRBORNE_POSITION: u64 = 11;
+const TC_AIRBORNE_VELOCITY: u64 = 19;
+
+// Each aircraft sends these in turn
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Message {
+  Identification,
+  EvenPosition,
+  OddPosition,
+  Velocity,
+}
+
+const MESSAGES: [Message; 4] = [Message::Identification, Message::EvenPosition, Message::OddPosition, Message::Velocity];
+
+struct SimulatedAircraft {
+  icao: u32,
+  callsign: String,
+  // feet, knots and degrees clockwise from north
+  altitude: i32,
+  ground_speed: f64,
+  track: f64,
+  // next message to send
+  message: usize,
+}
+
+// Makes up DF17 extended squitters with valid parity from a handful of aircraft flying straight
+// lines away from SIMULATED_ORIGIN, for exercising the outputs without a device. Frames are
+// timestamped as if received `rate` per second, so their MLAT timestamps pace them.
+pub struct Simulator {
+  rate: u32,
+  aircraft: Vec<SimulatedAircraft>,
+  // frames made so far
+  frames: u64,
+}
+
+impl Simulator {
+  pub fn new(rate: u32) -> Simulator {
+    assert!(rate > 0, "simulated frame rate must be positive");
+    let aircraft = (0..SIMULATED_AIRCRAFT).map(|i| SimulatedAircraft {
+      icao: SIMULATED_ICAO_BASE + i,
+      callsign: format!("SIM{:04}", i + 1),
+      altitude: 10000 + 1000 * i as i32,
+      ground_speed: 250.0 + 20.0 * i as f64,
+      track: 45.0 * i as f64,
+      message: 0,
+    }).collect();
+    Simulator { rate, aircraft, frames: 0 }
+  }
+
+  pub fn next_frame(&mut self) -> AdsbFrame {
+    let elapsed = self.frames as f64 / self.rate as f64;
+    let timestamp = (self.frames as u128 * MLAT_CLOCK_HZ as u128 / self.rate as u128) as u64;
+    let index = (self.frames % self.aircraft.len() as u64) as usize;
+    self.frames += 1;
+
+    let aircraft = &mut self.aircraft[index];
+    let message = MESSAGES[aircraft.message];
+    aircraft.message = (aircraft.message + 1) % MESSAGES.len();
+
+    let me = match message {
+      Message::Identification => identification(&aircraft.callsign),
+      Message::EvenPosition | Message::OddPosition => {
+        let (lat, lon) = position_after(aircraft, elapsed);
+        airborne_position(aircraft.altitude, lat, lon, message == Message::OddPosition)
+      },
+      Message::Velocity => airborne_velocity(aircraft.ground_speed, aircraft.track),
+    };
+    AdsbFrame {
+      data: extended_squitter(aircraft.icao, me),
+      slot: 0,
+      timestamp: Some(timestamp),
+      frequency: None,
+      snr: None,
+    }
+  }
+}
+
+// Dead reckoning from the origin, `elapsed` seconds in
+fn position_after(aircraft: &SimulatedAircraft, elapsed: f64) -> (f64, f64) {
+  let nautical_miles = aircraft.ground_speed * elapsed / 3600.0;
+  let track = aircraft.track.to_radians();
+  let lat = SIMULATED_ORIGIN.0 + nautical_miles * track.cos() / 60.0;
+  let lon = SIMULATED_ORIGIN.1 + nautical_miles * track.sin() / (60.0 * lat.to_radians().cos());
+  (lat, lon)
+}
+
+// A DF17 frame around a 56 bit ME field, parity included
+fn extended_squitter(icao: u32, me: u64) -> Vec<u8> {
+  let mut data = vec![DF17_CAPABILITY, (icao >> 16) as u8, (icao >> 8) as u8, icao as u8];
+  data.extend_from_slice(&me.to_be_bytes()[1..]);
+  let parity = crc24(&data);
+  data.extend_from_slice(&parity.to_be_bytes()[1..]);
+  data
+}
+
+// Characters missing from the identification character set are sent as spaces
+fn identification(callsign: &str) -> u64 {
+  let mut me = TC_IDENTIFICATION << 51;
+  for (i, c) in format!("{:<8}", callsign).bytes().take(8).enumerate() {
+    let code = CALLSIGN_CHARSET.iter().position(|&known| known == c && c != b'#').unwrap_or(32);
+    me |= (code as u64) << (42 - 6 * i);
+  }
+  me
+}
+
+// Altitude in the 25 ft encoding, with the CPR encoded position
+fn airborne_position(altitude: i32, lat: f64, lon: f64, odd: bool) -> u64 {
+  let n = ((altitude + 1000) / 25).max(0) as u64;
+  let ac = ((n & 0x7f0) << 1) | 0x10 | (n & 0x0f);
+  let (cpr_lat, cpr_lon) = cpr_encode(lat, lon, odd);
+  (TC_AIRBORNE_POSITION << 51) | (ac << 36) | ((odd as u64) << 34) | ((cpr_lat as u64) << 17) | cpr_lon as u64
+}
+
+// Subtype 1 (subsonic ground speed) velocity, level flight
+fn airborne_velocity(ground_speed: f64, track: f64) -> u64 {
+  let track = track.to_radians();
+  let ew = (ground_speed * track.sin()).round() as i64;
+  let ns = (ground_speed * track.cos()).round() as i64;
+  let component = |v: i64| -> (u64, u64) { ((v < 0) as u64, (v.unsigned_abs() + 1).min(1023)) };
+  let (ew_sign, ew_raw) = component(ew);
+  let (ns_sign, ns_raw) = component(ns);
+  (TC_AIRBORNE_VELOCITY << 51) | (1 << 48) | (ew_sign << 42) | (ew_raw << 32) | (ns_sign << 31) | (ns_raw << 21)
+}
+
+// The 17 bit CPR latitude and longitude of a position, the inverse of aircraft::cpr_global
+fn cpr_encode(lat: f64, lon: f64, odd: bool) -> (u32, u32) {
+  let i = odd as i32 as f64;
+  let dlat = 360.0 / (4.0 * CPR_NZ - i);
+  let yz = (CPR_SCALE * lat.rem_euclid(dlat) / dlat + 0.5).floor();
+  let rlat = dlat * (yz / CPR_SCALE + (lat / dlat).floor());
+  let dlon = 360.0 / ((cpr_nl(rlat) as f64 - i).max(1.0));
+  let xz = (CPR_SCALE * lon.rem_euclid(dlon) / dlon + 0.5).floor();
+  ((yz as u32) % CPR_SCALE as u32, (xz as u32) % CPR_SCALE as u32)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Instant;
+  use crate::aircraft::AircraftRegistry;
+  use crate::crc::syndrome;
+
+  #[test]
+  fn frames_are_valid_df17_timestamped_at_the_rate() {
+    let mut simulator = Simulator::new(100);
+    for n in 0..16u64 {
+      let frame = simulator.next_frame();
+      assert_eq!(frame.data.len(), 14);
+      assert_eq!(frame.data[0] >> 3, 17);
+      assert_eq!(syndrome(&frame.data), 0);
+      assert_eq!(frame.timestamp, Some(n * MLAT_CLOCK_HZ / 100));
+    }
+  }
+
+  #[test]
+  fn every_aircraft_decodes_in_full() {
+    let mut simulator = Simulator::new(100);
+    let mut registry = AircraftRegistry::new();
+    let now = Instant::now();
+    for _ in 0..SIMULATED_AIRCRAFT * MESSAGES.len() as u32 {
+      registry.ingest(&simulator.next_frame(), now);
+    }
+
+    assert_eq!(registry.len(), SIMULATED_AIRCRAFT as usize);
+    for i in 0..SIMULATED_AIRCRAFT {
+      let aircraft = registry.get(SIMULATED_ICAO_BASE + i).unwrap();
+      assert_eq!(aircraft.callsign.as_deref(), Some(format!("SIM{:04}", i + 1).as_str()));
+      assert_eq!(aircraft.altitude, Some(10000 + 1000 * i as i32));
+      let (lat, lon) = aircraft.position.unwrap();
+      assert!((lat - SIMULATED_ORIGIN.0).abs() < 0.1 && (lon - SIMULATED_ORIGIN.1).abs() < 0.1, "{} {}", lat, lon);
+      assert!((aircraft.ground_speed.unwrap() - (250.0 + 20.0 * i as f64)).abs() < 1.0);
+      assert!((aircraft.track.unwrap() - 45.0 * i as f64).abs() < 1.0);
+    }
+  }
+}
@@ -0,0 +1,1029 @@
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::mem::ManuallyDrop;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{trace, debug, info, warn};
+
+use crate::format::OutputFormat;
+use crate::ratelimit::RateLimitedLogger;
+use crate::spool::Spool;
+use crate::stats::SinkStats;
+
+// A remote server and, optionally, the format it wants, from a HOST:PORT[:FORMAT] spec
+#[derive(Clone, Debug, PartialEq)]
+pub struct SinkSpec {
+  pub addr: SocketAddr,
+  // None uses --output-format
+  pub format: Option<OutputFormat>,
+}
+
+impl FromStr for SinkSpec {
+  type Err = String;
+
+  fn from_str(spec: &str) -> Result<SinkSpec, String> {
+    // A trailing segment starting with a letter is a format name; ports are numeric and IPv6
+    // literals are bracketed, so neither is mistaken for one
+    let (address, format) = match spec.rsplit_once(':') {
+      Some((address, name)) if name.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+        match OutputFormat::from_name(name) {
+          Some(OutputFormat::None) => return Err(String::from("a sink can't use the none format, give --output-format none instead of a --sink")),
+          Some(format) => (address, Some(format)),
+          None => {
+            let names: Vec<&str> = OutputFormat::ALL.iter().map(|f| f.name()).collect();
+            return Err(format!("unknown output format '{}' (expected one of: {})", name, names.join(", ")));
+          },
+        }
+      },
+      _ => (spec, None),
+    };
+
+    Ok(SinkSpec { addr: parse_addr(address)?, format })
+  }
+}
+
+// Resolve a HOST:PORT address, taking the first address it resolves to
+pub fn parse_addr(address: &str) -> Result<SocketAddr, String> {
+  address.to_socket_addrs()
+    .map_err(|e| format!("invalid address '{}': {}", address, e))?
+    .next()
+    .ok_or_else(|| format!("'{}' did not resolve to any address", address))
+}
+
+// Whether frames go over the network, from --remote / --no-remote / --sink
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RemoteMode {
+  // no networking at all
+  Off,
+  // connect per the reconnect policy, failing for good is fatal
+  Required,
+  // try the default server once, and decode locally if nothing is listening
+  Optional,
+}
+
+// Where frames are sent, worked out from the command line before anything is opened
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputPlan {
+  pub remote: RemoteMode,
+  pub stdout: bool,
+}
+
+impl OutputPlan {
+  // `remote` is --remote when it was given, `sinks` whether any --sink was. --no-remote is
+  // short for --remote false --output-stdout; an explicit --remote true or a --sink insists on
+  // the network, otherwise the default server is only used if it's there.
+  pub fn new(remote: Option<bool>, no_remote: bool, sinks: bool, stdout: bool) -> OutputPlan {
+    if no_remote {
+      return OutputPlan { remote: RemoteMode::Off, stdout: true };
+    }
+    let remote = match remote {
+      Some(false) => RemoteMode::Off,
+      Some(true) => RemoteMode::Required,
+      None if sinks => RemoteMode::Required,
+      None => RemoteMode::Optional,
+    };
+    OutputPlan { remote, stdout }
+  }
+}
+
+// Backoff policy used both for the initial connect and for reconnecting mid-stream
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+  pub initial_delay: Duration,
+  pub max_delay: Duration,
+  // 0 retries forever
+  pub max_attempts: u32,
+}
+
+impl Default for ReconnectPolicy {
+  fn default() -> Self {
+    ReconnectPolicy {
+      initial_delay: Duration::from_millis(500),
+      max_delay: Duration::from_secs(30),
+      max_attempts: 0,
+    }
+  }
+}
+
+impl ReconnectPolicy {
+  // Delay before the given (1 based) retry, doubling each time up to max_delay
+  pub fn delay_for(&self, attempt: u32) -> Duration {
+    let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+    self.initial_delay.saturating_mul(factor).min(self.max_delay)
+  }
+
+  fn exhausted(&self, attempt: u32) -> bool {
+    self.max_attempts != 0 && attempt >= self.max_attempts
+  }
+}
+
+// Connect to addr, retrying per policy until it succeeds, the attempts run out or running is cleared
+pub fn connect_with_retry(addr: SocketAddr, policy: &ReconnectPolicy, running: &AtomicBool) -> io::Result<TcpStream> {
+  let mut attempt: u32 = 0;
+  // the first retries come quickly, don't log every one of them
+  let mut log = RateLimitedLogger::default();
+
+  loop {
+    attempt += 1;
+    match TcpStream::connect(addr) {
+      Ok(stream) => {
+        info!("Connected to {}", addr);
+        return Ok(stream);
+      },
+      Err(e) => {
+        if policy.exhausted(attempt) {
+          warn!("Unable to connect to {} ({}), giving up after {} attempts", addr, e, attempt);
+          return Err(e);
+        }
+
+        let delay = policy.delay_for(attempt);
+        if let Some(message) = log.event("connect_failed", Instant::now(), || {
+          format!("Unable to connect to {} ({}), retrying in {:?} (attempt {})", addr, e, delay, attempt)
+        }) {
+          warn!("{}", message);
+        }
+        thread::sleep(delay);
+      },
+    }
+
+    if !running.load(Ordering::SeqCst) {
+      return Err(io::Error::new(io::ErrorKind::Interrupted, "shutdown requested while connecting"));
+    }
+  }
+}
+
+// Default for --flush-interval-ms
+pub const DEFAULT_FLUSH_INTERVAL_MS: u64 = 50;
+
+// How much a sink buffers before writing to the socket. Unbuffered, every frame is its own TCP
+// segment; buffered, frames go out together once `bytes` have built up or `flush_interval` has
+// passed, whichever comes first, trading latency for fewer, fuller segments.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SinkBuffer {
+  // 0 sends every frame straight away
+  pub bytes: usize,
+  pub flush_interval: Duration,
+}
+
+impl Default for SinkBuffer {
+  fn default() -> Self {
+    SinkBuffer {
+      bytes: 0,
+      flush_interval: Duration::from_millis(DEFAULT_FLUSH_INTERVAL_MS),
+    }
+  }
+}
+
+// An output frames are written to once serialized
+pub trait FrameSink {
+  // Name the sink's counters are reported under, e.g. "tcp:127.0.0.1:30001"
+  fn name(&self) -> String;
+
+  // Counters of this sink alone
+  fn stats(&self) -> Arc<SinkStats>;
+
+  // Write one serialized frame. An error means the sink is gone for good.
+  fn send(&mut self, buf: &[u8], running: &AtomicBool) -> io::Result<()>;
+
+  // Send whatever has been buffered for longer than the flush interval, for when no frames
+  // are coming in to push it out
+  fn flush_if_due(&mut self, now: Instant, running: &AtomicBool) -> io::Result<()>;
+
+  // Send anything still buffered, before shutting down
+  fn finish(&mut self) -> io::Result<()>;
+
+  // Number of times the sink has had to re-establish its connection
+  fn reconnects(&self) -> u64 {
+    self.stats().snapshot().reconnects
+  }
+}
+
+// How long a spooling sink waits on each attempt to reconnect, holding up the other outputs.
+// The stats connection waits as long, holding up only itself.
+const SPOOL_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Where a spooling sink is at in reconnecting, while its frames go to the spool
+struct Disconnected {
+  // attempts made so far
+  attempt: u32,
+  next_attempt: Instant,
+}
+
+// TCP connection to the remote server that transparently reconnects when a write fails. With
+// a spool, frames go there while the server is down instead of holding everything up until
+// it's back, and are sent first thing after reconnecting.
+pub struct TcpSink {
+  addr: SocketAddr,
+  policy: ReconnectPolicy,
+  stream: BufWriter<TcpStream>,
+  buffer: SinkBuffer,
+  last_flush: Instant,
+  stats: Arc<SinkStats>,
+  log: RateLimitedLogger,
+  spool: Option<Spool>,
+  // Some while a spooling sink has lost its server; `stream` is the dead connection until then
+  disconnected: Option<Disconnected>,
+}
+
+impl TcpSink {
+  pub fn connect(addr: SocketAddr, policy: ReconnectPolicy, running: &AtomicBool) -> io::Result<TcpSink> {
+    info!("Connecting to {}", addr);
+    let stream = connect_with_retry(addr, &policy, running)?;
+
+    Ok(TcpSink::new(addr, policy, stream))
+  }
+
+  // Connect with a single attempt, keeping the policy for reconnecting later on
+  pub fn try_connect(addr: SocketAddr, policy: ReconnectPolicy) -> io::Result<TcpSink> {
+    let stream = TcpStream::connect(addr)?;
+    info!("Connected to {}", addr);
+
+    Ok(TcpSink::new(addr, policy, stream))
+  }
+
+  fn new(addr: SocketAddr, policy: ReconnectPolicy, stream: TcpStream) -> TcpSink {
+    let buffer = SinkBuffer::default();
+    TcpSink {
+      addr,
+      policy,
+      stream: BufWriter::with_capacity(buffer.bytes, stream),
+      buffer,
+      last_flush: Instant::now(),
+      stats: Arc::new(SinkStats::new()),
+      log: RateLimitedLogger::default(),
+      spool: None,
+      disconnected: None,
+    }
+  }
+
+  // Spool frames while the server is down rather than waiting for it to come back
+  pub fn with_spool(mut self, spool: Spool) -> TcpSink {
+    self.spool = Some(spool);
+    self
+  }
+
+  // Buffer writes as `buffer` says, rather than sending every frame on its own
+  pub fn with_buffer(mut self, buffer: SinkBuffer) -> TcpSink {
+    // nothing is buffered yet right after connecting, so swapping the writer loses nothing
+    let (stream, _) = self.stream.into_parts();
+    self.stream = BufWriter::with_capacity(buffer.bytes, stream);
+    self.buffer = buffer;
+    self
+  }
+
+  pub fn addr(&self) -> SocketAddr {
+    self.addr
+  }
+
+  // Either limit forces a flush. Unbuffered, that's every time.
+  fn flush_if_full(&mut self, now: Instant) -> io::Result<()> {
+    if self.stream.buffer().len() >= self.buffer.bytes || now.saturating_duration_since(self.last_flush) >= self.buffer.flush_interval {
+      self.flush(now)
+    } else {
+      Ok(())
+    }
+  }
+
+  fn flush(&mut self, now: Instant) -> io::Result<()> {
+    // Tell TCP to send the buffered data on the wire
+    trace!("flush server stream");
+    self.stream.flush()?;
+    self.last_flush = now;
+    Ok(())
+  }
+
+  // Replace a connection that failed with `error`, giving up per the reconnect policy
+  fn reconnect(&mut self, error: io::Error, running: &AtomicBool) -> io::Result<()> {
+    self.stats.failed(error.to_string());
+    let addr = self.addr;
+    if let Some(message) = self.log.event("send_failed", Instant::now(), || {
+      format!("Error sending buffer to {} ({}), reconnecting", addr, error)
+    }) {
+      warn!("{}", message);
+    }
+    let stream = connect_with_retry(self.addr, &self.policy, running).map_err(|e| {
+      self.stats.failed(e.to_string());
+      e
+    })?;
+    // frames still buffered were meant for the old connection and are dropped with it
+    let (_, unsent) = std::mem::replace(&mut self.stream, BufWriter::with_capacity(self.buffer.bytes, stream)).into_parts();
+    if let Ok(unsent) = unsent {
+      if !unsent.is_empty() {
+        debug!("Dropped {} buffered bytes for {} when reconnecting", unsent.len(), self.addr);
+      }
+    }
+    self.last_flush = Instant::now();
+    self.stats.reconnected();
+    Ok(())
+  }
+
+  // Start spooling after a connection failed with `error`. Frames still buffered for it go to
+  // the spool first, as they were due out before anything sent from now on; they were counted
+  // as sent when they were buffered.
+  fn disconnect(&mut self, error: io::Error) {
+    self.stats.failed(error.to_string());
+    let Some(spool) = self.spool.as_ref() else { return };
+    warn!("Lost connection to {} ({}), spooling frames to {} until it's back", self.addr, error, spool.path().display());
+    let unsent = self.stream.buffer().to_vec();
+    if !unsent.is_empty() {
+      self.spool_frame(&unsent, false);
+    }
+    self.disconnected = Some(Disconnected { attempt: 0, next_attempt: Instant::now() + self.policy.delay_for(1) });
+  }
+
+  // Write a frame to the spool, counting it unless it's the leftovers of the old connection
+  fn spool_frame(&mut self, buf: &[u8], count: bool) {
+    let addr = self.addr;
+    let Some(spool) = self.spool.as_mut() else { return };
+    match spool.push(buf) {
+      Ok(true) => {
+        if count {
+          self.stats.spooled();
+        }
+      },
+      Ok(false) => {
+        self.stats.spool_dropped();
+        if let Some(message) = self.log.event("spool_full", Instant::now(), || format!("Spool for {} is full, dropping frames", addr)) {
+          warn!("{}", message);
+        }
+      },
+      Err(e) => {
+        self.stats.spool_dropped();
+        if let Some(message) = self.log.event("spool_failed", Instant::now(), || format!("Unable to spool frames for {} ({}), dropping them", addr, e)) {
+          warn!("{}", message);
+        }
+      },
+    }
+  }
+
+  // While disconnected, try the server once if the policy says it's time. Err once the policy
+  // gives up on it, leaving the spool for the next run.
+  fn try_reconnect(&mut self, now: Instant) -> io::Result<()> {
+    let Some(disconnected) = self.disconnected.as_mut() else { return Ok(()) };
+    if now < disconnected.next_attempt {
+      return Ok(());
+    }
+    disconnected.attempt += 1;
+    match TcpStream::connect_timeout(&self.addr, SPOOL_CONNECT_TIMEOUT) {
+      Ok(stream) => {
+        let pending = self.spool.as_ref().map_or(0, |spool| spool.pending());
+        info!("Reconnected to {}, sending {} spooled bytes first", self.addr, pending);
+        // what the old connection had buffered is in the spool already
+        let _ = std::mem::replace(&mut self.stream, BufWriter::with_capacity(self.buffer.bytes, stream)).into_parts();
+        self.last_flush = now;
+        self.disconnected = None;
+        self.stats.reconnected();
+        Ok(())
+      },
+      Err(e) => {
+        self.stats.failed(e.to_string());
+        if self.policy.exhausted(disconnected.attempt) {
+          warn!("Unable to reconnect to {} ({}), giving up after {} attempts", self.addr, e, disconnected.attempt);
+          return Err(e);
+        }
+        let delay = self.policy.delay_for(disconnected.attempt + 1);
+        disconnected.next_attempt = now + delay;
+        let (addr, attempt) = (self.addr, disconnected.attempt);
+        if let Some(message) = self.log.event("connect_failed", now, || {
+          format!("Unable to reconnect to {} ({}), retrying in {:?} (attempt {})", addr, e, delay, attempt)
+        }) {
+          warn!("{}", message);
+        }
+        Ok(())
+      },
+    }
+  }
+
+  // Send what was spooled straight to the socket, ahead of anything live. Only ever called with
+  // nothing buffered, so the two can't get out of order.
+  fn drain_spool(&mut self) -> io::Result<()> {
+    match self.spool.as_mut() {
+      Some(spool) if spool.pending() > 0 => {
+        let pending = spool.pending();
+        spool.drain_to(self.stream.get_mut())?;
+        debug!("Sent {} spooled bytes to {}", pending, self.addr);
+        Ok(())
+      },
+      _ => Ok(()),
+    }
+  }
+
+  // send for a sink with a spool
+  fn send_spooling(&mut self, buf: &[u8]) -> io::Result<()> {
+    self.try_reconnect(Instant::now())?;
+    if self.disconnected.is_none() {
+      match self.drain_spool().and_then(|_| self.stream.write_all(buf)) {
+        Ok(()) => {
+          debug!("Sent {} bytes to {}", buf.len(), self.addr);
+          self.stats.sent(buf.len());
+          // a failed flush leaves the frame buffered, which disconnect spools
+          if let Err(e) = self.flush_if_full(Instant::now()) {
+            self.disconnect(e);
+          }
+          return Ok(());
+        },
+        Err(e) => self.disconnect(e),
+      }
+    }
+    self.spool_frame(buf, true);
+    Ok(())
+  }
+}
+
+impl FrameSink for TcpSink {
+  fn name(&self) -> String {
+    format!("tcp:{}", self.addr)
+  }
+
+  fn stats(&self) -> Arc<SinkStats> {
+    self.stats.clone()
+  }
+
+  // Write the whole buffer, reconnecting and resending if the connection dropped. With a
+  // SinkBuffer, it may only go out on a later send or flush_if_due.
+  fn send(&mut self, buf: &[u8], running: &AtomicBool) -> io::Result<()> {
+    if self.spool.is_some() {
+      return self.send_spooling(buf);
+    }
+    loop {
+      match self.stream.write_all(buf).and_then(|_| self.flush_if_full(Instant::now())) {
+        Ok(()) => {
+          debug!("Sent {} bytes to {}", buf.len(), self.addr);
+          self.stats.sent(buf.len());
+          return Ok(());
+        },
+        Err(e) => self.reconnect(e, running)?,
+      }
+    }
+  }
+
+  fn flush_if_due(&mut self, now: Instant, running: &AtomicBool) -> io::Result<()> {
+    // a spooling sink reconnects and catches up even when no frames are coming in
+    if self.spool.is_some() {
+      self.try_reconnect(now)?;
+      if self.disconnected.is_none() {
+        if let Err(e) = self.drain_spool() {
+          self.disconnect(e);
+        }
+      }
+    }
+    if self.disconnected.is_some() || self.stream.buffer().is_empty() || now.saturating_duration_since(self.last_flush) < self.buffer.flush_interval {
+      return Ok(());
+    }
+    if let Err(e) = self.flush(now) {
+      if self.spool.is_some() {
+        self.disconnect(e);
+      } else {
+        self.reconnect(e, running)?;
+      }
+    }
+    Ok(())
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    if let Some(spool) = self.spool.as_ref() {
+      if self.disconnected.is_some() {
+        info!("{} bytes for {} left in {}", spool.pending(), self.addr, spool.path().display());
+        return Ok(());
+      }
+    }
+    self.flush(Instant::now())
+  }
+}
+
+// First descriptor systemd passes with socket activation
+pub const SD_LISTEN_FDS_START: RawFd = 3;
+
+// Descriptors passed by systemd socket activation (LISTEN_FDS), if they were meant for this
+// process (LISTEN_PID)
+pub fn systemd_listen_fds() -> Vec<RawFd> {
+  let for_us = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(process::id());
+  let count = env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<RawFd>().ok()).unwrap_or(0);
+  if !for_us {
+    return Vec::new();
+  }
+  (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect()
+}
+
+// A listening socket handed to FdSink, whose clients each get every frame
+enum FdListener {
+  Tcp(TcpListener),
+  Unix(UnixListener),
+}
+
+impl FdListener {
+  // A client waiting to be accepted, None once there are no more
+  fn accept(&self) -> io::Result<Option<Box<dyn Write>>> {
+    let accepted = match self {
+      FdListener::Tcp(listener) => listener.accept().map(|(stream, _)| Box::new(stream) as Box<dyn Write>),
+      FdListener::Unix(listener) => listener.accept().map(|(stream, _)| Box::new(stream) as Box<dyn Write>),
+    };
+    match accepted {
+      Ok(client) => Ok(Some(client)),
+      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+      Err(e) => Err(e),
+    }
+  }
+}
+
+// What an inherited descriptor turned out to be
+enum FdTarget {
+  // a pipe, file, terminal or connected socket, written to directly
+  Stream(File),
+  // a listening socket, e.g. systemd's ListenStream= with Accept=no
+  Listener(FdListener, Vec<Box<dyn Write>>),
+}
+
+// Writes frames to a descriptor inherited from whoever started us, such as systemd with socket
+// activation, instead of connecting anywhere itself. The descriptor isn't reopened when it
+// fails, since only its owner could; a listening socket just loses the client that went away.
+pub struct FdSink {
+  fd: RawFd,
+  target: FdTarget,
+  stats: Arc<SinkStats>,
+}
+
+impl FdSink {
+  // Take ownership of `fd`, which is closed when the sink is dropped
+  pub fn inherit(fd: RawFd) -> io::Result<FdSink> {
+    // SAFETY: the caller hands the descriptor over, and nothing else in the process uses it.
+    // The probes are ManuallyDrop so only the kept wrapper ends up closing it.
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let file_type = file.metadata()?.file_type();
+    let target = if !file_type.is_socket() {
+      FdTarget::Stream(ManuallyDrop::into_inner(file))
+    } else {
+      let tcp = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+      let unix = ManuallyDrop::new(unsafe { UnixStream::from_raw_fd(fd) });
+      // only an inet socket has an address TcpStream understands
+      let inet = tcp.local_addr().is_ok();
+      let connected = if inet { tcp.peer_addr().is_ok() } else { unix.peer_addr().is_ok() };
+      if connected {
+        FdTarget::Stream(ManuallyDrop::into_inner(file))
+      } else {
+        let listener = if inet {
+          FdListener::Tcp(unsafe { TcpListener::from_raw_fd(fd) })
+        } else {
+          FdListener::Unix(unsafe { UnixListener::from_raw_fd(fd) })
+        };
+        match &listener {
+          FdListener::Tcp(listener) => listener.set_nonblocking(true)?,
+          FdListener::Unix(listener) => listener.set_nonblocking(true)?,
+        }
+        FdTarget::Listener(listener, Vec::new())
+      }
+    };
+
+    match target {
+      FdTarget::Stream(_) => info!("Writing frames to inherited descriptor {}", fd),
+      FdTarget::Listener(..) => info!("Serving frames to clients of inherited listening socket {}", fd),
+    }
+    Ok(FdSink { fd, target, stats: Arc::new(SinkStats::new()) })
+  }
+}
+
+impl FrameSink for FdSink {
+  fn name(&self) -> String {
+    format!("fd:{}", self.fd)
+  }
+
+  fn stats(&self) -> Arc<SinkStats> {
+    self.stats.clone()
+  }
+
+  fn send(&mut self, buf: &[u8], _running: &AtomicBool) -> io::Result<()> {
+    match &mut self.target {
+      FdTarget::Stream(file) => {
+        if let Err(e) = file.write_all(buf) {
+          self.stats.failed(e.to_string());
+          return Err(e);
+        }
+      },
+      FdTarget::Listener(listener, clients) => {
+        while let Some(client) = listener.accept()? {
+          info!("Client connected to inherited socket {}", self.fd);
+          clients.push(client);
+        }
+        let fd = self.fd;
+        let stats = &self.stats;
+        clients.retain_mut(|client| match client.write_all(buf) {
+          Ok(()) => true,
+          Err(e) => {
+            info!("Client of inherited socket {} went away ({})", fd, e);
+            stats.failed(e.to_string());
+            false
+          },
+        });
+      },
+    }
+    self.stats.sent(buf.len());
+    Ok(())
+  }
+
+  // Nothing is buffered
+  fn flush_if_due(&mut self, _now: Instant, _running: &AtomicBool) -> io::Result<()> {
+    Ok(())
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    match &mut self.target {
+      FdTarget::Stream(file) => file.flush(),
+      FdTarget::Listener(..) => Ok(()),
+    }
+  }
+}
+
+// Swallows every frame, for --output-format none. Frames are still counted so the statistics
+// show how many made it through the pipeline.
+#[derive(Default)]
+pub struct NullSink {
+  stats: Arc<SinkStats>,
+}
+
+impl NullSink {
+  pub fn new() -> NullSink {
+    NullSink { stats: Arc::new(SinkStats::new()) }
+  }
+}
+
+impl FrameSink for NullSink {
+  fn name(&self) -> String {
+    String::from("null")
+  }
+
+  fn stats(&self) -> Arc<SinkStats> {
+    self.stats.clone()
+  }
+
+  fn send(&mut self, buf: &[u8], _running: &AtomicBool) -> io::Result<()> {
+    self.stats.sent(buf.len());
+    Ok(())
+  }
+
+  fn flush_if_due(&mut self, _now: Instant, _running: &AtomicBool) -> io::Result<()> {
+    Ok(())
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+// The --stats-remote connection. A line every so often doesn't warrant holding anything up for
+// the server, so while it's down lines are dropped and a reconnect is tried as each one comes
+// due, backing off per the policy, as long as its attempts haven't run out.
+pub struct StatsSender {
+  addr: SocketAddr,
+  policy: ReconnectPolicy,
+  stream: Option<TcpStream>,
+  // None until the first connection attempt, then whenever connected
+  disconnected: Option<Disconnected>,
+  connected_before: bool,
+  stats: Arc<SinkStats>,
+  log: RateLimitedLogger,
+}
+
+impl StatsSender {
+  pub fn new(addr: SocketAddr, policy: ReconnectPolicy) -> StatsSender {
+    StatsSender {
+      addr,
+      policy,
+      stream: None,
+      disconnected: None,
+      connected_before: false,
+      stats: Arc::new(SinkStats::new()),
+      log: RateLimitedLogger::default(),
+    }
+  }
+
+  pub fn name(&self) -> String {
+    format!("stats:{}", self.addr)
+  }
+
+  pub fn stats(&self) -> Arc<SinkStats> {
+    self.stats.clone()
+  }
+
+  // Send one line, connecting first if need be. Errors only once the policy's attempts to
+  // connect have run out.
+  pub fn send(&mut self, line: &[u8], now: Instant) -> io::Result<()> {
+    if self.stream.is_none() {
+      self.connect(now)?;
+    }
+    let Some(stream) = self.stream.as_mut() else { return Ok(()) };
+    match stream.write_all(line) {
+      Ok(()) => self.stats.sent(line.len()),
+      Err(e) => {
+        self.stats.failed(e.to_string());
+        warn!("Error sending statistics to {} ({}), reconnecting", self.addr, e);
+        // the next line tries straight away, and backs off from there
+        self.stream = None;
+        self.disconnected = Some(Disconnected { attempt: 0, next_attempt: now });
+      },
+    }
+    Ok(())
+  }
+
+  fn connect(&mut self, now: Instant) -> io::Result<()> {
+    let disconnected = self.disconnected.get_or_insert(Disconnected { attempt: 0, next_attempt: now });
+    if now < disconnected.next_attempt {
+      return Ok(());
+    }
+    disconnected.attempt += 1;
+    match TcpStream::connect_timeout(&self.addr, SPOOL_CONNECT_TIMEOUT) {
+      Ok(stream) => {
+        info!("Connected to stats server {}", self.addr);
+        self.stream = Some(stream);
+        self.disconnected = None;
+        if self.connected_before {
+          self.stats.reconnected();
+        }
+        self.connected_before = true;
+        Ok(())
+      },
+      Err(e) => {
+        self.stats.failed(e.to_string());
+        if self.policy.exhausted(disconnected.attempt) {
+          return Err(e);
+        }
+        let delay = self.policy.delay_for(disconnected.attempt);
+        disconnected.next_attempt = now + delay;
+        let (addr, attempt) = (self.addr, disconnected.attempt);
+        if let Some(message) = self.log.event("stats_connect_failed", now, || {
+          format!("Unable to connect to stats server {} ({}), dropping statistics and retrying in {:?} (attempt {})", addr, e, delay, attempt)
+        }) {
+          warn!("{}", message);
+        }
+        Ok(())
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{BufRead, BufReader};
+  use std::os::unix::io::IntoRawFd;
+
+  fn quick_policy(max_attempts: u32) -> ReconnectPolicy {
+    ReconnectPolicy { initial_delay: Duration::from_millis(1), max_delay: Duration::from_millis(4), max_attempts }
+  }
+
+  // An address nothing is listening on
+  fn closed_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+  }
+
+  #[test]
+  fn sink_specs_take_an_optional_format() {
+    assert_eq!("127.0.0.1:30005:beast".parse::<SinkSpec>(),
+               Ok(SinkSpec { addr: "127.0.0.1:30005".parse().unwrap(), format: Some(OutputFormat::Beast) }));
+    assert_eq!("127.0.0.1:30001".parse::<SinkSpec>(), Ok(SinkSpec { addr: "127.0.0.1:30001".parse().unwrap(), format: None }));
+    // the last group of an IPv6 literal isn't taken for a format name
+    assert_eq!("[::1]:30002:avr-mlat".parse::<SinkSpec>(),
+               Ok(SinkSpec { addr: "[::1]:30002".parse().unwrap(), format: Some(OutputFormat::AvrMlat) }));
+  }
+
+  #[test]
+  fn unknown_sink_formats_are_rejected() {
+    let err = "127.0.0.1:30005:sbs".parse::<SinkSpec>().unwrap_err();
+    assert!(err.starts_with("unknown output format 'sbs'"), "{}", err);
+    assert!("127.0.0.1".parse::<SinkSpec>().is_err());
+  }
+
+  #[test]
+  fn delay_doubles_up_to_the_maximum() {
+    let policy = ReconnectPolicy::default();
+    assert_eq!(policy.delay_for(1), Duration::from_millis(500));
+    assert_eq!(policy.delay_for(2), Duration::from_secs(1));
+    assert_eq!(policy.delay_for(4), Duration::from_secs(4));
+    assert_eq!(policy.delay_for(7), Duration::from_secs(30));
+    assert_eq!(policy.delay_for(1000), Duration::from_secs(30));
+  }
+
+  #[test]
+  fn connect_with_retry_connects_to_a_listening_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let running = AtomicBool::new(true);
+
+    assert!(connect_with_retry(listener.local_addr().unwrap(), &quick_policy(1), &running).is_ok());
+  }
+
+  #[test]
+  fn connect_with_retry_gives_up_once_the_attempts_run_out() {
+    let running = AtomicBool::new(true);
+
+    let err = connect_with_retry(closed_addr(), &quick_policy(3), &running).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+  }
+
+  #[test]
+  fn connect_with_retry_stops_on_shutdown() {
+    let running = AtomicBool::new(false);
+
+    let err = connect_with_retry(closed_addr(), &quick_policy(0), &running).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+  }
+
+  #[test]
+  fn stats_lines_reach_a_listening_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut sender = StatsSender::new(listener.local_addr().unwrap(), quick_policy(1));
+
+    sender.send(b"{\"messages\":1}\n", Instant::now()).unwrap();
+    let mut line = String::new();
+    BufReader::new(listener.accept().unwrap().0).read_line(&mut line).unwrap();
+    assert_eq!(line, "{\"messages\":1}\n");
+    assert_eq!((sender.stats().snapshot().frames, sender.stats().snapshot().bytes), (1, 15));
+    assert_eq!(sender.name(), format!("stats:{}", listener.local_addr().unwrap()));
+  }
+
+  #[test]
+  fn stats_lines_are_dropped_while_the_server_is_down() {
+    let mut sender = StatsSender::new(closed_addr(), quick_policy(2));
+    let start = Instant::now();
+
+    assert!(sender.send(b"{}\n", start).is_ok());
+    // not due for another attempt yet
+    assert!(sender.send(b"{}\n", start).is_ok());
+    assert_eq!(sender.send(b"{}\n", start + Duration::from_secs(1)).unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+    assert_eq!(sender.stats().snapshot().frames, 0);
+    assert!(sender.stats().snapshot().last_error.is_some());
+  }
+
+  #[test]
+  fn the_default_server_is_optional_unless_asked_for() {
+    assert_eq!(OutputPlan::new(None, false, false, false), OutputPlan { remote: RemoteMode::Optional, stdout: false });
+    assert_eq!(OutputPlan::new(Some(true), false, false, false).remote, RemoteMode::Required);
+    assert_eq!(OutputPlan::new(None, false, true, false).remote, RemoteMode::Required);
+    assert_eq!(OutputPlan::new(Some(false), false, true, true), OutputPlan { remote: RemoteMode::Off, stdout: true });
+  }
+
+  #[test]
+  fn no_remote_decodes_to_stdout_only() {
+    assert_eq!(OutputPlan::new(Some(true), true, true, false), OutputPlan { remote: RemoteMode::Off, stdout: true });
+  }
+
+  // Whatever the server has received so far, waiting a little for it to arrive
+  fn received(server: &mut TcpStream) -> Vec<u8> {
+    server.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    let mut received = Vec::new();
+    let mut buf = [0u8; 256];
+    while let Ok(read) = io::Read::read(server, &mut buf) {
+      if read == 0 {
+        break;
+      }
+      received.extend_from_slice(&buf[..read]);
+    }
+    received
+  }
+
+  #[test]
+  fn unbuffered_sinks_send_every_frame_straight_away() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut sink = TcpSink::try_connect(listener.local_addr().unwrap(), quick_policy(1)).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+
+    sink.send(b"*8d4840d6202cc371c32ce0576098;\n", &AtomicBool::new(true)).unwrap();
+    assert_eq!(received(&mut server), b"*8d4840d6202cc371c32ce0576098;\n");
+  }
+
+  #[test]
+  fn buffered_sinks_send_once_the_buffer_fills() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let buffer = SinkBuffer { bytes: 20, flush_interval: Duration::from_secs(3600) };
+    let mut sink = TcpSink::try_connect(listener.local_addr().unwrap(), quick_policy(1)).unwrap().with_buffer(buffer);
+    let (mut server, _) = listener.accept().unwrap();
+    let running = AtomicBool::new(true);
+
+    sink.send(b"0123456789", &running).unwrap();
+    assert!(received(&mut server).is_empty());
+    sink.send(b"abcdefghij", &running).unwrap();
+    assert_eq!(received(&mut server), b"0123456789abcdefghij");
+  }
+
+  #[test]
+  fn buffered_sinks_send_once_the_interval_passes_and_on_finish() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let buffer = SinkBuffer { bytes: 1024, flush_interval: Duration::from_secs(3600) };
+    let mut sink = TcpSink::try_connect(listener.local_addr().unwrap(), quick_policy(1)).unwrap().with_buffer(buffer);
+    let (mut server, _) = listener.accept().unwrap();
+    let running = AtomicBool::new(true);
+
+    sink.send(b"0123456789", &running).unwrap();
+    sink.flush_if_due(Instant::now(), &running).unwrap();
+    assert!(received(&mut server).is_empty());
+    sink.flush_if_due(Instant::now() + Duration::from_secs(3600), &running).unwrap();
+    assert_eq!(received(&mut server), b"0123456789");
+
+    sink.send(b"abcdefghij", &running).unwrap();
+    sink.finish().unwrap();
+    assert_eq!(received(&mut server), b"abcdefghij");
+  }
+
+  #[test]
+  fn systemd_descriptors_are_taken_only_when_meant_for_us() {
+    env::set_var("LISTEN_FDS", "2");
+    env::set_var("LISTEN_PID", process::id().to_string());
+    assert_eq!(systemd_listen_fds(), vec![3, 4]);
+    env::set_var("LISTEN_PID", "1");
+    assert!(systemd_listen_fds().is_empty());
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_PID");
+  }
+
+  #[test]
+  fn inherited_files_and_connected_sockets_are_written_directly() {
+    let path = env::temp_dir().join(format!("bladerf_adsb-test-{}-inherit-fd", process::id()));
+    let mut sink = FdSink::inherit(File::create(&path).unwrap().into_raw_fd()).unwrap();
+    sink.send(b"*8d4840d6202cc371c32ce0576098;\n", &AtomicBool::new(true)).unwrap();
+    sink.finish().unwrap();
+    drop(sink);
+    assert_eq!(std::fs::read(&path).unwrap(), b"*8d4840d6202cc371c32ce0576098;\n");
+    std::fs::remove_file(&path).unwrap();
+
+    let (ours, mut theirs) = UnixStream::pair().unwrap();
+    let mut sink = FdSink::inherit(ours.into_raw_fd()).unwrap();
+    sink.send(b"frame", &AtomicBool::new(true)).unwrap();
+    drop(sink);
+    let mut received = Vec::new();
+    io::Read::read_to_end(&mut theirs, &mut received).unwrap();
+    assert_eq!(received, b"frame");
+  }
+
+  #[test]
+  fn inherited_listening_sockets_serve_every_client() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut sink = FdSink::inherit(listener.into_raw_fd()).unwrap();
+    let running = AtomicBool::new(true);
+
+    let mut first = TcpStream::connect(addr).unwrap();
+    let mut second = TcpStream::connect(addr).unwrap();
+    sink.send(b"frame", &running).unwrap();
+    assert_eq!(received(&mut first), b"frame");
+    assert_eq!(received(&mut second), b"frame");
+
+    // a client that leaves is dropped without taking the sink down
+    drop(first);
+    for _ in 0..3 {
+      sink.send(b"frame", &running).unwrap();
+    }
+    assert_eq!(received(&mut second), b"frameframeframe");
+    assert_eq!(sink.name(), format!("fd:{}", sink.fd));
+  }
+
+  #[test]
+  fn the_null_sink_counts_frames_and_sends_nothing() {
+    let mut sink = NullSink::new();
+    let running = AtomicBool::new(true);
+    // what --output-format none serializes a frame to
+    sink.send(&[], &running).unwrap();
+    sink.send(&[], &running).unwrap();
+    assert_eq!((sink.stats().snapshot().frames, sink.stats().snapshot().bytes), (2, 0));
+    assert_eq!(sink.name(), "null");
+    assert!("127.0.0.1:30002:none".parse::<SinkSpec>().is_err());
+  }
+
+  #[test]
+  fn frames_spooled_while_the_server_is_down_go_out_first_once_it_is_back() {
+    let dir = env::temp_dir().join(format!("bladerf_adsb-test-{}-sink-spool", process::id()));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let spool = Spool::open(&dir, "tcp", 1024).unwrap();
+    let mut sink = TcpSink::try_connect(addr, quick_policy(100)).unwrap().with_spool(spool);
+    let running = AtomicBool::new(true);
+
+    // the server goes away; writes into the dead connection are lost until one fails, after
+    // which frames are spooled
+    drop(listener.accept().unwrap());
+    drop(listener);
+    let frames: Vec<String> = (0..20).map(|i| format!("frame{:02};", i)).collect();
+    for frame in &frames {
+      sink.send(frame.as_bytes(), &running).unwrap();
+      thread::sleep(Duration::from_millis(5));
+    }
+    let spooled = sink.stats().snapshot().spooled as usize;
+    assert!(spooled > 0);
+
+    let listener = TcpListener::bind(addr).unwrap();
+    sink.flush_if_due(Instant::now() + Duration::from_secs(1), &running).unwrap();
+    sink.send(b"live;", &running).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    let expected = frames[frames.len() - spooled..].concat() + "live;";
+    assert_eq!(String::from_utf8(received(&mut server)).unwrap(), expected);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,195 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+// Default for --spool-max-bytes
+pub const DEFAULT_SPOOL_MAX_BYTES: u64 = 100 * 1024 * 1024;
+
+// How much of the spool is read back at a time when draining it
+const DRAIN_CHUNK_BYTES: usize = 64 * 1024;
+
+// Serialized frames a sink couldn't send while its server was down, kept on disk in the order
+// they were meant to go out so they can be sent ahead of live frames once it's back. Anything
+// left over when the program exits stays in the file and is sent after the next connect, all
+// of it if it was part way through being sent.
+pub struct Spool {
+  path: PathBuf,
+  file: File,
+  max_bytes: u64,
+  // bytes in the file, and how far into it has been sent
+  len: u64,
+  sent: u64,
+}
+
+impl Spool {
+  // The spool for the output called `name` in `dir`, e.g. "tcp-127.0.0.1-30001-beast", created
+  // along with `dir` if need be. Anything but alphanumerics, '.' and '-' in `name` becomes '-'.
+  pub fn open(dir: &Path, name: &str, max_bytes: u64) -> io::Result<Spool> {
+    fs::create_dir_all(dir)?;
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '-' }).collect();
+    let path = dir.join(format!("{}.spool", name));
+    let file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+    let len = file.metadata()?.len();
+    if len > 0 {
+      info!("{} holds {} bytes from an earlier run, sending them first", path.display(), len);
+    }
+    Ok(Spool { path, file, max_bytes, len, sent: 0 })
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  // Bytes still to be sent
+  pub fn pending(&self) -> u64 {
+    self.len - self.sent
+  }
+
+  // Append a frame, unless that would take the file past max_bytes. Returns whether it fit.
+  pub fn push(&mut self, buf: &[u8]) -> io::Result<bool> {
+    if self.len + buf.len() as u64 > self.max_bytes {
+      return Ok(false);
+    }
+    self.file.write_all(buf)?;
+    self.len += buf.len() as u64;
+    Ok(true)
+  }
+
+  // Send everything pending to `out` in order, then empty the file. If `out` fails part way,
+  // what's left is kept for next time; the chunk that was being written may then be sent
+  // again in part, so a reader may see one frame repeated or cut short.
+  pub fn drain_to<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+    let mut chunk = vec![0u8; DRAIN_CHUNK_BYTES];
+    while self.sent < self.len {
+      let want = DRAIN_CHUNK_BYTES.min((self.len - self.sent) as usize);
+      let read = self.file.read_at(&mut chunk[..want], self.sent)?;
+      if read == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} is shorter than expected", self.path.display())));
+      }
+      out.write_all(&chunk[..read])?;
+      self.sent += read as u64;
+    }
+    out.flush()?;
+    self.file.set_len(0)?;
+    self.len = 0;
+    self.sent = 0;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::process;
+
+  // A spool directory of its own for each test, removed afterwards
+  struct TempDir(PathBuf);
+
+  impl TempDir {
+    fn new(name: &str) -> TempDir {
+      TempDir(std::env::temp_dir().join(format!("bladerf_adsb-test-{}-{}", process::id(), name)))
+    }
+  }
+
+  impl Drop for TempDir {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  // Takes `limit` bytes, then fails
+  struct FailingWriter {
+    written: Vec<u8>,
+    limit: usize,
+  }
+
+  impl Write for FailingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      let room = self.limit - self.written.len();
+      if room == 0 {
+        return Err(io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+      }
+      let n = room.min(buf.len());
+      self.written.extend_from_slice(&buf[..n]);
+      Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn open_creates_the_directory_and_sanitizes_the_name() {
+    let dir = TempDir::new("spool-open");
+    let spool = Spool::open(&dir.0.join("nested"), "tcp-[::1]:30002/beast", 1024).unwrap();
+    assert_eq!(spool.path(), dir.0.join("nested").join("tcp----1--30002-beast.spool"));
+    assert!(spool.path().exists());
+    assert_eq!(spool.pending(), 0);
+  }
+
+  #[test]
+  fn push_stops_at_max_bytes() {
+    let dir = TempDir::new("spool-max");
+    let mut spool = Spool::open(&dir.0, "tcp", 10).unwrap();
+    assert!(spool.push(b"12345").unwrap());
+    assert!(spool.push(b"67890").unwrap());
+    assert!(!spool.push(b"x").unwrap());
+    assert_eq!(spool.pending(), 10);
+  }
+
+  #[test]
+  fn drain_sends_everything_in_order_then_empties_the_file() {
+    let dir = TempDir::new("spool-drain");
+    let mut spool = Spool::open(&dir.0, "tcp", 1024).unwrap();
+    spool.push(b"first;").unwrap();
+    spool.push(b"second;").unwrap();
+
+    let mut out = Vec::new();
+    spool.drain_to(&mut out).unwrap();
+    assert_eq!(out, b"first;second;");
+    assert_eq!(spool.pending(), 0);
+    assert_eq!(fs::metadata(spool.path()).unwrap().len(), 0);
+
+    // and takes new frames afterwards
+    spool.push(b"third;").unwrap();
+    let mut out = Vec::new();
+    spool.drain_to(&mut out).unwrap();
+    assert_eq!(out, b"third;");
+  }
+
+  #[test]
+  fn a_failed_drain_keeps_what_wasnt_sent() {
+    let dir = TempDir::new("spool-fail");
+    let mut spool = Spool::open(&dir.0, "tcp", 1024).unwrap();
+    spool.push(b"first;second;").unwrap();
+
+    let mut out = FailingWriter { written: Vec::new(), limit: 6 };
+    assert!(spool.drain_to(&mut out).is_err());
+    assert_eq!(spool.pending(), 13);
+
+    let mut out = Vec::new();
+    spool.drain_to(&mut out).unwrap();
+    assert_eq!(out, b"first;second;");
+  }
+
+  #[test]
+  fn leftovers_from_an_earlier_run_are_sent_first() {
+    let dir = TempDir::new("spool-reopen");
+    let mut spool = Spool::open(&dir.0, "tcp", 1024).unwrap();
+    spool.push(b"old;").unwrap();
+    drop(spool);
+
+    let mut spool = Spool::open(&dir.0, "tcp", 1024).unwrap();
+    assert_eq!(spool.pending(), 4);
+    spool.push(b"new;").unwrap();
+    let mut out = Vec::new();
+    spool.drain_to(&mut out).unwrap();
+    assert_eq!(out, b"old;new;");
+  }
+}
@@ -0,0 +1,502 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+// Point in time copy of the counters, this is what gets persisted
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatsSnapshot {
+  pub messages: u64,
+  pub crc_failures: u64,
+  pub crc_corrected: u64,
+  pub dropped: u64,
+  // frames dropped by --max-frame-rate
+  pub shed: u64,
+  // repeats dropped by --dedup-window-ms
+  pub duplicates: u64,
+  pub reconnects: u64,
+  // sync_rx calls that timed out, which a quiet band or USB contention cause, not overruns
+  pub rx_timeouts: u64,
+  pub uptime_secs: u64,
+  // samples checked for ADC clipping, and how many of them were at full scale
+  pub samples_checked: u64,
+  pub samples_clipped: u64,
+  // of the frames with a known SNR
+  pub snr: SnrSummary,
+  // per output, keyed by SinkStats name
+  pub sinks: BTreeMap<String, SinkSnapshot>,
+}
+
+// Minimum, mean and maximum SNR in dB over the frames that had one
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SnrSummary {
+  pub frames: u64,
+  pub min: Option<f32>,
+  pub mean: Option<f64>,
+  pub max: Option<f32>,
+}
+
+impl SnrSummary {
+  pub fn add(&mut self, snr: f32) {
+    self.merge(&SnrSummary { frames: 1, min: Some(snr), mean: Some(snr as f64), max: Some(snr) });
+  }
+
+  // Combine with the summary of other frames, e.g. the totals of an earlier run
+  pub fn merge(&mut self, other: &SnrSummary) {
+    if other.frames == 0 {
+      return;
+    }
+    let frames = self.frames + other.frames;
+    let weighted = |summary: &SnrSummary| summary.mean.unwrap_or(0.0) * summary.frames as f64;
+    self.mean = Some((weighted(self) + weighted(other)) / frames as f64);
+    self.min = self.min.into_iter().chain(other.min).reduce(f32::min);
+    self.max = self.max.into_iter().chain(other.max).reduce(f32::max);
+    self.frames = frames;
+  }
+}
+
+// Point in time copy of one output's counters
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SinkSnapshot {
+  pub frames: u64,
+  pub bytes: u64,
+  pub reconnects: u64,
+  // frames written to the --spool-dir spool while disconnected, and those dropped because it
+  // was full
+  pub spooled: u64,
+  pub spool_dropped: u64,
+  pub last_error: Option<String>,
+}
+
+// Counters owned by a single output, so with several of them it's clear which is unhealthy
+#[derive(Debug, Default)]
+pub struct SinkStats {
+  frames: AtomicU64,
+  bytes: AtomicU64,
+  reconnects: AtomicU64,
+  spooled: AtomicU64,
+  spool_dropped: AtomicU64,
+  last_error: Mutex<Option<String>>,
+}
+
+impl SinkStats {
+  pub fn new() -> SinkStats {
+    SinkStats::default()
+  }
+
+  // A frame of `bytes` went out
+  pub fn sent(&self, bytes: usize) {
+    self.frames.fetch_add(1, Ordering::Relaxed);
+    self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  pub fn reconnected(&self) {
+    self.reconnects.fetch_add(1, Ordering::Relaxed);
+  }
+
+  // A frame went to the spool instead, to be sent once reconnected
+  pub fn spooled(&self) {
+    self.spooled.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn spool_dropped(&self) {
+    self.spool_dropped.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn failed(&self, error: String) {
+    *self.last_error.lock().unwrap() = Some(error);
+  }
+
+  pub fn snapshot(&self) -> SinkSnapshot {
+    SinkSnapshot {
+      frames: self.frames.load(Ordering::Relaxed),
+      bytes: self.bytes.load(Ordering::Relaxed),
+      reconnects: self.reconnects.load(Ordering::Relaxed),
+      spooled: self.spooled.load(Ordering::Relaxed),
+      spool_dropped: self.spool_dropped.load(Ordering::Relaxed),
+      last_error: self.last_error.lock().unwrap().clone(),
+    }
+  }
+}
+
+impl StatsSnapshot {
+  pub fn clipping_percent(&self) -> f64 {
+    if self.samples_checked == 0 {
+      0.0
+    } else {
+      100.0 * self.samples_clipped as f64 / self.samples_checked as f64
+    }
+  }
+}
+
+// Counters shared between the RX and output threads
+pub struct Stats {
+  pub messages: AtomicU64,
+  pub crc_failures: AtomicU64,
+  // frames that failed CRC but were repaired by --fix-crc
+  pub crc_corrected: AtomicU64,
+  pub dropped: AtomicU64,
+  pub shed: AtomicU64,
+  pub duplicates: AtomicU64,
+  pub reconnects: AtomicU64,
+  pub rx_timeouts: AtomicU64,
+  pub samples_checked: AtomicU64,
+  pub samples_clipped: AtomicU64,
+  snr: Mutex<SnrSummary>,
+  sinks: Mutex<Vec<(String, Arc<SinkStats>)>>,
+  started: Instant,
+  // totals carried over from previous runs
+  base: StatsSnapshot,
+}
+
+impl Stats {
+  pub fn new() -> Stats {
+    Stats::with_base(StatsSnapshot::default())
+  }
+
+  // Continue counting on top of totals loaded from an earlier run
+  pub fn with_base(base: StatsSnapshot) -> Stats {
+    Stats {
+      messages: AtomicU64::new(0),
+      crc_failures: AtomicU64::new(0),
+      crc_corrected: AtomicU64::new(0),
+      dropped: AtomicU64::new(0),
+      shed: AtomicU64::new(0),
+      duplicates: AtomicU64::new(0),
+      reconnects: AtomicU64::new(0),
+      rx_timeouts: AtomicU64::new(0),
+      samples_checked: AtomicU64::new(0),
+      samples_clipped: AtomicU64::new(0),
+      snr: Mutex::new(SnrSummary::default()),
+      sinks: Mutex::new(Vec::new()),
+      started: Instant::now(),
+      base,
+    }
+  }
+
+  // Count a frame's SNR towards the summary
+  pub fn record_snr(&self, snr: f32) {
+    self.snr.lock().unwrap().add(snr);
+  }
+
+  // Report an output's counters under `name`, e.g. "tcp:127.0.0.1:30001"
+  pub fn add_sink(&self, name: String, sink: Arc<SinkStats>) {
+    self.sinks.lock().unwrap().push((name, sink));
+  }
+
+  // Cumulative totals, including those carried over from earlier runs
+  pub fn snapshot(&self) -> StatsSnapshot {
+    let mut sinks = self.base.sinks.clone();
+    for (name, sink) in self.sinks.lock().unwrap().iter() {
+      let live = sink.snapshot();
+      let total = sinks.entry(name.clone()).or_default();
+      total.frames += live.frames;
+      total.bytes += live.bytes;
+      total.reconnects += live.reconnects;
+      total.spooled += live.spooled;
+      total.spool_dropped += live.spool_dropped;
+      if live.last_error.is_some() {
+        total.last_error = live.last_error;
+      }
+    }
+
+    let mut snr = self.base.snr.clone();
+    snr.merge(&self.snr.lock().unwrap());
+
+    StatsSnapshot {
+      messages: self.base.messages + self.messages.load(Ordering::Relaxed),
+      crc_failures: self.base.crc_failures + self.crc_failures.load(Ordering::Relaxed),
+      crc_corrected: self.base.crc_corrected + self.crc_corrected.load(Ordering::Relaxed),
+      dropped: self.base.dropped + self.dropped.load(Ordering::Relaxed),
+      shed: self.base.shed + self.shed.load(Ordering::Relaxed),
+      duplicates: self.base.duplicates + self.duplicates.load(Ordering::Relaxed),
+      reconnects: self.base.reconnects + self.reconnects.load(Ordering::Relaxed),
+      rx_timeouts: self.base.rx_timeouts + self.rx_timeouts.load(Ordering::Relaxed),
+      uptime_secs: self.base.uptime_secs + self.started.elapsed().as_secs(),
+      samples_checked: self.base.samples_checked + self.samples_checked.load(Ordering::Relaxed),
+      samples_clipped: self.base.samples_clipped + self.samples_clipped.load(Ordering::Relaxed),
+      snr,
+      sinks,
+    }
+  }
+}
+
+impl Default for Stats {
+  fn default() -> Self {
+    Stats::new()
+  }
+}
+
+// Exponentially weighted moving average of a counter's rate. Fed the running total at
+// irregular intervals; each sample's weight depends on how much time it covers, so the
+// smoothing is the same whether it's polled every 100ms or every second
+pub struct RateMeter {
+  time_constant: f64,
+  rate: Option<f64>,
+  last_count: u64,
+  last_time: Instant,
+}
+
+impl RateMeter {
+  pub fn new(time_constant: Duration, count: u64, now: Instant) -> RateMeter {
+    RateMeter {
+      time_constant: time_constant.as_secs_f64(),
+      rate: None,
+      last_count: count,
+      last_time: now,
+    }
+  }
+
+  // Fold in the counter's current total and return the smoothed rate per second
+  pub fn update(&mut self, count: u64, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(self.last_time).as_secs_f64();
+    if elapsed <= 0.0 {
+      return self.rate();
+    }
+
+    let instant = count.saturating_sub(self.last_count) as f64 / elapsed;
+    let alpha = 1.0 - (-elapsed / self.time_constant).exp();
+    self.rate = Some(match self.rate {
+      Some(rate) => rate + alpha * (instant - rate),
+      // the first sample seeds the average rather than ramping up from zero
+      None => instant,
+    });
+    self.last_count = count;
+    self.last_time = now;
+    self.rate()
+  }
+
+  pub fn rate(&self) -> f64 {
+    self.rate.unwrap_or(0.0)
+  }
+}
+
+// Without a frame for this long, reception counts as idle
+pub const IDLE_AFTER: Duration = Duration::from_secs(5);
+
+// Whether frames are arriving, for the spinner
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activity {
+  // nothing received yet
+  Waiting,
+  Active,
+  // the last frame was this long ago
+  Idle(Duration),
+}
+
+impl Activity {
+  // From the age of the last frame, None if there hasn't been one
+  pub fn from_last_frame(age: Option<Duration>, idle_after: Duration) -> Activity {
+    match age {
+      None => Activity::Waiting,
+      Some(age) if age >= idle_after => Activity::Idle(age),
+      Some(_) => Activity::Active,
+    }
+  }
+}
+
+pub fn load(path: &Path) -> io::Result<StatsSnapshot> {
+  let json = fs::read_to_string(path)?;
+  serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// The snapshot as a single line of JSON, newline terminated, as sent to --stats-remote
+pub fn to_json_line(snapshot: &StatsSnapshot) -> String {
+  let mut line = serde_json::to_string(snapshot).expect("stats snapshot always serializes");
+  line.push('\n');
+  line
+}
+
+// Write the snapshot to a temporary file next to `path` and rename it into place, so a crash
+// mid-write never leaves a truncated stats file behind
+pub fn save(path: &Path, snapshot: &StatsSnapshot) -> io::Result<()> {
+  let json = serde_json::to_string_pretty(snapshot).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+  let mut tmp = path.as_os_str().to_owned();
+  tmp.push(".tmp");
+  fs::write(&tmp, json)?;
+  fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::PathBuf;
+  use std::process;
+
+  // A path under the temp directory named for this process and test, removed when dropped
+  struct TempPath(PathBuf);
+
+  impl TempPath {
+    fn new(name: &str) -> TempPath {
+      TempPath(std::env::temp_dir().join(format!("bladerf_adsb-test-{}-{}", process::id(), name)))
+    }
+  }
+
+  impl Drop for TempPath {
+    fn drop(&mut self) {
+      let _ = fs::remove_file(&self.0);
+    }
+  }
+
+  #[test]
+  fn saved_statistics_load_back_unchanged() {
+    let path = TempPath::new("stats.json");
+    let snapshot = StatsSnapshot { messages: 12345, crc_failures: 6, dropped: 7, reconnects: 2, uptime_secs: 3600, ..StatsSnapshot::default() };
+
+    save(&path.0, &snapshot).unwrap();
+    assert_eq!(load(&path.0).unwrap(), snapshot);
+  }
+
+  #[test]
+  fn fields_missing_from_an_older_file_default_to_zero() {
+    let path = TempPath::new("old-stats.json");
+    fs::write(&path.0, r#"{"messages": 10, "reconnects": 1}"#).unwrap();
+
+    assert_eq!(load(&path.0).unwrap(), StatsSnapshot { messages: 10, reconnects: 1, ..StatsSnapshot::default() });
+  }
+
+  #[test]
+  fn a_corrupt_file_is_invalid_data() {
+    let path = TempPath::new("corrupt-stats.json");
+    fs::write(&path.0, "{\"messages\": ").unwrap();
+
+    assert_eq!(load(&path.0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn counting_carries_on_from_the_loaded_totals() {
+    let stats = Stats::with_base(StatsSnapshot { messages: 100, dropped: 5, uptime_secs: 60, ..StatsSnapshot::default() });
+    stats.messages.fetch_add(3, Ordering::Relaxed);
+    stats.dropped.fetch_add(1, Ordering::Relaxed);
+
+    let snapshot = stats.snapshot();
+    assert_eq!((snapshot.messages, snapshot.dropped), (103, 6));
+    assert!(snapshot.uptime_secs >= 60);
+  }
+
+  #[test]
+  fn the_first_rate_is_taken_as_is() {
+    let start = Instant::now();
+    let mut meter = RateMeter::new(Duration::from_secs(5), 100, start);
+    assert_eq!(meter.rate(), 0.0);
+
+    assert_eq!(meter.update(300, start + Duration::from_secs(2)), 100.0);
+  }
+
+  #[test]
+  fn the_rate_moves_towards_new_samples_by_how_much_time_they_cover() {
+    let start = Instant::now();
+    let mut meter = RateMeter::new(Duration::from_secs(5), 0, start);
+    meter.update(100, start + Duration::from_secs(1));
+
+    // one time constant at 200/s closes 1 - 1/e of the gap from 100/s
+    let rate = meter.update(1100, start + Duration::from_secs(6));
+    let expected = 100.0 + 100.0 * (1.0 - (-1.0f64).exp());
+    assert!((rate - expected).abs() < 1e-9, "{} != {}", rate, expected);
+  }
+
+  #[test]
+  fn no_time_passing_leaves_the_rate_alone() {
+    let start = Instant::now();
+    let mut meter = RateMeter::new(Duration::from_secs(5), 0, start);
+    meter.update(50, start + Duration::from_secs(1));
+
+    assert_eq!(meter.update(5000, start + Duration::from_secs(1)), 50.0);
+  }
+
+  #[test]
+  fn a_counter_going_backwards_reads_as_no_messages() {
+    let start = Instant::now();
+    let mut meter = RateMeter::new(Duration::from_secs(5), 1000, start);
+
+    assert_eq!(meter.update(10, start + Duration::from_secs(1)), 0.0);
+  }
+
+  #[test]
+  fn clipping_is_a_percentage_of_the_samples_checked() {
+    assert_eq!(StatsSnapshot::default().clipping_percent(), 0.0);
+    assert_eq!(StatsSnapshot { samples_checked: 400, samples_clipped: 6, ..StatsSnapshot::default() }.clipping_percent(), 1.5);
+  }
+
+  #[test]
+  fn the_json_line_is_one_line_that_loads_back() {
+    let snapshot = StatsSnapshot { messages: 12, crc_failures: 3, ..StatsSnapshot::default() };
+    let line = to_json_line(&snapshot);
+
+    assert_eq!(line.find('\n'), Some(line.len() - 1));
+    assert_eq!(serde_json::from_str::<StatsSnapshot>(&line).unwrap(), snapshot);
+  }
+
+  #[test]
+  fn activity_goes_idle_once_frames_stop() {
+    assert_eq!(Activity::from_last_frame(None, IDLE_AFTER), Activity::Waiting);
+    assert_eq!(Activity::from_last_frame(Some(Duration::from_secs(1)), IDLE_AFTER), Activity::Active);
+    assert_eq!(Activity::from_last_frame(Some(IDLE_AFTER), IDLE_AFTER), Activity::Idle(IDLE_AFTER));
+  }
+
+  #[test]
+  fn sink_counters_are_reported_by_name() {
+    let stats = Stats::new();
+    let sink = Arc::new(SinkStats::new());
+    stats.add_sink(String::from("tcp:127.0.0.1:30005"), sink.clone());
+    sink.sent(16);
+    sink.sent(16);
+    sink.reconnected();
+    sink.failed(String::from("Connection reset by peer"));
+
+    assert_eq!(stats.snapshot().sinks["tcp:127.0.0.1:30005"], SinkSnapshot {
+      frames: 2,
+      bytes: 32,
+      reconnects: 1,
+      last_error: Some(String::from("Connection reset by peer")),
+      ..SinkSnapshot::default()
+    });
+  }
+
+  #[test]
+  fn sink_counters_carry_on_from_the_loaded_totals() {
+    let mut base = StatsSnapshot::default();
+    base.sinks.insert(String::from("stdout"), SinkSnapshot { frames: 10, bytes: 300, last_error: Some(String::from("old")), ..SinkSnapshot::default() });
+    base.sinks.insert(String::from("tcp:gone"), SinkSnapshot { frames: 4, ..SinkSnapshot::default() });
+    let stats = Stats::with_base(base);
+    let sink = Arc::new(SinkStats::new());
+    stats.add_sink(String::from("stdout"), sink.clone());
+    sink.sent(30);
+
+    let sinks = stats.snapshot().sinks;
+    assert_eq!((sinks["stdout"].frames, sinks["stdout"].bytes), (11, 330));
+    // an error from an earlier run stands until there's a newer one
+    assert_eq!(sinks["stdout"].last_error.as_deref(), Some("old"));
+    assert_eq!(sinks["tcp:gone"].frames, 4);
+  }
+
+  #[test]
+  fn snr_summaries_track_min_mean_and_max() {
+    let mut summary = SnrSummary::default();
+    summary.add(10.0);
+    summary.add(20.0);
+    summary.add(30.0);
+    assert_eq!(summary, SnrSummary { frames: 3, min: Some(10.0), mean: Some(20.0), max: Some(30.0) });
+
+    // an empty summary changes nothing
+    summary.merge(&SnrSummary::default());
+    assert_eq!(summary.frames, 3);
+  }
+
+  #[test]
+  fn snr_carries_on_from_the_loaded_totals() {
+    let base = SnrSummary { frames: 3, min: Some(10.0), mean: Some(20.0), max: Some(30.0) };
+    let stats = Stats::with_base(StatsSnapshot { snr: base, ..StatsSnapshot::default() });
+    stats.record_snr(40.0);
+    assert_eq!(stats.snapshot().snr, SnrSummary { frames: 4, min: Some(10.0), mean: Some(25.0), max: Some(40.0) });
+  }
+}
@@ -0,0 +1,157 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::sync::mpsc;
+use log::{debug, warn};
+
+use bladerf::BladeRFDevice;
+use bladerf::bladerf::bladerf_module::*;
+use bladerf::bladerf::bladerf_error::*;
+
+use crate::buffers::DEFAULT_RX_TIMEOUT_MS;
+use crate::frame::{extract_frames, received, AdsbFrame};
+
+// Frames buffered between the blocking reader and the async consumer
+const CHANNEL_DEPTH: usize = 256;
+
+// Async view of the RX stream. The blocking sync_rx calls run on tokio's blocking
+// pool so the executor is never stalled waiting on USB transfers.
+pub struct AsyncRxStream {
+  receiver: mpsc::Receiver<AdsbFrame>,
+  running: Arc<AtomicBool>,
+}
+
+impl AsyncRxStream {
+  // Start streaming. Must be called from within a tokio runtime.
+  //
+  // `setup` runs on the blocking pool and returns an opened device with the FPGA loaded,
+  // the RX module configured and sync_config already applied. The device handle never
+  // leaves that thread; the stream enables RX and tears the device down when dropped.
+  pub fn spawn<F>(setup: F) -> AsyncRxStream
+  where
+    F: FnOnce() -> Result<BladeRFDevice, isize> + Send + 'static,
+  {
+    let (sender, receiver) = mpsc::channel(CHANNEL_DEPTH);
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+
+    tokio::task::spawn_blocking(move || {
+      let dev = match setup() {
+        Ok(dev) => dev,
+        Err(e) => {
+          warn!("AsyncRxStream: device setup failed with error {}", e);
+          return;
+        },
+      };
+
+      if let Err(e) = dev.enable_module(BLADERF_MODULE_RX, true) {
+        warn!("AsyncRxStream: enable_module failed with error {}", e);
+        dev.close();
+        return;
+      }
+
+      let mut messages: [u8; 4096] = [0; 4096];
+      'rx: while r.load(Ordering::SeqCst) {
+        let samples = match dev.sync_rx(&mut messages, 1024, None, DEFAULT_RX_TIMEOUT_MS) {
+          Ok(samples) => samples as usize,
+          Err(e) if e == BLADERF_ERR_TIMEOUT as isize => {
+            debug!("AsyncRxStream: sync_rx timed out, retrying");
+            continue;
+          },
+          Err(e) => {
+            warn!("AsyncRxStream: sync_rx failed with error {}", e);
+            break;
+          },
+        };
+
+        for frame in extract_frames(received(&messages, samples)) {
+          // receiver dropped, nobody is listening any more
+          if sender.blocking_send(frame).is_err() {
+            break 'rx;
+          }
+        }
+      }
+
+      debug!("AsyncRxStream: closing device");
+      if let Err(e) = dev.shutdown(BLADERF_MODULE_RX) {
+        debug!("AsyncRxStream: unable to disable RX (error {})", e);
+      }
+    });
+
+    AsyncRxStream { receiver, running }
+  }
+
+  // Wait for the next frame, returns None once the stream has ended
+  pub async fn next_frame(&mut self) -> Option<AdsbFrame> {
+    self.receiver.recv().await
+  }
+
+  // Ask the blocking reader to stop after its current transfer
+  pub fn stop(&self) {
+    self.running.store(false, Ordering::SeqCst);
+  }
+}
+
+impl Stream for AsyncRxStream {
+  type Item = AdsbFrame;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<AdsbFrame>> {
+    self.receiver.poll_recv(cx)
+  }
+}
+
+impl Drop for AsyncRxStream {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bladerf::mock::MockBladeRf;
+  use crate::fixtures::DF17;
+
+  // A buffer holding DF17, in its second slot
+  fn buffer_with_frame() -> Vec<u8> {
+    let mut buf = vec![0u8; 4096];
+    buf[16] = 0x01;
+    buf[18..32].copy_from_slice(&DF17);
+    buf
+  }
+
+  #[tokio::test]
+  async fn frames_read_from_the_device_come_out_of_the_stream() {
+    let mock = MockBladeRf::new();
+    mock.push_rx_buffer(buffer_with_frame());
+    let device = mock.clone();
+    let mut stream = AsyncRxStream::spawn(move || Ok(BladeRFDevice::with_api(Box::new(device))));
+
+    let frame = stream.next_frame().await.unwrap();
+    assert_eq!(frame.to_avr(), "*8d4840d6202cc371c32ce0576098;\n");
+    assert_eq!(frame.slot, 1);
+    assert_eq!(mock.calls()[0], "enable_module(BLADERF_MODULE_RX, true)");
+  }
+
+  #[tokio::test]
+  async fn the_stream_ends_when_setup_fails() {
+    let mut stream = AsyncRxStream::spawn(|| Err(-1));
+
+    assert_eq!(stream.next_frame().await, None);
+  }
+
+  #[tokio::test]
+  async fn the_stream_ends_when_rx_cant_be_enabled() {
+    let mock = MockBladeRf::new();
+    mock.push_result("enable_module", BLADERF_ERR_IO as i32);
+    mock.push_rx_buffer(buffer_with_frame());
+    let device = mock.clone();
+    let mut stream = AsyncRxStream::spawn(move || Ok(BladeRFDevice::with_api(Box::new(device))));
+
+    assert_eq!(stream.next_frame().await, None);
+    assert!(!mock.calls().iter().any(|call| call.starts_with("sync_rx")));
+  }
+}
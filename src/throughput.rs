@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use crate::frame::BYTES_PER_SAMPLE;
+
+// How long the throughput subcommand streams for by default
+pub const DEFAULT_THROUGHPUT_DURATION: Duration = Duration::from_secs(10);
+
+// Jumps of at least half the counter range are taken as the counter going backwards
+const COUNTER_RESTART: u32 = 1 << 31;
+
+// Checks the sequence produced by BLADERF_RX_MUX_32BIT_COUNTER, where every SC16_Q11 sample
+// carries a 32 bit counter (I the low half, Q the high half, both little endian) that goes up
+// by one per sample. Any jump in the counter means samples were lost between the FPGA and us.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CounterCheck {
+  // counter value the next sample should carry, None until the first sample
+  expected: Option<u32>,
+  pub samples: u64,
+  // discontinuities seen, and the samples they skipped
+  pub gaps: u64,
+  pub missing: u64,
+}
+
+impl CounterCheck {
+  pub fn new() -> CounterCheck {
+    CounterCheck::default()
+  }
+
+  // Check the samples of one buffer, continuing from the previous one. A trailing partial
+  // sample is ignored.
+  pub fn feed(&mut self, buf: &[u8]) {
+    for sample in buf.chunks_exact(BYTES_PER_SAMPLE) {
+      let counter = u32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]);
+      if let Some(expected) = self.expected {
+        if counter != expected {
+          self.gaps += 1;
+          // a jump backwards means the counter restarted, so there's no telling how much was lost
+          let skipped = counter.wrapping_sub(expected);
+          if skipped < COUNTER_RESTART {
+            self.missing += skipped as u64;
+          }
+        }
+      }
+      self.expected = Some(counter.wrapping_add(1));
+      self.samples += 1;
+    }
+  }
+
+  // Samples per second actually delivered over the given time
+  pub fn achieved_rate(&self, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+      return 0.0;
+    }
+    self.samples as f64 / elapsed.as_secs_f64()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn counters(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_le_bytes()).collect()
+  }
+
+  #[test]
+  fn a_steady_count_has_no_gaps_across_buffers() {
+    let mut check = CounterCheck::new();
+    check.feed(&counters(&[7, 8, 9]));
+    check.feed(&counters(&[10, 11]));
+    assert_eq!((check.samples, check.gaps, check.missing), (5, 0, 0));
+  }
+
+  #[test]
+  fn a_jump_counts_the_samples_skipped() {
+    let mut check = CounterCheck::new();
+    check.feed(&counters(&[1, 2, 6, 7, 10]));
+    assert_eq!((check.samples, check.gaps, check.missing), (5, 2, 5));
+  }
+
+  #[test]
+  fn the_counter_wraps_without_a_gap() {
+    let mut check = CounterCheck::new();
+    check.feed(&counters(&[u32::MAX - 1, u32::MAX, 0, 1]));
+    assert_eq!(check.gaps, 0);
+  }
+
+  #[test]
+  fn a_restart_is_a_gap_of_unknown_size() {
+    let mut check = CounterCheck::new();
+    check.feed(&counters(&[1000, 1001, 3, 4]));
+    assert_eq!((check.gaps, check.missing), (1, 0));
+  }
+
+  #[test]
+  fn a_trailing_partial_sample_is_ignored() {
+    let mut check = CounterCheck::new();
+    let mut buf = counters(&[1, 2]);
+    buf.extend_from_slice(&[3, 0]);
+    check.feed(&buf);
+    assert_eq!(check.samples, 2);
+  }
+
+  #[test]
+  fn the_rate_is_samples_over_elapsed_time() {
+    let mut check = CounterCheck::new();
+    check.feed(&counters(&(0..1000).collect::<Vec<u32>>()));
+    assert_eq!(check.achieved_rate(Duration::from_millis(500)), 2000.0);
+    assert_eq!(check.achieved_rate(Duration::ZERO), 0.0);
+  }
+}
@@ -0,0 +1,104 @@
+// Frequencies, sample rates and bandwidths on the command line are in Hz, but read easier with
+// a suffix, and are easily mistaken for MHz without one. Below these a value is far under what
+// any bladeRF tunes, samples or filters at, so it was most likely meant as MHz.
+pub const MIN_PLAUSIBLE_FREQUENCY: u32 = 1000000;
+pub const MIN_PLAUSIBLE_RATE: u32 = 100000;
+
+// Parse a value in Hz with an optional k, M or G multiplier and optional "Hz", e.g. "1090M",
+// "1.09G", "16MHz" or "1090000000". The result has to come out a whole number of Hz.
+pub fn parse_hz(s: &str) -> Result<u32, String> {
+  let invalid = || format!("'{}' isn't a value in Hz, expected a number with an optional k, M or G suffix, e.g. 1090M", s);
+  let trimmed = s.trim();
+  let number = trimmed.strip_suffix("Hz").or_else(|| trimmed.strip_suffix("hz")).unwrap_or(trimmed);
+  let (number, exponent) = match number.chars().last() {
+    Some('k') | Some('K') => (&number[..number.len() - 1], 3),
+    Some('m') | Some('M') => (&number[..number.len() - 1], 6),
+    Some('g') | Some('G') => (&number[..number.len() - 1], 9),
+    _ => (number, 0),
+  };
+  // worked out in decimal, so "1.09G" is exactly 1090000000 rather than off by a rounding error
+  let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+  if (whole.is_empty() && fraction.is_empty()) || !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+    return Err(invalid());
+  }
+  if fraction.len() > exponent && fraction[exponent..].chars().any(|c| c != '0') {
+    return Err(format!("'{}' isn't a whole number of Hz", s));
+  }
+  let digits = format!("{}{:0<width$}", whole, &fraction[..fraction.len().min(exponent)], width = exponent);
+  let hz = digits.trim_start_matches('0');
+  match hz.parse::<u32>() {
+    Ok(hz) => Ok(hz),
+    Err(_) if hz.is_empty() => Ok(0),
+    Err(_) => Err(format!("'{}' is too large, at most {} Hz fits", s, u32::MAX)),
+  }
+}
+
+// A value under `min` Hz, with what it would be as MHz when that fits
+fn implausible(what: &str, hz: u32, min: u32, example: &str) -> Result<u32, String> {
+  if hz >= min {
+    return Ok(hz);
+  }
+  let hint = match hz.checked_mul(1000000) {
+    Some(mhz) if hz > 0 => format!("for {} MHz give {}M or {}", hz, hz, mhz),
+    _ => format!("e.g. {}", example),
+  };
+  Err(format!("{} Hz is too low for a {}, which is given in Hz; {}", hz, what, hint))
+}
+
+// --frequency and the control socket's frequency command
+pub fn parse_frequency(s: &str) -> Result<u32, String> {
+  implausible("frequency", parse_hz(s)?, MIN_PLAUSIBLE_FREQUENCY, "1090M")
+}
+
+pub fn parse_sample_rate(s: &str) -> Result<u32, String> {
+  implausible("sample rate", parse_hz(s)?, MIN_PLAUSIBLE_RATE, "16M")
+}
+
+pub fn parse_bandwidth(s: &str) -> Result<u32, String> {
+  implausible("bandwidth", parse_hz(s)?, MIN_PLAUSIBLE_RATE, "14M")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn suffixes_multiply_the_value() {
+    assert_eq!(parse_hz("1090000000"), Ok(1090000000));
+    assert_eq!(parse_hz("1090M"), Ok(1090000000));
+    assert_eq!(parse_hz("1.09G"), Ok(1090000000));
+    assert_eq!(parse_hz("16MHz"), Ok(16000000));
+    assert_eq!(parse_hz("500k"), Ok(500000));
+    assert_eq!(parse_hz("2.5khz"), Ok(2500));
+    assert_eq!(parse_hz(" 14m "), Ok(14000000));
+    assert_eq!(parse_hz(".5M"), Ok(500000));
+    assert_eq!(parse_hz("0"), Ok(0));
+  }
+
+  #[test]
+  fn anything_but_a_number_with_a_suffix_is_rejected() {
+    for s in ["", "M", "1090T", "10 90M", "-1090M", "1.0.9G", "."] {
+      assert_eq!(parse_hz(s), Err(format!("'{}' isn't a value in Hz, expected a number with an optional k, M or G suffix, e.g. 1090M", s)), "{:?}", s);
+    }
+  }
+
+  #[test]
+  fn values_must_be_whole_hz_that_fit() {
+    assert_eq!(parse_hz("1.5"), Err(String::from("'1.5' isn't a whole number of Hz")));
+    assert_eq!(parse_hz("1.0001k"), Err(String::from("'1.0001k' isn't a whole number of Hz")));
+    assert_eq!(parse_hz("1.000k"), Ok(1000));
+    assert_eq!(parse_hz("4294967295"), Ok(u32::MAX));
+    assert_eq!(parse_hz("4.3G"), Err(format!("'4.3G' is too large, at most {} Hz fits", u32::MAX)));
+  }
+
+  #[test]
+  fn implausibly_low_values_suggest_mhz() {
+    assert_eq!(parse_frequency("1090"), Err(String::from("1090 Hz is too low for a frequency, which is given in Hz; for 1090 MHz give 1090M or 1090000000")));
+    assert_eq!(parse_sample_rate("16"), Err(String::from("16 Hz is too low for a sample rate, which is given in Hz; for 16 MHz give 16M or 16000000")));
+    assert_eq!(parse_bandwidth("0"), Err(String::from("0 Hz is too low for a bandwidth, which is given in Hz; e.g. 14M")));
+    // too large to be meant as MHz either
+    assert_eq!(parse_frequency("999999"), Err(String::from("999999 Hz is too low for a frequency, which is given in Hz; e.g. 1090M")));
+    assert_eq!(parse_frequency("1090M"), Ok(1090000000));
+    assert_eq!(parse_sample_rate("100k"), Ok(MIN_PLAUSIBLE_RATE));
+  }
+}
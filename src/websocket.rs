@@ -0,0 +1,168 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use tungstenite::Message;
+
+// Messages queued per client before it's considered too slow and dropped
+pub const WS_CLIENT_QUEUE: usize = 256;
+
+// How often the listener checks for shutdown between connections
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Client {
+  addr: SocketAddr,
+  queue: SyncSender<String>,
+}
+
+// Broadcasts text messages to every connected WebSocket client. Each client is served by its own
+// thread from a bounded queue; broadcast never blocks, a client whose queue is full is dropped
+// so one slow browser can't hold up decoding.
+pub struct WebSocketSink {
+  addr: SocketAddr,
+  clients: Arc<Mutex<Vec<Client>>>,
+}
+
+impl WebSocketSink {
+  // Listen on addr and accept clients in the background until running is cleared
+  pub fn bind(addr: SocketAddr, running: Arc<AtomicBool>) -> io::Result<WebSocketSink> {
+    let listener = TcpListener::bind(addr)?;
+    // non-blocking so the accept loop notices shutdown
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    info!("Accepting WebSocket clients on {}", addr);
+
+    let clients: Arc<Mutex<Vec<Client>>> = Arc::new(Mutex::new(Vec::new()));
+    let accept_clients = clients.clone();
+    thread::Builder::new().name(String::from("adsb-ws")).spawn(move || {
+      while running.load(Ordering::SeqCst) {
+        match listener.accept() {
+          Ok((stream, peer)) => {
+            let (queue, messages) = sync_channel(WS_CLIENT_QUEUE);
+            accept_clients.lock().unwrap().push(Client { addr: peer, queue });
+            let spawned = thread::Builder::new().name(String::from("adsb-ws-client"))
+              .spawn(move || serve_client(stream, peer, messages));
+            if let Err(e) = spawned {
+              warn!("Unable to start a thread for WebSocket client {}: {}", peer, e);
+            }
+          },
+          Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+          Err(e) => {
+            warn!("Error accepting WebSocket client: {}", e);
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+          },
+        }
+      }
+      // dropping the queues ends every client thread
+      accept_clients.lock().unwrap().clear();
+    })?;
+
+    Ok(WebSocketSink { addr, clients })
+  }
+
+  pub fn addr(&self) -> SocketAddr {
+    self.addr
+  }
+
+  pub fn client_count(&self) -> usize {
+    self.clients.lock().unwrap().len()
+  }
+
+  // Queue a message for every client, dropping those that have fallen behind or gone away.
+  // Returns how many clients it was queued for.
+  pub fn broadcast(&self, message: &str) -> usize {
+    let mut clients = self.clients.lock().unwrap();
+    clients.retain(|client| match client.queue.try_send(String::from(message)) {
+      Ok(()) => true,
+      Err(TrySendError::Full(_)) => {
+        warn!("WebSocket client {} is too slow, dropping it", client.addr);
+        false
+      },
+      Err(TrySendError::Disconnected(_)) => {
+        debug!("WebSocket client {} disconnected", client.addr);
+        false
+      },
+    });
+    clients.len()
+  }
+}
+
+// Complete the handshake, then forward queued messages until either side goes away
+fn serve_client(stream: TcpStream, peer: SocketAddr, messages: Receiver<String>) {
+  // accepted sockets inherit non-blocking from the listener on some platforms
+  if let Err(e) = stream.set_nonblocking(false) {
+    warn!("Unable to set up WebSocket client {}: {}", peer, e);
+    return;
+  }
+
+  let mut socket = match tungstenite::accept(stream) {
+    Ok(socket) => socket,
+    Err(e) => {
+      debug!("WebSocket handshake with {} failed: {}", peer, e);
+      return;
+    },
+  };
+  info!("WebSocket client {} connected", peer);
+
+  for message in messages {
+    if let Err(e) = socket.send(Message::Text(message)) {
+      debug!("Lost WebSocket client {}: {}", peer, e);
+      return;
+    }
+  }
+  let _ = socket.close(None);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Instant;
+
+  fn sink() -> (WebSocketSink, Arc<AtomicBool>) {
+    let running = Arc::new(AtomicBool::new(true));
+    (WebSocketSink::bind("127.0.0.1:0".parse().unwrap(), running.clone()).unwrap(), running)
+  }
+
+  fn wait_for_clients(sink: &WebSocketSink, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while sink.client_count() != count {
+      assert!(Instant::now() < deadline, "{} clients, expected {}", sink.client_count(), count);
+      thread::sleep(Duration::from_millis(10));
+    }
+  }
+
+  #[test]
+  fn broadcast_without_clients_reaches_nobody() {
+    let (sink, running) = sink();
+    assert_eq!(sink.broadcast("{}"), 0);
+    running.store(false, Ordering::SeqCst);
+  }
+
+  #[test]
+  fn connected_clients_receive_broadcasts_in_order() {
+    let (sink, running) = sink();
+    let (mut client, _) = tungstenite::connect(format!("ws://{}", sink.addr())).unwrap();
+    wait_for_clients(&sink, 1);
+
+    assert_eq!(sink.broadcast("first"), 1);
+    assert_eq!(sink.broadcast("second"), 1);
+    assert_eq!(client.read().unwrap(), Message::Text(String::from("first")));
+    assert_eq!(client.read().unwrap(), Message::Text(String::from("second")));
+    running.store(false, Ordering::SeqCst);
+  }
+
+  #[test]
+  fn clients_are_dropped_on_shutdown() {
+    let (sink, running) = sink();
+    let _client = tungstenite::connect(format!("ws://{}", sink.addr())).unwrap();
+    wait_for_clients(&sink, 1);
+
+    running.store(false, Ordering::SeqCst);
+    wait_for_clients(&sink, 0);
+  }
+}